@@ -105,7 +105,11 @@ impl BottomAccelerationStructure {
                 device.compute_queue_family_index(),
             );
             cmd_buf.encode(|recorder| {
-                recorder.build_acceleration_structure_raw(build_geometry_info, &build_range_infos);
+                recorder.build_acceleration_structure_raw(
+                    name,
+                    build_geometry_info,
+                    &build_range_infos,
+                );
             });
             device.compute_queue().submit_blocking(&[cmd_buf]);
             let device_address = device
@@ -154,11 +158,11 @@ impl Device {
 
 impl Drop for BottomAceelerationStructureRef {
     fn drop(&mut self) {
-        unsafe {
-            self.device
-                .inner
+        let handle = self.handle;
+        self.device.destroy_deferred(move |device| unsafe {
+            device
                 .acceleration_structure_loader
-                .destroy_acceleration_structure(self.handle, None);
-        }
+                .destroy_acceleration_structure(handle, None);
+        });
     }
 }
@@ -0,0 +1,324 @@
+use ash::vk;
+
+use crate::{Buffer, CommandRecorder, Image};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PipelineStage {
+    Vertex,
+    TessellationControl,
+    TessellationEvaluation,
+    Geometry,
+    Fragment,
+    Compute,
+    RayTracing,
+    AllGraphics,
+    AllCommands,
+}
+
+impl PipelineStage {
+    fn flags(self) -> vk::PipelineStageFlags2KHR {
+        match self {
+            PipelineStage::Vertex => vk::PipelineStageFlags2KHR::VERTEX_SHADER,
+            PipelineStage::TessellationControl => {
+                vk::PipelineStageFlags2KHR::TESSELLATION_CONTROL_SHADER
+            }
+            PipelineStage::TessellationEvaluation => {
+                vk::PipelineStageFlags2KHR::TESSELLATION_EVALUATION_SHADER
+            }
+            PipelineStage::Geometry => vk::PipelineStageFlags2KHR::GEOMETRY_SHADER,
+            PipelineStage::Fragment => vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+            PipelineStage::Compute => vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+            PipelineStage::RayTracing => vk::PipelineStageFlags2KHR::RAY_TRACING_SHADER,
+            PipelineStage::AllGraphics => vk::PipelineStageFlags2KHR::ALL_GRAPHICS,
+            PipelineStage::AllCommands => vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+        }
+    }
+}
+
+// A single kind of access to a resource, as used on one side of a barrier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    // Nothing to wait for or to make visible, e.g. freshly created resources.
+    Nothing,
+    IndirectCommandRead,
+    IndexRead,
+    VertexAttributeRead,
+    UniformRead(PipelineStage),
+    ShaderSampledRead(PipelineStage),
+    ShaderRead(PipelineStage),
+    ShaderWrite(PipelineStage),
+    ColorAttachmentRead,
+    ColorAttachmentWrite,
+    DepthStencilAttachmentRead,
+    DepthStencilAttachmentWrite,
+    TransferRead,
+    TransferWrite,
+    HostRead,
+    HostWrite,
+    AccelerationStructureRead(PipelineStage),
+    AccelerationStructureBuildRead,
+    AccelerationStructureBuildWrite,
+    // Any access from any command, the slowest but always correct choice.
+    General,
+}
+
+impl Access {
+    pub fn stage_mask(self) -> vk::PipelineStageFlags2KHR {
+        self.masks().0
+    }
+
+    pub fn access_mask(self) -> vk::AccessFlags2KHR {
+        self.masks().1
+    }
+
+    pub fn is_write(self) -> bool {
+        matches!(
+            self,
+            Access::ShaderWrite(_)
+                | Access::ColorAttachmentWrite
+                | Access::DepthStencilAttachmentWrite
+                | Access::TransferWrite
+                | Access::HostWrite
+                | Access::AccelerationStructureBuildWrite
+                | Access::General
+        )
+    }
+
+    fn masks(self) -> (vk::PipelineStageFlags2KHR, vk::AccessFlags2KHR) {
+        match self {
+            Access::Nothing => (vk::PipelineStageFlags2KHR::NONE, vk::AccessFlags2KHR::NONE),
+            Access::IndirectCommandRead => {
+                (
+                    vk::PipelineStageFlags2KHR::DRAW_INDIRECT,
+                    vk::AccessFlags2KHR::INDIRECT_COMMAND_READ,
+                )
+            }
+            Access::IndexRead => {
+                (
+                    vk::PipelineStageFlags2KHR::INDEX_INPUT,
+                    vk::AccessFlags2KHR::INDEX_READ,
+                )
+            }
+            Access::VertexAttributeRead => {
+                (
+                    vk::PipelineStageFlags2KHR::VERTEX_ATTRIBUTE_INPUT,
+                    vk::AccessFlags2KHR::VERTEX_ATTRIBUTE_READ,
+                )
+            }
+            Access::UniformRead(stage) => (stage.flags(), vk::AccessFlags2KHR::UNIFORM_READ),
+            Access::ShaderSampledRead(stage) => {
+                (stage.flags(), vk::AccessFlags2KHR::SHADER_SAMPLED_READ)
+            }
+            Access::ShaderRead(stage) => (stage.flags(), vk::AccessFlags2KHR::SHADER_STORAGE_READ),
+            Access::ShaderWrite(stage) => {
+                (stage.flags(), vk::AccessFlags2KHR::SHADER_STORAGE_WRITE)
+            }
+            Access::ColorAttachmentRead => {
+                (
+                    vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+                    vk::AccessFlags2KHR::COLOR_ATTACHMENT_READ,
+                )
+            }
+            Access::ColorAttachmentWrite => {
+                (
+                    vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+                    vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
+                )
+            }
+            Access::DepthStencilAttachmentRead => {
+                (
+                    vk::PipelineStageFlags2KHR::EARLY_FRAGMENT_TESTS
+                        | vk::PipelineStageFlags2KHR::LATE_FRAGMENT_TESTS,
+                    vk::AccessFlags2KHR::DEPTH_STENCIL_ATTACHMENT_READ,
+                )
+            }
+            Access::DepthStencilAttachmentWrite => {
+                (
+                    vk::PipelineStageFlags2KHR::EARLY_FRAGMENT_TESTS
+                        | vk::PipelineStageFlags2KHR::LATE_FRAGMENT_TESTS,
+                    vk::AccessFlags2KHR::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                )
+            }
+            Access::TransferRead => {
+                (
+                    vk::PipelineStageFlags2KHR::ALL_TRANSFER,
+                    vk::AccessFlags2KHR::TRANSFER_READ,
+                )
+            }
+            Access::TransferWrite => {
+                (
+                    vk::PipelineStageFlags2KHR::ALL_TRANSFER,
+                    vk::AccessFlags2KHR::TRANSFER_WRITE,
+                )
+            }
+            Access::HostRead => {
+                (
+                    vk::PipelineStageFlags2KHR::HOST,
+                    vk::AccessFlags2KHR::HOST_READ,
+                )
+            }
+            Access::HostWrite => {
+                (
+                    vk::PipelineStageFlags2KHR::HOST,
+                    vk::AccessFlags2KHR::HOST_WRITE,
+                )
+            }
+            Access::AccelerationStructureRead(stage) => {
+                (
+                    stage.flags(),
+                    vk::AccessFlags2KHR::ACCELERATION_STRUCTURE_READ,
+                )
+            }
+            Access::AccelerationStructureBuildRead => {
+                (
+                    vk::PipelineStageFlags2KHR::ACCELERATION_STRUCTURE_BUILD,
+                    vk::AccessFlags2KHR::ACCELERATION_STRUCTURE_READ,
+                )
+            }
+            Access::AccelerationStructureBuildWrite => {
+                (
+                    vk::PipelineStageFlags2KHR::ACCELERATION_STRUCTURE_BUILD,
+                    vk::AccessFlags2KHR::ACCELERATION_STRUCTURE_WRITE,
+                )
+            }
+            Access::General => {
+                (
+                    vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+                    vk::AccessFlags2KHR::MEMORY_READ | vk::AccessFlags2KHR::MEMORY_WRITE,
+                )
+            }
+        }
+    }
+}
+
+// Collects barriers and records them as a single vkCmdPipelineBarrier2 on record().
+#[must_use = "barriers are only recorded by record()"]
+pub struct BarrierBuilder<'r, 'a> {
+    recorder: &'r mut CommandRecorder<'a>,
+    memory_barriers: Vec<vk::MemoryBarrier2KHR>,
+    buffer_barriers: Vec<vk::BufferMemoryBarrier2KHR>,
+    image_barriers: Vec<vk::ImageMemoryBarrier2KHR>,
+}
+
+impl<'r, 'a> BarrierBuilder<'r, 'a> {
+    pub fn memory(mut self, from: Access, to: Access) -> Self {
+        self.memory_barriers.push(
+            vk::MemoryBarrier2KHR::builder()
+                .src_stage_mask(from.stage_mask())
+                .src_access_mask(from.access_mask())
+                .dst_stage_mask(to.stage_mask())
+                .dst_access_mask(to.access_mask())
+                .build(),
+        );
+        self
+    }
+
+    pub fn buffer(self, buffer: &Buffer, from: Access, to: Access) -> Self {
+        self.buffer_range(buffer, 0, vk::WHOLE_SIZE, from, to)
+    }
+
+    pub fn buffer_range(
+        mut self,
+        buffer: &Buffer,
+        offset: u64,
+        size: u64,
+        from: Access,
+        to: Access,
+    ) -> Self {
+        self.buffer_barriers.push(
+            vk::BufferMemoryBarrier2KHR::builder()
+                .src_stage_mask(from.stage_mask())
+                .src_access_mask(from.access_mask())
+                .dst_stage_mask(to.stage_mask())
+                .dst_access_mask(to.access_mask())
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .buffer(buffer.handle())
+                .offset(offset)
+                .size(size)
+                .build(),
+        );
+        self.recorder
+            .command_buffer
+            .resources
+            .push(Box::new(buffer.clone()));
+        self
+    }
+
+    pub fn image(
+        mut self,
+        image: &Image,
+        from: Access,
+        to: Access,
+        from_layout: vk::ImageLayout,
+        to_layout: vk::ImageLayout,
+        range: vk::ImageSubresourceRange,
+    ) -> Self {
+        self.image_barriers.push(
+            vk::ImageMemoryBarrier2KHR::builder()
+                .src_stage_mask(from.stage_mask())
+                .src_access_mask(from.access_mask())
+                .dst_stage_mask(to.stage_mask())
+                .dst_access_mask(to.access_mask())
+                .old_layout(from_layout)
+                .new_layout(to_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.handle())
+                .subresource_range(range)
+                .build(),
+        );
+        self.recorder
+            .command_buffer
+            .resources
+            .push(Box::new(image.clone()));
+        self
+    }
+
+    pub fn record(self) {
+        if self.memory_barriers.is_empty()
+            && self.buffer_barriers.is_empty()
+            && self.image_barriers.is_empty()
+        {
+            return;
+        }
+        let dependency_info = vk::DependencyInfoKHR::builder()
+            .memory_barriers(&self.memory_barriers)
+            .buffer_memory_barriers(&self.buffer_barriers)
+            .image_memory_barriers(&self.image_barriers)
+            .build();
+        self.recorder.pipeline_barrier(&dependency_info);
+    }
+}
+
+impl<'a> CommandRecorder<'a> {
+    pub fn barrier<'r>(&'r mut self) -> BarrierBuilder<'r, 'a> {
+        BarrierBuilder {
+            recorder: self,
+            memory_barriers: Vec::new(),
+            buffer_barriers: Vec::new(),
+            image_barriers: Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn test_access_masks() {
+    let access = Access::ShaderWrite(PipelineStage::RayTracing);
+    assert_eq!(
+        access.stage_mask(),
+        vk::PipelineStageFlags2KHR::RAY_TRACING_SHADER
+    );
+    assert_eq!(
+        access.access_mask(),
+        vk::AccessFlags2KHR::SHADER_STORAGE_WRITE
+    );
+    assert!(access.is_write());
+
+    assert_eq!(
+        Access::TransferRead.stage_mask(),
+        vk::PipelineStageFlags2KHR::ALL_TRANSFER
+    );
+    assert!(!Access::TransferRead.is_write());
+    assert_eq!(Access::Nothing.access_mask(), vk::AccessFlags2KHR::NONE);
+}
@@ -17,6 +17,7 @@ pub(crate) struct BufferRef {
     allocation: Mutex<Allocation>,
     device_address: vk::DeviceAddress,
     size: usize,
+    usage: vk::BufferUsageFlags,
     location: gpu_allocator::MemoryLocation,
 }
 
@@ -51,6 +52,9 @@ impl Buffer {
     where
         I: num_traits::PrimInt,
     {
+        let usage = buffer_usage
+            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+            | vk::BufferUsageFlags::TRANSFER_DST;
         unsafe {
             let handle = device
                 .inner
@@ -58,11 +62,7 @@ impl Buffer {
                 .create_buffer(
                     &vk::BufferCreateInfo::builder()
                         .size(size.to_u64().unwrap())
-                        .usage(
-                            buffer_usage
-                                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
-                                | vk::BufferUsageFlags::TRANSFER_DST,
-                        )
+                        .usage(usage)
                         .sharing_mode(vk::SharingMode::CONCURRENT)
                         .queue_family_indices(device.all_queue_family_indices()),
                     None,
@@ -103,6 +103,7 @@ impl Buffer {
                     allocation: Mutex::new(allocation),
                     device_address,
                     size: size.to_usize().unwrap(),
+                    usage,
                     location,
                 }),
             }
@@ -185,6 +186,10 @@ impl Buffer {
         self.inner.handle
     }
 
+    pub fn usage(&self) -> vk::BufferUsageFlags {
+        self.inner.usage
+    }
+
     // pub fn is_device_local(&self) -> bool {
     //     self.property_flags & vk::MemoryPropertyFlags::DEVICE_LOCAL
     //         != vk::MemoryPropertyFlags::empty()
@@ -204,16 +209,14 @@ impl Buffer {
 
 impl Drop for BufferRef {
     fn drop(&mut self) {
-        self.device
-            .inner
-            .allocator
-            .lock()
-            .unwrap()
-            .free(self.allocation.lock().unwrap().to_owned())
-            .unwrap();
-        unsafe {
-            self.device.inner.handle.destroy_buffer(self.handle, None);
-        }
+        let handle = self.handle;
+        let allocation = self.allocation.lock().unwrap().to_owned();
+        self.device.destroy_deferred(move |device| {
+            device.allocator.lock().unwrap().free(allocation).unwrap();
+            unsafe {
+                device.handle.destroy_buffer(handle, None);
+            }
+        });
     }
 }
 
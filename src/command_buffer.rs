@@ -1,7 +1,9 @@
 use std::ffi::CString;
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
+use anyhow::{ensure, Result};
 use ash::vk;
 use ash::vk::Handle;
 
@@ -15,15 +17,106 @@ impl CommandBufferResource for crate::Framebuffer {}
 impl CommandBufferResource for crate::RenderPass {}
 impl CommandBufferResource for crate::ShaderBindingTables {}
 impl CommandBufferResource for crate::Buffer {}
+impl CommandBufferResource for crate::Image {}
+impl CommandBufferResource for crate::ImageView {}
+impl CommandBufferResource for crate::BinarySemaphore {}
+impl CommandBufferResource for crate::TimelineSemaphore {}
+impl CommandBufferResource for crate::Fence {}
+impl CommandBufferResource for Arc<crate::pipeline::PipelineHandle> {}
+
+// Resources of the last recording, shared with the pool so a pool reset can
+// release them.
+pub(crate) type Retained = Mutex<Vec<Box<dyn CommandBufferResource + Sync + Send>>>;
+
+impl CommandBufferResource for Arc<Retained> {}
+
+// Frees the handle once neither the command buffer nor a primary that executes
+// it holds on to it.
+pub(crate) struct CommandBufferAllocation {
+    pool: CommandPool,
+    handle: vk::CommandBuffer,
+}
+
+impl CommandBufferResource for Arc<CommandBufferAllocation> {}
+
+impl Drop for CommandBufferAllocation {
+    fn drop(&mut self) {
+        self.pool.free(self.handle);
+    }
+}
+
+// What a secondary command buffer inherits from the primary that executes it.
+#[derive(Clone)]
+pub enum CommandBufferInheritance {
+    // Executed outside of any render pass.
+    None,
+    // Executed inside `subpass` of `render_pass`, with contents SECONDARY_COMMAND_BUFFERS.
+    RenderPass {
+        render_pass: crate::RenderPass,
+        subpass: u32,
+        framebuffer: Option<crate::Framebuffer>,
+    },
+    // Executed inside a dynamic rendering scope with these attachment formats.
+    Rendering {
+        color_formats: Vec<vk::Format>,
+        depth_format: vk::Format,
+        stencil_format: vk::Format,
+        samples: vk::SampleCountFlags,
+    },
+}
 
 pub struct CommandBuffer {
+    // Declared first so the pool goes away before the device it belongs to.
+    pub(crate) pool: CommandPool,
     pub(crate) device: Device,
     pub(crate) handle: vk::CommandBuffer,
+    pub(crate) allocation: Arc<CommandBufferAllocation>,
+    // Collected while recording, moved to `retained` once the recording ends.
     pub(crate) resources: Vec<Box<dyn CommandBufferResource + Sync + Send>>,
+    pub(crate) retained: Arc<Retained>,
+    // None for primary command buffers.
+    pub(crate) inheritance: Option<CommandBufferInheritance>,
+    usage: vk::CommandBufferUsageFlags,
+    // Pool generation of the last completed encode, a pool reset invalidates it.
+    recorded_generation: Option<u64>,
+    // Set once a ONE_TIME_SUBMIT recording has been submitted.
+    consumed: AtomicBool,
+    pub(crate) profiler: Option<crate::GpuProfiler>,
 }
 
 impl CommandBuffer {
     pub(crate) fn new(name: Option<&str>, device: &Device, command_pool: &CommandPool) -> Self {
+        Self::allocate(name, device, command_pool, None)
+    }
+
+    pub(crate) fn new_secondary(
+        name: Option<&str>,
+        device: &Device,
+        command_pool: &CommandPool,
+        inheritance: CommandBufferInheritance,
+    ) -> Self {
+        if let CommandBufferInheritance::Rendering { .. } = &inheritance {
+            assert!(
+                device.extension_enabled(crate::name::device::Extension::KhrDynamicRendering),
+                "inheriting dynamic rendering state requires VK_KHR_dynamic_rendering"
+            );
+        }
+        Self::allocate(name, device, command_pool, Some(inheritance))
+    }
+
+    fn allocate(
+        name: Option<&str>,
+        device: &Device,
+        command_pool: &CommandPool,
+        inheritance: Option<CommandBufferInheritance>,
+    ) -> Self {
+        let _guard = command_pool.lock();
+        command_pool.free_pending();
+        let level = if inheritance.is_some() {
+            vk::CommandBufferLevel::SECONDARY
+        } else {
+            vk::CommandBufferLevel::PRIMARY
+        };
         unsafe {
             let handle = device
                 .inner
@@ -32,7 +125,7 @@ impl CommandBuffer {
                     &vk::CommandBufferAllocateInfo::builder()
                         .command_pool(command_pool.inner.handle)
                         .command_buffer_count(1)
-                        .level(vk::CommandBufferLevel::PRIMARY)
+                        .level(level)
                         .build(),
                 )
                 .unwrap()
@@ -43,11 +136,83 @@ impl CommandBuffer {
                 device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::COMMAND_BUFFER);
             }
 
+            let retained = Arc::new(Mutex::new(Vec::new()));
+            command_pool.register_retained(&retained);
             Self {
+                pool: command_pool.clone(),
                 handle,
+                allocation: Arc::new(CommandBufferAllocation {
+                    pool: command_pool.clone(),
+                    handle,
+                }),
                 device: device.clone(),
                 resources: Vec::new(),
+                retained,
+                inheritance,
+                usage: vk::CommandBufferUsageFlags::empty(),
+                recorded_generation: None,
+                consumed: AtomicBool::new(false),
+                profiler: None,
+            }
+        }
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        self.inheritance.is_some()
+    }
+
+    pub fn queue_family_index(&self) -> u32 {
+        self.pool.queue_family_index()
+    }
+
+    // Whether the last encode is still valid, i.e. the pool was not reset since.
+    pub fn is_recorded(&self) -> bool {
+        self.recorded_generation == Some(self.pool.generation())
+    }
+
+    // Returns the command buffer to the initial state and releases what it retained.
+    // Only valid for buffers that are not pending execution.
+    pub fn reset(&mut self) {
+        assert!(
+            self.pool.resets_individually(),
+            "command buffers of this pool are reset with CommandPool::reset"
+        );
+        let released = {
+            let _guard = self.pool.lock();
+            unsafe {
+                self.device
+                    .inner
+                    .handle
+                    .reset_command_buffer(self.handle, vk::CommandBufferResetFlags::empty())
+                    .unwrap();
             }
+            std::mem::take(&mut *self.retained.lock().unwrap())
+        };
+        drop(released);
+        self.recorded_generation = None;
+    }
+
+    pub(crate) fn check_submittable(&self) -> Result<()> {
+        ensure!(
+            self.is_recorded(),
+            "command buffer must be encoded before it is submitted"
+        );
+        ensure!(
+            !self
+                .usage
+                .contains(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+                || !self.consumed.load(Ordering::Acquire),
+            "one time submit command buffers must be encoded again before resubmission"
+        );
+        Ok(())
+    }
+
+    pub(crate) fn mark_submitted(&self) {
+        if self
+            .usage
+            .contains(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+        {
+            self.consumed.store(true, Ordering::Release);
         }
     }
 
@@ -75,20 +240,100 @@ impl CommandBuffer {
     where
         F: FnOnce(&mut CommandRecorder),
     {
+        self.encode_with_usage(vk::CommandBufferUsageFlags::empty(), func);
+    }
+
+    // `usage` takes ONE_TIME_SUBMIT and SIMULTANEOUS_USE, RENDER_PASS_CONTINUE is
+    // derived from the inheritance. Encoding again implicitly resets the buffer.
+    pub fn encode_with_usage<F>(&mut self, usage: vk::CommandBufferUsageFlags, func: F)
+    where
+        F: FnOnce(&mut CommandRecorder),
+    {
+        assert!(
+            !usage.contains(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE),
+            "RENDER_PASS_CONTINUE follows from the command buffer inheritance"
+        );
+        assert!(
+            !self.is_recorded() || self.pool.resets_individually(),
+            "reset the command pool before encoding this command buffer again"
+        );
+        let released = std::mem::take(&mut *self.retained.lock().unwrap());
+        drop(released);
+        self.recorded_generation = None;
+        let pool = self.pool.clone();
+        let _guard = pool.lock();
         unsafe {
             let device = self.device.inner.handle.clone();
+            let mut rendering_info =
+                crate::dynamic_rendering::CommandBufferInheritanceRenderingInfo::default();
+            let mut inheritance_info = vk::CommandBufferInheritanceInfo::builder();
+            let mut flags = usage;
+            match &self.inheritance {
+                None | Some(CommandBufferInheritance::None) => {}
+                Some(CommandBufferInheritance::RenderPass {
+                    render_pass,
+                    subpass,
+                    framebuffer,
+                }) => {
+                    inheritance_info = inheritance_info
+                        .render_pass(render_pass.inner.handle)
+                        .subpass(*subpass);
+                    if let Some(framebuffer) = framebuffer {
+                        inheritance_info = inheritance_info.framebuffer(framebuffer.inner.handle);
+                    }
+                    flags |= vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE;
+                }
+                Some(CommandBufferInheritance::Rendering {
+                    color_formats,
+                    depth_format,
+                    stencil_format,
+                    samples,
+                }) => {
+                    rendering_info.color_attachment_count = color_formats.len() as u32;
+                    rendering_info.p_color_attachment_formats = color_formats.as_ptr();
+                    rendering_info.depth_attachment_format = *depth_format;
+                    rendering_info.stencil_attachment_format = *stencil_format;
+                    rendering_info.rasterization_samples = *samples;
+                    inheritance_info = inheritance_info.push_next(&mut rendering_info);
+                    flags |= vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE;
+                }
+            }
+            let mut begin_info = vk::CommandBufferBeginInfo::builder().flags(flags);
+            if self.inheritance.is_some() {
+                begin_info = begin_info.inheritance_info(&inheritance_info);
+            }
             device
-                .begin_command_buffer(self.handle, &vk::CommandBufferBeginInfo::default())
+                .begin_command_buffer(self.handle, &begin_info)
                 .unwrap();
 
+            if let Some(CommandBufferInheritance::RenderPass {
+                render_pass,
+                framebuffer,
+                ..
+            }) = self.inheritance.clone()
+            {
+                self.resources.push(Box::new(render_pass));
+                if let Some(framebuffer) = framebuffer {
+                    self.resources.push(Box::new(framebuffer));
+                }
+            }
+
             let mut recorder = CommandRecorder {
                 command_buffer: self,
                 bind_point: None,
                 pipeline_layout: None,
+                ray_tracing_pipeline: None,
+                subpass_contents: None,
+                rendering_color_formats: None,
+                current_profile_scope: None,
             };
             func(&mut recorder);
             device.end_command_buffer(self.handle).unwrap();
         }
+        *self.retained.lock().unwrap() = std::mem::take(&mut self.resources);
+        self.usage = usage;
+        self.recorded_generation = Some(self.pool.generation());
+        self.consumed.store(false, Ordering::Release);
     }
 }
 
@@ -100,6 +345,22 @@ impl Device {
     ) -> CommandBuffer {
         CommandBuffer::new(name, self, &self.command_pool(queue_family_index))
     }
+
+    // Secondary command buffers come from the calling thread's pool, so create
+    // them on the thread that records them.
+    pub fn create_secondary_command_buffer(
+        &self,
+        name: Option<&str>,
+        queue_family_index: u32,
+        inheritance: CommandBufferInheritance,
+    ) -> CommandBuffer {
+        CommandBuffer::new_secondary(
+            name,
+            self,
+            &self.command_pool(queue_family_index),
+            inheritance,
+        )
+    }
 }
 
 #[test]
@@ -133,3 +394,45 @@ fn test_command_buffer() {
     let device = pdevice.create_device();
     // device.allocate_command_buffer();
 }
+
+#[test]
+fn test_parallel_secondary_command_buffers() {
+    use crate::device::test_device;
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+    let device = test_device();
+    let queue_family_index = device.graphics_queue_family_index();
+
+    let dst = device.create_buffer(
+        Some("dst"),
+        64,
+        vk::BufferUsageFlags::empty(),
+        gpu_allocator::MemoryLocation::GpuToCpu,
+    );
+    let secondaries = (0..16u32)
+        .into_par_iter()
+        .map(|i| {
+            let mut secondary = device.create_secondary_command_buffer(
+                Some("fill"),
+                queue_family_index,
+                CommandBufferInheritance::None,
+            );
+            secondary.encode(|recorder| {
+                recorder.fill_buffer(&dst, i as u64 * 4, 4, i);
+            });
+            secondary
+        })
+        .collect::<Vec<_>>();
+
+    let mut cmd_buf = device.create_command_buffer(Some("primary"), queue_family_index);
+    cmd_buf.encode(|recorder| {
+        recorder.execute_commands(&secondaries);
+    });
+    // The primary keeps the secondaries alive.
+    drop(secondaries);
+    device.graphics_queue().submit_blocking(&[cmd_buf]);
+
+    let guard = dst.lock_memory().unwrap();
+    let values: &[u32] = bytemuck::cast_slice(&guard.mapped_slice().unwrap()[..64]);
+    assert_eq!(values, (0..16).collect::<Vec<u32>>().as_slice());
+}
@@ -1,16 +1,82 @@
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::ThreadId;
 
 use ash::vk;
+use ash::vk::Handle;
 
+use crate::command_buffer::{CommandBuffer, CommandBufferInheritance, Retained};
 use crate::device::Device;
 
+// Vulkan requires the pool and its command buffers to be used by one thread at a
+// time. Reentrant, so a thread recording a command buffer of the pool can still
+// allocate from it, e.g. for an upload inside the encode closure.
+#[derive(Default)]
+struct PoolLock {
+    // The owning thread and how often it took the lock.
+    owner: Mutex<Option<(ThreadId, usize)>>,
+    released: Condvar,
+}
+
+pub(crate) struct PoolGuard<'a> {
+    lock: &'a PoolLock,
+}
+
+impl PoolLock {
+    fn lock(&self) -> PoolGuard<'_> {
+        let thread = std::thread::current().id();
+        let mut owner = self.owner.lock().unwrap();
+        loop {
+            match *owner {
+                Some((id, ref mut depth)) if id == thread => {
+                    *depth += 1;
+                    break;
+                }
+                None => {
+                    *owner = Some((thread, 1));
+                    break;
+                }
+                Some(_) => {}
+            }
+            owner = self.released.wait(owner).unwrap();
+        }
+        PoolGuard { lock: self }
+    }
+}
+
+impl<'a> Drop for PoolGuard<'a> {
+    fn drop(&mut self) {
+        let mut owner = self.lock.owner.lock().unwrap();
+        if let Some((_, depth)) = owner.as_mut() {
+            *depth -= 1;
+            if *depth == 0 {
+                *owner = None;
+                self.lock.released.notify_one();
+            }
+        }
+    }
+}
+
 pub(crate) struct CommandPoolRef {
     pub(crate) handle: vk::CommandPool,
     device_handle: ash::Device,
+    // Pools created by users keep the device alive. The device's own thread-local
+    // pools can't, that would be a reference cycle.
+    device: Option<Device>,
+    queue_family_index: u32,
+    flags: vk::CommandPoolCreateFlags,
+    // Command buffers dropped since the last allocate or reset. Freeing them is
+    // deferred so that drops on other threads never touch the pool directly.
+    pending_frees: Mutex<Vec<vk::CommandBuffer>>,
+    // Bumped by every reset, recorded command buffers remember the value.
+    generation: AtomicU64,
+    lock: PoolLock,
+    // What the command buffers of the pool retained, released by a pool reset.
+    retained: Mutex<Vec<Weak<Retained>>>,
 }
 
 #[derive(Clone)]
-pub(crate) struct CommandPool {
+pub struct CommandPool {
     pub(crate) inner: Arc<CommandPoolRef>,
 }
 
@@ -23,7 +89,13 @@ impl std::fmt::Debug for CommandPool {
 }
 
 impl CommandPool {
-    pub fn new(device: Device, queue_family_index: u32) -> Self {
+    pub(crate) fn new(
+        device: &Device,
+        name: Option<&str>,
+        queue_family_index: u32,
+        flags: vk::CommandPoolCreateFlags,
+        keep_device_alive: bool,
+    ) -> Self {
         log::debug!("creating command pool");
         unsafe {
             let handle = device
@@ -32,36 +104,183 @@ impl CommandPool {
                 .create_command_pool(
                     &vk::CommandPoolCreateInfo::builder()
                         .queue_family_index(queue_family_index)
+                        .flags(flags)
                         .build(),
                     None,
                 )
                 .unwrap();
+            if let Some(name) = name {
+                device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::COMMAND_POOL);
+            }
             Self {
                 inner: Arc::new(CommandPoolRef {
                     handle,
                     device_handle: device.handle().clone(),
+                    device: if keep_device_alive {
+                        Some(device.clone())
+                    } else {
+                        None
+                    },
+                    queue_family_index,
+                    flags,
+                    pending_frees: Mutex::new(Vec::new()),
+                    generation: AtomicU64::new(0),
+                    lock: PoolLock::default(),
+                    retained: Mutex::new(Vec::new()),
                 }),
             }
         }
     }
 
-    // pub fn allocate_command_buffer(&self) {
-    //     unsafe {
-    //         let command_buffers = self
-    //             .device
-    //             .inner
-    //             .handle
-    //             .allocate_command_buffers(&vk::CommandBufferAllocateInfo::builder().build())
-    //             .unwrap();
-    //     }
-    // }
+    pub fn queue_family_index(&self) -> u32 {
+        self.inner.queue_family_index
+    }
+
+    // Whether command buffers from this pool can be reset one by one.
+    pub fn resets_individually(&self) -> bool {
+        self.inner
+            .flags
+            .contains(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.inner.generation.load(Ordering::Acquire)
+    }
+
+    pub fn allocate_command_buffer(&self, name: Option<&str>) -> CommandBuffer {
+        CommandBuffer::new(name, self.device(), self)
+    }
+
+    pub fn allocate_secondary_command_buffer(
+        &self,
+        name: Option<&str>,
+        inheritance: CommandBufferInheritance,
+    ) -> CommandBuffer {
+        CommandBuffer::new_secondary(name, self.device(), self, inheritance)
+    }
+
+    fn device(&self) -> &Device {
+        // Only pools from `Device::create_command_pool` are handed out to users.
+        self.inner.device.as_ref().unwrap()
+    }
+
+    // Held while the pool handle or one of its command buffers is in use.
+    pub(crate) fn lock(&self) -> PoolGuard<'_> {
+        self.inner.lock.lock()
+    }
+
+    pub(crate) fn register_retained(&self, retained: &Arc<Retained>) {
+        let mut list = self.inner.retained.lock().unwrap();
+        list.retain(|weak| weak.strong_count() > 0);
+        list.push(Arc::downgrade(retained));
+    }
+
+    // Returns every command buffer of the pool to the initial state and releases
+    // what they retained. Only call this once all of their submissions have
+    // completed, e.g. after waiting on the frame's fence or timeline value.
+    pub fn reset(&self) {
+        let released = {
+            let _guard = self.lock();
+            self.free_pending();
+            unsafe {
+                self.inner
+                    .device_handle
+                    .reset_command_pool(self.inner.handle, vk::CommandPoolResetFlags::empty())
+                    .unwrap();
+            }
+            self.inner.generation.fetch_add(1, Ordering::AcqRel);
+            let mut list = self.inner.retained.lock().unwrap();
+            list.retain(|weak| weak.strong_count() > 0);
+            let released = list
+                .iter()
+                .filter_map(Weak::upgrade)
+                .map(|retained| {
+                    let mut retained = retained.lock().unwrap();
+                    std::mem::take(&mut *retained)
+                })
+                .collect::<Vec<_>>();
+            released
+        };
+        // Dropped outside the locks, these may be command buffers of this pool.
+        drop(released);
+    }
+
+    pub(crate) fn free(&self, command_buffer: vk::CommandBuffer) {
+        self.inner
+            .pending_frees
+            .lock()
+            .unwrap()
+            .push(command_buffer);
+    }
+
+    pub(crate) fn free_pending(&self) {
+        let _guard = self.lock();
+        let pending = std::mem::take(&mut *self.inner.pending_frees.lock().unwrap());
+        if !pending.is_empty() {
+            unsafe {
+                self.inner
+                    .device_handle
+                    .free_command_buffers(self.inner.handle, &pending);
+            }
+        }
+    }
+}
+
+impl Device {
+    // A pool for one frame's worth of command buffers, which are recycled
+    // together with `CommandPool::reset`.
+    pub fn create_command_pool(&self, name: Option<&str>, queue_family_index: u32) -> CommandPool {
+        CommandPool::new(
+            self,
+            name,
+            queue_family_index,
+            vk::CommandPoolCreateFlags::TRANSIENT,
+            true,
+        )
+    }
 }
 
 impl Drop for CommandPoolRef {
     fn drop(&mut self) {
         unsafe {
             log::debug!("destroying command pool");
+            // Destroying the pool frees all command buffers still allocated from it.
             self.device_handle.destroy_command_pool(self.handle, None);
         }
     }
 }
+
+#[test]
+fn test_command_pool_reset() {
+    use crate::device::test_device;
+
+    let device = test_device();
+
+    let dst = device.create_buffer(
+        Some("dst"),
+        4,
+        vk::BufferUsageFlags::empty(),
+        gpu_allocator::MemoryLocation::GpuToCpu,
+    );
+    let pool = device.create_command_pool(Some("frame"), device.graphics_queue_family_index());
+    let mut cmd_buf = pool.allocate_command_buffer(Some("fill"));
+    for frame in 0..3u32 {
+        cmd_buf.encode_with_usage(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT, |recorder| {
+            recorder.fill_buffer(&dst, 0, 4, frame);
+        });
+        device
+            .graphics_queue()
+            .submit_blocking(std::slice::from_ref(&cmd_buf));
+        let guard = dst.lock_memory().unwrap();
+        assert_eq!(
+            bytemuck::cast_slice::<u8, u32>(&guard.mapped_slice().unwrap()[..4]),
+            &[frame]
+        );
+        drop(guard);
+
+        pool.reset();
+        assert!(!cmd_buf.is_recorded());
+        // The reset released the buffer retained by the recording.
+        assert_eq!(Arc::strong_count(&dst.inner), 1);
+    }
+}
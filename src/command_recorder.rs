@@ -1,24 +1,231 @@
-use crate::command_buffer::CommandBufferResource;
+use std::sync::Arc;
+
+use crate::command_buffer::{CommandBufferInheritance, CommandBufferResource};
+use crate::debug_label::{render_pass_label, AutomaticLabel};
+use crate::pipeline::RayTracingPipelineVersion;
 use crate::{
     Buffer, CommandBuffer, DescriptorSet, Device, Framebuffer, GraphicsPipeline, Image,
     PipelineLayout, RenderPass,
 };
 use ash::vk;
 
+// Layout of one record consumed by `draw_indirect`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DrawIndirectCommand {
+    pub vertex_count: u32,
+    pub instance_count: u32,
+    pub first_vertex: u32,
+    pub first_instance: u32,
+}
+
+// Layout of one record consumed by `draw_indexed_indirect`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DrawIndexedIndirectCommand {
+    pub index_count: u32,
+    pub instance_count: u32,
+    pub first_index: u32,
+    pub vertex_offset: i32,
+    pub first_instance: u32,
+}
+
+unsafe impl bytemuck::Zeroable for DrawIndirectCommand {}
+unsafe impl bytemuck::Pod for DrawIndirectCommand {}
+unsafe impl bytemuck::Zeroable for DrawIndexedIndirectCommand {}
+unsafe impl bytemuck::Pod for DrawIndexedIndirectCommand {}
+
 pub struct CommandRecorder<'a> {
     pub(crate) command_buffer: &'a mut CommandBuffer,
     pub(crate) bind_point: Option<vk::PipelineBindPoint>,
     pub(crate) pipeline_layout: Option<PipelineLayout>,
+    // The bound ray tracing pipeline and the build its shader binding tables
+    // are resolved against.
+    pub(crate) ray_tracing_pipeline:
+        Option<(crate::RayTracingPipeline, Arc<RayTracingPipelineVersion>)>,
+    // Set while recording inside a render pass instance.
+    pub(crate) subpass_contents: Option<vk::SubpassContents>,
+    // Color formats of the current `begin_rendering` instance.
+    pub(crate) rendering_color_formats: Option<Vec<vk::Format>>,
+    // Innermost open `profile_scope`.
+    pub(crate) current_profile_scope: Option<usize>,
 }
 
 impl<'a> CommandRecorder<'a> {
-    // pub fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer, region: &[vk::BufferCopy]) {
-    //     unsafe {
-    //         self.copy_buffer_raw(src, dst, region);
-    //     }
-    //     self.command_buffer.resources.push(src);
-    //     self.command_buffer.resources.push(dst);
-    // }
+    pub fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer, regions: &[vk::BufferCopy]) {
+        for region in regions {
+            assert!(region.size > 0, "buffer copies must not be empty");
+            assert!(
+                region
+                    .src_offset
+                    .checked_add(region.size)
+                    .map_or(false, |end| end <= src.size() as u64),
+                "source range of {} bytes at offset {} exceeds buffer size {}",
+                region.size,
+                region.src_offset,
+                src.size()
+            );
+            assert!(
+                region
+                    .dst_offset
+                    .checked_add(region.size)
+                    .map_or(false, |end| end <= dst.size() as u64),
+                "destination range of {} bytes at offset {} exceeds buffer size {}",
+                region.size,
+                region.dst_offset,
+                dst.size()
+            );
+        }
+        unsafe {
+            self.copy_buffer_raw(src, dst, regions);
+        }
+        self.command_buffer.resources.push(Box::new(src.clone()));
+        self.command_buffer.resources.push(Box::new(dst.clone()));
+    }
+
+    // Copies tightly packed texels starting at `src_offset` into whole mip level
+    // `mip_level` of the selected array layers.
+    pub fn copy_buffer_to_image(
+        &mut self,
+        src: &Buffer,
+        src_offset: u64,
+        dst: &Image,
+        mip_level: u32,
+        base_array_layer: u32,
+        layer_count: u32,
+    ) {
+        assert!(
+            mip_level < dst.mip_levels(),
+            "mip level {} out of range, image has {} levels",
+            mip_level,
+            dst.mip_levels()
+        );
+        assert!(
+            layer_count > 0
+                && base_array_layer
+                    .checked_add(layer_count)
+                    .map_or(false, |end| end <= dst.array_layers()),
+            "{} array layers from {} out of range, image has {} layers",
+            layer_count,
+            base_array_layer,
+            dst.array_layers()
+        );
+        let aspect_mask = crate::image::format_aspect_mask(dst.format());
+        // Buffer copies write one aspect at a time.
+        assert!(
+            aspect_mask != vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL,
+            "copies into combined depth/stencil format {:?} are not supported",
+            dst.format()
+        );
+        let extent = dst.mip_extent(mip_level);
+        // Formats without a known block layout are left to the validation layers.
+        if let Some((block_width, block_height, block_size)) =
+            crate::image::block_layout(dst.format())
+        {
+            let size = ((extent.width + block_width - 1) / block_width) as u64
+                * ((extent.height + block_height - 1) / block_height) as u64
+                * extent.depth as u64
+                * layer_count as u64
+                * block_size;
+            assert!(
+                src_offset
+                    .checked_add(size)
+                    .map_or(false, |end| end <= src.size() as u64),
+                "copy reads {} bytes at offset {} from a {} byte buffer",
+                size,
+                src_offset,
+                src.size()
+            );
+        }
+        unsafe {
+            self.copy_buffer_to_image_raw(
+                src,
+                dst,
+                &[vk::BufferImageCopy::builder()
+                    .buffer_offset(src_offset)
+                    .buffer_row_length(0)
+                    .buffer_image_height(0)
+                    .image_subresource(
+                        vk::ImageSubresourceLayers::builder()
+                            .aspect_mask(aspect_mask)
+                            .mip_level(mip_level)
+                            .base_array_layer(base_array_layer)
+                            .layer_count(layer_count)
+                            .build(),
+                    )
+                    .image_offset(vk::Offset3D::default())
+                    .image_extent(extent)
+                    .build()],
+            );
+        }
+        self.command_buffer.resources.push(Box::new(src.clone()));
+        self.command_buffer.resources.push(Box::new(dst.clone()));
+    }
+
+    // Inline writes of at most 65536 bytes, recorded directly into the command buffer.
+    pub fn update_buffer(&mut self, dst: &Buffer, offset: u64, data: &[u8]) {
+        assert_eq!(offset % 4, 0, "update offset must be 4 byte aligned");
+        assert!(
+            !data.is_empty() && data.len() % 4 == 0 && data.len() <= 65536,
+            "update size must be a non-zero multiple of 4 of at most 65536 bytes, got {}",
+            data.len()
+        );
+        assert!(
+            offset
+                .checked_add(data.len() as u64)
+                .map_or(false, |end| end <= dst.size() as u64),
+            "update range of {} bytes at offset {} exceeds buffer size {}",
+            data.len(),
+            offset,
+            dst.size()
+        );
+        unsafe {
+            self.device_handle().cmd_update_buffer(
+                self.command_buffer.handle,
+                dst.handle(),
+                offset,
+                data,
+            );
+        }
+        self.command_buffer.resources.push(Box::new(dst.clone()));
+    }
+
+    // `size` may be vk::WHOLE_SIZE to fill up to the end of the buffer.
+    pub fn fill_buffer(&mut self, dst: &Buffer, offset: u64, size: u64, data: u32) {
+        assert_eq!(offset % 4, 0, "fill offset must be 4 byte aligned");
+        assert!(
+            offset < dst.size() as u64,
+            "fill offset {} exceeds buffer size {}",
+            offset,
+            dst.size()
+        );
+        if size != vk::WHOLE_SIZE {
+            assert!(
+                size > 0 && size % 4 == 0,
+                "fill size must be a non-zero multiple of 4, got {}",
+                size
+            );
+            assert!(
+                offset
+                    .checked_add(size)
+                    .map_or(false, |end| end <= dst.size() as u64),
+                "fill range of {} bytes at offset {} exceeds buffer size {}",
+                size,
+                offset,
+                dst.size()
+            );
+        }
+        unsafe {
+            self.device_handle().cmd_fill_buffer(
+                self.command_buffer.handle,
+                dst.handle(),
+                offset,
+                size,
+                data,
+            );
+        }
+        self.command_buffer.resources.push(Box::new(dst.clone()));
+    }
 
     pub(crate) unsafe fn copy_buffer_raw(
         &mut self,
@@ -38,19 +245,25 @@ impl<'a> CommandRecorder<'a> {
 
     pub(crate) fn build_acceleration_structure_raw(
         &mut self,
+        name: Option<&str>,
         info: vk::AccelerationStructureBuildGeometryInfoKHR,
         build_range_infos: &[vk::AccelerationStructureBuildRangeInfoKHR],
     ) {
-        unsafe {
-            self.device()
-                .inner
-                .acceleration_structure_loader
-                .cmd_build_acceleration_structures(
-                    self.command_buffer.handle,
-                    &[info],
-                    &[build_range_infos],
-                );
-        }
+        self.automatic_label(
+            || format!("build {}", name.unwrap_or("acceleration structure")),
+            AutomaticLabel::AccelerationStructure,
+            |recorder| unsafe {
+                recorder
+                    .device()
+                    .inner
+                    .acceleration_structure_loader
+                    .cmd_build_acceleration_structures(
+                        recorder.command_buffer.handle,
+                        &[info],
+                        &[build_range_infos],
+                    );
+            },
+        );
     }
 
     pub fn begin_render_pass<I>(
@@ -61,6 +274,44 @@ impl<'a> CommandRecorder<'a> {
     ) where
         I: FnOnce(&mut CommandRecorder),
     {
+        self.begin_render_pass_with_contents(
+            render_pass,
+            framebuffer,
+            vk::SubpassContents::INLINE,
+            f,
+        );
+    }
+
+    // Like `begin_render_pass`, but the subpass is filled by `execute_commands` only.
+    pub fn begin_render_pass_with_secondaries<I>(
+        &mut self,
+        render_pass: &RenderPass,
+        framebuffer: &Framebuffer,
+        f: I,
+    ) where
+        I: FnOnce(&mut CommandRecorder),
+    {
+        self.begin_render_pass_with_contents(
+            render_pass,
+            framebuffer,
+            vk::SubpassContents::SECONDARY_COMMAND_BUFFERS,
+            f,
+        );
+    }
+
+    fn begin_render_pass_with_contents<I>(
+        &mut self,
+        render_pass: &RenderPass,
+        framebuffer: &Framebuffer,
+        contents: vk::SubpassContents,
+        f: I,
+    ) where
+        I: FnOnce(&mut CommandRecorder),
+    {
+        assert!(
+            self.subpass_contents.is_none(),
+            "render passes cannot be nested"
+        );
         self.command_buffer
             .resources
             .push(Box::new(render_pass.clone()));
@@ -68,6 +319,22 @@ impl<'a> CommandRecorder<'a> {
             .resources
             .push(Box::new(framebuffer.clone()));
 
+        self.automatic_label(
+            || render_pass_label(framebuffer),
+            AutomaticLabel::RenderPass,
+            |recorder| recorder.record_render_pass(render_pass, framebuffer, contents, f),
+        );
+    }
+
+    fn record_render_pass<I>(
+        &mut self,
+        render_pass: &RenderPass,
+        framebuffer: &Framebuffer,
+        contents: vk::SubpassContents,
+        f: I,
+    ) where
+        I: FnOnce(&mut CommandRecorder),
+    {
         unsafe {
             let info = vk::RenderPassBeginInfo::builder()
                 .render_pass(render_pass.inner.handle)
@@ -84,16 +351,82 @@ impl<'a> CommandRecorder<'a> {
             self.device().handle().cmd_begin_render_pass(
                 self.command_buffer.handle,
                 &info,
-                vk::SubpassContents::INLINE,
+                contents,
             );
+            self.subpass_contents = Some(contents);
 
             f(self);
 
+            self.subpass_contents = None;
             self.device()
                 .handle()
                 .cmd_end_render_pass(self.command_buffer.handle);
-            // self.command_buffer.resources.push(render_pass);
-            // self.command_buffer.resources.push(framebuffer);
+        }
+    }
+
+    // Executes recorded secondary command buffers. The primary keeps their handles
+    // and everything they retain alive until it is reset, encoded again or dropped.
+    pub fn execute_commands(&mut self, secondaries: &[CommandBuffer]) {
+        assert!(
+            !self.command_buffer.is_secondary(),
+            "secondary command buffers cannot execute other command buffers"
+        );
+        assert_ne!(
+            self.subpass_contents,
+            Some(vk::SubpassContents::INLINE),
+            "the current subpass records its commands inline"
+        );
+        let in_render_pass = self.subpass_contents.is_some();
+        for secondary in secondaries {
+            assert!(
+                secondary.is_secondary(),
+                "only secondary command buffers can be executed"
+            );
+            assert!(
+                secondary.is_recorded(),
+                "secondary command buffer was never encoded"
+            );
+            assert_eq!(
+                secondary.queue_family_index(),
+                self.command_buffer.queue_family_index(),
+                "secondary command buffer belongs to another queue family"
+            );
+            let continues_render_pass =
+                !matches!(secondary.inheritance, Some(CommandBufferInheritance::None));
+            assert_eq!(
+                continues_render_pass, in_render_pass,
+                "secondary command buffer inheritance does not match the render pass state"
+            );
+            match &secondary.inheritance {
+                Some(CommandBufferInheritance::RenderPass { .. }) => {
+                    assert!(
+                        self.rendering_color_formats.is_none(),
+                        "secondary command buffer continues a render pass, not dynamic rendering"
+                    );
+                }
+                Some(CommandBufferInheritance::Rendering { color_formats, .. }) => {
+                    assert_eq!(
+                        self.rendering_color_formats.as_ref(),
+                        Some(color_formats),
+                        "secondary command buffer inherits other rendering attachments"
+                    );
+                }
+                _ => {}
+            }
+        }
+        let handles = secondaries.iter().map(|c| c.handle).collect::<Vec<_>>();
+        unsafe {
+            self.device()
+                .handle()
+                .cmd_execute_commands(self.command_buffer.handle, &handles);
+        }
+        for secondary in secondaries {
+            self.command_buffer
+                .resources
+                .push(Box::new(secondary.allocation.clone()));
+            self.command_buffer
+                .resources
+                .push(Box::new(secondary.retained.clone()));
         }
     }
 
@@ -101,34 +434,52 @@ impl<'a> CommandRecorder<'a> {
     where
         I: FnOnce(&mut CommandRecorder),
     {
-        unsafe {
-            self.device().handle().cmd_bind_pipeline(
-                self.command_buffer.handle,
-                vk::PipelineBindPoint::RAY_TRACING_KHR,
-                pipeline.inner.handle,
-            );
-            self.bind_point = Some(vk::PipelineBindPoint::RAY_TRACING_KHR);
-            self.pipeline_layout = Some(pipeline.inner.layout.clone());
-            f(self);
-        }
-        // self.command_buffer.resources.push(pipeline);
+        self.automatic_label(
+            || {
+                pipeline
+                    .inner
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| "ray tracing".to_owned())
+            },
+            AutomaticLabel::RayTracing,
+            |recorder| {
+                let version = pipeline.version();
+                unsafe {
+                    recorder.device().handle().cmd_bind_pipeline(
+                        recorder.command_buffer.handle,
+                        vk::PipelineBindPoint::RAY_TRACING_KHR,
+                        version.handle.handle,
+                    );
+                }
+                recorder
+                    .command_buffer
+                    .resources
+                    .push(Box::new(version.handle.clone()));
+                recorder.bind_point = Some(vk::PipelineBindPoint::RAY_TRACING_KHR);
+                recorder.pipeline_layout = Some(pipeline.inner.layout.clone());
+                recorder.ray_tracing_pipeline = Some((pipeline.clone(), version));
+                f(recorder);
+            },
+        );
     }
 
     pub fn bind_graphics_pipeline<I>(&mut self, pipeline: &GraphicsPipeline, f: I)
     where
         I: FnOnce(&mut CommandRecorder),
     {
+        let handle = pipeline.current_handle();
         unsafe {
             self.device().handle().cmd_bind_pipeline(
                 self.command_buffer.handle,
                 vk::PipelineBindPoint::GRAPHICS,
-                pipeline.inner.handle,
+                handle.handle,
             );
-            self.bind_point = Some(vk::PipelineBindPoint::GRAPHICS);
-            self.pipeline_layout = Some(pipeline.inner.layout.clone());
-            f(self);
         }
-        // self.command_buffer.resources.push(pipeline);
+        self.command_buffer.resources.push(Box::new(handle));
+        self.bind_point = Some(vk::PipelineBindPoint::GRAPHICS);
+        self.pipeline_layout = Some(pipeline.inner.layout.clone());
+        f(self);
     }
 
     pub fn bind_descriptor_sets(&mut self, descriptor_sets: Vec<&DescriptorSet>, first_set: u32) {
@@ -207,19 +558,231 @@ impl<'a> CommandRecorder<'a> {
         //     .for_each(|b| self.command_buffer.resources.push(b));
     }
 
-    pub fn draw_indexed(&self, index_count: u32, instance_count: u32) {
+    pub fn draw(
+        &self,
+        vertex_count: u32,
+        instance_count: u32,
+        first_vertex: u32,
+        first_instance: u32,
+    ) {
+        unsafe {
+            self.device().handle().cmd_draw(
+                self.command_buffer.handle,
+                vertex_count,
+                instance_count,
+                first_vertex,
+                first_instance,
+            );
+        }
+    }
+
+    pub fn draw_indexed(
+        &self,
+        index_count: u32,
+        instance_count: u32,
+        first_index: u32,
+        vertex_offset: i32,
+        first_instance: u32,
+    ) {
         unsafe {
             self.device().handle().cmd_draw_indexed(
                 self.command_buffer.handle,
                 index_count,
                 instance_count,
-                0,
-                0,
-                0,
+                first_index,
+                vertex_offset,
+                first_instance,
             );
         }
     }
 
+    // `buffer` holds `draw_count` DrawIndirectCommand records, `stride` bytes apart.
+    pub fn draw_indirect(&mut self, buffer: &Buffer, offset: u64, draw_count: u32, stride: u32) {
+        self.validate_indirect_buffer(
+            buffer,
+            offset,
+            draw_count,
+            stride,
+            std::mem::size_of::<DrawIndirectCommand>(),
+        );
+        unsafe {
+            self.device().handle().cmd_draw_indirect(
+                self.command_buffer.handle,
+                buffer.handle(),
+                offset,
+                draw_count,
+                stride,
+            );
+        }
+        self.command_buffer.resources.push(Box::new(buffer.clone()));
+    }
+
+    pub fn draw_indexed_indirect(
+        &mut self,
+        buffer: &Buffer,
+        offset: u64,
+        draw_count: u32,
+        stride: u32,
+    ) {
+        self.validate_indirect_buffer(
+            buffer,
+            offset,
+            draw_count,
+            stride,
+            std::mem::size_of::<DrawIndexedIndirectCommand>(),
+        );
+        unsafe {
+            self.device().handle().cmd_draw_indexed_indirect(
+                self.command_buffer.handle,
+                buffer.handle(),
+                offset,
+                draw_count,
+                stride,
+            );
+        }
+        self.command_buffer.resources.push(Box::new(buffer.clone()));
+    }
+
+    // The draw count is read as a u32 from `count_buffer` at `count_buffer_offset`
+    // and clamped to `max_draw_count`.
+    pub fn draw_indirect_count(
+        &mut self,
+        buffer: &Buffer,
+        offset: u64,
+        count_buffer: &Buffer,
+        count_buffer_offset: u64,
+        max_draw_count: u32,
+        stride: u32,
+    ) {
+        self.validate_indirect_buffer(
+            buffer,
+            offset,
+            max_draw_count,
+            stride,
+            std::mem::size_of::<DrawIndirectCommand>(),
+        );
+        self.validate_count_buffer(count_buffer, count_buffer_offset);
+        unsafe {
+            (self.draw_indirect_count_fn().cmd_draw_indirect_count_khr)(
+                self.command_buffer.handle,
+                buffer.handle(),
+                offset,
+                count_buffer.handle(),
+                count_buffer_offset,
+                max_draw_count,
+                stride,
+            );
+        }
+        self.command_buffer.resources.push(Box::new(buffer.clone()));
+        self.command_buffer
+            .resources
+            .push(Box::new(count_buffer.clone()));
+    }
+
+    pub fn draw_indexed_indirect_count(
+        &mut self,
+        buffer: &Buffer,
+        offset: u64,
+        count_buffer: &Buffer,
+        count_buffer_offset: u64,
+        max_draw_count: u32,
+        stride: u32,
+    ) {
+        self.validate_indirect_buffer(
+            buffer,
+            offset,
+            max_draw_count,
+            stride,
+            std::mem::size_of::<DrawIndexedIndirectCommand>(),
+        );
+        self.validate_count_buffer(count_buffer, count_buffer_offset);
+        unsafe {
+            (self
+                .draw_indirect_count_fn()
+                .cmd_draw_indexed_indirect_count_khr)(
+                self.command_buffer.handle,
+                buffer.handle(),
+                offset,
+                count_buffer.handle(),
+                count_buffer_offset,
+                max_draw_count,
+                stride,
+            );
+        }
+        self.command_buffer.resources.push(Box::new(buffer.clone()));
+        self.command_buffer
+            .resources
+            .push(Box::new(count_buffer.clone()));
+    }
+
+    fn validate_indirect_buffer(
+        &self,
+        buffer: &Buffer,
+        offset: u64,
+        draw_count: u32,
+        stride: u32,
+        command_size: usize,
+    ) {
+        assert!(
+            buffer
+                .usage()
+                .contains(vk::BufferUsageFlags::INDIRECT_BUFFER),
+            "indirect draws require an INDIRECT_BUFFER buffer"
+        );
+        assert_eq!(
+            offset % 4,
+            0,
+            "indirect buffer offset must be 4 byte aligned"
+        );
+        if draw_count > 1 {
+            assert_eq!(
+                self.device().inner.enabled_features.multi_draw_indirect,
+                vk::TRUE,
+                "multiDrawIndirect is not supported by the device"
+            );
+            assert!(
+                stride % 4 == 0 && stride as usize >= command_size,
+                "stride {} must be a multiple of 4 and at least {}",
+                stride,
+                command_size
+            );
+        }
+        if draw_count > 0 {
+            let end =
+                offset.checked_add((draw_count as u64 - 1) * stride as u64 + command_size as u64);
+            assert!(
+                end.map_or(false, |end| end <= buffer.size() as u64),
+                "{} indirect draws at offset {} exceed a {} byte buffer",
+                draw_count,
+                offset,
+                buffer.size()
+            );
+        }
+    }
+
+    fn validate_count_buffer(&self, count_buffer: &Buffer, count_buffer_offset: u64) {
+        assert!(
+            count_buffer
+                .usage()
+                .contains(vk::BufferUsageFlags::INDIRECT_BUFFER),
+            "count buffers require an INDIRECT_BUFFER buffer"
+        );
+        assert_eq!(
+            count_buffer_offset % 4,
+            0,
+            "count buffer offset must be 4 byte aligned"
+        );
+        assert!(count_buffer_offset
+            .checked_add(4)
+            .map_or(false, |end| end <= count_buffer.size() as u64));
+    }
+
+    fn draw_indirect_count_fn(&self) -> &vk::KhrDrawIndirectCountFn {
+        self.device()
+            .draw_indirect_count_fn()
+            .expect("VK_KHR_draw_indirect_count is not enabled")
+    }
+
     pub(crate) unsafe fn copy_buffer_to_image_raw(
         &mut self,
         src: &Buffer,
@@ -267,32 +830,49 @@ impl<'a> CommandRecorder<'a> {
         image: &crate::Image,
         layout: vk::ImageLayout,
         buffer: &crate::Buffer,
+    ) {
+        self.copy_image_to_buffer_regions(
+            image,
+            layout,
+            buffer,
+            &[vk::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_row_length: 0,
+                buffer_image_height: image.height(),
+                image_subresource: vk::ImageSubresourceLayers::builder()
+                    .base_array_layer(0)
+                    .layer_count(1)
+                    .aspect_mask(crate::image::copy_aspect_mask(image.format()))
+                    .mip_level(0)
+                    .build(),
+                image_offset: vk::Offset3D::default(),
+                image_extent: vk::Extent3D::builder()
+                    .width(image.width())
+                    .height(image.height())
+                    .depth(1)
+                    .build(),
+            }],
+        );
+    }
+
+    pub fn copy_image_to_buffer_regions(
+        &mut self,
+        image: &crate::Image,
+        layout: vk::ImageLayout,
+        buffer: &crate::Buffer,
+        regions: &[vk::BufferImageCopy],
     ) {
         unsafe {
-            let row_length = self.device().handle().cmd_copy_image_to_buffer(
+            self.device().handle().cmd_copy_image_to_buffer(
                 self.command_buffer.handle,
                 image.handle(),
                 layout,
                 buffer.handle(),
-                &[vk::BufferImageCopy {
-                    buffer_offset: 0,
-                    buffer_row_length: 0,
-                    buffer_image_height: image.height(),
-                    image_subresource: vk::ImageSubresourceLayers::builder()
-                        .base_array_layer(0)
-                        .layer_count(1)
-                        .aspect_mask(vk::ImageAspectFlags::COLOR)
-                        .mip_level(0)
-                        .build(),
-                    image_offset: vk::Offset3D::default(),
-                    image_extent: vk::Extent3D::builder()
-                        .width(image.width())
-                        .height(image.height())
-                        .depth(1)
-                        .build(),
-                }],
+                regions,
             );
         }
+        self.command_buffer.resources.push(Box::new(image.clone()));
+        self.command_buffer.resources.push(Box::new(buffer.clone()));
     }
 
     pub(crate) fn pipeline_barrier(&mut self, dependency_info: &vk::DependencyInfoKHR) {
@@ -357,6 +937,81 @@ impl<'a> CommandRecorder<'a> {
         }
     }
 
+    // Resolves a multisampled image into a single sampled image of the same format.
+    pub fn resolve_image(
+        &mut self,
+        src: &Image,
+        src_layout: vk::ImageLayout,
+        dst: &Image,
+        dst_layout: vk::ImageLayout,
+        regions: &[vk::ImageResolve],
+    ) {
+        assert_ne!(
+            src.samples(),
+            vk::SampleCountFlags::TYPE_1,
+            "resolve source must be multisampled"
+        );
+        assert_eq!(
+            dst.samples(),
+            vk::SampleCountFlags::TYPE_1,
+            "resolve destination must be single sampled"
+        );
+        assert_eq!(
+            src.format(),
+            dst.format(),
+            "resolve source and destination formats differ"
+        );
+        assert!(
+            self.subpass_contents.is_none(),
+            "images can't be resolved inside a render pass"
+        );
+        unsafe {
+            self.device().handle().cmd_resolve_image(
+                self.command_buffer.handle,
+                src.handle(),
+                src_layout,
+                dst.handle(),
+                dst_layout,
+                regions,
+            );
+        }
+        self.command_buffer.resources.push(Box::new(src.clone()));
+        self.command_buffer.resources.push(Box::new(dst.clone()));
+    }
+
+    // Resolves the first mip level and array layer of the whole image.
+    pub fn resolve_image_whole(
+        &mut self,
+        src: &Image,
+        src_layout: vk::ImageLayout,
+        dst: &Image,
+        dst_layout: vk::ImageLayout,
+    ) {
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        self.resolve_image(
+            src,
+            src_layout,
+            dst,
+            dst_layout,
+            &[vk::ImageResolve {
+                src_subresource: subresource,
+                src_offset: vk::Offset3D::default(),
+                dst_subresource: subresource,
+                dst_offset: vk::Offset3D::default(),
+                extent: vk::Extent3D {
+                    width: src.width().min(dst.width()),
+                    height: src.height().min(dst.height()),
+                    depth: 1,
+                },
+            }],
+        );
+    }
+
     pub fn trace_ray(
         &mut self,
         raygen_shader_binding_table: &crate::ShaderBindingTable,
@@ -367,28 +1022,96 @@ impl<'a> CommandRecorder<'a> {
         height: u32,
         depth: u32,
     ) {
+        let bound = self.ray_tracing_pipeline.as_ref();
+        let (raygen_buffer, raygen_region) = raygen_shader_binding_table.resolve(bound);
+        let (miss_buffer, miss_region) = miss_shader_binding_table.resolve(bound);
+        let (hit_buffer, hit_region) = hit_shader_binding_table.resolve(bound);
+        let (callable_buffer, callable_region) = callable_shader_binding_table.resolve(bound);
         unsafe {
             self.device().ray_tracing_pipeline_loader().cmd_trace_rays(
                 self.command_buffer.handle,
-                &raygen_shader_binding_table.region,
-                &miss_shader_binding_table.region,
-                &hit_shader_binding_table.region,
-                &callable_shader_binding_table.region,
+                &raygen_region,
+                &miss_region,
+                &hit_region,
+                &callable_region,
                 width,
                 height,
                 depth,
             );
         }
-        self.command_buffer
-            .resources
-            .push(Box::new(raygen_shader_binding_table.sbt_buffer.clone()));
+        for buffer in [raygen_buffer, miss_buffer, hit_buffer, callable_buffer] {
+            self.command_buffer.resources.push(Box::new(buffer));
+        }
     }
 
-    fn device_handle(&self) -> &ash::Device {
+    pub(crate) fn device_handle(&self) -> &ash::Device {
         &self.command_buffer.device.inner.handle
     }
 
-    fn device(&self) -> &Device {
+    pub(crate) fn device(&self) -> &Device {
         &self.command_buffer.device
     }
 }
+
+#[test]
+fn test_transfer_commands() {
+    use crate::device::test_device;
+
+    let device = test_device();
+
+    let src = device.create_buffer(
+        Some("src"),
+        16,
+        vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
+        gpu_allocator::MemoryLocation::GpuOnly,
+    );
+    let dst = device.create_buffer(
+        Some("dst"),
+        16,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        gpu_allocator::MemoryLocation::GpuToCpu,
+    );
+    let mut cmd_buf =
+        device.create_command_buffer(Some("transfer"), device.transfer_queue_family_index());
+    cmd_buf.encode(|recorder| {
+        recorder.fill_buffer(&src, 0, vk::WHOLE_SIZE, 0x01010101);
+        // Both are transfer writes, the update has to wait for the fill.
+        recorder.pipeline_barrier(
+            &vk::DependencyInfoKHR::builder()
+                .memory_barriers(&[vk::MemoryBarrier2KHR::builder()
+                    .src_stage_mask(vk::PipelineStageFlags2KHR::TRANSFER)
+                    .src_access_mask(vk::AccessFlags2KHR::TRANSFER_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2KHR::TRANSFER)
+                    .dst_access_mask(vk::AccessFlags2KHR::TRANSFER_WRITE)
+                    .build()])
+                .build(),
+        );
+        recorder.update_buffer(&src, 4, &[2, 2, 2, 2]);
+        recorder.pipeline_barrier(
+            &vk::DependencyInfoKHR::builder()
+                .memory_barriers(&[vk::MemoryBarrier2KHR::builder()
+                    .src_stage_mask(vk::PipelineStageFlags2KHR::TRANSFER)
+                    .src_access_mask(vk::AccessFlags2KHR::TRANSFER_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2KHR::TRANSFER)
+                    .dst_access_mask(vk::AccessFlags2KHR::TRANSFER_READ)
+                    .build()])
+                .build(),
+        );
+        recorder.copy_buffer(
+            &src,
+            &dst,
+            &[vk::BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size: 16,
+            }],
+        );
+    });
+    device.transfer_queue().submit_blocking(&[cmd_buf]);
+
+    let guard = dst.lock_memory().unwrap();
+    assert_eq!(
+        &guard.mapped_slice().unwrap()[..16],
+        &[1, 1, 1, 1, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1]
+    );
+}
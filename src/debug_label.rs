@@ -0,0 +1,161 @@
+use std::ffi::CString;
+
+use ash::vk;
+
+use crate::{CommandRecorder, Framebuffer, Queue};
+
+// Colors of the labels emitted by `Device::set_automatic_labels`.
+const RENDER_PASS_COLOR: [f32; 4] = [0.9, 0.5, 0.1, 1.0];
+const RAY_TRACING_COLOR: [f32; 4] = [0.2, 0.7, 0.3, 1.0];
+const ACCELERATION_STRUCTURE_COLOR: [f32; 4] = [0.3, 0.4, 0.9, 1.0];
+
+fn label_info(name: &CString, color: [f32; 4]) -> vk::DebugUtilsLabelEXT {
+    vk::DebugUtilsLabelEXT::builder()
+        .label_name(name)
+        .color(color)
+        .build()
+}
+
+// Interior nul bytes would make the name unrepresentable, drop them instead.
+fn label_name(name: &str) -> CString {
+    CString::new(name.replace('\0', "")).unwrap()
+}
+
+pub(crate) fn render_pass_label(framebuffer: &Framebuffer) -> String {
+    let names = framebuffer
+        .inner
+        .attachments
+        .iter()
+        .filter_map(|view| view.inner.image.inner.name.clone())
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        "render pass".to_owned()
+    } else {
+        format!("render pass ({})", names.join(", "))
+    }
+}
+
+impl<'a> CommandRecorder<'a> {
+    // Records `f` inside a debug label region, shown as a group in captures.
+    pub fn label<I>(&mut self, name: &str, color: [f32; 4], f: I)
+    where
+        I: FnOnce(&mut CommandRecorder),
+    {
+        let debug_utils_loader = match self.device().debug_utils_loader() {
+            Some(loader) => loader.clone(),
+            None => return f(self),
+        };
+        let name = label_name(name);
+        unsafe {
+            debug_utils_loader
+                .cmd_begin_debug_utils_label(self.command_buffer.handle, &label_info(&name, color));
+        }
+        f(self);
+        unsafe {
+            debug_utils_loader.cmd_end_debug_utils_label(self.command_buffer.handle);
+        }
+    }
+
+    pub fn insert_label(&mut self, name: &str, color: [f32; 4]) {
+        if let Some(debug_utils_loader) = self.device().debug_utils_loader() {
+            let name = label_name(name);
+            unsafe {
+                debug_utils_loader.cmd_insert_debug_utils_label(
+                    self.command_buffer.handle,
+                    &label_info(&name, color),
+                );
+            }
+        }
+    }
+
+    // Labels `f` only if automatic labels are enabled on the device.
+    pub(crate) fn automatic_label<N, I>(&mut self, name: N, kind: AutomaticLabel, f: I)
+    where
+        N: FnOnce() -> String,
+        I: FnOnce(&mut CommandRecorder),
+    {
+        if self.device().automatic_labels() {
+            let color = match kind {
+                AutomaticLabel::RenderPass => RENDER_PASS_COLOR,
+                AutomaticLabel::RayTracing => RAY_TRACING_COLOR,
+                AutomaticLabel::AccelerationStructure => ACCELERATION_STRUCTURE_COLOR,
+            };
+            self.label(&name(), color, f);
+        } else {
+            f(self);
+        }
+    }
+}
+
+pub(crate) enum AutomaticLabel {
+    RenderPass,
+    RayTracing,
+    AccelerationStructure,
+}
+
+impl Queue {
+    // Groups everything submitted by `f` under a queue label.
+    pub fn label<I>(&self, name: &str, color: [f32; 4], f: I)
+    where
+        I: FnOnce(&Queue),
+    {
+        let debug_utils_loader = match &self.inner.debug_utils_loader {
+            Some(loader) => loader,
+            None => return f(self),
+        };
+        let name = label_name(name);
+        {
+            let _lock = self.inner.lock.lock().unwrap();
+            unsafe {
+                debug_utils_loader
+                    .queue_begin_debug_utils_label(self.inner.handle, &label_info(&name, color));
+            }
+        }
+        f(self);
+        let _lock = self.inner.lock.lock().unwrap();
+        unsafe {
+            debug_utils_loader.queue_end_debug_utils_label(self.inner.handle);
+        }
+    }
+
+    pub fn insert_label(&self, name: &str, color: [f32; 4]) {
+        if let Some(debug_utils_loader) = &self.inner.debug_utils_loader {
+            let name = label_name(name);
+            let _lock = self.inner.lock.lock().unwrap();
+            unsafe {
+                debug_utils_loader
+                    .queue_insert_debug_utils_label(self.inner.handle, &label_info(&name, color));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_labels() {
+    use crate::device::test_device;
+
+    let device = test_device();
+    device.set_automatic_labels(true);
+
+    let buffer = device.create_buffer(
+        Some("labelled"),
+        16,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        gpu_allocator::MemoryLocation::GpuOnly,
+    );
+    let mut command_buffer =
+        device.create_command_buffer(Some("labels"), device.graphics_queue_family_index());
+    command_buffer.encode(|recorder| {
+        recorder.label("outer", [1.0, 0.0, 0.0, 1.0], |recorder| {
+            recorder.insert_label("before fill", [0.0, 1.0, 0.0, 1.0]);
+            recorder.label("inner", [0.0, 0.0, 1.0, 1.0], |recorder| {
+                recorder.fill_buffer(&buffer, 0, 16, 0);
+            });
+        });
+    });
+    let queue = device.graphics_queue();
+    queue.label("frame", [1.0, 1.0, 1.0, 1.0], |queue| {
+        queue.insert_label("submit", [1.0, 1.0, 0.0, 1.0]);
+        queue.submit_blocking(&[command_buffer]);
+    });
+}
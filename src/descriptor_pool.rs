@@ -46,12 +46,10 @@ impl DescriptorPool {
 
 impl Drop for DescriptorPoolRef {
     fn drop(&mut self) {
-        unsafe {
-            self.device
-                .inner
-                .handle
-                .destroy_descriptor_pool(self.handle, None);
-        }
+        let handle = self.handle;
+        self.device.destroy_deferred(move |device| unsafe {
+            device.handle.destroy_descriptor_pool(handle, None);
+        });
     }
 }
 
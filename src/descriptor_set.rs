@@ -242,15 +242,15 @@ pub enum DescriptorUpdate {
 
 impl Drop for DescriptorSetRef {
     fn drop(&mut self) {
-        unsafe {
-            self.descriptor_pool
-                .inner
-                .device
-                .inner
-                .handle
-                .free_descriptor_sets(self.descriptor_pool.inner.handle, &[self.handle])
-                .unwrap();
-        }
+        // The pool's own destruction is deferred as well and queued after this.
+        let handle = self.handle;
+        let pool = self.descriptor_pool.inner.handle;
+        self.descriptor_pool
+            .inner
+            .device
+            .destroy_deferred(move |device| unsafe {
+                device.handle.free_descriptor_sets(pool, &[handle]).unwrap();
+            });
     }
 }
 
@@ -1,10 +1,14 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::ffi::CString;
 use std::iter::FromIterator;
 use std::mem::ManuallyDrop;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Weak;
 
 use gpu_allocator::vulkan::*;
 
@@ -16,14 +20,24 @@ use crate::command_pool::CommandPool;
 use crate::instance::Instance;
 use crate::name;
 use crate::physical_device::PhysicalDevice;
+use crate::pipeline::PipelineCache;
 use crate::queue;
 use crate::queue::Queue;
 use crate::queue_family::QueueFamily;
 use crate::queue_family::QueueFamilyProperties;
+use crate::sampler::{SamplerDesc, SamplerRef};
 use crate::CommandBuffer;
 
 pub struct DeviceFeatures {}
 
+// A destruction postponed until the GPU is done with everything submitted
+// before the object was dropped.
+struct Garbage {
+    // Last submitted timeline value of the graphics, compute and transfer queues.
+    after: [u64; 3],
+    destroy: Box<dyn FnOnce(&DeviceRef) + Send>,
+}
+
 pub(crate) struct DeviceRef {
     pub handle: ash::Device,
     pub pdevice: PhysicalDevice,
@@ -31,12 +45,22 @@ pub(crate) struct DeviceRef {
     pub(crate) swapchain_loader: ash::extensions::khr::Swapchain,
     ray_tracing_pipeline_loader: ash::extensions::khr::RayTracingPipeline,
     synchronization2_loader: ash::extensions::khr::Synchronization2,
+    draw_indirect_count_fn: Option<vk::KhrDrawIndirectCountFn>,
+    dynamic_rendering_fn: Option<crate::dynamic_rendering::KhrDynamicRenderingFn>,
     pub(crate) allocator: Mutex<ManuallyDrop<Allocator>>,
     graphics_queue: ManuallyDrop<Queue>,
     transfer_queue: ManuallyDrop<Queue>,
     compute_queue: ManuallyDrop<Queue>,
     command_pool: ManuallyDrop<ThreadLocal<RefCell<BTreeMap<u32, CommandPool>>>>,
     all_queue_family_indices: Vec<u32>,
+    pub(crate) enabled_features: vk::PhysicalDeviceFeatures,
+    pub(crate) enabled_extensions: Vec<name::device::Extension>,
+    pub(crate) sampler_cache: Mutex<HashMap<SamplerDesc, Weak<SamplerRef>>>,
+    garbage: Mutex<VecDeque<Garbage>>,
+    // Timeline values known to be complete as of the last garbage collection.
+    completed: Mutex<[u64; 3]>,
+    automatic_labels: AtomicBool,
+    pub(crate) pipeline_cache: ManuallyDrop<PipelineCache>,
 }
 
 #[derive(Clone)]
@@ -136,12 +160,34 @@ impl Device {
                     .descriptor_binding_uniform_buffer_update_after_bind(false)
                     .build();
 
+            let mut timeline_semaphore_pnext =
+                vk::PhysicalDeviceTimelineSemaphoreFeatures::builder()
+                    .timeline_semaphore(true)
+                    .build();
+            let mut synchronization2_pnext =
+                vk::PhysicalDeviceSynchronization2FeaturesKHR::builder()
+                    .synchronization2(true)
+                    .build();
+            let mut host_query_reset_pnext = vk::PhysicalDeviceHostQueryResetFeatures::builder()
+                .host_query_reset(true)
+                .build();
+            let mut dynamic_rendering_pnext =
+                crate::dynamic_rendering::PhysicalDeviceDynamicRenderingFeatures {
+                    dynamic_rendering: vk::TRUE,
+                    ..Default::default()
+                };
+
             let vk_device_features = vk::PhysicalDeviceFeatures {
                 shader_storage_image_write_without_format: vk::TRUE,
                 shader_storage_image_read_without_format: vk::TRUE,
                 fill_mode_non_solid: vk::TRUE,
                 shader_int16: vk::TRUE,
                 shader_int64: vk::TRUE,
+                sampler_anisotropy: pdevice.features.sampler_anisotropy,
+                multi_draw_indirect: pdevice.features.multi_draw_indirect,
+                draw_indirect_first_instance: pdevice.features.draw_indirect_first_instance,
+                pipeline_statistics_query: pdevice.features.pipeline_statistics_query,
+                occlusion_query_precise: pdevice.features.occlusion_query_precise,
                 ..Default::default()
             };
 
@@ -168,6 +214,12 @@ impl Device {
                 } else {
                     device_create_info
                 };
+            device_create_info =
+                if device_extensions.contains(&name::device::Extension::KhrDynamicRendering) {
+                    device_create_info.push_next(&mut dynamic_rendering_pnext)
+                } else {
+                    device_create_info
+                };
 
             device_create_info = device_create_info
                 .push_next(&mut device_buffer_address_pnext)
@@ -175,7 +227,10 @@ impl Device {
                 .push_next(&mut scalar_block_layout_pnext)
                 .push_next(&mut vulkan_memory_model_pnext)
                 .push_next(&mut shader_float16_int8_pnext)
-                .push_next(&mut descriptor_indexing_pnext);
+                .push_next(&mut descriptor_indexing_pnext)
+                .push_next(&mut timeline_semaphore_pnext)
+                .push_next(&mut synchronization2_pnext)
+                .push_next(&mut host_query_reset_pnext);
 
             let handle = instance
                 .inner
@@ -201,6 +256,40 @@ impl Device {
                 &handle,
             );
 
+            // ash's DrawIndirectCount loader dispatches both commands to the
+            // indexed variant, so keep the raw function table instead.
+            let draw_indirect_count_fn =
+                if device_extensions.contains(&name::device::Extension::KhrDrawIndirectCount) {
+                    Some(vk::KhrDrawIndirectCountFn::load(|name| {
+                        std::mem::transmute(
+                            instance
+                                .inner
+                                .handle
+                                .get_device_proc_addr(handle.handle(), name.as_ptr()),
+                        )
+                    }))
+                } else {
+                    None
+                };
+
+            let dynamic_rendering_fn =
+                if device_extensions.contains(&name::device::Extension::KhrDynamicRendering) {
+                    Some(crate::dynamic_rendering::KhrDynamicRenderingFn::load(
+                        |name| {
+                            std::mem::transmute(
+                                instance
+                                    .inner
+                                    .handle
+                                    .get_device_proc_addr(handle.handle(), name.as_ptr()),
+                            )
+                        },
+                    ))
+                } else {
+                    None
+                };
+
+            let pipeline_cache = PipelineCache::new(&handle, pdevice.properties());
+
             let allocator = Allocator::new(&AllocatorCreateDesc {
                 instance: instance.inner.handle.clone(),
                 device: handle.clone(),
@@ -220,6 +309,7 @@ impl Device {
             let graphics_queue = Queue::new(
                 &handle,
                 synchronization2_loader.clone(),
+                instance.debug_utils_loader().cloned(),
                 &graphics_queue_family_properties,
                 0,
             );
@@ -230,6 +320,7 @@ impl Device {
             let compute_queue = Queue::new(
                 &handle,
                 synchronization2_loader.clone(),
+                instance.debug_utils_loader().cloned(),
                 &compute_queue_family_properties,
                 0,
             );
@@ -240,6 +331,7 @@ impl Device {
             let transfer_queue = Queue::new(
                 &handle,
                 synchronization2_loader.clone(),
+                instance.debug_utils_loader().cloned(),
                 &transfer_queue_family_properties,
                 0,
             );
@@ -255,30 +347,52 @@ impl Device {
             .into_iter()
             .collect();
 
-            Self {
-                inner: Arc::new(DeviceRef {
-                    handle,
-                    pdevice,
-                    graphics_queue: ManuallyDrop::new(graphics_queue),
-                    compute_queue: ManuallyDrop::new(compute_queue),
-                    transfer_queue: ManuallyDrop::new(transfer_queue),
-                    acceleration_structure_loader,
-                    synchronization2_loader,
-                    swapchain_loader,
-                    ray_tracing_pipeline_loader,
-                    allocator: Mutex::new(ManuallyDrop::new(allocator)),
-                    command_pool: ManuallyDrop::new(ThreadLocal::new()),
-                    all_queue_family_indices,
-                }),
+            let inner = Arc::new(DeviceRef {
+                handle,
+                pdevice,
+                graphics_queue: ManuallyDrop::new(graphics_queue),
+                compute_queue: ManuallyDrop::new(compute_queue),
+                transfer_queue: ManuallyDrop::new(transfer_queue),
+                acceleration_structure_loader,
+                synchronization2_loader,
+                draw_indirect_count_fn,
+                dynamic_rendering_fn,
+                swapchain_loader,
+                ray_tracing_pipeline_loader,
+                allocator: Mutex::new(ManuallyDrop::new(allocator)),
+                command_pool: ManuallyDrop::new(ThreadLocal::new()),
+                all_queue_family_indices,
+                enabled_features: vk_device_features,
+                enabled_extensions: device_extensions,
+                sampler_cache: Mutex::new(HashMap::new()),
+                garbage: Mutex::new(VecDeque::new()),
+                completed: Mutex::new([0; 3]),
+                automatic_labels: AtomicBool::new(false),
+                pipeline_cache: ManuallyDrop::new(pipeline_cache),
+            });
+            // Lets the queues collect garbage after submitting and waiting.
+            for queue in [
+                &inner.graphics_queue,
+                &inner.compute_queue,
+                &inner.transfer_queue,
+            ] {
+                *queue.inner.owner.lock().unwrap() = Arc::downgrade(&inner);
             }
+            Self { inner }
         }
     }
 
     pub(crate) fn command_pool(&self, queue_family_index: u32) -> CommandPool {
         let mut pools = self.inner.command_pool.get_or_default().borrow_mut();
-        let pool = pools
-            .entry(queue_family_index)
-            .or_insert_with(|| CommandPool::new(self.clone(), queue_family_index));
+        let pool = pools.entry(queue_family_index).or_insert_with(|| {
+            CommandPool::new(
+                self,
+                None,
+                queue_family_index,
+                vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+                false,
+            )
+        });
         pool.clone()
     }
 
@@ -323,16 +437,61 @@ impl Device {
         &self.inner.compute_queue
     }
 
+    pub fn supported_sample_counts(&self) -> vk::SampleCountFlags {
+        self.inner.pdevice.supported_sample_counts()
+    }
+
     pub fn wait_idle(&self) {
         unsafe {
             self.handle().device_wait_idle().unwrap();
         }
+        self.collect_garbage();
     }
 
     pub fn synchronization2_loader(&self) -> &ash::extensions::khr::Synchronization2 {
         &self.inner.synchronization2_loader
     }
 
+    pub(crate) fn draw_indirect_count_fn(&self) -> Option<&vk::KhrDrawIndirectCountFn> {
+        self.inner.draw_indirect_count_fn.as_ref()
+    }
+
+    pub(crate) fn dynamic_rendering_fn(
+        &self,
+    ) -> Option<&crate::dynamic_rendering::KhrDynamicRenderingFn> {
+        self.inner.dynamic_rendering_fn.as_ref()
+    }
+
+    // Runs `destroy` once every queue has completed the work submitted so far.
+    // The closure must not own anything that keeps the device alive.
+    pub(crate) fn destroy_deferred<F>(&self, destroy: F)
+    where
+        F: FnOnce(&DeviceRef) + Send + 'static,
+    {
+        let after = self.inner.last_submitted_values();
+        let mut garbage = self.inner.garbage.lock().unwrap();
+        // Destroying right away is only fine if it can't overtake queued entries.
+        if garbage.is_empty() && passed(&after, &self.inner.completed.lock().unwrap()) {
+            drop(garbage);
+            destroy(&self.inner);
+            return;
+        }
+        garbage.push_back(Garbage {
+            after,
+            destroy: Box::new(destroy),
+        });
+    }
+
+    // Destroys dropped objects the GPU is no longer using, returns how many were
+    // destroyed. Submitting and waiting on a queue already does this.
+    pub fn collect_garbage(&self) -> usize {
+        self.inner.collect_garbage()
+    }
+
+    pub(crate) fn extension_enabled(&self, extension: name::device::Extension) -> bool {
+        self.inner.enabled_extensions.contains(&extension)
+    }
+
     pub(crate) fn all_queue_family_indices(&self) -> &[u32] {
         &self.inner.all_queue_family_indices
     }
@@ -343,12 +502,12 @@ impl Device {
         object_handle: u64,
         object_type: vk::ObjectType,
     ) {
+        let debug_utils_loader = match self.debug_utils_loader() {
+            Some(loader) => loader,
+            None => return,
+        };
         unsafe {
-            self.inner
-                .pdevice
-                .instance
-                .inner
-                .debug_utils_loader
+            debug_utils_loader
                 .debug_utils_set_object_name(
                     self.handle().handle(),
                     &vk::DebugUtilsObjectNameInfoEXT::builder()
@@ -361,21 +520,87 @@ impl Device {
         }
     }
 
+    pub(crate) fn debug_utils_loader(&self) -> Option<&ash::extensions::ext::DebugUtils> {
+        self.inner.pdevice.instance.debug_utils_loader()
+    }
+
+    // Makes recorders wrap render passes, ray tracing pipelines and acceleration
+    // structure builds in debug labels named after the objects involved.
+    pub fn set_automatic_labels(&self, enabled: bool) {
+        self.inner
+            .automatic_labels
+            .store(enabled, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn automatic_labels(&self) -> bool {
+        self.debug_utils_loader().is_some()
+            && self
+                .inner
+                .automatic_labels
+                .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
     pub(crate) fn ray_tracing_pipeline_loader(&self) -> &ash::extensions::khr::RayTracingPipeline {
         &self.inner.ray_tracing_pipeline_loader
     }
 }
 
+fn passed(after: &[u64; 3], completed: &[u64; 3]) -> bool {
+    after.iter().zip(completed).all(|(a, c)| a <= c)
+}
+
+impl DeviceRef {
+    fn last_submitted_values(&self) -> [u64; 3] {
+        [
+            self.graphics_queue.inner.last_submitted_value(),
+            self.compute_queue.inner.last_submitted_value(),
+            self.transfer_queue.inner.last_submitted_value(),
+        ]
+    }
+
+    fn completed_values(&self) -> [u64; 3] {
+        [
+            self.graphics_queue.inner.completed_value(),
+            self.compute_queue.inner.completed_value(),
+            self.transfer_queue.inner.completed_value(),
+        ]
+    }
+
+    pub(crate) fn collect_garbage(&self) -> usize {
+        let completed = self.completed_values();
+        *self.completed.lock().unwrap() = completed;
+        let ready = {
+            let mut garbage = self.garbage.lock().unwrap();
+            // Entries are queued in submission order, so the ready ones form a prefix.
+            let count = garbage
+                .iter()
+                .take_while(|g| passed(&g.after, &completed))
+                .count();
+            garbage.drain(..count).collect::<Vec<_>>()
+        };
+        let count = ready.len();
+        for garbage in ready {
+            (garbage.destroy)(self);
+        }
+        count
+    }
+}
+
 impl Drop for DeviceRef {
     fn drop(&mut self) {
         log::debug!("dropping device");
 
         unsafe {
             self.handle.device_wait_idle().unwrap();
+            let garbage = std::mem::take(&mut *self.garbage.lock().unwrap());
+            for garbage in garbage {
+                (garbage.destroy)(self);
+            }
             ManuallyDrop::drop(&mut self.graphics_queue);
             ManuallyDrop::drop(&mut self.compute_queue);
             ManuallyDrop::drop(&mut self.transfer_queue);
             ManuallyDrop::drop(&mut self.command_pool);
+            ManuallyDrop::drop(&mut self.pipeline_cache);
 
             ManuallyDrop::drop(&mut self.allocator.lock().unwrap());
             self.handle.destroy_device(None);
@@ -383,6 +608,24 @@ impl Drop for DeviceRef {
     }
 }
 
+// The first discrete GPU, with logging set up for the test.
+#[cfg(test)]
+pub(crate) fn test_device() -> Device {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Trace)
+        .is_test(true)
+        .try_init()
+        .ok();
+    let entry = crate::Entry::new().unwrap();
+    let instance = entry.create_instance(&[], &[]);
+    let pdevice = instance
+        .enumerate_physical_device()
+        .into_iter()
+        .find(|p| p.device_type == vk::PhysicalDeviceType::DISCRETE_GPU)
+        .unwrap();
+    pdevice.create_device()
+}
+
 #[test]
 fn test_create_command_buffer() {
     env_logger::builder()
@@ -414,3 +657,46 @@ fn test_create_command_buffer() {
     let device = pdevice.create_device();
     // device.allocate_command_buffer();
 }
+
+#[test]
+fn test_deferred_destruction() {
+    let device = test_device();
+
+    let target = device.create_buffer(
+        Some("target"),
+        4,
+        vk::BufferUsageFlags::empty(),
+        gpu_allocator::MemoryLocation::GpuOnly,
+    );
+    let dropped = device.create_buffer(
+        Some("dropped"),
+        4,
+        vk::BufferUsageFlags::empty(),
+        gpu_allocator::MemoryLocation::GpuOnly,
+    );
+    // Holds the submission back until the host signals it.
+    let gate = crate::TimelineSemaphore::new(&device);
+    let mut cmd_buf = device.create_command_buffer(None, device.graphics_queue_family_index());
+    cmd_buf.encode(|recorder| recorder.fill_buffer(&target, 0, 4, 0));
+    let submission = device
+        .graphics_queue()
+        .submit(
+            crate::SubmitInfo::new()
+                .command_buffer(cmd_buf)
+                .wait_timeline(&gate, 1, vk::PipelineStageFlags2KHR::ALL_COMMANDS),
+        )
+        .unwrap();
+
+    drop(dropped);
+    assert_eq!(device.collect_garbage(), 0);
+
+    gate.signal(1);
+    // Waiting collects what the submission held back.
+    submission.wait();
+    assert!(device.inner.garbage.lock().unwrap().is_empty());
+
+    // Nothing is in flight anymore, so this is destroyed right away.
+    drop(submission);
+    drop(target);
+    assert!(device.inner.garbage.lock().unwrap().is_empty());
+}
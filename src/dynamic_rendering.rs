@@ -0,0 +1,619 @@
+use std::ffi::{c_void, CStr};
+
+use anyhow::{ensure, Result};
+use ash::vk;
+use ash::vk::Handle;
+
+use crate::image::{format_aspect_mask, is_integer_format};
+use crate::{CommandRecorder, ImageView};
+
+// VK_KHR_dynamic_rendering is newer than the ash release we depend on, so the
+// structures we need are declared here with the registry layout.
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PhysicalDeviceDynamicRenderingFeatures {
+    pub s_type: vk::StructureType,
+    pub p_next: *mut c_void,
+    pub dynamic_rendering: vk::Bool32,
+}
+
+impl Default for PhysicalDeviceDynamicRenderingFeatures {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1000044003),
+            p_next: std::ptr::null_mut(),
+            dynamic_rendering: vk::FALSE,
+        }
+    }
+}
+
+unsafe impl vk::ExtendsDeviceCreateInfo for PhysicalDeviceDynamicRenderingFeatures {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CommandBufferInheritanceRenderingInfo {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub flags: vk::Flags,
+    pub view_mask: u32,
+    pub color_attachment_count: u32,
+    pub p_color_attachment_formats: *const vk::Format,
+    pub depth_attachment_format: vk::Format,
+    pub stencil_attachment_format: vk::Format,
+    pub rasterization_samples: vk::SampleCountFlags,
+}
+
+impl Default for CommandBufferInheritanceRenderingInfo {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1000044004),
+            p_next: std::ptr::null(),
+            flags: 0,
+            view_mask: 0,
+            color_attachment_count: 0,
+            p_color_attachment_formats: std::ptr::null(),
+            depth_attachment_format: vk::Format::UNDEFINED,
+            stencil_attachment_format: vk::Format::UNDEFINED,
+            rasterization_samples: vk::SampleCountFlags::TYPE_1,
+        }
+    }
+}
+
+unsafe impl vk::ExtendsCommandBufferInheritanceInfo for CommandBufferInheritanceRenderingInfo {}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct RenderingAttachmentInfo {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub image_view: vk::ImageView,
+    pub image_layout: vk::ImageLayout,
+    pub resolve_mode: vk::ResolveModeFlags,
+    pub resolve_image_view: vk::ImageView,
+    pub resolve_image_layout: vk::ImageLayout,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    pub clear_value: vk::ClearValue,
+}
+
+impl Default for RenderingAttachmentInfo {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1000044001),
+            p_next: std::ptr::null(),
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+            resolve_mode: vk::ResolveModeFlags::NONE,
+            resolve_image_view: vk::ImageView::null(),
+            resolve_image_layout: vk::ImageLayout::UNDEFINED,
+            load_op: vk::AttachmentLoadOp::LOAD,
+            store_op: vk::AttachmentStoreOp::STORE,
+            clear_value: vk::ClearValue::default(),
+        }
+    }
+}
+
+// VK_RENDERING_CONTENTS_SECONDARY_COMMAND_BUFFERS_BIT_KHR
+pub(crate) const RENDERING_CONTENTS_SECONDARY_COMMAND_BUFFERS: vk::Flags = 0x1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct RenderingInfo {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub flags: vk::Flags,
+    pub render_area: vk::Rect2D,
+    pub layer_count: u32,
+    pub view_mask: u32,
+    pub color_attachment_count: u32,
+    pub p_color_attachments: *const RenderingAttachmentInfo,
+    pub p_depth_attachment: *const RenderingAttachmentInfo,
+    pub p_stencil_attachment: *const RenderingAttachmentInfo,
+}
+
+impl Default for RenderingInfo {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1000044000),
+            p_next: std::ptr::null(),
+            flags: 0,
+            render_area: vk::Rect2D::default(),
+            layer_count: 1,
+            view_mask: 0,
+            color_attachment_count: 0,
+            p_color_attachments: std::ptr::null(),
+            p_depth_attachment: std::ptr::null(),
+            p_stencil_attachment: std::ptr::null(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PipelineRenderingCreateInfo {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub view_mask: u32,
+    pub color_attachment_count: u32,
+    pub p_color_attachment_formats: *const vk::Format,
+    pub depth_attachment_format: vk::Format,
+    pub stencil_attachment_format: vk::Format,
+}
+
+impl Default for PipelineRenderingCreateInfo {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1000044002),
+            p_next: std::ptr::null(),
+            view_mask: 0,
+            color_attachment_count: 0,
+            p_color_attachment_formats: std::ptr::null(),
+            depth_attachment_format: vk::Format::UNDEFINED,
+            stencil_attachment_format: vk::Format::UNDEFINED,
+        }
+    }
+}
+
+unsafe impl vk::ExtendsGraphicsPipelineCreateInfo for PipelineRenderingCreateInfo {}
+
+#[derive(Clone)]
+pub(crate) struct KhrDynamicRenderingFn {
+    pub cmd_begin_rendering_khr: unsafe extern "system" fn(
+        command_buffer: vk::CommandBuffer,
+        p_rendering_info: *const RenderingInfo,
+    ),
+    pub cmd_end_rendering_khr: unsafe extern "system" fn(command_buffer: vk::CommandBuffer),
+}
+
+impl KhrDynamicRenderingFn {
+    pub fn load<F>(mut load: F) -> Self
+    where
+        F: FnMut(&CStr) -> *const c_void,
+    {
+        unsafe {
+            let begin = load(CStr::from_bytes_with_nul_unchecked(
+                b"vkCmdBeginRenderingKHR\0",
+            ));
+            let end = load(CStr::from_bytes_with_nul_unchecked(
+                b"vkCmdEndRenderingKHR\0",
+            ));
+            assert!(
+                !begin.is_null() && !end.is_null(),
+                "VK_KHR_dynamic_rendering commands are missing"
+            );
+            Self {
+                cmd_begin_rendering_khr: std::mem::transmute(begin),
+                cmd_end_rendering_khr: std::mem::transmute(end),
+            }
+        }
+    }
+}
+
+// One color or depth/stencil target of `CommandRecorder::begin_rendering`.
+#[derive(Clone)]
+pub struct RenderingAttachment {
+    view: ImageView,
+    // None picks the optimal attachment layout for the attachment's role.
+    layout: Option<vk::ImageLayout>,
+    load_op: vk::AttachmentLoadOp,
+    store_op: vk::AttachmentStoreOp,
+    clear_value: vk::ClearValue,
+    resolve: Option<(ImageView, vk::ResolveModeFlags)>,
+    // Used for the stencil aspect of a resolved depth/stencil attachment.
+    stencil_resolve_mode: vk::ResolveModeFlags,
+}
+
+impl RenderingAttachment {
+    pub fn new(view: &ImageView) -> Self {
+        Self {
+            view: view.clone(),
+            layout: None,
+            resolve: None,
+            stencil_resolve_mode: vk::ResolveModeFlags::SAMPLE_ZERO,
+            load_op: vk::AttachmentLoadOp::LOAD,
+            store_op: vk::AttachmentStoreOp::STORE,
+            clear_value: vk::ClearValue::default(),
+        }
+    }
+
+    pub fn layout(mut self, layout: vk::ImageLayout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    pub fn load_op(mut self, load_op: vk::AttachmentLoadOp) -> Self {
+        self.load_op = load_op;
+        self
+    }
+
+    pub fn store_op(mut self, store_op: vk::AttachmentStoreOp) -> Self {
+        self.store_op = store_op;
+        self
+    }
+
+    // Also switches the load op to CLEAR.
+    pub fn clear_color(mut self, color: [f32; 4]) -> Self {
+        self.load_op = vk::AttachmentLoadOp::CLEAR;
+        self.clear_value = vk::ClearValue {
+            color: vk::ClearColorValue { float32: color },
+        };
+        self
+    }
+
+    // Also switches the load op to CLEAR.
+    pub fn clear_depth_stencil(mut self, depth: f32, stencil: u32) -> Self {
+        self.load_op = vk::AttachmentLoadOp::CLEAR;
+        self.clear_value = vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue { depth, stencil },
+        };
+        self
+    }
+
+    // Resolves the multisampled attachment into `view` at the end of rendering.
+    // Color attachments need AVERAGE for float and SAMPLE_ZERO for integer
+    // formats. For depth/stencil attachments `mode` resolves depth and the
+    // device's depth stencil resolve properties have to support it.
+    pub fn resolve(mut self, view: &ImageView, mode: vk::ResolveModeFlags) -> Self {
+        assert_ne!(mode, vk::ResolveModeFlags::NONE);
+        self.resolve = Some((view.clone(), mode));
+        self
+    }
+
+    // Stencil resolve mode of a resolved depth/stencil attachment, SAMPLE_ZERO
+    // by default. NONE leaves the stencil aspect of the resolve view untouched.
+    pub fn stencil_resolve_mode(mut self, mode: vk::ResolveModeFlags) -> Self {
+        self.stencil_resolve_mode = mode;
+        self
+    }
+
+    pub fn view(&self) -> &ImageView {
+        &self.view
+    }
+
+    fn samples(&self) -> vk::SampleCountFlags {
+        self.view.inner.image.samples()
+    }
+
+    fn info(&self, default_layout: vk::ImageLayout) -> RenderingAttachmentInfo {
+        let (resolve_image_view, resolve_mode) = match &self.resolve {
+            Some((view, mode)) => (view.inner.handle, *mode),
+            None => (vk::ImageView::null(), vk::ResolveModeFlags::NONE),
+        };
+        let layout = self.layout.unwrap_or(default_layout);
+        RenderingAttachmentInfo {
+            image_view: self.view.inner.handle,
+            image_layout: layout,
+            resolve_mode,
+            resolve_image_view,
+            resolve_image_layout: layout,
+            load_op: self.load_op,
+            store_op: self.store_op,
+            clear_value: self.clear_value,
+            ..Default::default()
+        }
+    }
+}
+
+// Stencil format implied by a depth attachment format, UNDEFINED if it has none.
+pub(crate) fn stencil_format(depth_format: vk::Format) -> vk::Format {
+    if format_aspect_mask(depth_format).contains(vk::ImageAspectFlags::STENCIL) {
+        depth_format
+    } else {
+        vk::Format::UNDEFINED
+    }
+}
+
+// Fails unless the device can resolve depth and stencil with these modes.
+fn check_depth_stencil_resolve(
+    properties: &crate::physical_device::DepthStencilResolveProperties,
+    depth_mode: vk::ResolveModeFlags,
+    stencil_mode: vk::ResolveModeFlags,
+    has_stencil: bool,
+) -> Result<()> {
+    ensure!(
+        properties
+            .supported_depth_resolve_modes
+            .contains(depth_mode),
+        "depth resolve mode {:?} is not supported, supported are {:?}",
+        depth_mode,
+        properties.supported_depth_resolve_modes
+    );
+    if !has_stencil {
+        return Ok(());
+    }
+    ensure!(
+        stencil_mode == vk::ResolveModeFlags::NONE
+            || properties
+                .supported_stencil_resolve_modes
+                .contains(stencil_mode),
+        "stencil resolve mode {:?} is not supported, supported are {:?}",
+        stencil_mode,
+        properties.supported_stencil_resolve_modes
+    );
+    let independent = properties.independent_resolve
+        || depth_mode == stencil_mode
+        || (properties.independent_resolve_none && stencil_mode == vk::ResolveModeFlags::NONE);
+    ensure!(
+        independent,
+        "the device can't resolve depth with {:?} and stencil with {:?}",
+        depth_mode,
+        stencil_mode
+    );
+    Ok(())
+}
+
+impl<'a> CommandRecorder<'a> {
+    // Renders into the attachments without a RenderPass or Framebuffer. The render
+    // area covers the whole attachments, which must share one extent.
+    pub fn begin_rendering<I>(
+        &mut self,
+        color_attachments: &[RenderingAttachment],
+        depth_attachment: Option<&RenderingAttachment>,
+        f: I,
+    ) where
+        I: FnOnce(&mut CommandRecorder),
+    {
+        self.begin_rendering_with_contents(color_attachments, depth_attachment, false, f);
+    }
+
+    // Like `begin_rendering`, but the contents are recorded by `execute_commands` only.
+    pub fn begin_rendering_with_secondaries<I>(
+        &mut self,
+        color_attachments: &[RenderingAttachment],
+        depth_attachment: Option<&RenderingAttachment>,
+        f: I,
+    ) where
+        I: FnOnce(&mut CommandRecorder),
+    {
+        self.begin_rendering_with_contents(color_attachments, depth_attachment, true, f);
+    }
+
+    fn begin_rendering_with_contents<I>(
+        &mut self,
+        color_attachments: &[RenderingAttachment],
+        depth_attachment: Option<&RenderingAttachment>,
+        secondaries: bool,
+        f: I,
+    ) where
+        I: FnOnce(&mut CommandRecorder),
+    {
+        let dynamic_rendering_fn = self
+            .device()
+            .dynamic_rendering_fn()
+            .expect("begin_rendering requires VK_KHR_dynamic_rendering")
+            .clone();
+        assert!(
+            self.subpass_contents.is_none(),
+            "render passes cannot be nested"
+        );
+        let first = color_attachments
+            .first()
+            .or(depth_attachment)
+            .expect("rendering needs at least one attachment");
+        let (width, height) = (first.view.width(), first.view.height());
+        for attachment in color_attachments.iter().chain(depth_attachment) {
+            assert_eq!(
+                (attachment.view.width(), attachment.view.height()),
+                (width, height),
+                "rendering attachments must share one extent"
+            );
+        }
+        let samples = first.samples();
+        for attachment in color_attachments.iter().chain(depth_attachment) {
+            assert_eq!(
+                attachment.samples(),
+                samples,
+                "rendering attachments must share one sample count"
+            );
+            if let Some((resolve, _)) = &attachment.resolve {
+                assert_ne!(
+                    samples,
+                    vk::SampleCountFlags::TYPE_1,
+                    "only multisampled attachments can be resolved"
+                );
+                assert_eq!(
+                    resolve.inner.image.samples(),
+                    vk::SampleCountFlags::TYPE_1,
+                    "resolve attachments must be single sampled"
+                );
+                assert_eq!(
+                    resolve.format(),
+                    attachment.view.format(),
+                    "resolve attachment format differs from the attachment"
+                );
+            }
+        }
+        for attachment in color_attachments {
+            if let Some((_, mode)) = &attachment.resolve {
+                let format = attachment.view.format();
+                let expected = if is_integer_format(format) {
+                    vk::ResolveModeFlags::SAMPLE_ZERO
+                } else {
+                    vk::ResolveModeFlags::AVERAGE
+                };
+                assert_eq!(
+                    *mode, expected,
+                    "{:?} color attachments resolve with {:?}",
+                    format, expected
+                );
+            }
+        }
+        let has_stencil = depth_attachment.map_or(false, |attachment| {
+            stencil_format(attachment.view.format()) != vk::Format::UNDEFINED
+        });
+        if let Some(depth_attachment) = depth_attachment {
+            assert!(
+                format_aspect_mask(depth_attachment.view.format())
+                    .contains(vk::ImageAspectFlags::DEPTH),
+                "depth attachment has no depth aspect"
+            );
+            if let Some((_, depth_mode)) = &depth_attachment.resolve {
+                let stencil_mode = if has_stencil {
+                    depth_attachment.stencil_resolve_mode
+                } else {
+                    vk::ResolveModeFlags::NONE
+                };
+                if let Err(err) = check_depth_stencil_resolve(
+                    self.device()
+                        .inner
+                        .pdevice
+                        .depth_stencil_resolve_properties(),
+                    *depth_mode,
+                    stencil_mode,
+                    has_stencil,
+                ) {
+                    panic!("{}", err);
+                }
+            }
+        }
+
+        let color_infos = color_attachments
+            .iter()
+            .map(|attachment| attachment.info(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+            .collect::<Vec<_>>();
+        let depth_info = depth_attachment
+            .map(|attachment| attachment.info(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL));
+        // Same image as depth, but with its own resolve mode.
+        let stencil_info = match (depth_attachment, depth_info) {
+            (Some(attachment), Some(info)) if has_stencil => {
+                let mut info = info;
+                if attachment.resolve.is_some() {
+                    info.resolve_mode = attachment.stencil_resolve_mode;
+                    if info.resolve_mode == vk::ResolveModeFlags::NONE {
+                        info.resolve_image_view = vk::ImageView::null();
+                    }
+                }
+                Some(info)
+            }
+            _ => None,
+        };
+        let info = RenderingInfo {
+            flags: if secondaries {
+                RENDERING_CONTENTS_SECONDARY_COMMAND_BUFFERS
+            } else {
+                0
+            },
+            render_area: vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent: vk::Extent2D { width, height },
+            },
+            color_attachment_count: color_infos.len() as u32,
+            p_color_attachments: color_infos.as_ptr(),
+            p_depth_attachment: depth_info
+                .as_ref()
+                .map_or(std::ptr::null(), |info| info as *const _),
+            p_stencil_attachment: stencil_info
+                .as_ref()
+                .map_or(std::ptr::null(), |info| info as *const _),
+            ..Default::default()
+        };
+        for attachment in color_attachments.iter().chain(depth_attachment) {
+            self.command_buffer
+                .resources
+                .push(Box::new(attachment.view.clone()));
+            if let Some((resolve, _)) = &attachment.resolve {
+                self.command_buffer
+                    .resources
+                    .push(Box::new(resolve.clone()));
+            }
+        }
+
+        unsafe {
+            (dynamic_rendering_fn.cmd_begin_rendering_khr)(self.command_buffer.handle, &info);
+        }
+        self.subpass_contents = Some(if secondaries {
+            vk::SubpassContents::SECONDARY_COMMAND_BUFFERS
+        } else {
+            vk::SubpassContents::INLINE
+        });
+        self.rendering_color_formats = Some(
+            color_attachments
+                .iter()
+                .map(|attachment| attachment.view.format())
+                .collect(),
+        );
+
+        f(self);
+
+        self.subpass_contents = None;
+        self.rendering_color_formats = None;
+        unsafe {
+            (dynamic_rendering_fn.cmd_end_rendering_khr)(self.command_buffer.handle);
+        }
+    }
+}
+
+#[test]
+fn test_begin_rendering_clear() {
+    use crate::device::test_device;
+
+    let device = test_device();
+    if device.dynamic_rendering_fn().is_none() {
+        return;
+    }
+
+    let image = device.create_image(
+        Some("dynamic rendering target"),
+        vk::Format::R8G8B8A8_UNORM,
+        4,
+        4,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+        gpu_allocator::MemoryLocation::GpuOnly,
+    );
+    image.set_layout(
+        vk::ImageLayout::UNDEFINED,
+        vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    );
+    let view = image.create_view();
+    let mut command_buffer = device.create_command_buffer(
+        Some("dynamic rendering"),
+        device.graphics_queue_family_index(),
+    );
+    command_buffer.encode(|recorder| {
+        recorder.begin_rendering(
+            &[RenderingAttachment::new(&view).clear_color([1.0, 0.0, 0.0, 1.0])],
+            None,
+            |_| {},
+        );
+    });
+    device.graphics_queue().submit_blocking(&[command_buffer]);
+
+    let pixels = image.read_to_vec(0, 0).unwrap();
+    assert!(pixels.chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+}
+
+#[test]
+fn test_check_depth_stencil_resolve() {
+    use crate::physical_device::DepthStencilResolveProperties;
+
+    let mut properties = DepthStencilResolveProperties {
+        supported_depth_resolve_modes: vk::ResolveModeFlags::SAMPLE_ZERO
+            | vk::ResolveModeFlags::MIN,
+        supported_stencil_resolve_modes: vk::ResolveModeFlags::SAMPLE_ZERO,
+        independent_resolve_none: true,
+        independent_resolve: false,
+    };
+    let sample_zero = vk::ResolveModeFlags::SAMPLE_ZERO;
+    let none = vk::ResolveModeFlags::NONE;
+    let min = vk::ResolveModeFlags::MIN;
+    assert!(check_depth_stencil_resolve(&properties, sample_zero, sample_zero, true).is_ok());
+    assert!(check_depth_stencil_resolve(&properties, min, none, true).is_ok());
+    // Depth only formats ignore the stencil mode.
+    assert!(check_depth_stencil_resolve(&properties, min, sample_zero, false).is_ok());
+    assert!(check_depth_stencil_resolve(&properties, min, sample_zero, true).is_err());
+    assert!(
+        check_depth_stencil_resolve(&properties, vk::ResolveModeFlags::AVERAGE, none, false)
+            .is_err()
+    );
+    assert!(check_depth_stencil_resolve(&properties, sample_zero, min, true).is_err());
+
+    properties.independent_resolve = true;
+    assert!(check_depth_stencil_resolve(&properties, min, sample_zero, true).is_ok());
+    properties.independent_resolve = false;
+    properties.independent_resolve_none = false;
+    assert!(check_depth_stencil_resolve(&properties, min, none, true).is_err());
+
+    assert!(crate::image::is_integer_format(vk::Format::R32G32_UINT));
+    assert!(!crate::image::is_integer_format(vk::Format::R8G8B8A8_UNORM));
+}
@@ -10,8 +10,9 @@ pub struct FenceRef {
     device: Device,
 }
 
+#[derive(Clone)]
 pub struct Fence {
-    inner: Arc<FenceRef>,
+    pub(crate) inner: Arc<FenceRef>,
 }
 
 impl Fence {
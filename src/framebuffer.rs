@@ -8,7 +8,7 @@ pub(crate) struct FramebufferRef {
     pub(crate) device: Device,
     pub(crate) handle: vk::Framebuffer,
     render_pass: RenderPass,
-    attachments: Vec<ImageView>,
+    pub(crate) attachments: Vec<ImageView>,
     width: u32,
     height: u32,
 }
@@ -26,6 +26,33 @@ impl Framebuffer {
         height: u32,
         attachments: Vec<&ImageView>,
     ) -> Self {
+        if let Some(desc) = render_pass.desc() {
+            assert_eq!(
+                attachments.len(),
+                desc.attachments.len(),
+                "render pass expects {} attachments",
+                desc.attachments.len()
+            );
+            for (i, (view, attachment)) in attachments.iter().zip(&desc.attachments).enumerate() {
+                assert_eq!(
+                    view.format(),
+                    attachment.format,
+                    "framebuffer attachment {} has the wrong format",
+                    i
+                );
+                assert_eq!(
+                    view.image().samples(),
+                    attachment.samples,
+                    "framebuffer attachment {} has the wrong sample count",
+                    i
+                );
+                assert!(
+                    view.width() >= width && view.height() >= height,
+                    "framebuffer attachment {} is smaller than the framebuffer",
+                    i
+                );
+            }
+        }
         unsafe {
             let attachment_handles = attachments
                 .iter()
@@ -0,0 +1,222 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+
+use crate::shader_module::{ShaderModuleCode, ShaderModuleRef};
+use crate::{ShaderModule, ShaderStage};
+
+// New code for one module while its dependents are rebuilt.
+pub(crate) type CodeOverride<'a> = Option<(&'a ShaderModule, &'a Arc<ShaderModuleCode>)>;
+
+// A rebuilt pipeline that is not in use yet.
+pub(crate) struct PreparedReload {
+    // Swaps the new pipeline in and destroys the old one once the GPU is done with it.
+    pub(crate) commit: Box<dyn FnOnce()>,
+    // Destroys the new pipeline, used if another dependent of the module failed.
+    pub(crate) discard: Box<dyn FnOnce()>,
+}
+
+pub(crate) trait Reloadable: Send + Sync {
+    // Builds a replacement with `code` in place of `module`'s current code.
+    fn prepare(
+        self: Arc<Self>,
+        module: &ShaderModule,
+        code: &Arc<ShaderModuleCode>,
+    ) -> Result<PreparedReload>;
+}
+
+// Makes the file backed modules of `stages` rebuild `dependent` when reloaded.
+pub(crate) fn register_dependent<'a, I>(stages: I, dependent: Weak<dyn Reloadable>)
+where
+    I: IntoIterator<Item = &'a ShaderStage>,
+{
+    let mut modules: Vec<&ShaderModule> = Vec::new();
+    for stage in stages {
+        let module = stage.module();
+        if module.path().is_some() && !modules.iter().any(|m| Arc::ptr_eq(&m.inner, &module.inner))
+        {
+            modules.push(module);
+        }
+    }
+    for module in modules {
+        let mut dependents = module.inner.dependents.lock().unwrap();
+        dependents.retain(|dependent| dependent.strong_count() > 0);
+        dependents.push(dependent.clone());
+    }
+}
+
+impl ShaderModule {
+    // Reads the module's file again and rebuilds the pipelines using it. Either
+    // the module and all of them switch to the new code or nothing changes.
+    // Returns the number of rebuilt pipelines.
+    pub fn reload(&self) -> Result<usize> {
+        let path = match self.path() {
+            Some(path) => path,
+            None => bail!("shader module was not created from a file"),
+        };
+        let _reload = self.inner.reload_lock.lock().unwrap();
+        let device = self.code().device.clone();
+        let code = ShaderModuleCode::new(&device, &std::fs::read(path)?)?;
+        let dependents = {
+            let mut dependents = self.inner.dependents.lock().unwrap();
+            dependents.retain(|dependent| dependent.strong_count() > 0);
+            dependents
+                .iter()
+                .filter_map(Weak::upgrade)
+                .collect::<Vec<_>>()
+        };
+
+        let mut prepared = Vec::new();
+        for dependent in dependents {
+            match dependent.prepare(self, &code) {
+                Ok(reload) => prepared.push(reload),
+                Err(err) => {
+                    for reload in prepared {
+                        (reload.discard)();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        self.replace_code(code);
+        let count = prepared.len();
+        for reload in prepared {
+            (reload.commit)();
+        }
+        Ok(count)
+    }
+}
+
+struct WatchedModule {
+    module: Weak<ShaderModuleRef>,
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+pub struct ShaderReload {
+    pub path: PathBuf,
+    // The number of rebuilt pipelines, or why everything was kept as it was.
+    pub result: Result<usize>,
+}
+
+// Polls the files of watched shader modules and reloads the changed ones.
+#[derive(Default)]
+pub struct ShaderReloader {
+    modules: Vec<WatchedModule>,
+}
+
+impl ShaderReloader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn watch(&mut self, module: &ShaderModule) -> Result<()> {
+        let path = match module.path() {
+            Some(path) => path.to_owned(),
+            None => bail!("only shader modules created from a file can be watched"),
+        };
+        let modified = std::fs::metadata(&path)?.modified().ok();
+        self.modules.push(WatchedModule {
+            module: Arc::downgrade(&module.inner),
+            path,
+            modified,
+        });
+        Ok(())
+    }
+
+    // Old pipelines are destroyed once the submitted work is done, so call it
+    // between frames, before recording command buffers that bind them.
+    pub fn poll(&mut self) -> Vec<ShaderReload> {
+        self.modules
+            .retain(|watched| watched.module.strong_count() > 0);
+        let mut reloads = Vec::new();
+        for watched in &mut self.modules {
+            let modified = std::fs::metadata(&watched.path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            // A missing file is likely being replaced, look again next time.
+            if modified.is_none() || modified == watched.modified {
+                continue;
+            }
+            watched.modified = modified;
+            let module = match watched.module.upgrade() {
+                Some(inner) => ShaderModule { inner },
+                None => continue,
+            };
+            let result = module.reload();
+            match &result {
+                Ok(count) => {
+                    log::info!(
+                        "reloaded {}, rebuilt {} pipelines",
+                        watched.path.display(),
+                        count
+                    )
+                }
+                Err(err) => {
+                    log::error!("failed to reload {}: {:#}", watched.path.display(), err)
+                }
+            }
+            reloads.push(ShaderReload {
+                path: watched.path.clone(),
+                result,
+            });
+        }
+        reloads
+    }
+}
+
+#[test]
+fn test_reload_graphics_pipeline() {
+    use crate::device::test_device;
+    use crate::GraphicsPipelineBuilder;
+    use ash::vk;
+
+    let device = test_device();
+    if device.dynamic_rendering_fn().is_none() {
+        return;
+    }
+
+    let fixture =
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/spirv/vertex.spv");
+    let path = std::env::temp_dir().join("maligog_test_reload_vertex.spv");
+    std::fs::copy(&fixture, &path).unwrap();
+    let module = device.create_shader_module_from_file(&path).unwrap();
+    let stage = ShaderStage::new(&module, vk::ShaderStageFlags::VERTEX, "main").unwrap();
+    let layout = device
+        .create_reflected_layout(Some("reload"), &[&stage], 0)
+        .unwrap();
+    let pipeline = GraphicsPipelineBuilder::new(&layout.pipeline_layout)
+        .name("reload")
+        .stage(stage)
+        .rendering_formats(&[vk::Format::R8G8B8A8_UNORM], None)
+        .build(&device)
+        .unwrap();
+    let clone = pipeline.clone();
+    let old_handle = pipeline.handle();
+    let old_build = pipeline.current_handle();
+    let mut cmd_buf = device.create_command_buffer(None, device.graphics_queue_family_index());
+    cmd_buf.encode(|recorder| {
+        recorder.bind_graphics_pipeline(&pipeline, |_| {});
+    });
+
+    // Broken SPIR-V keeps the module and the pipeline as they were.
+    std::fs::write(&path, [0u8; 7]).unwrap();
+    assert!(module.reload().is_err());
+    assert_eq!(clone.handle(), old_handle);
+
+    std::fs::copy(&fixture, &path).unwrap();
+    assert_eq!(module.reload().unwrap(), 1);
+    assert_ne!(clone.handle(), old_handle);
+    // The recorded command buffer keeps the old build alive.
+    assert_eq!(Arc::strong_count(&old_build), 2);
+    drop(cmd_buf);
+    assert_eq!(Arc::strong_count(&old_build), 1);
+    assert_eq!(
+        pipeline.desc().unwrap().stages[0].module,
+        module.code().handle
+    );
+    std::fs::remove_file(&path).ok();
+}
@@ -4,6 +4,7 @@ use std::sync::LockResult;
 use std::sync::Mutex;
 use std::sync::MutexGuard;
 
+use anyhow::{bail, ensure, Context, Result};
 use ash::vk;
 use ash::vk::Handle;
 
@@ -23,8 +24,16 @@ pub struct ImageRef {
     pub(crate) handle: vk::Image,
     pub(crate) device: Device,
     image_type: ImageType,
+    kind: vk::ImageType,
     width: u32,
     height: u32,
+    depth: u32,
+    mip_levels: u32,
+    array_layers: u32,
+    samples: vk::SampleCountFlags,
+    flags: vk::ImageCreateFlags,
+    usage: vk::ImageUsageFlags,
+    tiling: vk::ImageTiling,
     layout: std::sync::atomic::AtomicI32,
     format: vk::Format,
     pub name: Option<String>,
@@ -35,6 +44,70 @@ pub struct Image {
     pub(crate) inner: Arc<ImageRef>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageDesc {
+    pub image_type: vk::ImageType,
+    pub format: vk::Format,
+    pub extent: vk::Extent3D,
+    pub mip_levels: u32,
+    pub array_layers: u32,
+    pub samples: vk::SampleCountFlags,
+    pub flags: vk::ImageCreateFlags,
+    pub usage: vk::ImageUsageFlags,
+    pub tiling: vk::ImageTiling,
+    pub location: gpu_allocator::MemoryLocation,
+}
+
+impl ImageDesc {
+    pub fn new_2d(
+        format: vk::Format,
+        width: u32,
+        height: u32,
+        usage: vk::ImageUsageFlags,
+        location: gpu_allocator::MemoryLocation,
+    ) -> Self {
+        Self {
+            image_type: vk::ImageType::TYPE_2D,
+            format,
+            extent: vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_levels: 1,
+            array_layers: 1,
+            samples: vk::SampleCountFlags::TYPE_1,
+            flags: vk::ImageCreateFlags::empty(),
+            usage,
+            tiling: default_tiling(location),
+            location,
+        }
+    }
+
+    pub fn tiling(mut self, tiling: vk::ImageTiling) -> Self {
+        self.tiling = tiling;
+        self
+    }
+
+    // Multisampled images are limited to single mip 2D images with optimal tiling.
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+}
+
+// Host-visible images default to linear tiling so they can be mapped directly.
+pub(crate) fn default_tiling(location: gpu_allocator::MemoryLocation) -> vk::ImageTiling {
+    match location {
+        gpu_allocator::MemoryLocation::Unknown => {
+            unimplemented!()
+        }
+        gpu_allocator::MemoryLocation::GpuOnly => vk::ImageTiling::OPTIMAL,
+        gpu_allocator::MemoryLocation::CpuToGpu => vk::ImageTiling::LINEAR,
+        gpu_allocator::MemoryLocation::GpuToCpu => vk::ImageTiling::LINEAR,
+    }
+}
+
 impl Image {
     pub fn new(
         name: Option<&str>,
@@ -45,63 +118,87 @@ impl Image {
         image_usage: vk::ImageUsageFlags,
         location: gpu_allocator::MemoryLocation,
     ) -> Self {
+        Self::with_desc(
+            name,
+            device,
+            &ImageDesc::new_2d(format, width, height, image_usage, location),
+        )
+        .unwrap()
+    }
+
+    pub fn with_desc(name: Option<&str>, device: &Device, desc: &ImageDesc) -> Result<Self> {
+        let location = desc.location;
+        let tiling = desc.tiling;
+        ensure!(
+            desc.samples.as_raw().count_ones() == 1,
+            "{:?} is not a single sample count",
+            desc.samples
+        );
+        if desc.samples != vk::SampleCountFlags::TYPE_1 {
+            ensure!(
+                desc.image_type == vk::ImageType::TYPE_2D
+                    && desc.mip_levels == 1
+                    && tiling == vk::ImageTiling::OPTIMAL
+                    && !desc.flags.contains(vk::ImageCreateFlags::CUBE_COMPATIBLE),
+                "multisampled images must be 2D with one mip level and optimal tiling"
+            );
+        }
+        if tiling == vk::ImageTiling::LINEAR || desc.samples != vk::SampleCountFlags::TYPE_1 {
+            check_image_format_properties(device, desc)?;
+        }
+        // Linear images keep their texel layout, so host writes made before the
+        // first transition are preserved.
+        let initial_layout = match tiling {
+            vk::ImageTiling::LINEAR => vk::ImageLayout::PREINITIALIZED,
+            _ => vk::ImageLayout::UNDEFINED,
+        };
         unsafe {
-            let handle = device
-                .inner
-                .handle
-                .create_image(
-                    &vk::ImageCreateInfo::builder()
-                        .image_type(vk::ImageType::TYPE_2D)
-                        .format(format)
-                        .extent(vk::Extent3D {
-                            width,
-                            height,
-                            depth: 1,
-                        })
-                        .samples(vk::SampleCountFlags::TYPE_1)
-                        .mip_levels(1)
-                        .array_layers(1)
-                        .tiling(match location {
-                            gpu_allocator::MemoryLocation::Unknown => {
-                                unimplemented!()
-                            }
-                            gpu_allocator::MemoryLocation::GpuOnly => vk::ImageTiling::OPTIMAL,
-                            gpu_allocator::MemoryLocation::CpuToGpu => vk::ImageTiling::LINEAR,
-                            gpu_allocator::MemoryLocation::GpuToCpu => vk::ImageTiling::LINEAR,
-                        })
-                        .usage(image_usage)
-                        .sharing_mode(vk::SharingMode::CONCURRENT)
-                        .queue_family_indices(device.all_queue_family_indices())
-                        .initial_layout(vk::ImageLayout::UNDEFINED)
-                        .build(),
-                    None,
-                )
-                .unwrap();
-            let allocation = device
-                .inner
-                .allocator
-                .lock()
-                .unwrap()
-                .allocate(&AllocationCreateDesc {
-                    name: name.unwrap_or("default"),
-                    requirements: device.inner.handle.get_image_memory_requirements(handle),
-                    location: location,
-                    linear: match location {
-                        gpu_allocator::MemoryLocation::Unknown => {
-                            unimplemented!()
-                        }
-                        gpu_allocator::MemoryLocation::GpuOnly => true,
-                        gpu_allocator::MemoryLocation::CpuToGpu => false,
-                        gpu_allocator::MemoryLocation::GpuToCpu => false,
-                    },
-                })
-                .unwrap();
-
-            device
-                .inner
-                .handle
-                .bind_image_memory(handle, allocation.memory(), allocation.offset())
-                .unwrap();
+            let handle = device.inner.handle.create_image(
+                &vk::ImageCreateInfo::builder()
+                    .image_type(desc.image_type)
+                    .format(desc.format)
+                    .extent(desc.extent)
+                    .samples(desc.samples)
+                    .mip_levels(desc.mip_levels)
+                    .array_layers(desc.array_layers)
+                    .flags(desc.flags)
+                    .tiling(tiling)
+                    .usage(desc.usage)
+                    .sharing_mode(vk::SharingMode::CONCURRENT)
+                    .queue_family_indices(device.all_queue_family_indices())
+                    .initial_layout(initial_layout)
+                    .build(),
+                None,
+            )?;
+            let allocation =
+                device
+                    .inner
+                    .allocator
+                    .lock()
+                    .unwrap()
+                    .allocate(&AllocationCreateDesc {
+                        name: name.unwrap_or("default"),
+                        requirements: device.inner.handle.get_image_memory_requirements(handle),
+                        location: location,
+                        linear: tiling == vk::ImageTiling::LINEAR,
+                    });
+            let allocation = match allocation {
+                Ok(allocation) => allocation,
+                Err(e) => {
+                    device.inner.handle.destroy_image(handle, None);
+                    return Err(e.into());
+                }
+            };
+
+            if let Err(e) = device.inner.handle.bind_image_memory(
+                handle,
+                allocation.memory(),
+                allocation.offset(),
+            ) {
+                device.inner.allocator.lock().unwrap().free(allocation)?;
+                device.inner.handle.destroy_image(handle, None);
+                return Err(e.into());
+            }
             if let Some(name) = name {
                 device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::IMAGE);
             }
@@ -110,20 +207,28 @@ impl Image {
                 allocation: Mutex::new(allocation),
             };
 
-            let layout = std::sync::atomic::AtomicI32::new(vk::ImageLayout::UNDEFINED.as_raw());
+            let layout = std::sync::atomic::AtomicI32::new(initial_layout.as_raw());
 
-            Self {
+            Ok(Self {
                 inner: Arc::new(ImageRef {
                     device: device.clone(),
                     handle,
-                    width,
-                    height,
+                    kind: desc.image_type,
+                    width: desc.extent.width,
+                    height: desc.extent.height,
+                    depth: desc.extent.depth,
+                    mip_levels: desc.mip_levels,
+                    array_layers: desc.array_layers,
+                    samples: desc.samples,
+                    flags: desc.flags,
+                    usage: desc.usage,
+                    tiling,
                     layout,
                     image_type,
-                    format,
+                    format: desc.format,
                     name: name.map(|s| s.to_owned()),
                 }),
-            }
+            })
         }
     }
 
@@ -138,7 +243,7 @@ impl Image {
         data: I,
     ) -> Self {
         let data = data.as_ref();
-        let mut image = Self::new(
+        let image = Self::new(
             name,
             &device,
             format,
@@ -147,60 +252,116 @@ impl Image {
             image_usage | vk::ImageUsageFlags::TRANSFER_DST,
             location,
         );
-        image.set_layout(
-            vk::ImageLayout::UNDEFINED,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        );
-        let mut guard = image.lock_memory().unwrap().unwrap();
-        match guard.mapped_slice_mut() {
-            Some(mapped) => {
-                mapped[0..data.len()].copy_from_slice(data.as_ref());
-            }
-            None => {
-                let staging_buffer = device.create_buffer_init(
-                    Some("staging buffer"),
-                    data,
-                    vk::BufferUsageFlags::TRANSFER_SRC,
-                    gpu_allocator::MemoryLocation::CpuToGpu,
-                );
-                let mut cmd_buf = device.create_command_buffer(
-                    Some("transfer staging"),
-                    device.transfer_queue_family_index(),
-                );
-                cmd_buf.encode(|recorder| {
-                    unsafe {
-                        recorder.copy_buffer_to_image_raw(
-                            &staging_buffer,
-                            &image,
-                            &[vk::BufferImageCopy::builder()
-                                .image_extent(vk::Extent3D {
-                                    width: image.width(),
-                                    height: image.height(),
-                                    depth: 1,
-                                })
-                                .image_offset(vk::Offset3D::default())
-                                .image_subresource(
-                                    vk::ImageSubresourceLayers::builder()
-                                        .layer_count(1)
-                                        .base_array_layer(0)
-                                        .aspect_mask(vk::ImageAspectFlags::COLOR)
-                                        .mip_level(0)
-                                        .build(),
-                                )
-                                .buffer_offset(0)
-                                .buffer_image_height(0)
-                                .buffer_row_length(0)
-                                .build()],
-                        )
-                    }
-                });
-                device.transfer_queue().submit_blocking(&[cmd_buf]);
-            }
+        if image.is_host_accessible() {
+            image.write_from_slice(0, 0, data).unwrap();
+            image.set_layout(image.layout(), vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+            return image;
         }
-        drop(guard);
+        image.set_layout(image.layout(), vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        let staging_buffer = device.create_buffer_init(
+            Some("staging buffer"),
+            data,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        );
+        let mut cmd_buf = device.create_command_buffer(
+            Some("transfer staging"),
+            device.transfer_queue_family_index(),
+        );
+        cmd_buf.encode(|recorder| unsafe {
+            recorder.copy_buffer_to_image_raw(
+                &staging_buffer,
+                &image,
+                &[vk::BufferImageCopy::builder()
+                    .image_extent(vk::Extent3D {
+                        width: image.width(),
+                        height: image.height(),
+                        depth: 1,
+                    })
+                    .image_offset(vk::Offset3D::default())
+                    .image_subresource(
+                        vk::ImageSubresourceLayers::builder()
+                            .layer_count(1)
+                            .base_array_layer(0)
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .mip_level(0)
+                            .build(),
+                    )
+                    .buffer_offset(0)
+                    .buffer_image_height(0)
+                    .buffer_row_length(0)
+                    .build()],
+            )
+        });
+        device.transfer_queue().submit_blocking(&[cmd_buf]);
         image
     }
 
+    // The host may only touch linear images through their mapping while they
+    // are in the PREINITIALIZED or GENERAL layout.
+    pub fn is_host_accessible(&self) -> bool {
+        if self.tiling() != vk::ImageTiling::LINEAR {
+            return false;
+        }
+        if !matches!(
+            self.layout(),
+            vk::ImageLayout::PREINITIALIZED | vk::ImageLayout::GENERAL
+        ) {
+            return false;
+        }
+        match self.lock_memory() {
+            Some(guard) => guard.unwrap().mapped_ptr().is_some(),
+            None => false,
+        }
+    }
+
+    pub fn subresource_layout(&self, mip_level: u32, array_layer: u32) -> vk::SubresourceLayout {
+        assert_eq!(self.tiling(), vk::ImageTiling::LINEAR);
+        assert!(mip_level < self.mip_levels());
+        assert!(array_layer < self.array_layers());
+        assert_eq!(
+            self.samples(),
+            vk::SampleCountFlags::TYPE_1,
+            "multisampled images must be resolved before reading them back"
+        );
+        unsafe {
+            self.inner.device.handle().get_image_subresource_layout(
+                self.handle(),
+                vk::ImageSubresource {
+                    aspect_mask: copy_aspect_mask(self.format()),
+                    mip_level,
+                    array_layer,
+                },
+            )
+        }
+    }
+
+    // Writes tightly packed texels into one subresource of a host accessible
+    // linear image, honoring the driver's row and depth pitch.
+    pub fn write_from_slice(&self, mip_level: u32, array_layer: u32, data: &[u8]) -> Result<()> {
+        ensure!(
+            self.is_host_accessible(),
+            "image is not a mapped linear image in PREINITIALIZED or GENERAL layout"
+        );
+        let extent = self.mip_extent(mip_level);
+        let (row_size, rows) = block_rows(self.format(), copy_aspect_mask(self.format()), extent)?;
+        ensure!(
+            data.len() == row_size * rows as usize * extent.depth as usize,
+            "expected {} bytes for mip level {}, got {}",
+            row_size * rows as usize * extent.depth as usize,
+            mip_level,
+            data.len()
+        );
+        let subresource_layout = self.subresource_layout(mip_level, array_layer);
+        let mut guard = self.lock_memory().unwrap().unwrap();
+        let mapped = guard.mapped_slice_mut().unwrap();
+        for (i, row) in data.chunks_exact(row_size).enumerate() {
+            let start = row_offset(&subresource_layout, i, rows);
+            mapped[start..start + row_size].copy_from_slice(row);
+        }
+        Ok(())
+    }
+
     pub fn lock_memory(&self) -> Option<LockResult<MutexGuard<Allocation>>> {
         match &self.inner.image_type {
             ImageType::Allocated { allocation } => Some(allocation.lock()),
@@ -212,6 +373,12 @@ impl Image {
         vk::ImageLayout::from_raw(self.inner.layout.load(std::sync::atomic::Ordering::SeqCst))
     }
 
+    pub(crate) fn store_layout(&self, layout: vk::ImageLayout) {
+        self.inner
+            .layout
+            .store(layout.as_raw(), std::sync::atomic::Ordering::SeqCst);
+    }
+
     // pub fn new_with<I: AsRef<[u8]>>(
     //     name: Option<&str>,
     //     device: &Device,
@@ -335,8 +502,16 @@ impl Image {
                 device: device.clone(),
                 handle,
                 image_type: ImageType::FromHandle,
+                kind: vk::ImageType::TYPE_2D,
                 width,
                 height,
+                depth: 1,
+                mip_levels: 1,
+                array_layers: 1,
+                samples: vk::SampleCountFlags::TYPE_1,
+                flags: vk::ImageCreateFlags::empty(),
+                usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST,
+                tiling: vk::ImageTiling::OPTIMAL,
                 layout: std::sync::atomic::AtomicI32::new(vk::ImageLayout::UNDEFINED.as_raw()),
                 format,
                 name: None,
@@ -430,28 +605,78 @@ impl Image {
         self.inner.height
     }
 
+    pub fn depth(&self) -> u32 {
+        self.inner.depth
+    }
+
+    pub fn image_type(&self) -> vk::ImageType {
+        self.inner.kind
+    }
+
+    pub fn flags(&self) -> vk::ImageCreateFlags {
+        self.inner.flags
+    }
+
+    pub fn usage(&self) -> vk::ImageUsageFlags {
+        self.inner.usage
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.inner.mip_levels
+    }
+
+    pub fn array_layers(&self) -> u32 {
+        self.inner.array_layers
+    }
+
+    pub fn samples(&self) -> vk::SampleCountFlags {
+        self.inner.samples
+    }
+
+    pub fn tiling(&self) -> vk::ImageTiling {
+        self.inner.tiling
+    }
+
     pub fn linear_size(&self) -> u64 {
-        let pixel_size = match self.format() {
-            crate::Format::R32_SFLOAT => 4,
-            crate::Format::R32G32B32A32_SFLOAT => 16,
-            crate::Format::B8G8R8A8_UNORM | crate::Format::R8G8B8A8_UNORM => 4,
-            _ => {
+        let texel_size = match try_texel_size(self.format()) {
+            Some(size) => size,
+            None => {
                 unimplemented!("{:?}", self.format());
             }
         };
-        (self.width() * self.height()) as u64 * pixel_size
+        (self.width() * self.height()) as u64 * texel_size
+    }
+
+    pub fn mip_extent(&self, mip_level: u32) -> vk::Extent3D {
+        vk::Extent3D {
+            width: (self.width() >> mip_level).max(1),
+            height: (self.height() >> mip_level).max(1),
+            depth: (self.depth() >> mip_level).max(1),
+        }
+    }
+
+    // Every mip level and array layer of the image.
+    pub fn subresource_range(&self) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange::builder()
+            .aspect_mask(format_aspect_mask(self.format()))
+            .base_mip_level(0)
+            .level_count(self.mip_levels())
+            .base_array_layer(0)
+            .layer_count(self.array_layers())
+            .build()
     }
 
     pub(crate) fn handle(&self) -> vk::Image {
         self.inner.handle
     }
 
-    pub fn set_layout(&self, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout) {
-        let mut cmd_buf = self.device().create_command_buffer(
-            Some("set layout barrier"),
-            self.device().transfer_queue_family_index(),
-        );
-        let image_memory_barrier = vk::ImageMemoryBarrier2KHR::builder()
+    pub(crate) fn layout_transition_barrier(
+        &self,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+        subresource_range: vk::ImageSubresourceRange,
+    ) -> vk::ImageMemoryBarrier2KHR {
+        vk::ImageMemoryBarrier2KHR::builder()
             .src_stage_mask(vk::PipelineStageFlags2KHR::ALL_COMMANDS)
             .src_access_mask(vk::AccessFlags2KHR::MEMORY_READ | vk::AccessFlags2KHR::MEMORY_WRITE)
             .dst_stage_mask(vk::PipelineStageFlags2KHR::ALL_COMMANDS)
@@ -461,16 +686,17 @@ impl Image {
             .image(self.handle())
             .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
             .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-            .subresource_range(
-                vk::ImageSubresourceRange::builder()
-                    .aspect_mask(vk::ImageAspectFlags::COLOR)
-                    .base_mip_level(0)
-                    .level_count(1)
-                    .base_array_layer(0)
-                    .layer_count(1)
-                    .build(),
-            )
-            .build();
+            .subresource_range(subresource_range)
+            .build()
+    }
+
+    pub fn set_layout(&self, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout) {
+        let mut cmd_buf = self.device().create_command_buffer(
+            Some("set layout barrier"),
+            self.device().transfer_queue_family_index(),
+        );
+        let image_memory_barrier =
+            self.layout_transition_barrier(old_layout, new_layout, self.subresource_range());
         cmd_buf.encode(|recorder| {
             recorder.pipeline_barrier(
                 &vk::DependencyInfoKHR::builder()
@@ -479,22 +705,370 @@ impl Image {
             )
         });
         self.device().transfer_queue().submit_blocking(&[cmd_buf]);
+        self.inner
+            .layout
+            .store(new_layout.as_raw(), std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // Reads one subresource back as tightly packed texels in the image's format.
+    // Only the depth aspect of combined depth/stencil formats is read.
+    pub fn read_to_vec(&self, mip_level: u32, array_layer: u32) -> Result<Vec<u8>> {
+        assert!(mip_level < self.mip_levels());
+        assert!(array_layer < self.array_layers());
+        let extent = self.mip_extent(mip_level);
+        let aspect_mask = copy_aspect_mask(self.format());
+        let (row_size, rows) = block_rows(self.format(), aspect_mask, extent)?;
+        let mut data = vec![0; row_size * rows as usize * extent.depth as usize];
+
+        if self.is_host_accessible() {
+            let subresource_layout = self.subresource_layout(mip_level, array_layer);
+            let guard = self.lock_memory().unwrap().unwrap();
+            let mapped = guard.mapped_slice().unwrap();
+            for (i, row) in data.chunks_exact_mut(row_size).enumerate() {
+                let start = row_offset(&subresource_layout, i, rows);
+                row.copy_from_slice(&mapped[start..start + row_size]);
+            }
+            return Ok(data);
+        }
+
+        let device = self.device();
+        let readback_buffer = device.create_buffer(
+            Some("readback buffer"),
+            data.len(),
+            vk::BufferUsageFlags::TRANSFER_DST,
+            gpu_allocator::MemoryLocation::GpuToCpu,
+        );
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(format_aspect_mask(self.format()))
+            .base_mip_level(mip_level)
+            .level_count(1)
+            .base_array_layer(array_layer)
+            .layer_count(1)
+            .build();
+        let old_layout = self.layout();
+        let restore_layout = match old_layout {
+            vk::ImageLayout::UNDEFINED | vk::ImageLayout::PREINITIALIZED => {
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+            }
+            layout => layout,
+        };
+        let mut cmd_buf = device
+            .create_command_buffer(Some("image readback"), device.transfer_queue_family_index());
+        cmd_buf.encode(|recorder| {
+            recorder.pipeline_barrier(
+                &vk::DependencyInfoKHR::builder()
+                    .image_memory_barriers(&[self.layout_transition_barrier(
+                        old_layout,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        subresource_range,
+                    )])
+                    .build(),
+            );
+            recorder.copy_image_to_buffer_regions(
+                self,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                &readback_buffer,
+                &[vk::BufferImageCopy::builder()
+                    .buffer_offset(0)
+                    .buffer_row_length(0)
+                    .buffer_image_height(0)
+                    .image_subresource(
+                        vk::ImageSubresourceLayers::builder()
+                            .aspect_mask(aspect_mask)
+                            .mip_level(mip_level)
+                            .base_array_layer(array_layer)
+                            .layer_count(1)
+                            .build(),
+                    )
+                    .image_offset(vk::Offset3D::default())
+                    .image_extent(extent)
+                    .build()],
+            );
+            recorder.pipeline_barrier(
+                &vk::DependencyInfoKHR::builder()
+                    .image_memory_barriers(&[self.layout_transition_barrier(
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        restore_layout,
+                        subresource_range,
+                    )])
+                    .build(),
+            );
+        });
+        device.transfer_queue().submit_blocking(&[cmd_buf]);
+        self.inner
+            .layout
+            .store(restore_layout.as_raw(), std::sync::atomic::Ordering::SeqCst);
+
+        let guard = readback_buffer.lock_memory().unwrap();
+        data.copy_from_slice(&guard.mapped_slice().unwrap()[..data.len()]);
+        Ok(data)
+    }
+}
+
+// Bytes per row of texel blocks and block rows per depth slice of an extent.
+fn block_rows(
+    format: vk::Format,
+    aspect: vk::ImageAspectFlags,
+    extent: vk::Extent3D,
+) -> Result<(usize, u32)> {
+    let (block_width, block_height, block_size) = aspect_block_layout(format, aspect)
+        .with_context(|| format!("unsupported format {:?}", format))?;
+    let row_size = ((extent.width + block_width - 1) / block_width) as usize * block_size as usize;
+    Ok((row_size, (extent.height + block_height - 1) / block_height))
+}
+
+// Offset of the i-th row of a subresource, counting rows across depth slices.
+fn row_offset(subresource_layout: &vk::SubresourceLayout, row: usize, height: u32) -> usize {
+    let y = (row % height as usize) as u64;
+    let z = (row / height as usize) as u64;
+    (subresource_layout.offset
+        + z * subresource_layout.depth_pitch
+        + y * subresource_layout.row_pitch) as usize
+}
+
+fn check_image_format_properties(device: &Device, desc: &ImageDesc) -> Result<()> {
+    let properties = unsafe {
+        device
+            .inner
+            .pdevice
+            .instance
+            .inner
+            .handle
+            .get_physical_device_image_format_properties(
+                device.inner.pdevice.handle,
+                desc.format,
+                desc.image_type,
+                desc.tiling,
+                desc.usage,
+                desc.flags,
+            )
+    };
+    let properties = match properties {
+        Ok(properties) => properties,
+        Err(vk::Result::ERROR_FORMAT_NOT_SUPPORTED) => {
+            bail!(
+                "format {:?} with {:?} tiling and usage {:?} is not supported",
+                desc.format,
+                desc.tiling,
+                desc.usage
+            )
+        }
+        Err(e) => return Err(e.into()),
+    };
+    ensure!(
+        desc.extent.width <= properties.max_extent.width
+            && desc.extent.height <= properties.max_extent.height
+            && desc.extent.depth <= properties.max_extent.depth,
+        "extent {:?} exceeds the maximum {:?} for {:?} with {:?} tiling",
+        desc.extent,
+        properties.max_extent,
+        desc.format,
+        desc.tiling
+    );
+    ensure!(
+        desc.mip_levels <= properties.max_mip_levels,
+        "{} mip levels exceed the maximum of {} for {:?} with {:?} tiling",
+        desc.mip_levels,
+        properties.max_mip_levels,
+        desc.format,
+        desc.tiling
+    );
+    ensure!(
+        desc.array_layers <= properties.max_array_layers,
+        "{} array layers exceed the maximum of {} for {:?} with {:?} tiling",
+        desc.array_layers,
+        properties.max_array_layers,
+        desc.format,
+        desc.tiling
+    );
+    ensure!(
+        properties.sample_counts.contains(desc.samples),
+        "{:?} is not supported for {:?} with usage {:?}, supported are {:?}",
+        desc.samples,
+        desc.format,
+        desc.usage,
+        properties.sample_counts
+    );
+    Ok(())
+}
+
+pub(crate) fn try_texel_size(format: vk::Format) -> Option<u64> {
+    match format {
+        vk::Format::R8_UNORM | vk::Format::R8_UINT | vk::Format::R8_SRGB => Some(1),
+        vk::Format::R8G8_UNORM | vk::Format::R16_SFLOAT | vk::Format::D16_UNORM => Some(2),
+        vk::Format::R32_SFLOAT
+        | vk::Format::R32_UINT
+        | vk::Format::D32_SFLOAT
+        | vk::Format::R16G16_SFLOAT
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB => Some(4),
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R32G32_SFLOAT => Some(8),
+        vk::Format::R32G32B32A32_SFLOAT => Some(16),
+        _ => None,
+    }
+}
+
+// Texel block width, height and size in bytes, (1, 1, texel size) for
+// uncompressed formats.
+pub(crate) fn block_layout(format: vk::Format) -> Option<(u32, u32, u64)> {
+    const ASTC_BLOCKS: [(u32, u32); 14] = [
+        (4, 4),
+        (5, 4),
+        (5, 5),
+        (6, 5),
+        (6, 6),
+        (8, 5),
+        (8, 6),
+        (8, 8),
+        (10, 5),
+        (10, 6),
+        (10, 8),
+        (10, 10),
+        (12, 10),
+        (12, 12),
+    ];
+    if let Some(size) = try_texel_size(format) {
+        return Some((1, 1, size));
+    }
+    let raw = format.as_raw();
+    let bc1 = vk::Format::BC1_RGB_UNORM_BLOCK.as_raw();
+    let etc2 = vk::Format::ETC2_R8G8B8_UNORM_BLOCK.as_raw();
+    let astc = vk::Format::ASTC_4X4_UNORM_BLOCK.as_raw();
+    match format {
+        vk::Format::BC1_RGB_UNORM_BLOCK
+        | vk::Format::BC1_RGB_SRGB_BLOCK
+        | vk::Format::BC1_RGBA_UNORM_BLOCK
+        | vk::Format::BC1_RGBA_SRGB_BLOCK
+        | vk::Format::BC4_UNORM_BLOCK
+        | vk::Format::BC4_SNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8_SRGB_BLOCK
+        | vk::Format::ETC2_R8G8B8A1_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8A1_SRGB_BLOCK
+        | vk::Format::EAC_R11_UNORM_BLOCK
+        | vk::Format::EAC_R11_SNORM_BLOCK => Some((4, 4, 8)),
+        _ if (bc1..=vk::Format::BC7_SRGB_BLOCK.as_raw()).contains(&raw)
+            || (etc2..=vk::Format::EAC_R11G11_SNORM_BLOCK.as_raw()).contains(&raw) =>
+        {
+            Some((4, 4, 16))
+        }
+        // ASTC formats come in unorm and srgb pairs ordered by block size.
+        _ if (astc..=vk::Format::ASTC_12X12_SRGB_BLOCK.as_raw()).contains(&raw) => {
+            let (width, height) = ASTC_BLOCKS[(raw - astc) as usize / 2];
+            Some((width, height, 16))
+        }
+        _ => None,
+    }
+}
+
+// Block layout of a single aspect as buffer copies lay it out. Packed depth
+// takes 4 bytes and stencil 1.
+pub(crate) fn aspect_block_layout(
+    format: vk::Format,
+    aspect: vk::ImageAspectFlags,
+) -> Option<(u32, u32, u64)> {
+    if aspect == vk::ImageAspectFlags::STENCIL {
+        return Some((1, 1, 1));
+    }
+    match format {
+        vk::Format::D16_UNORM_S8_UINT => Some((1, 1, 2)),
+        vk::Format::X8_D24_UNORM_PACK32
+        | vk::Format::D24_UNORM_S8_UINT
+        | vk::Format::D32_SFLOAT_S8_UINT => Some((1, 1, 4)),
+        _ => block_layout(format),
+    }
+}
+
+// Unsigned and signed integer color formats, which can't be averaged.
+pub(crate) fn is_integer_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::R8_UINT
+            | vk::Format::R8_SINT
+            | vk::Format::R8G8_UINT
+            | vk::Format::R8G8_SINT
+            | vk::Format::R8G8B8_UINT
+            | vk::Format::R8G8B8_SINT
+            | vk::Format::B8G8R8_UINT
+            | vk::Format::B8G8R8_SINT
+            | vk::Format::R8G8B8A8_UINT
+            | vk::Format::R8G8B8A8_SINT
+            | vk::Format::B8G8R8A8_UINT
+            | vk::Format::B8G8R8A8_SINT
+            | vk::Format::A8B8G8R8_UINT_PACK32
+            | vk::Format::A8B8G8R8_SINT_PACK32
+            | vk::Format::A2R10G10B10_UINT_PACK32
+            | vk::Format::A2R10G10B10_SINT_PACK32
+            | vk::Format::A2B10G10R10_UINT_PACK32
+            | vk::Format::A2B10G10R10_SINT_PACK32
+            | vk::Format::R16_UINT
+            | vk::Format::R16_SINT
+            | vk::Format::R16G16_UINT
+            | vk::Format::R16G16_SINT
+            | vk::Format::R16G16B16_UINT
+            | vk::Format::R16G16B16_SINT
+            | vk::Format::R16G16B16A16_UINT
+            | vk::Format::R16G16B16A16_SINT
+            | vk::Format::R32_UINT
+            | vk::Format::R32_SINT
+            | vk::Format::R32G32_UINT
+            | vk::Format::R32G32_SINT
+            | vk::Format::R32G32B32_UINT
+            | vk::Format::R32G32B32_SINT
+            | vk::Format::R32G32B32A32_UINT
+            | vk::Format::R32G32B32A32_SINT
+            | vk::Format::R64_UINT
+            | vk::Format::R64_SINT
+            | vk::Format::R64G64_UINT
+            | vk::Format::R64G64_SINT
+            | vk::Format::R64G64B64_UINT
+            | vk::Format::R64G64B64_SINT
+            | vk::Format::R64G64B64A64_UINT
+            | vk::Format::R64G64B64A64_SINT
+    )
+}
+
+pub(crate) fn format_aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::X8_D24_UNORM_PACK32 | vk::Format::D32_SFLOAT => {
+            vk::ImageAspectFlags::DEPTH
+        }
+        vk::Format::S8_UINT => vk::ImageAspectFlags::STENCIL,
+        vk::Format::D16_UNORM_S8_UINT
+        | vk::Format::D24_UNORM_S8_UINT
+        | vk::Format::D32_SFLOAT_S8_UINT => {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        }
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
+
+// Buffer copies and subresource layouts take a single aspect, so combined
+// depth/stencil formats default to depth.
+pub(crate) fn copy_aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    let aspect_mask = format_aspect_mask(format);
+    if aspect_mask.contains(vk::ImageAspectFlags::DEPTH) {
+        vk::ImageAspectFlags::DEPTH
+    } else {
+        aspect_mask
     }
 }
 
 impl Drop for ImageRef {
     fn drop(&mut self) {
         match &self.image_type {
-            ImageType::Allocated { allocation, .. } => unsafe {
-                self.device
-                    .inner
-                    .allocator
-                    .lock()
-                    .unwrap()
-                    .free(allocation.lock().unwrap().clone())
-                    .unwrap();
-                self.device.inner.handle.destroy_image(self.handle, None);
-            },
+            ImageType::Allocated { allocation, .. } => {
+                let handle = self.handle;
+                let allocation = allocation.lock().unwrap().clone();
+                self.device.destroy_deferred(move |device| {
+                    device.allocator.lock().unwrap().free(allocation).unwrap();
+                    unsafe {
+                        device.handle.destroy_image(handle, None);
+                    }
+                });
+            }
             ImageType::FromHandle => {}
         }
     }
@@ -513,6 +1087,10 @@ impl Device {
         Image::new(name, self, format, width, height, image_usage, location)
     }
 
+    pub fn create_image_with_desc(&self, name: Option<&str>, desc: &ImageDesc) -> Result<Image> {
+        Image::with_desc(name, self, desc)
+    }
+
     pub fn create_image_init<D>(
         &self,
         name: Option<&str>,
@@ -538,3 +1116,176 @@ impl Device {
         )
     }
 }
+
+#[test]
+fn test_image_read_to_vec() {
+    use crate::device::test_device;
+
+    let device = test_device();
+
+    let pixels = (0..4 * 3 * 4).map(|i| i as u8).collect::<Vec<u8>>();
+    let image = device.create_image_init(
+        Some("readback image"),
+        vk::Format::R8G8B8A8_UNORM,
+        4,
+        3,
+        vk::ImageUsageFlags::TRANSFER_SRC,
+        gpu_allocator::MemoryLocation::GpuOnly,
+        &pixels,
+    );
+    assert_eq!(image.read_to_vec(0, 0).unwrap(), pixels);
+}
+
+#[test]
+fn test_linear_image_row_pitch() {
+    use crate::device::test_device;
+
+    let device = test_device();
+
+    // an odd width makes the driver pad rows past the tightly packed size
+    let image = device
+        .create_image_with_desc(
+            Some("linear image"),
+            &ImageDesc::new_2d(
+                vk::Format::R8G8B8A8_UNORM,
+                5,
+                3,
+                vk::ImageUsageFlags::TRANSFER_SRC,
+                gpu_allocator::MemoryLocation::GpuToCpu,
+            ),
+        )
+        .unwrap();
+    assert_eq!(image.tiling(), vk::ImageTiling::LINEAR);
+    assert!(image.is_host_accessible());
+    let pixels = (0..5 * 3 * 4).map(|i| i as u8).collect::<Vec<u8>>();
+    image.write_from_slice(0, 0, &pixels).unwrap();
+    assert_eq!(image.read_to_vec(0, 0).unwrap(), pixels);
+    assert!(image.write_from_slice(0, 0, &pixels[1..]).is_err());
+}
+
+#[test]
+fn test_resolve_image() {
+    use crate::device::test_device;
+
+    let device = test_device();
+    assert!(device
+        .supported_sample_counts()
+        .contains(device.inner.pdevice.max_sample_count()));
+    if !device
+        .supported_sample_counts()
+        .contains(vk::SampleCountFlags::TYPE_4)
+    {
+        return;
+    }
+
+    let msaa = device
+        .create_image_with_desc(
+            Some("msaa image"),
+            &ImageDesc::new_2d(
+                vk::Format::R8G8B8A8_UNORM,
+                4,
+                4,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+                gpu_allocator::MemoryLocation::GpuOnly,
+            )
+            .samples(vk::SampleCountFlags::TYPE_4),
+        )
+        .unwrap();
+    assert_eq!(msaa.samples(), vk::SampleCountFlags::TYPE_4);
+    let resolved = device.create_image(
+        Some("resolved image"),
+        vk::Format::R8G8B8A8_UNORM,
+        4,
+        4,
+        vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST,
+        gpu_allocator::MemoryLocation::GpuOnly,
+    );
+    msaa.set_layout(vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL);
+    resolved.set_layout(vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL);
+
+    let mut command_buffer =
+        device.create_command_buffer(Some("resolve"), device.graphics_queue_family_index());
+    command_buffer.encode(|recorder| {
+        recorder.clear_color_image(
+            &msaa,
+            &vk::ClearColorValue {
+                float32: [0.0, 1.0, 0.0, 1.0],
+            },
+        );
+        recorder.pipeline_barrier(
+            &vk::DependencyInfoKHR::builder()
+                .memory_barriers(&[vk::MemoryBarrier2KHR::builder()
+                    .src_stage_mask(vk::PipelineStageFlags2KHR::TRANSFER)
+                    .src_access_mask(vk::AccessFlags2KHR::TRANSFER_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2KHR::TRANSFER)
+                    .dst_access_mask(vk::AccessFlags2KHR::TRANSFER_READ)
+                    .build()])
+                .build(),
+        );
+        recorder.resolve_image_whole(
+            &msaa,
+            vk::ImageLayout::GENERAL,
+            &resolved,
+            vk::ImageLayout::GENERAL,
+        );
+    });
+    device.graphics_queue().submit_blocking(&[command_buffer]);
+
+    let pixels = resolved.read_to_vec(0, 0).unwrap();
+    assert!(pixels.chunks(4).all(|pixel| pixel == [0, 255, 0, 255]));
+}
+
+#[test]
+fn test_block_layout() {
+    assert_eq!(block_layout(vk::Format::R8G8B8A8_UNORM), Some((1, 1, 4)));
+    assert_eq!(
+        block_layout(vk::Format::BC1_RGBA_SRGB_BLOCK),
+        Some((4, 4, 8))
+    );
+    assert_eq!(block_layout(vk::Format::BC7_UNORM_BLOCK), Some((4, 4, 16)));
+    assert_eq!(
+        block_layout(vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK),
+        Some((4, 4, 16))
+    );
+    assert_eq!(
+        block_layout(vk::Format::EAC_R11_SNORM_BLOCK),
+        Some((4, 4, 8))
+    );
+    assert_eq!(
+        block_layout(vk::Format::ASTC_4X4_UNORM_BLOCK),
+        Some((4, 4, 16))
+    );
+    assert_eq!(
+        block_layout(vk::Format::ASTC_10X8_SRGB_BLOCK),
+        Some((10, 8, 16))
+    );
+    assert_eq!(
+        block_layout(vk::Format::ASTC_12X12_SRGB_BLOCK),
+        Some((12, 12, 16))
+    );
+    assert_eq!(block_layout(vk::Format::R64_UINT), None);
+}
+
+#[test]
+fn test_copy_aspect() {
+    let format = vk::Format::D24_UNORM_S8_UINT;
+    assert_eq!(copy_aspect_mask(format), vk::ImageAspectFlags::DEPTH);
+    assert_eq!(
+        aspect_block_layout(format, copy_aspect_mask(format)),
+        Some((1, 1, 4))
+    );
+    assert_eq!(
+        aspect_block_layout(format, vk::ImageAspectFlags::STENCIL),
+        Some((1, 1, 1))
+    );
+    assert_eq!(
+        aspect_block_layout(vk::Format::D16_UNORM_S8_UINT, vk::ImageAspectFlags::DEPTH),
+        Some((1, 1, 2))
+    );
+    assert_eq!(
+        copy_aspect_mask(vk::Format::R8G8B8A8_UNORM),
+        vk::ImageAspectFlags::COLOR
+    );
+}
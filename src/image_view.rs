@@ -1,13 +1,219 @@
 use std::sync::Arc;
 
+use anyhow::{bail, ensure, Result};
 use ash::vk::{self, Handle};
 
+use crate::image::{format_aspect_mask, try_texel_size};
 use crate::{Device, Image};
 
+#[derive(Clone, Debug)]
+pub struct ImageViewDesc {
+    pub view_type: vk::ImageViewType,
+    // None keeps the image's own format
+    pub format: Option<vk::Format>,
+    pub components: vk::ComponentMapping,
+    // None selects every aspect of the view format
+    pub aspect_mask: Option<vk::ImageAspectFlags>,
+    pub base_mip_level: u32,
+    // None selects the remaining mip levels / array layers
+    pub level_count: Option<u32>,
+    pub base_array_layer: u32,
+    pub layer_count: Option<u32>,
+}
+
+impl Default for ImageViewDesc {
+    fn default() -> Self {
+        Self {
+            view_type: vk::ImageViewType::TYPE_2D,
+            format: None,
+            components: vk::ComponentMapping::default(),
+            aspect_mask: None,
+            base_mip_level: 0,
+            level_count: None,
+            base_array_layer: 0,
+            layer_count: None,
+        }
+    }
+}
+
+impl ImageViewDesc {
+    pub fn view_type(mut self, view_type: vk::ImageViewType) -> Self {
+        self.view_type = view_type;
+        self
+    }
+
+    pub fn format(mut self, format: vk::Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn components(mut self, components: vk::ComponentMapping) -> Self {
+        self.components = components;
+        self
+    }
+
+    pub fn aspect_mask(mut self, aspect_mask: vk::ImageAspectFlags) -> Self {
+        self.aspect_mask = Some(aspect_mask);
+        self
+    }
+
+    pub fn mip_levels(mut self, base_mip_level: u32, level_count: u32) -> Self {
+        self.base_mip_level = base_mip_level;
+        self.level_count = Some(level_count);
+        self
+    }
+
+    pub fn array_layers(mut self, base_array_layer: u32, layer_count: u32) -> Self {
+        self.base_array_layer = base_array_layer;
+        self.layer_count = Some(layer_count);
+        self
+    }
+
+    fn is_array(&self) -> bool {
+        matches!(
+            self.view_type,
+            vk::ImageViewType::TYPE_1D_ARRAY
+                | vk::ImageViewType::TYPE_2D_ARRAY
+                | vk::ImageViewType::CUBE
+                | vk::ImageViewType::CUBE_ARRAY
+        )
+    }
+
+    pub(crate) fn validate(&self, image: &Image) -> Result<vk::ImageSubresourceRange> {
+        let format = self.format.unwrap_or_else(|| image.format());
+        if format != image.format() {
+            ensure!(
+                image.flags().contains(vk::ImageCreateFlags::MUTABLE_FORMAT),
+                "view format {:?} differs from image format {:?} but the image is not MUTABLE_FORMAT",
+                format,
+                image.format()
+            );
+            if let (Some(view_size), Some(image_size)) =
+                (try_texel_size(format), try_texel_size(image.format()))
+            {
+                ensure!(
+                    view_size == image_size,
+                    "view format {:?} is not size-compatible with image format {:?}",
+                    format,
+                    image.format()
+                );
+            }
+        }
+
+        let format_aspect = format_aspect_mask(format);
+        let aspect_mask = self.aspect_mask.unwrap_or(format_aspect);
+        ensure!(
+            !aspect_mask.is_empty() && format_aspect.contains(aspect_mask),
+            "aspect {:?} is not part of format {:?}",
+            aspect_mask,
+            format
+        );
+
+        ensure!(
+            self.base_mip_level < image.mip_levels(),
+            "base mip level {} out of range, image has {} levels",
+            self.base_mip_level,
+            image.mip_levels()
+        );
+        let level_count = self
+            .level_count
+            .unwrap_or(image.mip_levels() - self.base_mip_level);
+        ensure!(
+            level_count > 0 && self.base_mip_level + level_count <= image.mip_levels(),
+            "mip levels {}..{} out of range, image has {} levels",
+            self.base_mip_level,
+            self.base_mip_level + level_count,
+            image.mip_levels()
+        );
+
+        ensure!(
+            self.base_array_layer < image.array_layers(),
+            "base array layer {} out of range, image has {} layers",
+            self.base_array_layer,
+            image.array_layers()
+        );
+        let layer_count = match (self.layer_count, self.is_array()) {
+            (Some(count), _) => count,
+            (None, true) => image.array_layers() - self.base_array_layer,
+            (None, false) => 1,
+        };
+        ensure!(
+            layer_count > 0 && self.base_array_layer + layer_count <= image.array_layers(),
+            "array layers {}..{} out of range, image has {} layers",
+            self.base_array_layer,
+            self.base_array_layer + layer_count,
+            image.array_layers()
+        );
+        if !self.is_array() {
+            ensure!(
+                layer_count == 1,
+                "{:?} views must have exactly one layer",
+                self.view_type
+            );
+        }
+
+        match (image.image_type(), self.view_type) {
+            (vk::ImageType::TYPE_1D, vk::ImageViewType::TYPE_1D)
+            | (vk::ImageType::TYPE_1D, vk::ImageViewType::TYPE_1D_ARRAY)
+            | (vk::ImageType::TYPE_2D, vk::ImageViewType::TYPE_2D)
+            | (vk::ImageType::TYPE_2D, vk::ImageViewType::TYPE_2D_ARRAY)
+            | (vk::ImageType::TYPE_3D, vk::ImageViewType::TYPE_3D) => {}
+            (vk::ImageType::TYPE_2D, vk::ImageViewType::CUBE)
+            | (vk::ImageType::TYPE_2D, vk::ImageViewType::CUBE_ARRAY) => {
+                ensure!(
+                    image
+                        .flags()
+                        .contains(vk::ImageCreateFlags::CUBE_COMPATIBLE),
+                    "cube views require a CUBE_COMPATIBLE image"
+                );
+                ensure!(
+                    layer_count % 6 == 0,
+                    "cube views require a multiple of 6 layers, got {}",
+                    layer_count
+                );
+                if self.view_type == vk::ImageViewType::CUBE {
+                    ensure!(layer_count == 6, "cube views require exactly 6 layers");
+                }
+            }
+            (vk::ImageType::TYPE_3D, vk::ImageViewType::TYPE_2D)
+            | (vk::ImageType::TYPE_3D, vk::ImageViewType::TYPE_2D_ARRAY) => {
+                ensure!(
+                    image
+                        .flags()
+                        .contains(vk::ImageCreateFlags::TYPE_2D_ARRAY_COMPATIBLE),
+                    "2D views of a 3D image require a 2D_ARRAY_COMPATIBLE image"
+                );
+                ensure!(
+                    level_count == 1,
+                    "2D views of a 3D image must have exactly one mip level"
+                );
+            }
+            (image_type, view_type) => {
+                bail!(
+                    "view type {:?} is incompatible with image type {:?}",
+                    view_type,
+                    image_type
+                );
+            }
+        }
+
+        Ok(vk::ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level: self.base_mip_level,
+            level_count,
+            base_array_layer: self.base_array_layer,
+            layer_count,
+        })
+    }
+}
+
 pub struct ImageViewRef {
     pub(crate) device: Device,
     pub(crate) handle: vk::ImageView,
     pub(crate) image: Image,
+    view_type: vk::ImageViewType,
+    format: vk::Format,
+    subresource_range: vk::ImageSubresourceRange,
 }
 
 #[derive(Clone)]
@@ -17,45 +223,40 @@ pub struct ImageView {
 
 impl ImageView {
     pub fn new(device: &Device, image: &Image) -> Self {
+        Self::with_desc(device, image, &ImageViewDesc::default()).unwrap()
+    }
+
+    pub fn with_desc(device: &Device, image: &Image, desc: &ImageViewDesc) -> Result<Self> {
+        let subresource_range = desc.validate(image)?;
+        let format = desc.format.unwrap_or_else(|| image.format());
         unsafe {
-            let handle = device
-                .handle()
-                .create_image_view(
-                    &vk::ImageViewCreateInfo::builder()
-                        .components(
-                            vk::ComponentMapping::builder()
-                                .r(vk::ComponentSwizzle::IDENTITY)
-                                .g(vk::ComponentSwizzle::IDENTITY)
-                                .b(vk::ComponentSwizzle::IDENTITY)
-                                .a(vk::ComponentSwizzle::IDENTITY)
-                                .build(),
-                        )
-                        .view_type(vk::ImageViewType::TYPE_2D)
-                        .format(image.format())
-                        .subresource_range(
-                            vk::ImageSubresourceRange::builder()
-                                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                                .base_mip_level(0)
-                                .level_count(1)
-                                .base_array_layer(0)
-                                .layer_count(1)
-                                .build(),
-                        )
-                        .image(image.handle())
-                        .build(),
-                    None,
-                )
-                .unwrap();
+            let handle = device.handle().create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .components(desc.components)
+                    .view_type(desc.view_type)
+                    .format(format)
+                    .subresource_range(subresource_range)
+                    .image(image.handle())
+                    .build(),
+                None,
+            )?;
             if let Some(name) = &image.inner.name {
-                device.debug_set_object_name(name.as_str(), handle.as_raw(), vk::ObjectType::IMAGE_VIEW);
+                device.debug_set_object_name(
+                    name.as_str(),
+                    handle.as_raw(),
+                    vk::ObjectType::IMAGE_VIEW,
+                );
             }
-            Self {
+            Ok(Self {
                 inner: Arc::new(ImageViewRef {
                     image: image.clone(),
                     handle,
                     device: device.clone(),
+                    view_type: desc.view_type,
+                    format,
+                    subresource_range,
                 }),
-            }
+            })
         }
     }
 
@@ -63,20 +264,37 @@ impl ImageView {
         self.inner.image.clone()
     }
 
+    pub fn view_type(&self) -> vk::ImageViewType {
+        self.inner.view_type
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.inner.format
+    }
+
+    pub fn subresource_range(&self) -> vk::ImageSubresourceRange {
+        self.inner.subresource_range
+    }
+
     pub fn width(&self) -> u32 {
-        self.image().width()
+        self.image()
+            .mip_extent(self.inner.subresource_range.base_mip_level)
+            .width
     }
 
     pub fn height(&self) -> u32 {
-        self.image().height()
+        self.image()
+            .mip_extent(self.inner.subresource_range.base_mip_level)
+            .height
     }
 }
 
 impl Drop for ImageViewRef {
     fn drop(&mut self) {
-        unsafe {
-            self.device.handle().destroy_image_view(self.handle, None);
-        }
+        let handle = self.handle;
+        self.device.destroy_deferred(move |device| unsafe {
+            device.handle.destroy_image_view(handle, None);
+        });
     }
 }
 
@@ -84,4 +302,8 @@ impl Image {
     pub fn create_view(&self) -> ImageView {
         ImageView::new(&self.inner.device, self)
     }
+
+    pub fn create_view_with(&self, desc: &ImageViewDesc) -> Result<ImageView> {
+        ImageView::with_desc(&self.inner.device, self, desc)
+    }
 }
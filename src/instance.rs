@@ -34,20 +34,6 @@ unsafe extern "system" fn vulkan_debug_callback(
     //     log::debug!("suppress device address validation");
     //     return vk::FALSE;
     // }
-    if message_id_number == 1303270965
-        || message_id_number == -243358461
-        || message_id_number == 169239225
-        || message_id_number == 1287084845
-        || message_id_number == -564812795
-        || message_id_number == -1536361367
-        || message_id_number == -945112042
-        || message_id_number == -1127038373
-        || message_id_number == 1999069451
-    {
-        log::debug!("suppress invalid image layout");
-        return vk::FALSE;
-    }
-
     use vk::DebugUtilsMessageSeverityFlagsEXT;
     match message_severity {
         DebugUtilsMessageSeverityFlagsEXT::VERBOSE => {
@@ -57,7 +43,13 @@ unsafe extern "system" fn vulkan_debug_callback(
             log::warn!("{:?} : {}\n", message_type, message,);
         }
         DebugUtilsMessageSeverityFlagsEXT::ERROR => {
-            log::error!("{:?} {} : {}\n", message_type, message_id_number, message,);
+            log::error!(
+                "{:?} {} {} : {}\n",
+                message_type,
+                message_id_name,
+                message_id_number,
+                message,
+            );
         }
         DebugUtilsMessageSeverityFlagsEXT::INFO => {
             log::info!("{:?} : {}\n", message_type, message,);
@@ -179,6 +171,19 @@ impl Instance {
         }
     }
 
+    // None unless VK_EXT_debug_utils is enabled, labels and object names are skipped then.
+    pub(crate) fn debug_utils_loader(&self) -> Option<&ash::extensions::ext::DebugUtils> {
+        if self
+            .inner
+            .enabled_extensions
+            .contains(&name::instance::Extension::ExtDebugUtils)
+        {
+            Some(&self.inner.debug_utils_loader)
+        } else {
+            None
+        }
+    }
+
     pub fn enumerate_physical_device(&self) -> Vec<PhysicalDevice> {
         unsafe {
             let pdevices = self.inner.handle.enumerate_physical_devices().unwrap();
@@ -188,12 +193,25 @@ impl Instance {
                 .map(|pdevice| {
                     let props = self.inner.handle.get_physical_device_properties(*pdevice);
                     let mut props2 = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+                    let mut resolve_props =
+                        vk::PhysicalDeviceDepthStencilResolveProperties::default();
                     self.inner.handle.get_physical_device_properties2(
                         *pdevice,
                         &mut vk::PhysicalDeviceProperties2::builder()
                             .push_next(&mut props2)
+                            .push_next(&mut resolve_props)
                             .build(),
                     );
+                    let depth_stencil_resolve_properties =
+                        crate::physical_device::DepthStencilResolveProperties {
+                            supported_depth_resolve_modes: resolve_props
+                                .supported_depth_resolve_modes,
+                            supported_stencil_resolve_modes: resolve_props
+                                .supported_stencil_resolve_modes,
+                            independent_resolve_none: resolve_props.independent_resolve_none
+                                == vk::TRUE,
+                            independent_resolve: resolve_props.independent_resolve == vk::TRUE,
+                        };
                     let ray_tracing_pipeline_properties =
                         crate::physical_device::PhysicalDeviceRayTracingPipelineProperties {
                             shader_group_handle_size: props2.shader_group_handle_size,
@@ -225,6 +243,7 @@ impl Instance {
                                     .queue_flags
                                     .contains(vk::QueueFlags::TRANSFER),
                                 count: properties.queue_count,
+                                timestamp_valid_bits: properties.timestamp_valid_bits,
                             }
                         })
                         .collect();
@@ -238,6 +257,9 @@ impl Instance {
                         handle: *pdevice,
                         instance: self.clone(),
                         ray_tracing_pipeline_properties,
+                        depth_stencil_resolve_properties,
+                        properties: props,
+                        features: self.inner.handle.get_physical_device_features(*pdevice),
                         queue_families,
                     }
                 })
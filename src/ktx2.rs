@@ -0,0 +1,565 @@
+use std::convert::TryInto;
+
+use anyhow::{bail, ensure, Context, Result};
+use ash::vk;
+
+use crate::image::{block_layout, ImageDesc};
+use crate::{Device, Image};
+
+const IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const HEADER_SIZE: usize = 80;
+const LEVEL_INDEX_ENTRY_SIZE: usize = 24;
+const BASIC_DESCRIPTOR_BLOCK_MIN_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SupercompressionScheme {
+    None,
+    BasisLZ,
+    Zstandard,
+    ZLIB,
+    Unknown(u32),
+}
+
+impl From<u32> for SupercompressionScheme {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::BasisLZ,
+            2 => Self::Zstandard,
+            3 => Self::ZLIB,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub byte_offset: u64,
+    pub byte_length: u64,
+    pub uncompressed_byte_length: u64,
+}
+
+// The basic descriptor block of the Khronos Data Format Descriptor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataFormatDescriptor {
+    pub color_model: u8,
+    pub color_primaries: u8,
+    pub transfer_function: u8,
+    pub flags: u8,
+    pub texel_block_dimensions: [u32; 4],
+    pub bytes_plane: [u8; 8],
+}
+
+impl DataFormatDescriptor {
+    pub const TRANSFER_FUNCTION_SRGB: u8 = 2;
+    pub const FLAG_ALPHA_PREMULTIPLIED: u8 = 1;
+
+    pub fn is_srgb(&self) -> bool {
+        self.transfer_function == Self::TRANSFER_FUNCTION_SRGB
+    }
+
+    pub fn is_alpha_premultiplied(&self) -> bool {
+        self.flags & Self::FLAG_ALPHA_PREMULTIPLIED != 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ktx2 {
+    pub format: vk::Format,
+    pub type_size: u32,
+    pub pixel_width: u32,
+    pub pixel_height: u32,
+    pub pixel_depth: u32,
+    pub layer_count: u32,
+    pub face_count: u32,
+    pub level_count: u32,
+    pub supercompression_scheme: SupercompressionScheme,
+    pub levels: Vec<Level>,
+    pub data_format_descriptor: DataFormatDescriptor,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("unexpected end of ktx2 data")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .context("unexpected end of ktx2 data")?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// The spec wants 1 for block compressed and 8 bit formats and the component
+// size for everything else.
+fn expected_type_size(format: vk::Format) -> Option<u32> {
+    match format {
+        vk::Format::R16_SFLOAT
+        | vk::Format::D16_UNORM
+        | vk::Format::R16G16_SFLOAT
+        | vk::Format::R16G16B16A16_SFLOAT => Some(2),
+        vk::Format::R32_SFLOAT
+        | vk::Format::R32_UINT
+        | vk::Format::D32_SFLOAT
+        | vk::Format::R32G32_SFLOAT
+        | vk::Format::R32G32B32A32_SFLOAT => Some(4),
+        _ => block_layout(format).map(|_| 1),
+    }
+}
+
+// Bytes of one mip level across all layers and faces, None on overflow.
+fn level_size(
+    format: vk::Format,
+    extent: vk::Extent3D,
+    layers: u32,
+    mip_level: u32,
+) -> Option<u64> {
+    let (block_width, block_height, block_size) = block_layout(format)?;
+    let blocks = |size: u32, block: u32| {
+        ((size >> mip_level).max(1) as u64 + block as u64 - 1) / block as u64
+    };
+    let depth = (extent.depth >> mip_level).max(1);
+    blocks(extent.width, block_width)
+        .checked_mul(blocks(extent.height, block_height))?
+        .checked_mul(depth as u64)?
+        .checked_mul(layers as u64)?
+        .checked_mul(block_size)
+}
+
+impl Ktx2 {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        ensure!(data.len() >= HEADER_SIZE, "ktx2 data too short for header");
+        ensure!(data[0..12] == IDENTIFIER, "invalid ktx2 identifier");
+
+        let format = vk::Format::from_raw(read_u32(data, 12)? as i32);
+        let type_size = read_u32(data, 16)?;
+        let pixel_width = read_u32(data, 20)?;
+        let pixel_height = read_u32(data, 24)?;
+        let pixel_depth = read_u32(data, 28)?;
+        let layer_count = read_u32(data, 32)?;
+        let face_count = read_u32(data, 36)?;
+        let level_count = read_u32(data, 40)?;
+        let supercompression_scheme = SupercompressionScheme::from(read_u32(data, 44)?);
+        let dfd_byte_offset = read_u32(data, 48)? as usize;
+        let dfd_byte_length = read_u32(data, 52)? as usize;
+
+        ensure!(pixel_width > 0, "ktx2 pixel width must not be 0");
+        ensure!(
+            pixel_depth == 0 || pixel_height > 0,
+            "ktx2 3D textures must have a pixel height"
+        );
+        ensure!(
+            face_count == 1 || face_count == 6,
+            "invalid ktx2 face count {}",
+            face_count
+        );
+        ensure!(
+            face_count == 1 || (pixel_width == pixel_height && pixel_depth == 0),
+            "ktx2 cube maps must be square and 2D"
+        );
+        let expected_type_size = expected_type_size(format)
+            .with_context(|| format!("unsupported ktx2 format {:?}", format))?;
+        ensure!(
+            type_size == expected_type_size,
+            "ktx2 type size {} does not match format {:?}",
+            type_size,
+            format
+        );
+        let extent = vk::Extent3D {
+            width: pixel_width,
+            height: pixel_height.max(1),
+            depth: pixel_depth.max(1),
+        };
+        let max_level_count = 32
+            - extent
+                .width
+                .max(extent.height)
+                .max(extent.depth)
+                .leading_zeros();
+        ensure!(
+            level_count <= max_level_count,
+            "ktx2 level count {} exceeds {} for {}x{}x{}",
+            level_count,
+            max_level_count,
+            extent.width,
+            extent.height,
+            extent.depth
+        );
+        let layers = layer_count
+            .max(1)
+            .checked_mul(face_count)
+            .context("ktx2 layer count overflows")?;
+
+        let levels = (0..level_count.max(1) as usize)
+            .map(|i| {
+                let offset = HEADER_SIZE + i * LEVEL_INDEX_ENTRY_SIZE;
+                let level = Level {
+                    byte_offset: read_u64(data, offset)?,
+                    byte_length: read_u64(data, offset + 8)?,
+                    uncompressed_byte_length: read_u64(data, offset + 16)?,
+                };
+                ensure!(
+                    level
+                        .byte_offset
+                        .checked_add(level.byte_length)
+                        .map_or(false, |end| end <= data.len() as u64),
+                    "ktx2 level {} is out of bounds",
+                    i
+                );
+                // Supercompressed levels only have a known size once inflated.
+                let length = match supercompression_scheme {
+                    SupercompressionScheme::None => level.byte_length,
+                    _ => level.uncompressed_byte_length,
+                };
+                let expected = level_size(format, extent, layers, i as u32);
+                ensure!(
+                    expected == Some(length),
+                    "ktx2 level {} is {} bytes, expected {:?}",
+                    i,
+                    length,
+                    expected
+                );
+                Ok(level)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        ensure!(
+            dfd_byte_offset
+                .checked_add(dfd_byte_length)
+                .map_or(false, |end| end <= data.len()),
+            "ktx2 data format descriptor is out of bounds"
+        );
+        let data_format_descriptor = Self::parse_data_format_descriptor(
+            &data[dfd_byte_offset..dfd_byte_offset + dfd_byte_length],
+        )?;
+
+        Ok(Self {
+            format,
+            type_size,
+            pixel_width,
+            pixel_height,
+            pixel_depth,
+            layer_count,
+            face_count,
+            level_count,
+            supercompression_scheme,
+            levels,
+            data_format_descriptor,
+        })
+    }
+
+    fn parse_data_format_descriptor(dfd: &[u8]) -> Result<DataFormatDescriptor> {
+        let total_size = read_u32(dfd, 0)? as usize;
+        ensure!(
+            total_size == dfd.len(),
+            "ktx2 data format descriptor size mismatch"
+        );
+        let block = &dfd[4..];
+        let vendor_and_type = read_u32(block, 0)?;
+        let block_size = (read_u32(block, 4)? >> 16) as usize;
+        ensure!(
+            vendor_and_type == 0,
+            "first ktx2 descriptor block is not a khronos basic block"
+        );
+        ensure!(
+            block_size >= BASIC_DESCRIPTOR_BLOCK_MIN_SIZE && block_size <= block.len(),
+            "invalid ktx2 basic descriptor block size {}",
+            block_size
+        );
+        Ok(DataFormatDescriptor {
+            color_model: block[8],
+            color_primaries: block[9],
+            transfer_function: block[10],
+            flags: block[11],
+            texel_block_dimensions: [
+                block[12] as u32 + 1,
+                block[13] as u32 + 1,
+                block[14] as u32 + 1,
+                block[15] as u32 + 1,
+            ],
+            bytes_plane: block[16..24].try_into().unwrap(),
+        })
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.level_count.max(1)
+    }
+
+    pub fn array_layers(&self) -> u32 {
+        self.layer_count.max(1) * self.face_count
+    }
+
+    pub fn is_cube(&self) -> bool {
+        self.face_count == 6
+    }
+
+    pub fn image_type(&self) -> vk::ImageType {
+        if self.pixel_depth > 0 {
+            vk::ImageType::TYPE_3D
+        } else if self.pixel_height == 0 {
+            vk::ImageType::TYPE_1D
+        } else {
+            vk::ImageType::TYPE_2D
+        }
+    }
+
+    pub fn extent(&self) -> vk::Extent3D {
+        vk::Extent3D {
+            width: self.pixel_width,
+            height: self.pixel_height.max(1),
+            depth: self.pixel_depth.max(1),
+        }
+    }
+
+    // One region per mip level, each covering every layer and face of that level.
+    // Offsets are relative to the start of the ktx2 data, which keeps the
+    // alignment the container guarantees for each level.
+    pub fn buffer_image_copies(&self) -> Vec<vk::BufferImageCopy> {
+        let extent = self.extent();
+        self.levels
+            .iter()
+            .enumerate()
+            .map(|(mip_level, level)| {
+                vk::BufferImageCopy::builder()
+                    .buffer_offset(level.byte_offset)
+                    .buffer_row_length(0)
+                    .buffer_image_height(0)
+                    .image_subresource(
+                        vk::ImageSubresourceLayers::builder()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .mip_level(mip_level as u32)
+                            .base_array_layer(0)
+                            .layer_count(self.array_layers())
+                            .build(),
+                    )
+                    .image_offset(vk::Offset3D::default())
+                    .image_extent(vk::Extent3D {
+                        width: (extent.width >> mip_level).max(1),
+                        height: (extent.height >> mip_level).max(1),
+                        depth: (extent.depth >> mip_level).max(1),
+                    })
+                    .build()
+            })
+            .collect()
+    }
+}
+
+impl Image {
+    pub fn from_ktx2(
+        name: Option<&str>,
+        device: &Device,
+        data: &[u8],
+        image_usage: vk::ImageUsageFlags,
+    ) -> Result<Self> {
+        let ktx2 = Ktx2::parse(data)?;
+        if ktx2.supercompression_scheme != SupercompressionScheme::None {
+            bail!(
+                "unsupported ktx2 supercompression scheme {:?}",
+                ktx2.supercompression_scheme
+            );
+        }
+        let format_properties = unsafe {
+            device
+                .inner
+                .pdevice
+                .instance
+                .inner
+                .handle
+                .get_physical_device_format_properties(device.inner.pdevice.handle, ktx2.format)
+        };
+        let required_features =
+            vk::FormatFeatureFlags::SAMPLED_IMAGE | vk::FormatFeatureFlags::TRANSFER_DST;
+        if !format_properties
+            .optimal_tiling_features
+            .contains(required_features)
+        {
+            bail!("format {:?} is not supported by the device", ktx2.format);
+        }
+
+        let image = Image::with_desc(
+            name,
+            device,
+            &ImageDesc {
+                image_type: ktx2.image_type(),
+                format: ktx2.format,
+                extent: ktx2.extent(),
+                mip_levels: ktx2.mip_levels(),
+                array_layers: ktx2.array_layers(),
+                samples: vk::SampleCountFlags::TYPE_1,
+                flags: match ktx2.is_cube() {
+                    true => vk::ImageCreateFlags::CUBE_COMPATIBLE,
+                    false => vk::ImageCreateFlags::empty(),
+                },
+                usage: image_usage
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+                tiling: vk::ImageTiling::OPTIMAL,
+                location: gpu_allocator::MemoryLocation::GpuOnly,
+            },
+        )?;
+        image.set_layout(
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+
+        let staging_buffer = device.create_buffer_init(
+            Some("ktx2 staging buffer"),
+            data,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        );
+        let regions = ktx2.buffer_image_copies();
+        let mut cmd_buf =
+            device.create_command_buffer(Some("ktx2 upload"), device.transfer_queue_family_index());
+        cmd_buf.encode(|recorder| unsafe {
+            recorder.copy_buffer_to_image_raw(&staging_buffer, &image, &regions);
+        });
+        device.transfer_queue().submit_blocking(&[cmd_buf]);
+        image.set_layout(
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        Ok(image)
+    }
+}
+
+impl Device {
+    pub fn create_image_from_ktx2(
+        &self,
+        name: Option<&str>,
+        data: &[u8],
+        image_usage: vk::ImageUsageFlags,
+    ) -> Result<Image> {
+        Image::from_ktx2(name, self, data, image_usage)
+    }
+}
+
+#[cfg(test)]
+fn build_test_ktx2(
+    format: vk::Format,
+    width: u32,
+    height: u32,
+    face_count: u32,
+    level_sizes: &[u64],
+    supercompression_scheme: u32,
+) -> Vec<u8> {
+    let dfd_offset = HEADER_SIZE + level_sizes.len() * LEVEL_INDEX_ENTRY_SIZE;
+    let dfd_length = 4 + BASIC_DESCRIPTOR_BLOCK_MIN_SIZE + 16;
+    let mut data = Vec::new();
+    data.extend_from_slice(&IDENTIFIER);
+    for value in &[
+        format.as_raw() as u32,
+        1,
+        width,
+        height,
+        0,
+        0,
+        face_count,
+        level_sizes.len() as u32,
+        supercompression_scheme,
+        dfd_offset as u32,
+        dfd_length as u32,
+        0,
+        0,
+    ] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.extend_from_slice(&0u64.to_le_bytes());
+
+    // levels are stored smallest first, but indexed from the base level
+    let mut offsets = vec![0; level_sizes.len()];
+    let mut offset = (dfd_offset + dfd_length + 15) as u64 / 16 * 16;
+    for (i, size) in level_sizes.iter().enumerate().rev() {
+        offsets[i] = offset;
+        offset += size;
+    }
+    for (size, offset) in level_sizes.iter().zip(offsets.iter()) {
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+    }
+
+    data.extend_from_slice(&(dfd_length as u32).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&(((BASIC_DESCRIPTOR_BLOCK_MIN_SIZE + 16) as u32) << 16).to_le_bytes());
+    data.extend_from_slice(&[1, 1, 2, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend_from_slice(&[0; 16]);
+
+    data.resize(offset as usize, 0xCD);
+    data
+}
+
+#[test]
+fn test_parse_ktx2() {
+    let data = build_test_ktx2(vk::Format::R8G8B8A8_SRGB, 4, 4, 1, &[64, 16, 4], 0);
+    let ktx2 = Ktx2::parse(&data).unwrap();
+    assert_eq!(ktx2.format, vk::Format::R8G8B8A8_SRGB);
+    assert_eq!(ktx2.extent().width, 4);
+    assert_eq!(ktx2.mip_levels(), 3);
+    assert_eq!(ktx2.array_layers(), 1);
+    assert_eq!(ktx2.image_type(), vk::ImageType::TYPE_2D);
+    assert!(ktx2.data_format_descriptor.is_srgb());
+    assert_eq!(ktx2.data_format_descriptor.bytes_plane[0], 4);
+
+    let regions = ktx2.buffer_image_copies();
+    assert_eq!(regions.len(), 3);
+    assert_eq!(regions[0].buffer_offset, ktx2.levels[0].byte_offset);
+    assert!(regions[2].buffer_offset < regions[1].buffer_offset);
+    assert_eq!(regions[2].image_extent.width, 1);
+    assert_eq!(regions[1].image_subresource.mip_level, 1);
+}
+
+#[test]
+fn test_parse_ktx2_cube() {
+    let data = build_test_ktx2(vk::Format::BC7_UNORM_BLOCK, 8, 8, 6, &[6 * 64, 6 * 16], 0);
+    let ktx2 = Ktx2::parse(&data).unwrap();
+    assert!(ktx2.is_cube());
+    assert_eq!(ktx2.array_layers(), 6);
+    assert_eq!(
+        ktx2.buffer_image_copies()[1].image_subresource.layer_count,
+        6
+    );
+}
+
+#[test]
+fn test_parse_ktx2_invalid() {
+    let mut data = build_test_ktx2(vk::Format::R8G8B8A8_UNORM, 4, 4, 1, &[64], 0);
+    assert!(Ktx2::parse(&data[..40]).is_err());
+    let truncated = &data[..data.len() - 1];
+    assert!(Ktx2::parse(truncated).is_err());
+    data[0] = 0;
+    assert!(Ktx2::parse(&data).is_err());
+
+    // A level whose offset plus length wraps around u64.
+    let mut data = build_test_ktx2(vk::Format::R8G8B8A8_UNORM, 4, 4, 1, &[64], 0);
+    data[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(Ktx2::parse(&data).is_err());
+
+    // The second level of a 4x4 RGBA8 texture is 16 bytes.
+    let data = build_test_ktx2(vk::Format::R8G8B8A8_UNORM, 4, 4, 1, &[64, 12], 0);
+    assert!(Ktx2::parse(&data).is_err());
+    let data = build_test_ktx2(vk::Format::BC7_UNORM_BLOCK, 8, 8, 1, &[64, 16, 8], 0);
+    assert!(Ktx2::parse(&data).is_err());
+
+    // A 4x4 texture has at most 3 levels.
+    let data = build_test_ktx2(vk::Format::R8G8B8A8_UNORM, 4, 4, 1, &[64, 16, 4, 4], 0);
+    assert!(Ktx2::parse(&data).is_err());
+
+    // R32_SFLOAT is uploaded as 4 byte words, but the builder writes a type size of 1.
+    let data = build_test_ktx2(vk::Format::R32_SFLOAT, 4, 4, 1, &[64], 0);
+    assert!(Ktx2::parse(&data).is_err());
+    let data = build_test_ktx2(vk::Format::UNDEFINED, 4, 4, 1, &[64], 0);
+    assert!(Ktx2::parse(&data).is_err());
+
+    let data = build_test_ktx2(vk::Format::R8G8B8A8_UNORM, 4, 4, 1, &[64], 2);
+    let ktx2 = Ktx2::parse(&data).unwrap();
+    assert_eq!(
+        ktx2.supercompression_scheme,
+        SupercompressionScheme::Zstandard
+    );
+}
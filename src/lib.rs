@@ -2,28 +2,38 @@
 #![feature(negative_impls)]
 
 mod acceleration_structure;
+mod barrier;
 pub mod buffer;
 mod buffer_view;
 mod command_buffer;
 mod command_pool;
 mod command_recorder;
+mod debug_label;
 pub mod descriptor;
 mod descriptor_pool;
 mod descriptor_set;
 mod descriptor_set_layout;
 pub mod device;
+mod dynamic_rendering;
 pub mod entry;
 mod fence;
 mod framebuffer;
+mod hot_reload;
 mod image;
 mod image_view;
 pub mod instance;
+#[cfg(feature = "ktx2")]
+pub mod ktx2;
 pub mod name;
 pub mod physical_device;
 mod pipeline;
+mod profiler;
+mod query_pool;
 mod queue;
 mod queue_family;
 mod ray_tracing;
+pub mod reflection;
+mod render_graph;
 mod render_pass;
 mod sampler;
 mod semaphore;
@@ -37,10 +47,12 @@ pub use acceleration_structure::{
     AABBGeometry, BLASInstance, BottomAccelerationStructure, InstanceGeometry,
     TopAccelerationStructure, TriangleGeometry,
 };
+pub use barrier::{Access, BarrierBuilder, PipelineStage};
 pub use buffer::Buffer;
 pub use buffer_view::{BufferView, IndexBufferView, VertexBufferView};
-pub use command_buffer::CommandBuffer;
-pub use command_recorder::CommandRecorder;
+pub use command_buffer::{CommandBuffer, CommandBufferInheritance};
+pub use command_pool::CommandPool;
+pub use command_recorder::{CommandRecorder, DrawIndexedIndirectCommand, DrawIndirectCommand};
 pub use descriptor::Descriptor;
 pub use descriptor::DescriptorType;
 pub use descriptor_pool::DescriptorPool;
@@ -49,22 +61,38 @@ pub use descriptor_set::DescriptorUpdate;
 pub use descriptor_set_layout::DescriptorSetLayout;
 pub use descriptor_set_layout::DescriptorSetLayoutBinding;
 pub use device::Device;
+pub use dynamic_rendering::RenderingAttachment;
 pub use entry::Entry;
 pub use fence::Fence;
 pub use framebuffer::Framebuffer;
-pub use image::Image;
-pub use image_view::ImageView;
+pub use hot_reload::{ShaderReload, ShaderReloader};
+pub use image::{Image, ImageDesc};
+pub use image_view::{ImageView, ImageViewDesc};
 pub use instance::Instance;
-pub use pipeline::{GraphicsPipeline, PipelineLayout, RayTracingPipeline};
+pub use pipeline::{
+    BlendMode, DepthTest, GraphicsPipeline, GraphicsPipelineBuilder, GraphicsPipelineDesc,
+    PipelineCache, PipelineLayout, RayTracingPipeline, RenderTargetDesc, ShaderStageDesc,
+    VertexAttribute, VertexBinding,
+};
+pub use profiler::{FrameTimings, GpuProfiler, ScopeTiming};
+pub use query_pool::{
+    Occlusion, PipelineStatistics, PipelineStatisticsResult, PlainQueryType, QueryPool, QueryType,
+    ScopedQueryType, Timestamp,
+};
+pub use queue::{Queue, SubmissionHandle, SubmitInfo};
 pub use ray_tracing::HitGroup;
 pub use ray_tracing::{
     ProceduralHitGroup, ShaderBindingTable, ShaderBindingTables, TrianglesHitGroup,
 };
-pub use render_pass::RenderPass;
-pub use sampler::Sampler;
+pub use reflection::{ReflectedLayout, ShaderReflection};
+pub use render_graph::{
+    BufferDesc, GraphResource, PassBuilder, PassResources, QueueType, RenderGraph,
+};
+pub use render_pass::{AttachmentDesc, RenderPass, RenderPassBuilder, RenderPassDesc, SubpassDesc};
+pub use sampler::{Sampler, SamplerDesc};
 pub use semaphore::{BinarySemaphore, TimelineSemaphore};
 pub use shader_module::ShaderModule;
-pub use shader_stage::ShaderStage;
+pub use shader_stage::{ShaderStage, SpecializationValue};
 pub use surface::Surface;
 pub use swapchain::Swapchain;
 
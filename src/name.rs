@@ -47,6 +47,10 @@ pub mod device {
         KhrSynchronization2,
         #[strum(serialize = "VK_KHR_vulkan_memory_model")]
         KhrVulkanMemoryModel,
+        #[strum(serialize = "VK_KHR_draw_indirect_count")]
+        KhrDrawIndirectCount,
+        #[strum(serialize = "VK_KHR_dynamic_rendering")]
+        KhrDynamicRendering,
     }
 }
 
@@ -20,6 +20,16 @@ pub struct PhysicalDeviceRayTracingPipelineProperties {
     pub max_ray_hit_attribute_size: u32,
 }
 
+#[derive(Debug, Clone)]
+pub struct DepthStencilResolveProperties {
+    pub supported_depth_resolve_modes: vk::ResolveModeFlags,
+    pub supported_stencil_resolve_modes: vk::ResolveModeFlags,
+    // Whether one of depth and stencil may use NONE while the other resolves.
+    pub independent_resolve_none: bool,
+    // Whether depth and stencil may use any two supported modes.
+    pub independent_resolve: bool,
+}
+
 #[derive(Clone)]
 pub struct PhysicalDevice {
     pub(crate) name: String,
@@ -27,6 +37,9 @@ pub struct PhysicalDevice {
     pub(crate) handle: vk::PhysicalDevice,
     pub(crate) instance: Instance,
     pub(crate) ray_tracing_pipeline_properties: PhysicalDeviceRayTracingPipelineProperties,
+    pub(crate) depth_stencil_resolve_properties: DepthStencilResolveProperties,
+    pub(crate) properties: vk::PhysicalDeviceProperties,
+    pub(crate) features: vk::PhysicalDeviceFeatures,
     pub queue_families: Vec<QueueFamilyProperties>,
 }
 
@@ -35,6 +48,36 @@ impl PhysicalDevice {
         self.device_type
     }
 
+    pub fn properties(&self) -> &vk::PhysicalDeviceProperties {
+        &self.properties
+    }
+
+    pub fn limits(&self) -> &vk::PhysicalDeviceLimits {
+        &self.properties.limits
+    }
+
+    // Sample counts usable for framebuffers with color, depth and stencil attachments.
+    pub fn supported_sample_counts(&self) -> vk::SampleCountFlags {
+        let limits = self.limits();
+        limits.framebuffer_color_sample_counts
+            & limits.framebuffer_depth_sample_counts
+            & limits.framebuffer_stencil_sample_counts
+    }
+
+    pub fn max_sample_count(&self) -> vk::SampleCountFlags {
+        let counts = self.supported_sample_counts().as_raw();
+        // TYPE_1 is always supported, so the highest bit is the maximum.
+        vk::SampleCountFlags::from_raw(1 << (31 - counts.leading_zeros()))
+    }
+
+    pub fn depth_stencil_resolve_properties(&self) -> &DepthStencilResolveProperties {
+        &self.depth_stencil_resolve_properties
+    }
+
+    pub fn features(&self) -> &vk::PhysicalDeviceFeatures {
+        &self.features
+    }
+
     pub fn supported_device_extensions_raw(&self) -> Vec<String> {
         unsafe {
             self.instance
@@ -1,22 +1,45 @@
 use std::ffi::CString;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use super::PipelineLayout;
-use crate::{Device, RenderPass, ShaderStage};
+use anyhow::{ensure, Context, Result};
+
+use super::{PipelineHandle, PipelineLayout};
+use crate::dynamic_rendering::{stencil_format, PipelineRenderingCreateInfo};
+use crate::hot_reload::{register_dependent, CodeOverride, PreparedReload, Reloadable};
+use crate::shader_module::ShaderModuleCode;
+use crate::{Device, RenderPass, ShaderModule, ShaderStage};
 use ash::vk::{self, Handle};
 
 pub struct GraphicsPipelineRef {
-    pub(crate) handle: vk::Pipeline,
+    // Swapped by shader hot reload.
+    handle: Mutex<Arc<PipelineHandle>>,
+    name: Option<String>,
     pub(crate) layout: PipelineLayout,
     stages: Vec<ShaderStage>,
-    render_pass: RenderPass,
+    // None for pipelines used with dynamic rendering.
+    render_pass: Option<RenderPass>,
+    // Only known for pipelines built by a GraphicsPipelineBuilder.
+    desc: Mutex<Option<GraphicsPipelineDesc>>,
+    // Pipelines created from raw create infos can't be rebuilt by hot reload.
+    builder: Option<GraphicsPipelineBuilder>,
     device: Device,
 }
 
+#[derive(Clone)]
 pub struct GraphicsPipeline {
     pub(crate) inner: Arc<GraphicsPipelineRef>,
 }
 
+// What a graphics pipeline renders into.
+enum RenderTarget<'a> {
+    RenderPass(&'a RenderPass, u32),
+    // Dynamic rendering, the formats must match the attachments at draw time.
+    Rendering {
+        color_formats: &'a [vk::Format],
+        depth_format: Option<vk::Format>,
+    },
+}
+
 impl GraphicsPipeline {
     pub fn new(
         name: Option<&str>,
@@ -33,11 +56,120 @@ impl GraphicsPipeline {
         viewport_state: &vk::PipelineViewportStateCreateInfo,
         dynamic_state: &vk::PipelineDynamicStateCreateInfo,
     ) -> Self {
+        let codes = stages
+            .iter()
+            .map(|s| s.code(None))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let handle = Self::create_handle(
+            device,
+            layout,
+            &stages,
+            &codes,
+            RenderTarget::RenderPass(render_pass, 0),
+            vertex_input_state,
+            input_assembly_state,
+            rasterization_state,
+            multisample_state,
+            depth_stencil_state,
+            color_blend_state,
+            viewport_state,
+            dynamic_state,
+        )
+        .unwrap();
+        Self::from_handle(
+            handle,
+            name,
+            device,
+            layout,
+            stages,
+            Some(render_pass.clone()),
+            None,
+            None,
+        )
+    }
+
+    // A pipeline for `CommandRecorder::begin_rendering` with attachments of the given formats.
+    pub fn with_rendering_formats(
+        name: Option<&str>,
+        device: &Device,
+        layout: &PipelineLayout,
+        stages: Vec<ShaderStage>,
+        color_formats: &[vk::Format],
+        depth_format: Option<vk::Format>,
+        vertex_input_state: &vk::PipelineVertexInputStateCreateInfo,
+        input_assembly_state: &vk::PipelineInputAssemblyStateCreateInfo,
+        rasterization_state: &vk::PipelineRasterizationStateCreateInfo,
+        multisample_state: &vk::PipelineMultisampleStateCreateInfo,
+        depth_stencil_state: &vk::PipelineDepthStencilStateCreateInfo,
+        color_blend_state: &vk::PipelineColorBlendStateCreateInfo,
+        viewport_state: &vk::PipelineViewportStateCreateInfo,
+        dynamic_state: &vk::PipelineDynamicStateCreateInfo,
+    ) -> Self {
+        assert!(
+            device.extension_enabled(crate::name::device::Extension::KhrDynamicRendering),
+            "pipelines without a render pass require VK_KHR_dynamic_rendering"
+        );
+        assert_eq!(
+            color_blend_state.attachment_count as usize,
+            color_formats.len(),
+            "one color blend attachment state is needed per color format"
+        );
+        let codes = stages
+            .iter()
+            .map(|s| s.code(None))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let handle = Self::create_handle(
+            device,
+            layout,
+            &stages,
+            &codes,
+            RenderTarget::Rendering {
+                color_formats,
+                depth_format,
+            },
+            vertex_input_state,
+            input_assembly_state,
+            rasterization_state,
+            multisample_state,
+            depth_stencil_state,
+            color_blend_state,
+            viewport_state,
+            dynamic_state,
+        )
+        .unwrap();
+        Self::from_handle(handle, name, device, layout, stages, None, None, None)
+    }
+
+    fn create_handle(
+        device: &Device,
+        layout: &PipelineLayout,
+        stages: &[ShaderStage],
+        // The code of each stage's module.
+        codes: &[Arc<ShaderModuleCode>],
+        target: RenderTarget,
+        vertex_input_state: &vk::PipelineVertexInputStateCreateInfo,
+        input_assembly_state: &vk::PipelineInputAssemblyStateCreateInfo,
+        rasterization_state: &vk::PipelineRasterizationStateCreateInfo,
+        multisample_state: &vk::PipelineMultisampleStateCreateInfo,
+        depth_stencil_state: &vk::PipelineDepthStencilStateCreateInfo,
+        color_blend_state: &vk::PipelineColorBlendStateCreateInfo,
+        viewport_state: &vk::PipelineViewportStateCreateInfo,
+        dynamic_state: &vk::PipelineDynamicStateCreateInfo,
+    ) -> Result<vk::Pipeline> {
+        let specialization_infos = stages
+            .iter()
+            .map(|s| s.specialization_info())
+            .collect::<Vec<_>>();
         let stage_create_infos = stages
             .iter()
-            .map(|s| s.shader_stage_create_info())
+            .zip(codes)
+            .zip(&specialization_infos)
+            .map(|((s, code), info)| s.shader_stage_create_info(code, info.as_ref()))
             .collect::<Vec<_>>();
-        let info = vk::GraphicsPipelineCreateInfo::builder()
+        let mut rendering_info = PipelineRenderingCreateInfo::default();
+        let mut info = vk::GraphicsPipelineCreateInfo::builder()
             .layout(layout.inner.handle)
             .stages(&stage_create_infos)
             .vertex_input_state(vertex_input_state)
@@ -47,39 +179,642 @@ impl GraphicsPipeline {
             .depth_stencil_state(depth_stencil_state)
             .color_blend_state(color_blend_state)
             .viewport_state(viewport_state)
-            .dynamic_state(dynamic_state)
-            .render_pass(render_pass.inner.handle)
-            .build();
+            .dynamic_state(dynamic_state);
+        match target {
+            RenderTarget::RenderPass(render_pass, subpass) => {
+                info = info.render_pass(render_pass.inner.handle).subpass(subpass);
+            }
+            RenderTarget::Rendering {
+                color_formats,
+                depth_format,
+            } => {
+                let depth_format = depth_format.unwrap_or(vk::Format::UNDEFINED);
+                rendering_info.color_attachment_count = color_formats.len() as u32;
+                rendering_info.p_color_attachment_formats = color_formats.as_ptr();
+                rendering_info.depth_attachment_format = depth_format;
+                rendering_info.stencil_attachment_format = stencil_format(depth_format);
+                info = info.push_next(&mut rendering_info);
+            }
+        }
+        let info = info.build();
+        let _cache = device.inner.pipeline_cache.read();
         unsafe {
-            let handle = device
+            Ok(device
                 .inner
                 .handle
-                .create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)
-                .unwrap()
-                .first()
-                .unwrap()
-                .to_owned();
-            if let Some(name) = name {
-                device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::PIPELINE);
+                .create_graphics_pipelines(device.inner.pipeline_cache.handle, &[info], None)
+                .map_err(|(_, err)| err)?[0])
+        }
+    }
+
+    fn from_handle(
+        handle: vk::Pipeline,
+        name: Option<&str>,
+        device: &Device,
+        layout: &PipelineLayout,
+        stages: Vec<ShaderStage>,
+        render_pass: Option<RenderPass>,
+        desc: Option<GraphicsPipelineDesc>,
+        builder: Option<GraphicsPipelineBuilder>,
+    ) -> Self {
+        if let Some(name) = name {
+            device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::PIPELINE);
+        }
+        let inner = Arc::new(GraphicsPipelineRef {
+            handle: Mutex::new(PipelineHandle::new(device, handle)),
+            name: name.map(|name| name.to_owned()),
+            device: device.clone(),
+            layout: layout.clone(),
+            stages,
+            render_pass,
+            desc: Mutex::new(desc),
+            builder,
+        });
+        if inner.builder.is_some() {
+            let dependent: Arc<dyn Reloadable> = inner.clone();
+            register_dependent(&inner.stages, Arc::downgrade(&dependent));
+        }
+        Self { inner }
+    }
+
+    pub(crate) fn handle(&self) -> vk::Pipeline {
+        self.inner.handle.lock().unwrap().handle
+    }
+
+    // The current build, retained by command buffers that bind it.
+    pub(crate) fn current_handle(&self) -> Arc<PipelineHandle> {
+        self.inner.handle.lock().unwrap().clone()
+    }
+
+    pub fn desc(&self) -> Option<GraphicsPipelineDesc> {
+        self.inner.desc.lock().unwrap().clone()
+    }
+}
+
+impl Reloadable for GraphicsPipelineRef {
+    fn prepare(
+        self: Arc<Self>,
+        module: &ShaderModule,
+        code: &Arc<ShaderModuleCode>,
+    ) -> Result<PreparedReload> {
+        let builder = self.builder.as_ref().unwrap();
+        let (handle, desc) = builder
+            .create_handle(&self.device, Some((module, code)))
+            .with_context(|| {
+                format!(
+                    "failed to rebuild graphics pipeline {}",
+                    self.name.as_deref().unwrap_or("")
+                )
+            })?;
+        let device = self.device.clone();
+        Ok(PreparedReload {
+            commit: Box::new(move || {
+                if let Some(name) = &self.name {
+                    self.device.debug_set_object_name(
+                        name,
+                        handle.as_raw(),
+                        vk::ObjectType::PIPELINE,
+                    );
+                }
+                *self.desc.lock().unwrap() = Some(desc);
+                // The old build is destroyed once no command buffer retains it.
+                *self.handle.lock().unwrap() = PipelineHandle::new(&self.device, handle);
+            }),
+            discard: Box::new(move || unsafe {
+                device.inner.handle.destroy_pipeline(handle, None);
+            }),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    Opaque,
+    // Straight alpha, src * a + dst * (1 - a).
+    Alpha,
+    PremultipliedAlpha,
+    Additive,
+}
+
+impl BlendMode {
+    fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        let state = vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::all())
+            .color_blend_op(vk::BlendOp::ADD)
+            .alpha_blend_op(vk::BlendOp::ADD);
+        let state = match self {
+            BlendMode::Opaque => state.blend_enable(false),
+            BlendMode::Alpha => {
+                state
+                    .blend_enable(true)
+                    .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                    .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                    .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                    .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
             }
-            Self {
-                inner: Arc::new(GraphicsPipelineRef {
-                    handle,
-                    device: device.clone(),
-                    layout: layout.clone(),
-                    stages,
-                    render_pass: render_pass.clone(),
-                }),
+            BlendMode::PremultipliedAlpha => {
+                state
+                    .blend_enable(true)
+                    .src_color_blend_factor(vk::BlendFactor::ONE)
+                    .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                    .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                    .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
             }
-        }
+            BlendMode::Additive => {
+                state
+                    .blend_enable(true)
+                    .src_color_blend_factor(vk::BlendFactor::ONE)
+                    .dst_color_blend_factor(vk::BlendFactor::ONE)
+                    .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                    .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+            }
+        };
+        state.build()
     }
 }
 
-impl Drop for GraphicsPipelineRef {
-    fn drop(&mut self) {
-        unsafe {
-            self.device.inner.handle.destroy_pipeline(self.handle, None);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VertexBinding {
+    pub binding: u32,
+    pub stride: u32,
+    pub input_rate: vk::VertexInputRate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VertexAttribute {
+    pub location: u32,
+    pub binding: u32,
+    pub format: vk::Format,
+    pub offset: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DepthTest {
+    pub write: bool,
+    pub compare_op: vk::CompareOp,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ShaderStageDesc {
+    pub stage: vk::ShaderStageFlags,
+    pub entry_point: String,
+    // Unique while the pipeline keeps the module alive.
+    pub module: vk::ShaderModule,
+    // Constant ids with their bytes.
+    pub specialization: Vec<(u32, Vec<u8>)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RenderTargetDesc {
+    RenderPass {
+        render_pass: vk::RenderPass,
+        subpass: u32,
+    },
+    Rendering {
+        color_formats: Vec<vk::Format>,
+        depth_format: Option<vk::Format>,
+    },
+}
+
+// Everything a GraphicsPipelineBuilder feeds into vkCreateGraphicsPipelines.
+// Viewport and scissor are always dynamic, so they are not part of it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GraphicsPipelineDesc {
+    pub stages: Vec<ShaderStageDesc>,
+    pub vertex_bindings: Vec<VertexBinding>,
+    pub vertex_attributes: Vec<VertexAttribute>,
+    pub topology: vk::PrimitiveTopology,
+    pub primitive_restart: bool,
+    pub polygon_mode: vk::PolygonMode,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub samples: vk::SampleCountFlags,
+    pub depth_test: Option<DepthTest>,
+    // One per color attachment.
+    pub blend_modes: Vec<BlendMode>,
+    pub target: RenderTargetDesc,
+}
+
+#[derive(Clone)]
+pub struct GraphicsPipelineBuilder {
+    name: Option<String>,
+    layout: PipelineLayout,
+    stages: Vec<ShaderStage>,
+    vertex_bindings: Vec<VertexBinding>,
+    vertex_attributes: Vec<VertexAttribute>,
+    topology: vk::PrimitiveTopology,
+    primitive_restart: bool,
+    polygon_mode: vk::PolygonMode,
+    cull_mode: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    samples: vk::SampleCountFlags,
+    depth_test: Option<DepthTest>,
+    // Empty means opaque for every color attachment of the target.
+    blend_modes: Vec<BlendMode>,
+    // Needed for render passes without a description when blend() isn't used.
+    color_attachment_count: Option<usize>,
+    render_pass: Option<(RenderPass, u32)>,
+    rendering_formats: Option<(Vec<vk::Format>, Option<vk::Format>)>,
+}
+
+impl GraphicsPipelineBuilder {
+    // Triangle lists, no culling, no blending and no depth test.
+    pub fn new(layout: &PipelineLayout) -> Self {
+        Self {
+            name: None,
+            layout: layout.clone(),
+            stages: Vec::new(),
+            vertex_bindings: Vec::new(),
+            vertex_attributes: Vec::new(),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            primitive_restart: false,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::NONE,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            samples: vk::SampleCountFlags::TYPE_1,
+            depth_test: None,
+            blend_modes: Vec::new(),
+            color_attachment_count: None,
+            render_pass: None,
+            rendering_formats: None,
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn stage(mut self, stage: ShaderStage) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn vertex_binding(
+        mut self,
+        binding: u32,
+        stride: u32,
+        input_rate: vk::VertexInputRate,
+    ) -> Self {
+        self.vertex_bindings.push(VertexBinding {
+            binding,
+            stride,
+            input_rate,
+        });
+        self
+    }
+
+    pub fn vertex_attribute(
+        mut self,
+        location: u32,
+        binding: u32,
+        format: vk::Format,
+        offset: u32,
+    ) -> Self {
+        self.vertex_attributes.push(VertexAttribute {
+            location,
+            binding,
+            format,
+            offset,
+        });
+        self
+    }
+
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn primitive_restart(mut self, enabled: bool) -> Self {
+        self.primitive_restart = enabled;
+        self
+    }
+
+    pub fn polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags, front_face: vk::FrontFace) -> Self {
+        self.cull_mode = cull_mode;
+        self.front_face = front_face;
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn depth_test(mut self, write: bool, compare_op: vk::CompareOp) -> Self {
+        self.depth_test = Some(DepthTest { write, compare_op });
+        self
+    }
+
+    // Blend mode of the next color attachment.
+    pub fn blend(mut self, blend_mode: BlendMode) -> Self {
+        self.blend_modes.push(blend_mode);
+        self
+    }
+
+    // Number of color attachments of the subpass. Only needed for render passes
+    // created from a raw create info that don't call blend(), 0 for depth only.
+    pub fn color_attachment_count(mut self, count: u32) -> Self {
+        self.color_attachment_count = Some(count as usize);
+        self
+    }
+
+    pub fn render_pass(mut self, render_pass: &RenderPass, subpass: u32) -> Self {
+        self.render_pass = Some((render_pass.clone(), subpass));
+        self.rendering_formats = None;
+        self
+    }
+
+    pub fn rendering_formats(
+        mut self,
+        color_formats: &[vk::Format],
+        depth_format: Option<vk::Format>,
+    ) -> Self {
+        self.rendering_formats = Some((color_formats.to_vec(), depth_format));
+        self.render_pass = None;
+        self
+    }
+
+    fn desc(&self, codes: &[Arc<ShaderModuleCode>]) -> Result<GraphicsPipelineDesc> {
+        ensure!(
+            self.stages
+                .iter()
+                .any(|stage| stage.stage == vk::ShaderStageFlags::VERTEX),
+            "graphics pipelines need a vertex shader"
+        );
+        for attribute in &self.vertex_attributes {
+            ensure!(
+                self.vertex_bindings
+                    .iter()
+                    .any(|binding| binding.binding == attribute.binding),
+                "vertex attribute {} uses undeclared binding {}",
+                attribute.location,
+                attribute.binding
+            );
+        }
+        let (target, color_count) = match (&self.render_pass, &self.rendering_formats) {
+            (Some((render_pass, subpass)), _) => {
+                let color_count = match render_pass.desc() {
+                    Some(desc) => {
+                        let subpass_desc = desc.subpasses.get(*subpass as usize);
+                        ensure!(
+                            subpass_desc.is_some(),
+                            "render pass has no subpass {}",
+                            subpass
+                        );
+                        let subpass_desc = subpass_desc.unwrap();
+                        for &attachment in subpass_desc
+                            .color_attachments
+                            .iter()
+                            .chain(&subpass_desc.depth_stencil_attachment)
+                        {
+                            ensure!(
+                                desc.attachments[attachment as usize].samples == self.samples,
+                                "pipeline uses {:?}, but attachment {} has {:?}",
+                                self.samples,
+                                attachment,
+                                desc.attachments[attachment as usize].samples
+                            );
+                        }
+                        ensure!(
+                            self.depth_test.is_none()
+                                || subpass_desc.depth_stencil_attachment.is_some(),
+                            "depth test enabled, but subpass {} has no depth attachment",
+                            subpass
+                        );
+                        subpass_desc.color_attachments.len()
+                    }
+                    // Raw render passes don't tell, so the count has to be given.
+                    None => {
+                        match (self.color_attachment_count, self.blend_modes.len()) {
+                            (Some(count), _) => count,
+                            (None, 0) => {
+                                anyhow::bail!(
+                                    "the render pass has no description, call blend() for each \
+                                     color attachment or set color_attachment_count()"
+                                )
+                            }
+                            (None, count) => count,
+                        }
+                    }
+                };
+                (
+                    RenderTargetDesc::RenderPass {
+                        render_pass: render_pass.handle(),
+                        subpass: *subpass,
+                    },
+                    color_count,
+                )
+            }
+            (None, Some((color_formats, depth_format))) => {
+                ensure!(
+                    self.depth_test.is_none() || depth_format.is_some(),
+                    "depth test enabled without a depth format"
+                );
+                (
+                    RenderTargetDesc::Rendering {
+                        color_formats: color_formats.clone(),
+                        depth_format: *depth_format,
+                    },
+                    color_formats.len(),
+                )
+            }
+            (None, None) => {
+                anyhow::bail!("graphics pipelines need a render pass or rendering formats")
+            }
+        };
+        if let Some(count) = self.color_attachment_count {
+            ensure!(
+                count == color_count,
+                "color attachment count {} differs from the target's {}",
+                count,
+                color_count
+            );
         }
+        let blend_modes = if self.blend_modes.is_empty() {
+            vec![BlendMode::Opaque; color_count]
+        } else {
+            self.blend_modes.clone()
+        };
+        ensure!(
+            blend_modes.len() == color_count,
+            "{} blend modes for {} color attachments",
+            blend_modes.len(),
+            color_count
+        );
+        Ok(GraphicsPipelineDesc {
+            stages: self
+                .stages
+                .iter()
+                .zip(codes)
+                .map(|(stage, code)| {
+                    ShaderStageDesc {
+                        stage: stage.stage(),
+                        entry_point: stage.entry_point().to_owned(),
+                        module: code.handle,
+                        specialization: stage.specialization_constants(),
+                    }
+                })
+                .collect(),
+            vertex_bindings: self.vertex_bindings.clone(),
+            vertex_attributes: self.vertex_attributes.clone(),
+            topology: self.topology,
+            primitive_restart: self.primitive_restart,
+            polygon_mode: self.polygon_mode,
+            cull_mode: self.cull_mode,
+            front_face: self.front_face,
+            samples: self.samples,
+            depth_test: self.depth_test,
+            blend_modes,
+            target,
+        })
+    }
+
+    pub fn build(self, device: &Device) -> Result<GraphicsPipeline> {
+        let (handle, desc) = self.create_handle(device, None)?;
+        Ok(GraphicsPipeline::from_handle(
+            handle,
+            self.name.as_deref(),
+            device,
+            &self.layout,
+            self.stages.clone(),
+            self.render_pass
+                .as_ref()
+                .map(|(render_pass, _)| render_pass.clone()),
+            Some(desc),
+            Some(self.clone()),
+        ))
+    }
+
+    fn create_handle(
+        &self,
+        device: &Device,
+        reload: CodeOverride,
+    ) -> Result<(vk::Pipeline, GraphicsPipelineDesc)> {
+        let codes = self
+            .stages
+            .iter()
+            .map(|stage| stage.code(reload))
+            .collect::<Result<Vec<_>>>()?;
+        let desc = self.desc(&codes)?;
+        if let RenderTargetDesc::Rendering { .. } = desc.target {
+            ensure!(
+                device.extension_enabled(crate::name::device::Extension::KhrDynamicRendering),
+                "pipelines without a render pass require VK_KHR_dynamic_rendering"
+            );
+        }
+        if desc.polygon_mode != vk::PolygonMode::FILL {
+            ensure!(
+                device.inner.enabled_features.fill_mode_non_solid == vk::TRUE,
+                "{:?} requires the fillModeNonSolid feature",
+                desc.polygon_mode
+            );
+        }
+
+        let bindings = desc
+            .vertex_bindings
+            .iter()
+            .map(|binding| {
+                vk::VertexInputBindingDescription {
+                    binding: binding.binding,
+                    stride: binding.stride,
+                    input_rate: binding.input_rate,
+                }
+            })
+            .collect::<Vec<_>>();
+        let attributes = desc
+            .vertex_attributes
+            .iter()
+            .map(|attribute| {
+                vk::VertexInputAttributeDescription {
+                    location: attribute.location,
+                    binding: attribute.binding,
+                    format: attribute.format,
+                    offset: attribute.offset,
+                }
+            })
+            .collect::<Vec<_>>();
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&bindings)
+            .vertex_attribute_descriptions(&attributes)
+            .build();
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(desc.topology)
+            .primitive_restart_enable(desc.primitive_restart)
+            .build();
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(desc.polygon_mode)
+            .cull_mode(desc.cull_mode)
+            .front_face(desc.front_face)
+            .line_width(1.0)
+            .build();
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(desc.samples)
+            .build();
+        let depth_stencil_state = match desc.depth_test {
+            Some(depth_test) => {
+                vk::PipelineDepthStencilStateCreateInfo::builder()
+                    .depth_test_enable(true)
+                    .depth_write_enable(depth_test.write)
+                    .depth_compare_op(depth_test.compare_op)
+                    .build()
+            }
+            None => vk::PipelineDepthStencilStateCreateInfo::default(),
+        };
+        let blend_attachments = desc
+            .blend_modes
+            .iter()
+            .map(|blend_mode| blend_mode.attachment_state())
+            .collect::<Vec<_>>();
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+            .attachments(&blend_attachments)
+            .build();
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1)
+            .build();
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&dynamic_states)
+            .build();
+
+        let target = match (&self.render_pass, &desc.target) {
+            (Some((render_pass, subpass)), _) => RenderTarget::RenderPass(render_pass, *subpass),
+            (
+                None,
+                RenderTargetDesc::Rendering {
+                    color_formats,
+                    depth_format,
+                },
+            ) => {
+                RenderTarget::Rendering {
+                    color_formats,
+                    depth_format: *depth_format,
+                }
+            }
+            _ => unreachable!(),
+        };
+        let handle = GraphicsPipeline::create_handle(
+            device,
+            &self.layout,
+            &self.stages,
+            &codes,
+            target,
+            &vertex_input_state,
+            &input_assembly_state,
+            &rasterization_state,
+            &multisample_state,
+            &depth_stencil_state,
+            &color_blend_state,
+            &viewport_state,
+            &dynamic_state,
+        )?;
+        Ok((handle, desc))
     }
 }
 
@@ -116,3 +851,159 @@ impl Device {
         )
     }
 }
+
+impl Device {
+    pub fn create_graphics_pipeline_with_rendering_formats(
+        &self,
+        name: Option<&str>,
+        layout: &PipelineLayout,
+        stages: Vec<ShaderStage>,
+        color_formats: &[vk::Format],
+        depth_format: Option<vk::Format>,
+        vertex_input_state: &vk::PipelineVertexInputStateCreateInfo,
+        input_assembly_state: &vk::PipelineInputAssemblyStateCreateInfo,
+        rasterization_state: &vk::PipelineRasterizationStateCreateInfo,
+        multisample_state: &vk::PipelineMultisampleStateCreateInfo,
+        depth_stencil_state: &vk::PipelineDepthStencilStateCreateInfo,
+        color_blend_state: &vk::PipelineColorBlendStateCreateInfo,
+        viewport_state: &vk::PipelineViewportStateCreateInfo,
+        dynamic_state: &vk::PipelineDynamicStateCreateInfo,
+    ) -> GraphicsPipeline {
+        GraphicsPipeline::with_rendering_formats(
+            name,
+            &self,
+            layout,
+            stages,
+            color_formats,
+            depth_format,
+            vertex_input_state,
+            input_assembly_state,
+            rasterization_state,
+            multisample_state,
+            depth_stencil_state,
+            color_blend_state,
+            viewport_state,
+            dynamic_state,
+        )
+    }
+}
+
+#[cfg(test)]
+fn test_desc(builder: &GraphicsPipelineBuilder) -> Result<GraphicsPipelineDesc> {
+    let codes = builder
+        .stages
+        .iter()
+        .map(|stage| stage.code(None))
+        .collect::<Result<Vec<_>>>()?;
+    builder.desc(&codes)
+}
+
+#[test]
+fn test_graphics_pipeline_desc() {
+    use crate::device::test_device;
+    use crate::render_pass::{AttachmentDesc, RenderPassBuilder, SubpassDesc};
+
+    let device = test_device();
+
+    let fixture = |name: &str| {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/spirv")
+            .join(name);
+        device
+            .create_shader_module(std::fs::read(path).unwrap())
+            .unwrap()
+    };
+    let vertex =
+        ShaderStage::new(&fixture("vertex.spv"), vk::ShaderStageFlags::VERTEX, "main").unwrap();
+    let fragment = ShaderStage::new(
+        &fixture("fragment.spv"),
+        vk::ShaderStageFlags::FRAGMENT,
+        "main",
+    )
+    .unwrap();
+    let layout = device.create_pipeline_layout(None, &[], &[]);
+    let color_target = GraphicsPipelineBuilder::new(&layout)
+        .stage(vertex.clone())
+        .stage(fragment.clone())
+        .rendering_formats(&[vk::Format::R8G8B8A8_UNORM], None);
+
+    let desc = test_desc(&color_target).unwrap();
+    assert_eq!(desc.topology, vk::PrimitiveTopology::TRIANGLE_LIST);
+    assert!(!desc.primitive_restart);
+    assert_eq!(desc.polygon_mode, vk::PolygonMode::FILL);
+    assert_eq!(desc.cull_mode, vk::CullModeFlags::NONE);
+    assert_eq!(desc.front_face, vk::FrontFace::COUNTER_CLOCKWISE);
+    assert_eq!(desc.samples, vk::SampleCountFlags::TYPE_1);
+    assert_eq!(desc.depth_test, None);
+    assert_eq!(desc.blend_modes, vec![BlendMode::Opaque]);
+    assert_eq!(desc.stages.len(), 2);
+    // Equal builders describe equal pipelines.
+    assert_eq!(test_desc(&color_target.clone()).unwrap(), desc);
+
+    let fragment_only = GraphicsPipelineBuilder::new(&layout)
+        .stage(fragment.clone())
+        .rendering_formats(&[vk::Format::R8G8B8A8_UNORM], None);
+    assert!(test_desc(&fragment_only).is_err());
+
+    let undeclared_binding =
+        color_target
+            .clone()
+            .vertex_attribute(0, 1, vk::Format::R32G32B32_SFLOAT, 0);
+    assert!(test_desc(&undeclared_binding).is_err());
+    let declared_binding = undeclared_binding.vertex_binding(1, 12, vk::VertexInputRate::VERTEX);
+    assert!(test_desc(&declared_binding).is_ok());
+
+    let too_many_blends = color_target
+        .clone()
+        .blend(BlendMode::Alpha)
+        .blend(BlendMode::Additive);
+    assert!(test_desc(&too_many_blends).is_err());
+
+    let no_depth = color_target.clone().depth_test(true, vk::CompareOp::LESS);
+    assert!(test_desc(&no_depth).is_err());
+    let with_depth =
+        no_depth.rendering_formats(&[vk::Format::R8G8B8A8_UNORM], Some(vk::Format::D32_SFLOAT));
+    assert!(test_desc(&with_depth).is_ok());
+
+    let msaa_pass = RenderPassBuilder::new()
+        .attachment(
+            AttachmentDesc::new(vk::Format::R8G8B8A8_UNORM).samples(vk::SampleCountFlags::TYPE_4),
+        )
+        .attachment(AttachmentDesc::new(vk::Format::R8G8B8A8_UNORM))
+        .subpass(SubpassDesc::new().color(0).resolve(1))
+        .build(&device)
+        .unwrap();
+    let single_sampled = color_target.clone().render_pass(&msaa_pass, 0);
+    assert!(test_desc(&single_sampled).is_err());
+    let multisampled = single_sampled.samples(vk::SampleCountFlags::TYPE_4);
+    assert!(test_desc(&multisampled).is_ok());
+    assert!(test_desc(&multisampled.clone().depth_test(true, vk::CompareOp::LESS)).is_err());
+    assert!(test_desc(&multisampled.render_pass(&msaa_pass, 1)).is_err());
+
+    // Render passes from raw create infos need the color count spelled out.
+    let depth_attachment = vk::AttachmentDescription::builder()
+        .format(vk::Format::D32_SFLOAT)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+        .build();
+    let depth_reference = vk::AttachmentReference::builder()
+        .attachment(0)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+        .build();
+    let subpass = vk::SubpassDescription::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .depth_stencil_attachment(&depth_reference)
+        .build();
+    let depth_only_pass = device.create_render_pass(
+        &vk::RenderPassCreateInfo::builder()
+            .attachments(&[depth_attachment])
+            .subpasses(&[subpass])
+            .build(),
+    );
+    let depth_only = GraphicsPipelineBuilder::new(&layout)
+        .stage(vertex)
+        .render_pass(&depth_only_pass, 0);
+    assert!(test_desc(&depth_only).is_err());
+    let depth_only = depth_only.color_attachment_count(0);
+    assert!(test_desc(&depth_only).unwrap().blend_modes.is_empty());
+}
@@ -1,10 +1,16 @@
 mod graphics_pipeline;
+mod pipeline_cache;
 mod pipeline_layout;
 mod ray_tracing_pipeline;
 
-pub use graphics_pipeline::GraphicsPipeline;
+pub use graphics_pipeline::{
+    BlendMode, DepthTest, GraphicsPipeline, GraphicsPipelineBuilder, GraphicsPipelineDesc,
+    RenderTargetDesc, ShaderStageDesc, VertexAttribute, VertexBinding,
+};
+pub use pipeline_cache::PipelineCache;
 pub use pipeline_layout::PipelineLayout;
 pub use ray_tracing_pipeline::RayTracingPipeline;
+pub(crate) use ray_tracing_pipeline::RayTracingPipelineVersion;
 
 use std::ffi::CString;
 use std::sync::Arc;
@@ -16,6 +22,31 @@ use crate::Device;
 use crate::RenderPass;
 use crate::ShaderStage;
 
+// One build of a pipeline. Hot reload swaps in a new one, command buffers that
+// bound the old one keep it alive until they are reset.
+pub(crate) struct PipelineHandle {
+    pub(crate) handle: vk::Pipeline,
+    device: Device,
+}
+
+impl PipelineHandle {
+    pub(crate) fn new(device: &Device, handle: vk::Pipeline) -> Arc<Self> {
+        Arc::new(Self {
+            handle,
+            device: device.clone(),
+        })
+    }
+}
+
+impl Drop for PipelineHandle {
+    fn drop(&mut self) {
+        let handle = self.handle;
+        self.device.destroy_deferred(move |device| unsafe {
+            device.handle.destroy_pipeline(handle, None);
+        });
+    }
+}
+
 pub trait Pipeline {
     fn layout(&self) -> PipelineLayout;
 }
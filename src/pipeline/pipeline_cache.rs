@@ -0,0 +1,171 @@
+use std::path::Path;
+use std::sync::{RwLock, RwLockReadGuard};
+
+use anyhow::Result;
+use ash::vk;
+
+use crate::Device;
+
+// Size of VkPipelineCacheHeaderVersionOne.
+const HEADER_SIZE: usize = 32;
+
+// The device's pipeline cache, passed to every pipeline it creates. It holds a
+// raw ash::Device since the DeviceRef owns it.
+pub struct PipelineCache {
+    pub(crate) handle: vk::PipelineCache,
+    device: ash::Device,
+    properties: vk::PhysicalDeviceProperties,
+    // vkMergePipelineCaches needs the destination externally synchronized, so
+    // merges take it exclusively and pipeline creation shares it.
+    lock: RwLock<()>,
+}
+
+impl PipelineCache {
+    pub(crate) fn new(device: &ash::Device, properties: &vk::PhysicalDeviceProperties) -> Self {
+        unsafe {
+            let handle = device
+                .create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)
+                .unwrap();
+            Self {
+                handle,
+                device: device.clone(),
+                properties: *properties,
+                lock: RwLock::new(()),
+            }
+        }
+    }
+
+    // Held while a pipeline is created with the cache.
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, ()> {
+        self.lock.read().unwrap()
+    }
+
+    pub fn data(&self) -> Result<Vec<u8>> {
+        let _lock = self.read();
+        unsafe { Ok(self.device.get_pipeline_cache_data(self.handle)?) }
+    }
+
+    // Writes to a temporary file first so an interrupted save never leaves a
+    // truncated cache behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let data = self.data()?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, &data)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    // Merges a cache saved by `save`. Returns false, keeping the cache as it is,
+    // if the file is missing or was written by another device or driver.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let path = path.as_ref();
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        if !header_matches(&data, &self.properties) {
+            log::info!(
+                "discarding pipeline cache {}, it belongs to another device or driver",
+                path.display()
+            );
+            return Ok(false);
+        }
+        Ok(self.merge_data(&data))
+    }
+
+    fn merge_data(&self, data: &[u8]) -> bool {
+        unsafe {
+            let src = match self.device.create_pipeline_cache(
+                &vk::PipelineCacheCreateInfo::builder()
+                    .initial_data(data)
+                    .build(),
+                None,
+            ) {
+                Ok(src) => src,
+                Err(err) => {
+                    log::info!("discarding corrupted pipeline cache: {}", err);
+                    return false;
+                }
+            };
+            let result = {
+                let _lock = self.lock.write().unwrap();
+                self.device.merge_pipeline_caches(self.handle, &[src])
+            };
+            self.device.destroy_pipeline_cache(src, None);
+            match result {
+                Ok(()) => true,
+                Err(err) => {
+                    log::info!("failed to merge pipeline cache: {}", err);
+                    false
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PipelineCache {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline_cache(self.handle, None);
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+// Checks the VkPipelineCacheHeaderVersionOne at the start of cache data.
+fn header_matches(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    if data.len() < HEADER_SIZE {
+        return false;
+    }
+    let header_size = read_u32(data, 0) as usize;
+    header_size >= HEADER_SIZE
+        && header_size <= data.len()
+        && read_u32(data, 4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && read_u32(data, 8) == properties.vendor_id
+        && read_u32(data, 12) == properties.device_id
+        && data[16..32] == properties.pipeline_cache_uuid
+}
+
+impl Device {
+    pub fn pipeline_cache(&self) -> &PipelineCache {
+        &self.inner.pipeline_cache
+    }
+}
+
+#[test]
+fn test_pipeline_cache_header() {
+    let mut properties = vk::PhysicalDeviceProperties::default();
+    properties.vendor_id = 0x10de;
+    properties.device_id = 0x2204;
+    properties.pipeline_cache_uuid = [7; vk::UUID_SIZE];
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&(HEADER_SIZE as u32).to_ne_bytes());
+    data.extend_from_slice(&(vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_ne_bytes());
+    data.extend_from_slice(&0x10deu32.to_ne_bytes());
+    data.extend_from_slice(&0x2204u32.to_ne_bytes());
+    data.extend_from_slice(&[7; vk::UUID_SIZE]);
+    data.extend_from_slice(&[0; 64]);
+    assert!(header_matches(&data, &properties));
+
+    assert!(!header_matches(&data[..HEADER_SIZE - 1], &properties));
+
+    let mut other_device = properties;
+    other_device.device_id = 0x2206;
+    assert!(!header_matches(&data, &other_device));
+
+    let mut other_driver = properties;
+    other_driver.pipeline_cache_uuid[15] = 8;
+    assert!(!header_matches(&data, &other_driver));
+
+    let mut corrupted = data.clone();
+    corrupted[0..4].copy_from_slice(&1000u32.to_ne_bytes());
+    assert!(!header_matches(&corrupted, &properties));
+}
@@ -1,18 +1,31 @@
 use std::ffi::CString;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use super::PipelineLayout;
-use crate::{Buffer, Device, RenderPass, ShaderStage};
+use anyhow::{Context, Result};
+
+use super::{PipelineHandle, PipelineLayout};
+use crate::hot_reload::{register_dependent, CodeOverride, PreparedReload, Reloadable};
+use crate::shader_module::ShaderModuleCode;
+use crate::{Buffer, Device, RenderPass, ShaderModule, ShaderStage};
 use ash::vk::{self, Handle};
 
+// One build of a ray tracing pipeline. Shader binding tables are filled from
+// the group handles of the version they are used with.
+pub(crate) struct RayTracingPipelineVersion {
+    pub(crate) handle: Arc<PipelineHandle>,
+    pub(crate) shader_group_handles: Vec<u8>,
+}
+
 pub(crate) struct RayTracingPipelineRef {
-    pub(crate) handle: vk::Pipeline,
+    pub(crate) name: Option<String>,
+    // Swapped as a whole by shader hot reload.
+    version: Mutex<Arc<RayTracingPipelineVersion>>,
     pub(crate) layout: PipelineLayout,
     pub(crate) ray_gen_shader: ShaderStage,
     pub(crate) miss_shaders: Vec<ShaderStage>,
     pub(crate) hit_groups: Vec<Box<dyn crate::HitGroup + 'static>>,
+    recursion_depth: u32,
     pub(crate) device: Device,
-    pub(crate) shader_group_handles: Vec<u8>,
 }
 
 #[derive(Clone)]
@@ -36,21 +49,79 @@ impl RayTracingPipeline {
             assert!(miss_shader.stage == vk::ShaderStageFlags::MISS_KHR);
         }
 
-        let mut stage_create_infos = Vec::new();
-        stage_create_infos.push(ray_gen_shader.shader_stage_create_info());
-        stage_create_infos.extend(
-            miss_shaders
-                .iter()
-                .map(|s| s.shader_stage_create_info())
-                .collect::<Vec<_>>(),
-        );
-        stage_create_infos.extend(
-            hit_groups
+        let (handle, shader_group_handles) = Self::create_handle(
+            device,
+            layout,
+            ray_gen_shader,
+            miss_shaders,
+            hit_groups,
+            recursion_depth,
+            None,
+        )
+        .unwrap();
+        if let Some(name) = name {
+            device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::PIPELINE);
+        }
+
+        let miss_shaders = miss_shaders
+            .iter()
+            .map(|s| s.to_owned().to_owned())
+            .collect();
+
+        let inner = Arc::new(RayTracingPipelineRef {
+            name: name.map(|s| s.to_owned()),
+            version: Mutex::new(Arc::new(RayTracingPipelineVersion {
+                handle: PipelineHandle::new(device, handle),
+                shader_group_handles,
+            })),
+            layout: layout.to_owned(),
+            device: device.clone(),
+            ray_gen_shader: ray_gen_shader.to_owned(),
+            miss_shaders,
+            hit_groups: hit_groups
                 .iter()
-                .map(|g| g.shader_stage_create_infos())
-                .flatten()
-                .collect::<Vec<_>>(),
+                .map(|g| dyn_clone::clone_box(*g))
+                .collect(),
+            recursion_depth,
+        });
+        let dependent: Arc<dyn Reloadable> = inner.clone();
+        register_dependent(
+            std::iter::once(&inner.ray_gen_shader)
+                .chain(&inner.miss_shaders)
+                .chain(inner.hit_groups.iter().flat_map(|g| g.shader_stages())),
+            Arc::downgrade(&dependent),
         );
+        Self { inner }
+    }
+
+    // Creates the pipeline and returns it with its shader group handles.
+    fn create_handle(
+        device: &Device,
+        layout: &PipelineLayout,
+        ray_gen_shader: &ShaderStage,
+        miss_shaders: &[&ShaderStage],
+        hit_groups: &[&dyn crate::HitGroup],
+        recursion_depth: u32,
+        reload: CodeOverride,
+    ) -> Result<(vk::Pipeline, Vec<u8>)> {
+        let stages = std::iter::once(ray_gen_shader)
+            .chain(miss_shaders.iter().copied())
+            .chain(hit_groups.iter().flat_map(|g| g.shader_stages()))
+            .collect::<Vec<_>>();
+        let codes = stages
+            .iter()
+            .map(|s| s.code(reload))
+            .collect::<Result<Vec<_>>>()?;
+        let specialization_infos = stages
+            .iter()
+            .map(|s| s.specialization_info())
+            .collect::<Vec<_>>();
+        let stage_create_infos = stages
+            .iter()
+            .zip(&codes)
+            .zip(&specialization_infos)
+            .map(|((s, code), info)| s.shader_stage_create_info(code, info.as_ref()))
+            .collect::<Vec<_>>();
 
         let mut group_create_infos = Vec::new();
         let mut i = 0;
@@ -103,12 +174,13 @@ impl RayTracingPipeline {
         }
         drop(i);
 
+        let cache = device.inner.pipeline_cache.read();
         unsafe {
             let handle = device
                 .ray_tracing_pipeline_loader()
                 .create_ray_tracing_pipelines(
                     vk::DeferredOperationKHR::null(),
-                    vk::PipelineCache::null(),
+                    device.inner.pipeline_cache.handle,
                     &[vk::RayTracingPipelineCreateInfoKHR::builder()
                         .layout(layout.inner.handle)
                         .stages(stage_create_infos.as_slice())
@@ -116,15 +188,11 @@ impl RayTracingPipeline {
                         .max_pipeline_ray_recursion_depth(recursion_depth)
                         .build()],
                     None,
-                )
-                .unwrap()
+                )?
                 .first()
                 .unwrap()
                 .to_owned();
-
-            if let Some(name) = name {
-                device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::PIPELINE);
-            }
+            drop(cache);
 
             let rt_p = &device.inner.pdevice.ray_tracing_pipeline_properties;
             let shader_group_handles = device
@@ -134,37 +202,75 @@ impl RayTracingPipeline {
                     0,
                     group_create_infos.len() as u32,
                     rt_p.shader_group_handle_size as usize * group_create_infos.len(),
-                )
-                .unwrap();
-
-            let miss_shaders = miss_shaders
-                .iter()
-                .map(|s| s.to_owned().to_owned())
-                .collect();
-
-            Self {
-                inner: Arc::new(RayTracingPipelineRef {
-                    handle,
-                    layout: layout.to_owned(),
-                    device: device.clone(),
-                    ray_gen_shader: ray_gen_shader.to_owned(),
-                    miss_shaders,
-                    hit_groups: hit_groups
-                        .iter()
-                        .map(|g| dyn_clone::clone_box(*g))
-                        .collect(),
-                    shader_group_handles,
-                }),
+                );
+            match shader_group_handles {
+                Ok(shader_group_handles) => Ok((handle, shader_group_handles)),
+                Err(err) => {
+                    device.handle().destroy_pipeline(handle, None);
+                    Err(err.into())
+                }
             }
         }
     }
+
+    pub(crate) fn handle(&self) -> vk::Pipeline {
+        self.version().handle.handle
+    }
+
+    // The current build, retained by command buffers that bind it.
+    pub(crate) fn version(&self) -> Arc<RayTracingPipelineVersion> {
+        self.inner.version.lock().unwrap().clone()
+    }
 }
 
-impl Drop for RayTracingPipelineRef {
-    fn drop(&mut self) {
-        unsafe {
-            self.device.handle().destroy_pipeline(self.handle, None);
-        }
+impl Reloadable for RayTracingPipelineRef {
+    fn prepare(
+        self: Arc<Self>,
+        module: &ShaderModule,
+        code: &Arc<ShaderModuleCode>,
+    ) -> Result<PreparedReload> {
+        let miss_shaders = self.miss_shaders.iter().collect::<Vec<_>>();
+        let hit_groups = self
+            .hit_groups
+            .iter()
+            .map(|g| g.as_ref())
+            .collect::<Vec<_>>();
+        let (handle, shader_group_handles) = RayTracingPipeline::create_handle(
+            &self.device,
+            &self.layout,
+            &self.ray_gen_shader,
+            &miss_shaders,
+            &hit_groups,
+            self.recursion_depth,
+            Some((module, code)),
+        )
+        .with_context(|| {
+            format!(
+                "failed to rebuild ray tracing pipeline {}",
+                self.name.as_deref().unwrap_or("")
+            )
+        })?;
+        let device = self.device.clone();
+        Ok(PreparedReload {
+            commit: Box::new(move || {
+                if let Some(name) = &self.name {
+                    self.device.debug_set_object_name(
+                        name,
+                        handle.as_raw(),
+                        vk::ObjectType::PIPELINE,
+                    );
+                }
+                // Shader binding tables refill themselves for the new version the
+                // next time rays are traced with them.
+                *self.version.lock().unwrap() = Arc::new(RayTracingPipelineVersion {
+                    handle: PipelineHandle::new(&self.device, handle),
+                    shader_group_handles,
+                });
+            }),
+            discard: Box::new(move || unsafe {
+                device.handle().destroy_pipeline(handle, None);
+            }),
+        })
     }
 }
 
@@ -0,0 +1,288 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ash::vk;
+
+use crate::query_pool::{QueryPool, Timestamp};
+use crate::{CommandBuffer, CommandRecorder, Device};
+
+// GPU time of one `profile_scope` and of the scopes nested in it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScopeTiming {
+    pub name: String,
+    // False if the scope was recorded on a queue family with no timestampValidBits.
+    pub timestamps_supported: bool,
+    // None if timestamps are unsupported or the results were not available.
+    pub duration: Option<Duration>,
+    pub children: Vec<ScopeTiming>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameTimings {
+    pub frame: u64,
+    pub scopes: Vec<ScopeTiming>,
+}
+
+struct ScopeRecord {
+    name: String,
+    parent: Option<usize>,
+    // First of the two queries of this scope, None if no timestamps were written.
+    query: Option<u32>,
+    valid_bits: u32,
+}
+
+struct ProfilerFrame {
+    frame: u64,
+    scopes: Vec<ScopeRecord>,
+    next_query: u32,
+}
+
+struct GpuProfilerState {
+    frames: Vec<ProfilerFrame>,
+    current: usize,
+    frame: u64,
+}
+
+pub(crate) struct GpuProfilerRef {
+    pool: QueryPool<Timestamp>,
+    queries_per_frame: u32,
+    timestamp_period: f32,
+    state: Mutex<GpuProfilerState>,
+}
+
+// Collects `profile_scope` timestamps of the command buffers it is attached to.
+// Each of the `frames_in_flight` frames uses its own range of queries.
+#[derive(Clone)]
+pub struct GpuProfiler {
+    pub(crate) inner: Arc<GpuProfilerRef>,
+}
+
+impl GpuProfiler {
+    pub fn new(device: &Device, frames_in_flight: u32, max_scopes_per_frame: u32) -> Self {
+        assert!(frames_in_flight > 0 && max_scopes_per_frame > 0);
+        let queries_per_frame = max_scopes_per_frame * 2;
+        let frames = (0..frames_in_flight)
+            .map(|_| {
+                ProfilerFrame {
+                    frame: 0,
+                    scopes: Vec::new(),
+                    next_query: 0,
+                }
+            })
+            .collect();
+        Self {
+            inner: Arc::new(GpuProfilerRef {
+                pool: QueryPool::new(
+                    device,
+                    Some("gpu profiler"),
+                    queries_per_frame * frames_in_flight,
+                ),
+                queries_per_frame,
+                timestamp_period: device.inner.pdevice.limits().timestamp_period,
+                state: Mutex::new(GpuProfilerState {
+                    frames,
+                    current: 0,
+                    frame: 0,
+                }),
+            }),
+        }
+    }
+
+    // Starts recording into the next frame slot. Returns the timings of the frame
+    // that used the slot before, whose submissions should have completed by now.
+    pub fn begin_frame(&self) -> Option<FrameTimings> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.frame += 1;
+        state.current = (state.current + 1) % state.frames.len();
+        let current = state.current;
+        let first_query = current as u32 * self.inner.queries_per_frame;
+        let frame = state.frame;
+        let slot = &mut state.frames[current];
+
+        let timings = if slot.frame == 0 {
+            None
+        } else {
+            let results = self
+                .inner
+                .pool
+                .get_timestamps(first_query, slot.next_query)
+                .unwrap_or_else(|err| {
+                    log::warn!("failed to read gpu timestamps: {}", err);
+                    vec![None; slot.next_query as usize]
+                });
+            Some(FrameTimings {
+                frame: slot.frame,
+                scopes: build_timing_tree(&slot.scopes, &results, self.inner.timestamp_period),
+            })
+        };
+
+        if slot.next_query > 0 {
+            self.inner.pool.reset(first_query, slot.next_query);
+        }
+        slot.frame = frame;
+        slot.scopes.clear();
+        slot.next_query = 0;
+        timings
+    }
+
+    // Registers a scope and returns its index and, if timestamps can be written,
+    // the absolute index of its first query.
+    fn open_scope(
+        &self,
+        name: &str,
+        parent: Option<usize>,
+        valid_bits: u32,
+    ) -> (usize, Option<u32>) {
+        let mut state = self.inner.state.lock().unwrap();
+        let current = state.current;
+        let slot = &mut state.frames[current];
+        let parent = parent.filter(|parent| *parent < slot.scopes.len());
+        let query = if valid_bits == 0 {
+            None
+        } else if slot.next_query + 2 > self.inner.queries_per_frame {
+            log::warn!("gpu profiler ran out of queries, {} is not timed", name);
+            None
+        } else {
+            slot.next_query += 2;
+            Some(slot.next_query - 2)
+        };
+        slot.scopes.push(ScopeRecord {
+            name: name.to_owned(),
+            parent,
+            query,
+            valid_bits,
+        });
+        (
+            slot.scopes.len() - 1,
+            query.map(|query| current as u32 * self.inner.queries_per_frame + query),
+        )
+    }
+}
+
+fn build_timing_tree(
+    scopes: &[ScopeRecord],
+    results: &[Option<u64>],
+    timestamp_period: f32,
+) -> Vec<ScopeTiming> {
+    fn children_of(
+        parent: Option<usize>,
+        scopes: &[ScopeRecord],
+        results: &[Option<u64>],
+        timestamp_period: f32,
+    ) -> Vec<ScopeTiming> {
+        scopes
+            .iter()
+            .enumerate()
+            .filter(|(_, scope)| scope.parent == parent)
+            .map(|(i, scope)| {
+                let duration = scope.query.and_then(|query| {
+                    let start = (*results.get(query as usize)?)?;
+                    let end = (*results.get(query as usize + 1)?)?;
+                    let mask = if scope.valid_bits >= 64 {
+                        u64::MAX
+                    } else {
+                        (1u64 << scope.valid_bits) - 1
+                    };
+                    let ticks = end.wrapping_sub(start) & mask;
+                    Some(Duration::from_nanos(
+                        (ticks as f64 * timestamp_period as f64) as u64,
+                    ))
+                });
+                ScopeTiming {
+                    name: scope.name.clone(),
+                    timestamps_supported: scope.valid_bits != 0,
+                    duration,
+                    children: children_of(Some(i), scopes, results, timestamp_period),
+                }
+            })
+            .collect()
+    }
+    children_of(None, scopes, results, timestamp_period)
+}
+
+impl CommandBuffer {
+    // `profile_scope` is a no-op on command buffers without a profiler.
+    pub fn set_profiler(&mut self, profiler: Option<&GpuProfiler>) {
+        self.profiler = profiler.cloned();
+    }
+}
+
+impl<'a> CommandRecorder<'a> {
+    pub fn profile_scope<I>(&mut self, name: &str, f: I)
+    where
+        I: FnOnce(&mut CommandRecorder),
+    {
+        let profiler = match &self.command_buffer.profiler {
+            Some(profiler) => profiler.clone(),
+            None => return f(self),
+        };
+        let queue_family_index = self.command_buffer.queue_family_index() as usize;
+        let valid_bits =
+            self.device().inner.pdevice.queue_families()[queue_family_index].timestamp_valid_bits();
+        let (scope, query) = profiler.open_scope(name, self.current_profile_scope, valid_bits);
+
+        if let Some(query) = query {
+            self.write_timestamp(
+                &profiler.inner.pool,
+                query,
+                vk::PipelineStageFlags2KHR::TOP_OF_PIPE,
+            );
+        }
+        let parent = self.current_profile_scope.replace(scope);
+        f(self);
+        self.current_profile_scope = parent;
+        if let Some(query) = query {
+            self.write_timestamp(
+                &profiler.inner.pool,
+                query + 1,
+                vk::PipelineStageFlags2KHR::BOTTOM_OF_PIPE,
+            );
+        }
+    }
+}
+
+impl Device {
+    pub fn create_gpu_profiler(
+        &self,
+        frames_in_flight: u32,
+        max_scopes_per_frame: u32,
+    ) -> GpuProfiler {
+        GpuProfiler::new(self, frames_in_flight, max_scopes_per_frame)
+    }
+}
+
+#[test]
+fn test_build_timing_tree() {
+    let scope = |name: &str, parent, query| {
+        ScopeRecord {
+            name: name.to_owned(),
+            parent,
+            query,
+            valid_bits: 64,
+        }
+    };
+    let scopes = vec![
+        scope("frame", None, Some(0)),
+        scope("shadows", Some(0), Some(2)),
+        scope("lighting", Some(0), Some(4)),
+        ScopeRecord {
+            valid_bits: 0,
+            ..scope("transfer", None, None)
+        },
+    ];
+    let results = vec![Some(100), Some(400), Some(110), Some(200), Some(200), None];
+    let tree = build_timing_tree(&scopes, &results, 2.0);
+
+    assert_eq!(tree.len(), 2);
+    assert_eq!(tree[0].name, "frame");
+    assert_eq!(tree[0].duration, Some(Duration::from_nanos(600)));
+    assert_eq!(tree[0].children.len(), 2);
+    assert_eq!(
+        tree[0].children[0].duration,
+        Some(Duration::from_nanos(180))
+    );
+    assert_eq!(tree[0].children[1].duration, None);
+    assert_eq!(tree[1].name, "transfer");
+    assert!(!tree[1].timestamps_supported);
+    assert_eq!(tree[1].duration, None);
+}
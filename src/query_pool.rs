@@ -0,0 +1,493 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use anyhow::{ensure, Result};
+use ash::vk;
+use ash::vk::Handle;
+
+use crate::command_buffer::CommandBufferResource;
+use crate::{Buffer, CommandRecorder, Device};
+
+pub trait QueryType: Send + Sync + 'static {
+    const QUERY_TYPE: vk::QueryType;
+}
+
+// Query types whose pools need no configuration besides the query count.
+pub trait PlainQueryType: QueryType {}
+
+// Query types recorded with `begin_query` / `end_query`.
+pub trait ScopedQueryType: QueryType {}
+
+// Timestamps written by `CommandRecorder::write_timestamp`, in device ticks.
+pub struct Timestamp;
+
+impl QueryType for Timestamp {
+    const QUERY_TYPE: vk::QueryType = vk::QueryType::TIMESTAMP;
+}
+impl PlainQueryType for Timestamp {}
+
+// Number of samples that passed the depth and stencil tests.
+pub struct Occlusion;
+
+impl QueryType for Occlusion {
+    const QUERY_TYPE: vk::QueryType = vk::QueryType::OCCLUSION;
+}
+impl PlainQueryType for Occlusion {}
+impl ScopedQueryType for Occlusion {}
+
+// Invocation and primitive counters selected at pool creation.
+pub struct PipelineStatistics;
+
+impl QueryType for PipelineStatistics {
+    const QUERY_TYPE: vk::QueryType = vk::QueryType::PIPELINE_STATISTICS;
+}
+impl ScopedQueryType for PipelineStatistics {}
+
+// The counters of one pipeline statistics query, in the order of their flag bits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PipelineStatisticsResult {
+    pub values: Vec<(vk::QueryPipelineStatisticFlags, u64)>,
+}
+
+impl PipelineStatisticsResult {
+    pub fn get(&self, statistic: vk::QueryPipelineStatisticFlags) -> Option<u64> {
+        self.values
+            .iter()
+            .find(|(flag, _)| *flag == statistic)
+            .map(|(_, value)| *value)
+    }
+}
+
+fn statistic_flags(
+    statistics: vk::QueryPipelineStatisticFlags,
+) -> Vec<vk::QueryPipelineStatisticFlags> {
+    (0..32)
+        .map(|bit| vk::QueryPipelineStatisticFlags::from_raw(1 << bit))
+        .filter(|flag| statistics.contains(*flag))
+        .collect()
+}
+
+pub(crate) struct QueryPoolRef {
+    pub(crate) handle: vk::QueryPool,
+    device: Device,
+    count: u32,
+    statistics: vk::QueryPipelineStatisticFlags,
+}
+
+pub struct QueryPool<T: QueryType> {
+    pub(crate) inner: Arc<QueryPoolRef>,
+    marker: PhantomData<T>,
+}
+
+impl<T: QueryType> Clone for QueryPool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: QueryType> CommandBufferResource for QueryPool<T> {}
+
+impl<T: PlainQueryType> QueryPool<T> {
+    // All queries start out reset.
+    pub fn new(device: &Device, name: Option<&str>, count: u32) -> Self {
+        Self::create(
+            device,
+            name,
+            count,
+            vk::QueryPipelineStatisticFlags::empty(),
+        )
+    }
+}
+
+impl<T: QueryType> QueryPool<T> {
+    fn create(
+        device: &Device,
+        name: Option<&str>,
+        count: u32,
+        statistics: vk::QueryPipelineStatisticFlags,
+    ) -> Self {
+        assert!(count > 0, "query pools must hold at least one query");
+        unsafe {
+            let handle = device
+                .inner
+                .handle
+                .create_query_pool(
+                    &vk::QueryPoolCreateInfo::builder()
+                        .query_type(T::QUERY_TYPE)
+                        .query_count(count)
+                        .pipeline_statistics(statistics)
+                        .build(),
+                    None,
+                )
+                .unwrap();
+            if let Some(name) = name {
+                device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::QUERY_POOL);
+            }
+            let pool = Self {
+                inner: Arc::new(QueryPoolRef {
+                    handle,
+                    device: device.clone(),
+                    count,
+                    statistics,
+                }),
+                marker: PhantomData,
+            };
+            pool.reset(0, count);
+            pool
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.inner.count
+    }
+
+    // Resets queries from the host, they must not be in use by pending work.
+    pub fn reset(&self, first_query: u32, query_count: u32) {
+        self.check_range(first_query, query_count);
+        unsafe {
+            self.inner.device.inner.handle.reset_query_pool(
+                self.inner.handle,
+                first_query,
+                query_count,
+            );
+        }
+    }
+
+    fn check_range(&self, first_query: u32, query_count: u32) {
+        assert!(
+            first_query + query_count <= self.inner.count,
+            "queries {}..{} out of range, pool holds {}",
+            first_query,
+            first_query + query_count,
+            self.inner.count
+        );
+    }
+
+    // Number of 64 bit values one query produces, not counting availability.
+    fn values_per_query(&self) -> usize {
+        if T::QUERY_TYPE == vk::QueryType::PIPELINE_STATISTICS {
+            self.inner.statistics.as_raw().count_ones() as usize
+        } else {
+            1
+        }
+    }
+
+    // The values of each query, None where the result is not available yet.
+    fn read_results(&self, first_query: u32, query_count: u32) -> Result<Vec<Option<Vec<u64>>>> {
+        self.check_range(first_query, query_count);
+        let stride = self.values_per_query() + 1;
+        let mut data = vec![0u64; stride * query_count as usize];
+        let result = unsafe {
+            self.inner
+                .device
+                .inner
+                .handle
+                .fp_v1_0()
+                .get_query_pool_results(
+                    self.inner.device.inner.handle.handle(),
+                    self.inner.handle,
+                    first_query,
+                    query_count,
+                    data.len() * std::mem::size_of::<u64>(),
+                    data.as_mut_ptr() as *mut _,
+                    (stride * std::mem::size_of::<u64>()) as u64,
+                    vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WITH_AVAILABILITY,
+                )
+        };
+        match result {
+            vk::Result::SUCCESS | vk::Result::NOT_READY => {}
+            err => return Err(err.into()),
+        }
+        Ok(data
+            .chunks(stride)
+            .map(|query| {
+                let (available, values) = query.split_last().unwrap();
+                if *available != 0 {
+                    Some(values.to_vec())
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+impl QueryPool<Timestamp> {
+    // Raw ticks of each query, None for queries whose result is not available yet.
+    pub fn get_timestamps(&self, first_query: u32, query_count: u32) -> Result<Vec<Option<u64>>> {
+        Ok(self
+            .read_results(first_query, query_count)?
+            .into_iter()
+            .map(|values| values.map(|values| values[0]))
+            .collect())
+    }
+}
+
+impl QueryPool<Occlusion> {
+    // Passed samples of each query, None where the result is not available yet.
+    pub fn get_results(&self, first_query: u32, query_count: u32) -> Result<Vec<Option<u64>>> {
+        Ok(self
+            .read_results(first_query, query_count)?
+            .into_iter()
+            .map(|values| values.map(|values| values[0]))
+            .collect())
+    }
+}
+
+impl QueryPool<PipelineStatistics> {
+    pub fn with_statistics(
+        device: &Device,
+        name: Option<&str>,
+        count: u32,
+        statistics: vk::QueryPipelineStatisticFlags,
+    ) -> Result<Self> {
+        let features = &device.inner.enabled_features;
+        ensure!(
+            features.pipeline_statistics_query == vk::TRUE,
+            "pipelineStatisticsQuery is not enabled on this device"
+        );
+        ensure!(!statistics.is_empty(), "no pipeline statistics requested");
+        let tessellation = vk::QueryPipelineStatisticFlags::TESSELLATION_CONTROL_SHADER_PATCHES
+            | vk::QueryPipelineStatisticFlags::TESSELLATION_EVALUATION_SHADER_INVOCATIONS;
+        ensure!(
+            !statistics.intersects(tessellation) || features.tessellation_shader == vk::TRUE,
+            "tessellation statistics require the tessellationShader feature"
+        );
+        let geometry = vk::QueryPipelineStatisticFlags::GEOMETRY_SHADER_INVOCATIONS
+            | vk::QueryPipelineStatisticFlags::GEOMETRY_SHADER_PRIMITIVES;
+        ensure!(
+            !statistics.intersects(geometry) || features.geometry_shader == vk::TRUE,
+            "geometry statistics require the geometryShader feature"
+        );
+        Ok(Self::create(device, name, count, statistics))
+    }
+
+    pub fn statistics(&self) -> vk::QueryPipelineStatisticFlags {
+        self.inner.statistics
+    }
+
+    pub fn get_results(
+        &self,
+        first_query: u32,
+        query_count: u32,
+    ) -> Result<Vec<Option<PipelineStatisticsResult>>> {
+        let flags = statistic_flags(self.inner.statistics);
+        Ok(self
+            .read_results(first_query, query_count)?
+            .into_iter()
+            .map(|values| {
+                values.map(|values| {
+                    PipelineStatisticsResult {
+                        values: flags.iter().copied().zip(values).collect(),
+                    }
+                })
+            })
+            .collect())
+    }
+}
+
+impl Drop for QueryPoolRef {
+    fn drop(&mut self) {
+        let handle = self.handle;
+        self.device.destroy_deferred(move |device| unsafe {
+            device.handle.destroy_query_pool(handle, None);
+        });
+    }
+}
+
+impl<'a> CommandRecorder<'a> {
+    pub fn write_timestamp(
+        &mut self,
+        query_pool: &QueryPool<Timestamp>,
+        query: u32,
+        stage: vk::PipelineStageFlags2KHR,
+    ) {
+        query_pool.check_range(query, 1);
+        unsafe {
+            self.device()
+                .synchronization2_loader()
+                .cmd_write_timestamp2(
+                    self.command_buffer.handle,
+                    stage,
+                    query_pool.inner.handle,
+                    query,
+                );
+        }
+        self.command_buffer
+            .resources
+            .push(Box::new(query_pool.clone()));
+    }
+
+    // PRECISE is only valid for occlusion queries with occlusionQueryPrecise enabled.
+    pub fn begin_query<T: ScopedQueryType>(
+        &mut self,
+        query_pool: &QueryPool<T>,
+        query: u32,
+        flags: vk::QueryControlFlags,
+    ) {
+        query_pool.check_range(query, 1);
+        if flags.contains(vk::QueryControlFlags::PRECISE) {
+            assert_eq!(
+                T::QUERY_TYPE,
+                vk::QueryType::OCCLUSION,
+                "only occlusion queries can be precise"
+            );
+            assert_eq!(
+                self.device().inner.enabled_features.occlusion_query_precise,
+                vk::TRUE,
+                "occlusionQueryPrecise is not enabled on this device"
+            );
+        }
+        unsafe {
+            self.device_handle().cmd_begin_query(
+                self.command_buffer.handle,
+                query_pool.inner.handle,
+                query,
+                flags,
+            );
+        }
+        self.command_buffer
+            .resources
+            .push(Box::new(query_pool.clone()));
+    }
+
+    pub fn end_query<T: ScopedQueryType>(&mut self, query_pool: &QueryPool<T>, query: u32) {
+        query_pool.check_range(query, 1);
+        unsafe {
+            self.device_handle().cmd_end_query(
+                self.command_buffer.handle,
+                query_pool.inner.handle,
+                query,
+            );
+        }
+    }
+
+    // Must be recorded outside of render passes.
+    pub fn reset_query_pool<T: QueryType>(
+        &mut self,
+        query_pool: &QueryPool<T>,
+        first_query: u32,
+        query_count: u32,
+    ) {
+        query_pool.check_range(first_query, query_count);
+        assert!(
+            self.subpass_contents.is_none(),
+            "query pools can't be reset inside a render pass"
+        );
+        unsafe {
+            self.device_handle().cmd_reset_query_pool(
+                self.command_buffer.handle,
+                query_pool.inner.handle,
+                first_query,
+                query_count,
+            );
+        }
+        self.command_buffer
+            .resources
+            .push(Box::new(query_pool.clone()));
+    }
+
+    // Writes the results tightly packed, each query followed by its availability
+    // value if WITH_AVAILABILITY is set.
+    pub fn copy_query_results<T: QueryType>(
+        &mut self,
+        query_pool: &QueryPool<T>,
+        first_query: u32,
+        query_count: u32,
+        dst: &Buffer,
+        dst_offset: u64,
+        flags: vk::QueryResultFlags,
+    ) {
+        query_pool.check_range(first_query, query_count);
+        let value_size = if flags.contains(vk::QueryResultFlags::TYPE_64) {
+            8
+        } else {
+            4
+        };
+        let values = query_pool.values_per_query()
+            + flags.contains(vk::QueryResultFlags::WITH_AVAILABILITY) as usize;
+        let stride = (values * value_size) as u64;
+        assert_eq!(
+            dst_offset % value_size as u64,
+            0,
+            "destination offset must be aligned to the result size"
+        );
+        assert!(
+            dst_offset + stride * query_count as u64 <= dst.size() as u64,
+            "query results need {} bytes at offset {}, buffer holds {}",
+            stride * query_count as u64,
+            dst_offset,
+            dst.size()
+        );
+        assert!(
+            self.subpass_contents.is_none(),
+            "query results can't be copied inside a render pass"
+        );
+        unsafe {
+            self.device_handle().cmd_copy_query_pool_results(
+                self.command_buffer.handle,
+                query_pool.inner.handle,
+                first_query,
+                query_count,
+                dst.handle(),
+                dst_offset,
+                stride,
+                flags,
+            );
+        }
+        self.command_buffer
+            .resources
+            .push(Box::new(query_pool.clone()));
+        self.command_buffer.resources.push(Box::new(dst.clone()));
+    }
+}
+
+impl Device {
+    pub fn create_query_pool<T: PlainQueryType>(
+        &self,
+        name: Option<&str>,
+        count: u32,
+    ) -> QueryPool<T> {
+        QueryPool::new(self, name, count)
+    }
+
+    pub fn create_pipeline_statistics_query_pool(
+        &self,
+        name: Option<&str>,
+        count: u32,
+        statistics: vk::QueryPipelineStatisticFlags,
+    ) -> Result<QueryPool<PipelineStatistics>> {
+        QueryPool::with_statistics(self, name, count, statistics)
+    }
+}
+
+#[test]
+fn test_statistic_flags_order() {
+    let flags = statistic_flags(
+        vk::QueryPipelineStatisticFlags::COMPUTE_SHADER_INVOCATIONS
+            | vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS
+            | vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS,
+    );
+    assert_eq!(
+        flags,
+        vec![
+            vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS,
+            vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS,
+            vk::QueryPipelineStatisticFlags::COMPUTE_SHADER_INVOCATIONS,
+        ]
+    );
+    let result = PipelineStatisticsResult {
+        values: flags.into_iter().zip(vec![3, 5, 7]).collect(),
+    };
+    assert_eq!(
+        result.get(vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS),
+        Some(5)
+    );
+    assert_eq!(
+        result.get(vk::QueryPipelineStatisticFlags::CLIPPING_PRIMITIVES),
+        None
+    );
+}
@@ -1,11 +1,15 @@
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Weak;
 
 use ash::vk;
 
-use crate::device::Device;
+use anyhow::{ensure, Result};
+
+use crate::command_buffer::CommandBufferResource;
+use crate::device::{Device, DeviceRef};
 use crate::queue_family::QueueFamilyProperties;
-use crate::CommandBuffer;
+use crate::{BinarySemaphore, CommandBuffer, Fence, TimelineSemaphore};
 
 pub(crate) struct QueueRef {
     pub(crate) handle: vk::Queue,
@@ -13,88 +17,420 @@ pub(crate) struct QueueRef {
     device: ash::Device,
     command_buffers: Vec<CommandBuffer>,
     synchronization2_loader: ash::extensions::khr::Synchronization2,
-    lock: Mutex<()>,
+    pub(crate) debug_utils_loader: Option<ash::extensions::ext::DebugUtils>,
+    // Every submission signals the next value, so completion can be polled
+    // without a fence per submit.
+    timeline: vk::Semaphore,
+    // Value of the last successful submission, guarded by the lock.
+    pub(crate) lock: Mutex<u64>,
+    // The device owning the queue, set once it is created.
+    pub(crate) owner: Mutex<Weak<DeviceRef>>,
 }
 
 pub struct Queue {
     pub(crate) inner: Arc<QueueRef>,
 }
 
+// Everything a single vkQueueSubmit2 batch consumes. Command buffers are moved in
+// and handed back by `SubmissionHandle::into_command_buffers`.
+#[derive(Default)]
+pub struct SubmitInfo {
+    command_buffers: Vec<CommandBuffer>,
+    wait_semaphores: Vec<vk::SemaphoreSubmitInfoKHR>,
+    signal_semaphores: Vec<vk::SemaphoreSubmitInfoKHR>,
+    fence: Option<Fence>,
+    // Semaphores referenced above, kept alive until the submission completes.
+    resources: Vec<Box<dyn CommandBufferResource + Sync + Send>>,
+}
+
+impl SubmitInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn command_buffer(mut self, command_buffer: CommandBuffer) -> Self {
+        self.command_buffers.push(command_buffer);
+        self
+    }
+
+    pub fn command_buffers<I>(mut self, command_buffers: I) -> Self
+    where
+        I: IntoIterator<Item = CommandBuffer>,
+    {
+        self.command_buffers.extend(command_buffers);
+        self
+    }
+
+    pub fn wait_binary(
+        mut self,
+        semaphore: &BinarySemaphore,
+        stage_mask: vk::PipelineStageFlags2KHR,
+    ) -> Self {
+        self.wait_semaphores.push(
+            vk::SemaphoreSubmitInfoKHR::builder()
+                .semaphore(semaphore.inner.handle)
+                .stage_mask(stage_mask)
+                .build(),
+        );
+        self.resources.push(Box::new(semaphore.clone()));
+        self
+    }
+
+    pub fn wait_timeline(
+        mut self,
+        semaphore: &TimelineSemaphore,
+        value: u64,
+        stage_mask: vk::PipelineStageFlags2KHR,
+    ) -> Self {
+        self.wait_semaphores.push(
+            vk::SemaphoreSubmitInfoKHR::builder()
+                .semaphore(semaphore.inner.handle)
+                .value(value)
+                .stage_mask(stage_mask)
+                .build(),
+        );
+        self.resources.push(Box::new(semaphore.clone()));
+        self
+    }
+
+    pub fn signal_binary(
+        mut self,
+        semaphore: &BinarySemaphore,
+        stage_mask: vk::PipelineStageFlags2KHR,
+    ) -> Self {
+        self.signal_semaphores.push(
+            vk::SemaphoreSubmitInfoKHR::builder()
+                .semaphore(semaphore.inner.handle)
+                .stage_mask(stage_mask)
+                .build(),
+        );
+        self.resources.push(Box::new(semaphore.clone()));
+        self
+    }
+
+    pub fn signal_timeline(
+        mut self,
+        semaphore: &TimelineSemaphore,
+        value: u64,
+        stage_mask: vk::PipelineStageFlags2KHR,
+    ) -> Self {
+        self.signal_semaphores.push(
+            vk::SemaphoreSubmitInfoKHR::builder()
+                .semaphore(semaphore.inner.handle)
+                .value(value)
+                .stage_mask(stage_mask)
+                .build(),
+        );
+        self.resources.push(Box::new(semaphore.clone()));
+        self
+    }
+
+    // The fence must be unsignaled, it is signaled together with the submission.
+    pub fn fence(mut self, fence: &Fence) -> Self {
+        self.fence = Some(fence.clone());
+        self
+    }
+}
+
+// Tracks one submission through the queue's timeline. Dropping it blocks until
+// the GPU is done with the command buffers it owns.
+pub struct SubmissionHandle {
+    command_buffers: Vec<CommandBuffer>,
+    resources: Vec<Box<dyn CommandBufferResource + Sync + Send>>,
+    queue: Arc<QueueRef>,
+    value: u64,
+}
+
+impl SubmissionHandle {
+    // The value the queue's timeline reaches once this submission completes.
+    pub fn timeline_value(&self) -> u64 {
+        self.value
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.queue.completed_value() >= self.value
+    }
+
+    pub fn wait(&self) {
+        self.queue.wait_for(self.value);
+    }
+
+    // Waits for completion and returns the command buffers for reuse.
+    pub fn into_command_buffers(mut self) -> Vec<CommandBuffer> {
+        self.wait();
+        std::mem::take(&mut self.command_buffers)
+    }
+}
+
+impl Drop for SubmissionHandle {
+    fn drop(&mut self) {
+        if !self.command_buffers.is_empty() || !self.resources.is_empty() {
+            self.wait();
+        }
+    }
+}
+
+impl QueueRef {
+    pub(crate) fn last_submitted_value(&self) -> u64 {
+        *self.lock.lock().unwrap()
+    }
+
+    pub(crate) fn completed_value(&self) -> u64 {
+        unsafe {
+            self.device
+                .get_semaphore_counter_value(self.timeline)
+                .unwrap()
+        }
+    }
+
+    fn wait_for(&self, value: u64) {
+        unsafe {
+            self.device
+                .wait_semaphores(
+                    &vk::SemaphoreWaitInfo::builder()
+                        .semaphores(&[self.timeline])
+                        .values(&[value])
+                        .build(),
+                    std::u64::MAX,
+                )
+                .unwrap();
+        }
+        self.collect_garbage();
+    }
+
+    fn collect_garbage(&self) {
+        let owner = self.owner.lock().unwrap().upgrade();
+        if let Some(device) = owner {
+            device.collect_garbage();
+        }
+    }
+
+    // Submits one batch and returns the timeline value it signals on completion.
+    fn submit_raw(
+        &self,
+        command_buffers: &[&CommandBuffer],
+        wait_semaphores: &[vk::SemaphoreSubmitInfoKHR],
+        signal_semaphores: &[vk::SemaphoreSubmitInfoKHR],
+        fence: vk::Fence,
+    ) -> Result<u64> {
+        for command_buffer in command_buffers {
+            ensure!(
+                !command_buffer.is_secondary(),
+                "secondary command buffers are executed through a primary"
+            );
+            ensure!(
+                command_buffer.queue_family_index() == self.queue_family_properties.index,
+                "command buffer was allocated for queue family {}, queue belongs to {}",
+                command_buffer.queue_family_index(),
+                self.queue_family_properties.index
+            );
+            command_buffer.check_submittable()?;
+        }
+        let command_buffer_infos = command_buffers
+            .iter()
+            .map(|cmd_buf| {
+                vk::CommandBufferSubmitInfoKHR::builder()
+                    .command_buffer(cmd_buf.handle)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        let mut last_value = self.lock.lock().unwrap();
+        let value = *last_value + 1;
+        let mut signal_semaphores = signal_semaphores.to_vec();
+        signal_semaphores.push(
+            vk::SemaphoreSubmitInfoKHR::builder()
+                .semaphore(self.timeline)
+                .value(value)
+                .stage_mask(vk::PipelineStageFlags2KHR::ALL_COMMANDS)
+                .build(),
+        );
+        unsafe {
+            self.synchronization2_loader.queue_submit2(
+                self.handle,
+                &[vk::SubmitInfo2KHR::builder()
+                    .wait_semaphore_infos(wait_semaphores)
+                    .command_buffer_infos(&command_buffer_infos)
+                    .signal_semaphore_infos(&signal_semaphores)
+                    .build()],
+                fence,
+            )?;
+        }
+        *last_value = value;
+        // Only now that the GPU has them.
+        for command_buffer in command_buffers {
+            command_buffer.mark_submitted();
+        }
+        Ok(value)
+    }
+}
+
 impl Queue {
     pub(crate) fn new(
         device: &ash::Device,
         synchronization2_loader: ash::extensions::khr::Synchronization2,
+        debug_utils_loader: Option<ash::extensions::ext::DebugUtils>,
         queue_family_properties: &QueueFamilyProperties,
         queue_index: u32,
     ) -> Self {
         unsafe {
             let handle = device.get_device_queue(queue_family_properties.index, queue_index);
+            let timeline = device
+                .create_semaphore(
+                    &vk::SemaphoreCreateInfo::builder()
+                        .push_next(
+                            &mut vk::SemaphoreTypeCreateInfo::builder()
+                                .semaphore_type(vk::SemaphoreType::TIMELINE)
+                                .initial_value(0)
+                                .build(),
+                        )
+                        .build(),
+                    None,
+                )
+                .unwrap();
             Self {
                 inner: Arc::new(QueueRef {
                     handle,
                     synchronization2_loader,
+                    debug_utils_loader,
                     queue_family_properties: queue_family_properties.clone(),
                     device: device.clone(),
                     command_buffers: vec![],
-                    lock: Mutex::new(()),
+                    timeline,
+                    lock: Mutex::new(0),
+                    owner: Mutex::new(Weak::new()),
                 }),
             }
         }
     }
 
-    pub fn submit_blocking(&self, command_buffers: &[CommandBuffer]) {
-        unsafe {
-            let command_buffer_submit_infos = command_buffers
-                .iter()
-                .map(|cmd_buf| {
-                    vk::CommandBufferSubmitInfoKHR::builder()
-                        .command_buffer(cmd_buf.handle)
-                        .build()
-                })
-                .collect::<Vec<_>>();
-
-            let fence_handle = self
-                .inner
-                .device
-                .create_fence(&vk::FenceCreateInfo::builder().build(), None)
-                .unwrap();
-
-            let lock = self.inner.lock.lock().unwrap();
-            // self.inner
-            //     .synchronization2_loader
-            //     .queue_submit2(
-            //         self.inner.handle,
-            //         &[vk::SubmitInfo2KHR::builder()
-            //             .command_buffer_infos(&command_buffer_submit_infos)
-            //             .build()],
-            //         fence_handle,
-            //     )
-            //     .unwrap();
-
-            let command_buffer_handles = command_buffers
-                .iter()
-                .map(|cmd_buf| cmd_buf.handle)
-                .collect::<Vec<_>>();
-            self.inner.device.queue_submit(
-                self.inner.handle,
-                &[vk::SubmitInfo::builder()
-                    .command_buffers(&command_buffer_handles)
-                    .build()],
-                fence_handle,
-            );
-
-            self.inner
-                .device
-                .wait_for_fences(&[fence_handle], true, std::u64::MAX);
-            drop(lock);
-
-            self.inner.device.destroy_fence(fence_handle, None);
+    pub fn submit(&self, info: SubmitInfo) -> Result<SubmissionHandle> {
+        let SubmitInfo {
+            command_buffers,
+            wait_semaphores,
+            signal_semaphores,
+            fence,
+            mut resources,
+        } = info;
+        let value = self.inner.submit_raw(
+            &command_buffers.iter().collect::<Vec<_>>(),
+            &wait_semaphores,
+            &signal_semaphores,
+            fence
+                .as_ref()
+                .map_or(vk::Fence::null(), |fence| fence.inner.handle),
+        )?;
+        if let Some(fence) = fence {
+            resources.push(Box::new(fence));
         }
+        self.inner.collect_garbage();
+        Ok(SubmissionHandle {
+            command_buffers,
+            resources,
+            queue: self.inner.clone(),
+            value,
+        })
+    }
+
+    pub fn submit_blocking(&self, command_buffers: &[CommandBuffer]) {
+        let value = self
+            .inner
+            .submit_raw(
+                &command_buffers.iter().collect::<Vec<_>>(),
+                &[],
+                &[],
+                vk::Fence::null(),
+            )
+            .unwrap();
+        self.inner.wait_for(value);
     }
 }
 
 impl Drop for QueueRef {
     fn drop(&mut self) {
         log::debug!("dropping queue and its command buffers");
+        unsafe {
+            self.device.destroy_semaphore(self.timeline, None);
+        }
     }
 }
+
+#[test]
+fn test_submit_with_timeline() {
+    use crate::device::test_device;
+
+    let device = test_device();
+
+    let dst = device.create_buffer(
+        Some("dst"),
+        8,
+        vk::BufferUsageFlags::empty(),
+        gpu_allocator::MemoryLocation::GpuToCpu,
+    );
+    let timeline = TimelineSemaphore::new(&device);
+
+    let mut first =
+        device.create_command_buffer(Some("first"), device.graphics_queue_family_index());
+    first.encode(|recorder| recorder.fill_buffer(&dst, 0, 4, 1));
+    let mut second =
+        device.create_command_buffer(Some("second"), device.graphics_queue_family_index());
+    second.encode(|recorder| recorder.fill_buffer(&dst, 4, 4, 2));
+
+    let first = device
+        .graphics_queue()
+        .submit(SubmitInfo::new().command_buffer(first).signal_timeline(
+            &timeline,
+            1,
+            vk::PipelineStageFlags2KHR::ALL_TRANSFER,
+        ))
+        .unwrap();
+    let second = device
+        .graphics_queue()
+        .submit(SubmitInfo::new().command_buffer(second).wait_timeline(
+            &timeline,
+            1,
+            vk::PipelineStageFlags2KHR::ALL_TRANSFER,
+        ))
+        .unwrap();
+    assert!(first.timeline_value() < second.timeline_value());
+
+    second.wait();
+    assert!(second.is_complete());
+    let command_buffers = first.into_command_buffers();
+    assert_eq!(command_buffers.len(), 1);
+
+    let guard = dst.lock_memory().unwrap();
+    assert_eq!(
+        bytemuck::cast_slice::<u8, u32>(&guard.mapped_slice().unwrap()[..8]),
+        &[1, 2]
+    );
+}
+
+#[test]
+fn test_failed_submit_keeps_command_buffers() {
+    use crate::device::test_device;
+
+    let device = test_device();
+
+    let dst = device.create_buffer(
+        Some("dst"),
+        4,
+        vk::BufferUsageFlags::empty(),
+        gpu_allocator::MemoryLocation::GpuToCpu,
+    );
+    let mut recorded =
+        device.create_command_buffer(Some("recorded"), device.graphics_queue_family_index());
+    recorded.encode_with_usage(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT, |recorder| {
+        recorder.fill_buffer(&dst, 0, 4, 1)
+    });
+    let unrecorded =
+        device.create_command_buffer(Some("unrecorded"), device.graphics_queue_family_index());
+
+    let queue = device.graphics_queue();
+    assert!(queue
+        .inner
+        .submit_raw(&[&recorded, &unrecorded], &[], &[], vk::Fence::null())
+        .is_err());
+    // Nothing reached the GPU, so the one time submit buffer can still go.
+    queue.submit_blocking(&[recorded]);
+}
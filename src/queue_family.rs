@@ -7,6 +7,7 @@ pub struct QueueFamilyProperties {
     pub(crate) support_compute: bool,
     pub(crate) support_transfer: bool,
     pub(crate) count: u32,
+    pub(crate) timestamp_valid_bits: u32,
 }
 
 impl QueueFamilyProperties {
@@ -21,6 +22,11 @@ impl QueueFamilyProperties {
     pub fn support_transfer(&self) -> bool {
         self.support_transfer
     }
+
+    // Zero if queues of this family can't write timestamps.
+    pub fn timestamp_valid_bits(&self) -> u32 {
+        self.timestamp_valid_bits
+    }
 }
 
 pub struct QueueFamily {
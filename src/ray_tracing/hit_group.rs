@@ -44,8 +44,9 @@ impl ProceduralHitGroup {
     }
 }
 
-pub trait HitGroup: dyn_clone::DynClone + 'static {
-    fn shader_stage_create_infos(&self) -> Vec<vk::PipelineShaderStageCreateInfo>;
+pub trait HitGroup: dyn_clone::DynClone + Send + Sync + 'static {
+    // In closest hit, intersection, any hit order.
+    fn shader_stages(&self) -> Vec<&ShaderStage>;
     fn shader_group_type(&self) -> vk::RayTracingShaderGroupTypeKHR;
     fn has_closest_hit_shader(&self) -> bool;
     fn has_any_hit_shader(&self) -> bool;
@@ -53,13 +54,10 @@ pub trait HitGroup: dyn_clone::DynClone + 'static {
 }
 
 impl HitGroup for TrianglesHitGroup {
-    fn shader_stage_create_infos(&self) -> Vec<vk::PipelineShaderStageCreateInfo> {
-        let mut infos = Vec::new();
-        infos.push(self.closest_hit_shader.shader_stage_create_info());
-        if let Some(any_hit_shader) = self.any_hit_shader.as_ref() {
-            infos.push(any_hit_shader.shader_stage_create_info());
-        }
-        infos
+    fn shader_stages(&self) -> Vec<&ShaderStage> {
+        let mut stages = vec![&self.closest_hit_shader];
+        stages.extend(self.any_hit_shader.as_ref());
+        stages
     }
 
     fn shader_group_type(&self) -> vk::RayTracingShaderGroupTypeKHR {
@@ -79,14 +77,10 @@ impl HitGroup for TrianglesHitGroup {
     }
 }
 impl HitGroup for ProceduralHitGroup {
-    fn shader_stage_create_infos(&self) -> Vec<vk::PipelineShaderStageCreateInfo> {
-        let mut infos = Vec::new();
-        infos.push(self.closest_hit_shader.shader_stage_create_info());
-        infos.push(self.intersection_shader.shader_stage_create_info());
-        if let Some(any_hit_shader) = self.any_hit_shader.as_ref() {
-            infos.push(any_hit_shader.shader_stage_create_info());
-        }
-        infos
+    fn shader_stages(&self) -> Vec<&ShaderStage> {
+        let mut stages = vec![&self.closest_hit_shader, &self.intersection_shader];
+        stages.extend(self.any_hit_shader.as_ref());
+        stages
     }
 
     fn shader_group_type(&self) -> vk::RayTracingShaderGroupTypeKHR {
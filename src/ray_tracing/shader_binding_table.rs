@@ -1,49 +1,81 @@
 use std::ffi::CString;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use crate::pipeline::RayTracingPipelineVersion;
 use crate::{Buffer, Device, RenderPass, ShaderStage};
 use ash::vk::{self, Handle};
 
+#[derive(Clone, Copy)]
+enum TableKind {
+    RayGen,
+    Miss,
+    Hit,
+    Callable,
+}
+
+// One region of a ShaderBindingTables. It is resolved when rays are traced, so
+// it always matches the pipeline version bound at that point.
 pub struct ShaderBindingTable {
-    pub(crate) sbt_buffer: Buffer,
-    pub(crate) region: vk::StridedDeviceAddressRegionKHR,
+    tables: Arc<ShaderBindingTablesRef>,
+    kind: TableKind,
 }
 
-pub(crate) struct ShaderBindingTablesRef {
-    rt_pipeline: crate::RayTracingPipeline,
+struct Tables {
+    // The pipeline build the group handles were copied from.
+    version: Arc<RayTracingPipelineVersion>,
     sbt_buffer: Buffer,
-    ray_tracing_pipeline: crate::RayTracingPipeline,
     raygen_table: vk::StridedDeviceAddressRegionKHR,
     miss_table: vk::StridedDeviceAddressRegionKHR,
     hit_table: vk::StridedDeviceAddressRegionKHR,
     callable_table: vk::StridedDeviceAddressRegionKHR,
 }
 
+pub(crate) struct ShaderBindingTablesRef {
+    ray_tracing_pipeline: crate::RayTracingPipeline,
+    hit_groups: Vec<u32>,
+    // Refilled when the pipeline's version changed since the last use.
+    tables: Mutex<Arc<Tables>>,
+}
+
 #[derive(Clone)]
 pub struct ShaderBindingTables {
     inner: Arc<ShaderBindingTablesRef>,
 }
 
-impl ShaderBindingTables {
-    pub(crate) fn new(
-        device: &Device,
+impl ShaderBindingTablesRef {
+    // The tables for `version` of the pipeline. The old buffer is destroyed once
+    // no command buffer retains it.
+    fn tables(&self, version: &Arc<RayTracingPipelineVersion>) -> Arc<Tables> {
+        let mut tables = self.tables.lock().unwrap();
+        if !Arc::ptr_eq(&tables.version, version) {
+            *tables = Arc::new(Self::build_tables(
+                &self.ray_tracing_pipeline,
+                version,
+                &self.hit_groups,
+            ));
+        }
+        tables.clone()
+    }
+
+    fn build_tables(
         pipeline: &crate::RayTracingPipeline,
+        version: &Arc<RayTracingPipelineVersion>,
         hit_groups: &[u32],
-    ) -> Self {
+    ) -> Tables {
+        let device = &pipeline.inner.device;
+        let shader_group_handles = &version.shader_group_handles;
         let rt_p = &device.inner.pdevice.ray_tracing_pipeline_properties;
         let sbt_base_alignment = rt_p.shader_group_base_alignment as usize;
         let handle_size = rt_p.shader_group_handle_size as usize;
         let sbt_buffer_size = sbt_base_alignment * 2 + hit_groups.len() * handle_size;
         let mut sbt_buffer_data = vec![0; sbt_buffer_size];
         //raygen
-        sbt_buffer_data[0..handle_size]
-            .copy_from_slice(&pipeline.inner.shader_group_handles[0..handle_size]);
+        sbt_buffer_data[0..handle_size].copy_from_slice(&shader_group_handles[0..handle_size]);
         //miss
         let miss_group_count = pipeline.inner.miss_shaders.len();
         sbt_buffer_data[sbt_base_alignment..sbt_base_alignment + handle_size * miss_group_count]
             .copy_from_slice(
-                &pipeline.inner.shader_group_handles
-                    [handle_size..handle_size + handle_size * miss_group_count],
+                &shader_group_handles[handle_size..handle_size + handle_size * miss_group_count],
             );
         // hit group
         let hit_group_count = hit_groups.len();
@@ -51,7 +83,7 @@ impl ShaderBindingTables {
             sbt_buffer_data[(2 * sbt_base_alignment + i * handle_size)
                 ..(2 * sbt_base_alignment + (i + 1) * handle_size)]
                 .copy_from_slice(
-                    &pipeline.inner.shader_group_handles[(2 + *hit_group as usize) * handle_size
+                    &shader_group_handles[(2 + *hit_group as usize) * handle_size
                         ..(2 + *hit_group as usize + 1) * handle_size],
                 )
         }
@@ -80,50 +112,79 @@ impl ShaderBindingTables {
             .size((handle_size * hit_group_count) as u64)
             .build();
         let callable_table = vk::StridedDeviceAddressRegionKHR::default();
-        Self {
-            inner: Arc::new(ShaderBindingTablesRef {
-                rt_pipeline: pipeline.clone(),
-                sbt_buffer,
-                ray_tracing_pipeline: pipeline.to_owned(),
-                raygen_table: ray_gen_table,
-                miss_table,
-                hit_table,
-                callable_table,
-            }),
+        Tables {
+            version: version.clone(),
+            sbt_buffer,
+            raygen_table: ray_gen_table,
+            miss_table,
+            hit_table,
+            callable_table,
         }
     }
+}
 
-    pub fn ray_gen_table(&self) -> ShaderBindingTable {
+impl ShaderBindingTables {
+    pub(crate) fn new(pipeline: &crate::RayTracingPipeline, hit_groups: &[u32]) -> Self {
+        let tables =
+            ShaderBindingTablesRef::build_tables(pipeline, &pipeline.version(), hit_groups);
+        let inner = Arc::new(ShaderBindingTablesRef {
+            ray_tracing_pipeline: pipeline.clone(),
+            hit_groups: hit_groups.to_vec(),
+            tables: Mutex::new(Arc::new(tables)),
+        });
+        Self { inner }
+    }
+
+    fn table(&self, kind: TableKind) -> ShaderBindingTable {
         ShaderBindingTable {
-            sbt_buffer: self.inner.sbt_buffer.clone(),
-            region: self.inner.raygen_table,
+            tables: self.inner.clone(),
+            kind,
         }
     }
 
+    pub fn ray_gen_table(&self) -> ShaderBindingTable {
+        self.table(TableKind::RayGen)
+    }
+
     pub fn miss_table(&self) -> ShaderBindingTable {
-        ShaderBindingTable {
-            sbt_buffer: self.inner.sbt_buffer.clone(),
-            region: self.inner.miss_table,
-        }
+        self.table(TableKind::Miss)
     }
 
     pub fn hit_table(&self) -> ShaderBindingTable {
-        ShaderBindingTable {
-            sbt_buffer: self.inner.sbt_buffer.clone(),
-            region: self.inner.hit_table,
-        }
+        self.table(TableKind::Hit)
     }
 
     pub fn callable_table(&self) -> ShaderBindingTable {
-        ShaderBindingTable {
-            sbt_buffer: self.inner.sbt_buffer.clone(),
-            region: self.inner.callable_table,
-        }
+        self.table(TableKind::Callable)
+    }
+}
+
+impl ShaderBindingTable {
+    // The buffer and region to trace rays with. `bound` is the pipeline version
+    // bound by the recorder, tables of other pipelines use their current one.
+    pub(crate) fn resolve(
+        &self,
+        bound: Option<&(crate::RayTracingPipeline, Arc<RayTracingPipelineVersion>)>,
+    ) -> (Buffer, vk::StridedDeviceAddressRegionKHR) {
+        let pipeline = &self.tables.ray_tracing_pipeline;
+        let tables = match bound {
+            Some((bound, version)) if Arc::ptr_eq(&bound.inner, &pipeline.inner) => {
+                self.tables.tables(version)
+            }
+            _ => self.tables.tables(&pipeline.version()),
+        };
+        let region = match self.kind {
+            TableKind::RayGen => tables.raygen_table,
+            TableKind::Miss => tables.miss_table,
+            TableKind::Hit => tables.hit_table,
+            TableKind::Callable => tables.callable_table,
+        };
+        (tables.sbt_buffer.clone(), region)
     }
 }
 
 impl crate::RayTracingPipeline {
     pub fn create_shader_binding_tables(&self, hit_groups: &[u32]) -> ShaderBindingTables {
-        ShaderBindingTables::new(&self.inner.device, self, hit_groups)
+        ShaderBindingTables::new(self, hit_groups)
     }
 }
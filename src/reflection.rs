@@ -0,0 +1,1104 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, ensure, Result};
+use ash::vk;
+
+use crate::{
+    DescriptorSetLayout, DescriptorSetLayoutBinding, DescriptorType, Device, PipelineLayout,
+    ShaderModule, ShaderStage,
+};
+
+const MAGIC: u32 = 0x0723_0203;
+
+mod op {
+    pub const NAME: u32 = 5;
+    pub const ENTRY_POINT: u32 = 15;
+    pub const EXECUTION_MODE: u32 = 16;
+    pub const TYPE_BOOL: u32 = 20;
+    pub const TYPE_INT: u32 = 21;
+    pub const TYPE_FLOAT: u32 = 22;
+    pub const TYPE_VECTOR: u32 = 23;
+    pub const TYPE_MATRIX: u32 = 24;
+    pub const TYPE_IMAGE: u32 = 25;
+    pub const TYPE_SAMPLER: u32 = 26;
+    pub const TYPE_SAMPLED_IMAGE: u32 = 27;
+    pub const TYPE_ARRAY: u32 = 28;
+    pub const TYPE_RUNTIME_ARRAY: u32 = 29;
+    pub const TYPE_STRUCT: u32 = 30;
+    pub const TYPE_POINTER: u32 = 32;
+    pub const CONSTANT_TRUE: u32 = 41;
+    pub const CONSTANT_FALSE: u32 = 42;
+    pub const CONSTANT: u32 = 43;
+    pub const CONSTANT_COMPOSITE: u32 = 44;
+    pub const SPEC_CONSTANT_TRUE: u32 = 48;
+    pub const SPEC_CONSTANT_FALSE: u32 = 49;
+    pub const SPEC_CONSTANT: u32 = 50;
+    pub const SPEC_CONSTANT_COMPOSITE: u32 = 51;
+    pub const VARIABLE: u32 = 59;
+    pub const DECORATE: u32 = 71;
+    pub const MEMBER_DECORATE: u32 = 72;
+    pub const EXECUTION_MODE_ID: u32 = 331;
+    pub const TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+}
+
+mod decoration {
+    pub const SPEC_ID: u32 = 1;
+    pub const BUFFER_BLOCK: u32 = 3;
+    pub const ARRAY_STRIDE: u32 = 6;
+    pub const MATRIX_STRIDE: u32 = 7;
+    pub const BUILT_IN: u32 = 11;
+    pub const BINDING: u32 = 33;
+    pub const DESCRIPTOR_SET: u32 = 34;
+    pub const OFFSET: u32 = 35;
+}
+
+mod storage_class {
+    pub const UNIFORM_CONSTANT: u32 = 0;
+    pub const UNIFORM: u32 = 2;
+    pub const PUSH_CONSTANT: u32 = 9;
+    pub const STORAGE_BUFFER: u32 = 12;
+}
+
+const BUILT_IN_WORKGROUP_SIZE: u32 = 25;
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+const EXECUTION_MODE_LOCAL_SIZE_ID: u32 = 38;
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+// Deepest type nesting size_of follows, a guard against cyclic types.
+const MAX_TYPE_DEPTH: u32 = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryPoint {
+    pub name: String,
+    pub stage: vk::ShaderStageFlags,
+    // Only set for compute like stages.
+    pub workgroup_size: Option<[u32; 3]>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    // 0 for runtime arrays.
+    pub count: u32,
+    pub runtime_array: bool,
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScalarKind {
+    Bool,
+    Int { signed: bool },
+    Float,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpecializationConstant {
+    pub id: u32,
+    pub name: Option<String>,
+    // Size in bytes of the constant's scalar type, booleans take 4.
+    pub size: u32,
+    pub kind: ScalarKind,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderReflection {
+    pub entry_points: Vec<EntryPoint>,
+    // Sorted by set and binding.
+    pub descriptor_bindings: Vec<DescriptorBinding>,
+    // Byte range of the push constant block members, if the module has one.
+    pub push_constant_range: Option<(u32, u32)>,
+    pub specialization_constants: Vec<SpecializationConstant>,
+}
+
+impl ShaderReflection {
+    pub fn entry_point(&self, name: &str) -> Option<&EntryPoint> {
+        self.entry_points.iter().find(|e| e.name == name)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Type {
+    Scalar { size: u32, kind: ScalarKind },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+    AccelerationStructure,
+}
+
+// (decoration, operands)
+type Decorations = Vec<(u32, Vec<u32>)>;
+
+#[derive(Default)]
+struct Module {
+    names: HashMap<u32, String>,
+    types: HashMap<u32, Type>,
+    // Scalar constants by id, the first word of their value.
+    constants: HashMap<u32, u32>,
+    composites: HashMap<u32, Vec<u32>>,
+    decorations: HashMap<u32, Decorations>,
+    member_decorations: HashMap<(u32, u32), Decorations>,
+    // (id, result type, storage class)
+    variables: Vec<(u32, u32, u32)>,
+    // (id, result type)
+    spec_constants: Vec<(u32, u32)>,
+}
+
+impl Module {
+    fn decoration(&self, id: u32, decoration: u32) -> Option<&[u32]> {
+        self.decorations
+            .get(&id)?
+            .iter()
+            .find(|(d, _)| *d == decoration)
+            .map(|(_, operands)| operands.as_slice())
+    }
+
+    fn member_decoration(&self, id: u32, member: u32, decoration: u32) -> Option<u32> {
+        self.member_decorations
+            .get(&(id, member))?
+            .iter()
+            .find(|(d, _)| *d == decoration)
+            .and_then(|(_, operands)| operands.first().copied())
+    }
+
+    fn ty(&self, id: u32) -> Result<&Type> {
+        match self.types.get(&id) {
+            Some(ty) => Ok(ty),
+            None => bail!("type %{} is not declared", id),
+        }
+    }
+
+    fn constant(&self, id: u32) -> Result<u32> {
+        match self.constants.get(&id) {
+            Some(value) => Ok(*value),
+            None => bail!("%{} is not a scalar constant", id),
+        }
+    }
+
+    // Size in bytes of a type inside an explicitly laid out block.
+    fn size_of(&self, id: u32, matrix_stride: Option<u32>) -> Result<u32> {
+        self.nested_size_of(id, matrix_stride, 0)
+    }
+
+    fn nested_size_of(&self, id: u32, matrix_stride: Option<u32>, depth: u32) -> Result<u32> {
+        ensure!(
+            depth < MAX_TYPE_DEPTH,
+            "type %{} is nested more than {} levels deep",
+            id,
+            MAX_TYPE_DEPTH
+        );
+        let size = match self.ty(id)? {
+            Type::Scalar { size, .. } => Some(*size),
+            Type::Vector { component, count } => {
+                self.nested_size_of(*component, None, depth + 1)?
+                    .checked_mul(*count)
+            }
+            Type::Matrix { column, count } => {
+                match matrix_stride {
+                    Some(stride) => stride.checked_mul(*count),
+                    None => {
+                        self.nested_size_of(*column, None, depth + 1)?
+                            .checked_mul(*count)
+                    }
+                }
+            }
+            Type::Array { element, length } => {
+                let stride = match self.decoration(id, decoration::ARRAY_STRIDE) {
+                    Some(operands) if !operands.is_empty() => operands[0],
+                    Some(_) => bail!("ArrayStride decoration without a stride"),
+                    None => self.nested_size_of(*element, matrix_stride, depth + 1)?,
+                };
+                stride.checked_mul(self.constant(*length)?)
+            }
+            Type::RuntimeArray { .. } => Some(0),
+            Type::Struct { members } => {
+                let mut size = 0;
+                for (i, member) in members.iter().enumerate() {
+                    let offset = self
+                        .member_decoration(id, i as u32, decoration::OFFSET)
+                        .unwrap_or(0);
+                    let stride = self.member_decoration(id, i as u32, decoration::MATRIX_STRIDE);
+                    let end =
+                        offset.checked_add(self.nested_size_of(*member, stride, depth + 1)?);
+                    ensure!(end.is_some(), "member {} of %{} overflows", i, id);
+                    size = size.max(end.unwrap());
+                }
+                Some(size)
+            }
+            ty => bail!("{:?} has no size in a block", ty),
+        };
+        ensure!(size.is_some(), "size of type %{} overflows", id);
+        Ok(size.unwrap())
+    }
+
+    // Member byte range of a push constant block.
+    fn block_range(&self, id: u32) -> Result<(u32, u32)> {
+        let members = match self.ty(id)? {
+            Type::Struct { members } => members,
+            _ => bail!("push constant %{} is not a struct", id),
+        };
+        let mut start = u32::MAX;
+        let mut end = 0;
+        for (i, member) in members.iter().enumerate() {
+            let offset = self
+                .member_decoration(id, i as u32, decoration::OFFSET)
+                .unwrap_or(0);
+            let stride = self.member_decoration(id, i as u32, decoration::MATRIX_STRIDE);
+            let member_end = offset.checked_add(self.size_of(*member, stride)?);
+            ensure!(
+                member_end.is_some(),
+                "push constant member {} of %{} overflows",
+                i,
+                id
+            );
+            start = start.min(offset);
+            end = end.max(member_end.unwrap());
+        }
+        if members.is_empty() {
+            start = 0;
+        }
+        Ok((start, end - start))
+    }
+
+    fn descriptor_type(&self, pointee: u32, storage_class: u32) -> Result<vk::DescriptorType> {
+        Ok(match (storage_class, self.ty(pointee)?) {
+            (storage_class::STORAGE_BUFFER, _) => vk::DescriptorType::STORAGE_BUFFER,
+            (storage_class::UNIFORM, _) => {
+                if self.decoration(pointee, decoration::BUFFER_BLOCK).is_some() {
+                    vk::DescriptorType::STORAGE_BUFFER
+                } else {
+                    vk::DescriptorType::UNIFORM_BUFFER
+                }
+            }
+            (_, Type::Sampler) => vk::DescriptorType::SAMPLER,
+            (_, Type::SampledImage) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            (_, Type::AccelerationStructure) => vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            (_, Type::Image { dim, sampled }) => {
+                match (*dim, *sampled) {
+                    (DIM_BUFFER, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                    (DIM_BUFFER, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                    (DIM_SUBPASS_DATA, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+                    (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                    _ => vk::DescriptorType::SAMPLED_IMAGE,
+                }
+            }
+            (_, ty) => bail!("{:?} can't be bound to a descriptor", ty),
+        })
+    }
+}
+
+fn execution_model_stage(model: u32) -> Result<vk::ShaderStageFlags> {
+    Ok(match model {
+        0 => vk::ShaderStageFlags::VERTEX,
+        1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        3 => vk::ShaderStageFlags::GEOMETRY,
+        4 => vk::ShaderStageFlags::FRAGMENT,
+        5 => vk::ShaderStageFlags::COMPUTE,
+        5267 => vk::ShaderStageFlags::TASK_NV,
+        5268 => vk::ShaderStageFlags::MESH_NV,
+        5313 => vk::ShaderStageFlags::RAYGEN_KHR,
+        5314 => vk::ShaderStageFlags::INTERSECTION_KHR,
+        5315 => vk::ShaderStageFlags::ANY_HIT_KHR,
+        5316 => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        5317 => vk::ShaderStageFlags::MISS_KHR,
+        5318 => vk::ShaderStageFlags::CALLABLE_KHR,
+        model => bail!("unsupported execution model {}", model),
+    })
+}
+
+// Decodes a nul terminated, word padded literal string. Returns the string and
+// the number of words it occupies.
+pub(crate) fn literal_string(words: &[u32]) -> Result<(String, usize)> {
+    let mut bytes = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        for byte in word.to_le_bytes().iter() {
+            if *byte == 0 {
+                return Ok((String::from_utf8(bytes)?, i + 1));
+            }
+            bytes.push(*byte);
+        }
+    }
+    bail!("literal string is not nul terminated")
+}
+
+// Splits the instruction stream into (opcode, operands) pairs.
+pub(crate) fn instructions(words: &[u32]) -> Result<Vec<(u32, &[u32])>> {
+    ensure!(words.len() >= 5, "SPIR-V module is shorter than its header");
+    ensure!(
+        words[0] == MAGIC,
+        "bad SPIR-V magic number {:#010x}",
+        words[0]
+    );
+    let mut instructions = Vec::new();
+    let mut offset = 5;
+    while offset < words.len() {
+        let word_count = (words[offset] >> 16) as usize;
+        let opcode = words[offset] & 0xffff;
+        ensure!(
+            word_count > 0 && offset + word_count <= words.len(),
+            "instruction at word {} has an invalid length {}",
+            offset,
+            word_count
+        );
+        instructions.push((opcode, &words[offset + 1..offset + word_count]));
+        offset += word_count;
+    }
+    Ok(instructions)
+}
+
+fn operands(opcode: u32, operands: &[u32], count: usize) -> Result<()> {
+    ensure!(
+        operands.len() >= count,
+        "instruction {} needs {} operands, has {}",
+        opcode,
+        count,
+        operands.len()
+    );
+    Ok(())
+}
+
+// Names and stages of the module's entry points, without reflecting the rest.
+pub(crate) fn entry_points(words: &[u32]) -> Result<Vec<(String, vk::ShaderStageFlags)>> {
+    let mut entry_points = Vec::new();
+    for (opcode, ops) in instructions(words)? {
+        if opcode == op::ENTRY_POINT {
+            operands(opcode, ops, 3)?;
+            entry_points.push((literal_string(&ops[2..])?.0, execution_model_stage(ops[0])?));
+        }
+    }
+    Ok(entry_points)
+}
+
+pub fn reflect(words: &[u32]) -> Result<ShaderReflection> {
+    let mut module = Module::default();
+    // (model, id, name)
+    let mut entry_points = Vec::new();
+    // (entry point id, mode, operands, operands are ids)
+    let mut execution_modes = Vec::new();
+
+    for (opcode, ops) in instructions(words)? {
+        match opcode {
+            op::NAME => {
+                operands(opcode, ops, 2)?;
+                module.names.insert(ops[0], literal_string(&ops[1..])?.0);
+            }
+            op::ENTRY_POINT => {
+                operands(opcode, ops, 3)?;
+                entry_points.push((ops[0], ops[1], literal_string(&ops[2..])?.0));
+            }
+            op::EXECUTION_MODE | op::EXECUTION_MODE_ID => {
+                operands(opcode, ops, 2)?;
+                execution_modes.push((ops[0], ops[1], ops[2..].to_vec()));
+            }
+            op::TYPE_BOOL => {
+                operands(opcode, ops, 1)?;
+                module.types.insert(
+                    ops[0],
+                    Type::Scalar {
+                        size: 4,
+                        kind: ScalarKind::Bool,
+                    },
+                );
+            }
+            op::TYPE_INT => {
+                operands(opcode, ops, 3)?;
+                module.types.insert(
+                    ops[0],
+                    Type::Scalar {
+                        size: ops[1] / 8,
+                        kind: ScalarKind::Int {
+                            signed: ops[2] != 0,
+                        },
+                    },
+                );
+            }
+            op::TYPE_FLOAT => {
+                operands(opcode, ops, 2)?;
+                module.types.insert(
+                    ops[0],
+                    Type::Scalar {
+                        size: ops[1] / 8,
+                        kind: ScalarKind::Float,
+                    },
+                );
+            }
+            op::TYPE_VECTOR => {
+                operands(opcode, ops, 3)?;
+                module.types.insert(
+                    ops[0],
+                    Type::Vector {
+                        component: ops[1],
+                        count: ops[2],
+                    },
+                );
+            }
+            op::TYPE_MATRIX => {
+                operands(opcode, ops, 3)?;
+                module.types.insert(
+                    ops[0],
+                    Type::Matrix {
+                        column: ops[1],
+                        count: ops[2],
+                    },
+                );
+            }
+            op::TYPE_IMAGE => {
+                operands(opcode, ops, 8)?;
+                module.types.insert(
+                    ops[0],
+                    Type::Image {
+                        dim: ops[2],
+                        sampled: ops[6],
+                    },
+                );
+            }
+            op::TYPE_SAMPLER => {
+                operands(opcode, ops, 1)?;
+                module.types.insert(ops[0], Type::Sampler);
+            }
+            op::TYPE_SAMPLED_IMAGE => {
+                operands(opcode, ops, 2)?;
+                module.types.insert(ops[0], Type::SampledImage);
+            }
+            op::TYPE_ARRAY => {
+                operands(opcode, ops, 3)?;
+                module.types.insert(
+                    ops[0],
+                    Type::Array {
+                        element: ops[1],
+                        length: ops[2],
+                    },
+                );
+            }
+            op::TYPE_RUNTIME_ARRAY => {
+                operands(opcode, ops, 2)?;
+                module
+                    .types
+                    .insert(ops[0], Type::RuntimeArray { element: ops[1] });
+            }
+            op::TYPE_STRUCT => {
+                operands(opcode, ops, 1)?;
+                module.types.insert(
+                    ops[0],
+                    Type::Struct {
+                        members: ops[1..].to_vec(),
+                    },
+                );
+            }
+            op::TYPE_POINTER => {
+                operands(opcode, ops, 3)?;
+                module
+                    .types
+                    .insert(ops[0], Type::Pointer { pointee: ops[2] });
+            }
+            op::TYPE_ACCELERATION_STRUCTURE => {
+                operands(opcode, ops, 1)?;
+                module.types.insert(ops[0], Type::AccelerationStructure);
+            }
+            op::CONSTANT | op::SPEC_CONSTANT => {
+                operands(opcode, ops, 3)?;
+                module.constants.insert(ops[1], ops[2]);
+                if opcode == op::SPEC_CONSTANT {
+                    module.spec_constants.push((ops[1], ops[0]));
+                }
+            }
+            op::CONSTANT_TRUE | op::CONSTANT_FALSE => {
+                operands(opcode, ops, 2)?;
+                module
+                    .constants
+                    .insert(ops[1], (opcode == op::CONSTANT_TRUE) as u32);
+            }
+            op::SPEC_CONSTANT_TRUE | op::SPEC_CONSTANT_FALSE => {
+                operands(opcode, ops, 2)?;
+                module
+                    .constants
+                    .insert(ops[1], (opcode == op::SPEC_CONSTANT_TRUE) as u32);
+                module.spec_constants.push((ops[1], ops[0]));
+            }
+            op::CONSTANT_COMPOSITE | op::SPEC_CONSTANT_COMPOSITE => {
+                operands(opcode, ops, 2)?;
+                module.composites.insert(ops[1], ops[2..].to_vec());
+            }
+            op::VARIABLE => {
+                operands(opcode, ops, 3)?;
+                module.variables.push((ops[1], ops[0], ops[2]));
+            }
+            op::DECORATE => {
+                operands(opcode, ops, 2)?;
+                module
+                    .decorations
+                    .entry(ops[0])
+                    .or_default()
+                    .push((ops[1], ops[2..].to_vec()));
+            }
+            op::MEMBER_DECORATE => {
+                operands(opcode, ops, 3)?;
+                module
+                    .member_decorations
+                    .entry((ops[0], ops[1]))
+                    .or_default()
+                    .push((ops[2], ops[3..].to_vec()));
+            }
+            _ => {}
+        }
+    }
+
+    // A constant decorated as the WorkgroupSize built-in overrides LocalSize, and
+    // may be all a compute like entry point has.
+    let builtin_workgroup_size = module
+        .composites
+        .iter()
+        .find(|(id, _)| {
+            module.decoration(**id, decoration::BUILT_IN) == Some(&[BUILT_IN_WORKGROUP_SIZE])
+        })
+        .map(|(_, components)| components.clone());
+    let builtin_workgroup_size = match builtin_workgroup_size {
+        Some(components) => {
+            ensure!(
+                components.len() == 3,
+                "WorkgroupSize must have 3 components"
+            );
+            Some([
+                module.constant(components[0])?,
+                module.constant(components[1])?,
+                module.constant(components[2])?,
+            ])
+        }
+        None => None,
+    };
+
+    let mut reflection = ShaderReflection::default();
+    for (model, id, name) in entry_points {
+        let stage = execution_model_stage(model)?;
+        let mut workgroup_size = None;
+        for (entry, mode, operands) in &execution_modes {
+            if *entry != id || operands.len() < 3 {
+                continue;
+            }
+            match *mode {
+                EXECUTION_MODE_LOCAL_SIZE => {
+                    workgroup_size = Some([operands[0], operands[1], operands[2]]);
+                }
+                EXECUTION_MODE_LOCAL_SIZE_ID => {
+                    workgroup_size = Some([
+                        module.constant(operands[0])?,
+                        module.constant(operands[1])?,
+                        module.constant(operands[2])?,
+                    ]);
+                }
+                _ => {}
+            }
+        }
+        let compute_like = vk::ShaderStageFlags::COMPUTE
+            | vk::ShaderStageFlags::TASK_NV
+            | vk::ShaderStageFlags::MESH_NV;
+        if compute_like.contains(stage) {
+            workgroup_size = builtin_workgroup_size.or(workgroup_size);
+        }
+        reflection.entry_points.push(EntryPoint {
+            name,
+            stage,
+            workgroup_size,
+        });
+    }
+
+    for &(id, result_type, storage_class) in &module.variables {
+        let pointee = match module.ty(result_type)? {
+            Type::Pointer { pointee } => *pointee,
+            _ => bail!("variable %{} does not have a pointer type", id),
+        };
+        if storage_class == storage_class::PUSH_CONSTANT {
+            let range = module.block_range(pointee)?;
+            ensure!(
+                reflection.push_constant_range.is_none(),
+                "module declares more than one push constant block"
+            );
+            reflection.push_constant_range = Some(range);
+            continue;
+        }
+        if storage_class != storage_class::UNIFORM_CONSTANT
+            && storage_class != storage_class::UNIFORM
+            && storage_class != storage_class::STORAGE_BUFFER
+        {
+            continue;
+        }
+        let (set, binding) = match (
+            module.decoration(id, decoration::DESCRIPTOR_SET),
+            module.decoration(id, decoration::BINDING),
+        ) {
+            (Some(set), Some(binding)) if !set.is_empty() && !binding.is_empty() => {
+                (set[0], binding[0])
+            }
+            _ => continue,
+        };
+
+        // Peel arrays of descriptors down to the descriptor type.
+        let mut ty = pointee;
+        let mut count = 1;
+        let mut runtime_array = false;
+        loop {
+            match module.ty(ty)? {
+                Type::Array { element, length } => {
+                    count *= module.constant(*length)?;
+                    ty = *element;
+                }
+                Type::RuntimeArray { element } => {
+                    runtime_array = true;
+                    ty = *element;
+                }
+                _ => break,
+            }
+        }
+        reflection.descriptor_bindings.push(DescriptorBinding {
+            set,
+            binding,
+            descriptor_type: module.descriptor_type(ty, storage_class)?,
+            count: if runtime_array { 0 } else { count },
+            runtime_array,
+            name: module.names.get(&id).cloned(),
+        });
+    }
+    reflection
+        .descriptor_bindings
+        .sort_by_key(|binding| (binding.set, binding.binding));
+
+    for &(id, result_type) in &module.spec_constants {
+        if let Some(spec_id) = module.decoration(id, decoration::SPEC_ID) {
+            ensure!(!spec_id.is_empty(), "SpecId decoration without an id");
+            let (size, kind) = match module.ty(result_type)? {
+                Type::Scalar { size, kind } => (*size, *kind),
+                ty => {
+                    bail!(
+                        "specialization constant %{} has non scalar type {:?}",
+                        id,
+                        ty
+                    )
+                }
+            };
+            reflection
+                .specialization_constants
+                .push(SpecializationConstant {
+                    id: spec_id[0],
+                    name: module.names.get(&id).cloned(),
+                    size,
+                    kind,
+                });
+        }
+    }
+    reflection.specialization_constants.sort_by_key(|c| c.id);
+    Ok(reflection)
+}
+
+// One binding of a set merged from several stages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergedBinding {
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub runtime_array: bool,
+    pub stage_flags: vk::ShaderStageFlags,
+}
+
+pub type MergedSets = BTreeMap<u32, Vec<MergedBinding>>;
+
+// Combines the bindings and push constants of the given stages. Sets are keyed
+// by index, stages must agree on the type and count of shared bindings.
+pub fn merge_reflections(
+    stages: &[(vk::ShaderStageFlags, &ShaderReflection)],
+) -> Result<(MergedSets, Vec<vk::PushConstantRange>)> {
+    let mut sets: BTreeMap<u32, BTreeMap<u32, MergedBinding>> = BTreeMap::new();
+    let mut push_constant_range: Option<vk::PushConstantRange> = None;
+    for (stage, reflection) in stages {
+        for binding in &reflection.descriptor_bindings {
+            let merged = sets
+                .entry(binding.set)
+                .or_default()
+                .entry(binding.binding)
+                .or_insert_with(|| {
+                    MergedBinding {
+                        binding: binding.binding,
+                        descriptor_type: binding.descriptor_type,
+                        count: binding.count,
+                        runtime_array: binding.runtime_array,
+                        stage_flags: vk::ShaderStageFlags::empty(),
+                    }
+                });
+            ensure!(
+                merged.descriptor_type == binding.descriptor_type
+                    && merged.count == binding.count
+                    && merged.runtime_array == binding.runtime_array,
+                "set {} binding {} is declared differently by {:?}",
+                binding.set,
+                binding.binding,
+                stage
+            );
+            merged.stage_flags |= *stage;
+        }
+        if let Some((offset, size)) = reflection.push_constant_range {
+            let range = push_constant_range.get_or_insert(vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::empty(),
+                offset,
+                size,
+            });
+            let end = (range.offset + range.size).max(offset + size);
+            range.offset = range.offset.min(offset);
+            range.size = end - range.offset;
+            range.stage_flags |= *stage;
+        }
+    }
+    Ok((
+        sets.into_iter()
+            .map(|(set, bindings)| (set, bindings.into_values().collect()))
+            .collect(),
+        push_constant_range.into_iter().collect(),
+    ))
+}
+
+// Descriptor set layouts and the pipeline layout built from reflected stages.
+// Set layouts are indexed by set number, unused sets get empty layouts.
+#[derive(Clone)]
+pub struct ReflectedLayout {
+    pub set_layouts: Vec<DescriptorSetLayout>,
+    pub pipeline_layout: PipelineLayout,
+}
+
+fn descriptor_type(ty: vk::DescriptorType) -> Result<DescriptorType> {
+    Ok(match ty {
+        vk::DescriptorType::SAMPLER => DescriptorType::Sampler(None),
+        vk::DescriptorType::SAMPLED_IMAGE => DescriptorType::SampledImage,
+        vk::DescriptorType::UNIFORM_BUFFER => DescriptorType::UniformBuffer,
+        vk::DescriptorType::STORAGE_BUFFER => DescriptorType::StorageBuffer,
+        vk::DescriptorType::ACCELERATION_STRUCTURE_KHR => DescriptorType::AccelerationStructure,
+        vk::DescriptorType::STORAGE_IMAGE => DescriptorType::StorageImage,
+        ty => bail!("descriptor type {:?} is not supported", ty),
+    })
+}
+
+impl ShaderModule {
+    pub fn reflect(&self) -> Result<ShaderReflection> {
+        reflect(&self.code().words)
+    }
+}
+
+impl Device {
+    // Runtime arrays become variable count bindings of `runtime_array_capacity`
+    // descriptors.
+    pub fn create_reflected_layout(
+        &self,
+        name: Option<&str>,
+        stages: &[&ShaderStage],
+        runtime_array_capacity: u32,
+    ) -> Result<ReflectedLayout> {
+        let reflections = stages
+            .iter()
+            .map(|stage| {
+                let reflection = stage.module().reflect()?;
+                ensure!(
+                    reflection.entry_point(stage.entry_point()).is_some(),
+                    "shader module has no entry point {}",
+                    stage.entry_point()
+                );
+                Ok(reflection)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let stages = stages
+            .iter()
+            .zip(reflections.iter())
+            .map(|(stage, reflection)| (stage.stage(), reflection))
+            .collect::<Vec<_>>();
+        let (sets, push_constant_ranges) = merge_reflections(&stages)?;
+
+        let set_count = sets.keys().next_back().map_or(0, |set| set + 1);
+        let mut set_layouts = Vec::new();
+        for set in 0..set_count {
+            let merged = sets.get(&set).map(Vec::as_slice).unwrap_or_default();
+            // Only the highest binding of a set may have a variable count.
+            if let Some(binding) = merged.iter().find(|binding| binding.runtime_array) {
+                let last = merged.iter().map(|binding| binding.binding).max();
+                ensure!(
+                    last == Some(binding.binding),
+                    "runtime array at set {} binding {} is not the highest binding of its set",
+                    set,
+                    binding.binding
+                );
+            }
+            let bindings = merged
+                .iter()
+                .map(|binding| {
+                    Ok(DescriptorSetLayoutBinding {
+                        binding: binding.binding,
+                        descriptor_type: descriptor_type(binding.descriptor_type)?,
+                        stage_flags: binding.stage_flags,
+                        descriptor_count: if binding.runtime_array {
+                            runtime_array_capacity
+                        } else {
+                            binding.count
+                        },
+                        variable_count: binding.runtime_array,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let layout_name = name.map(|name| format!("{} set {}", name, set));
+            set_layouts.push(self.create_descriptor_set_layout(layout_name.as_deref(), &bindings));
+        }
+        let pipeline_layout = self.create_pipeline_layout(
+            name,
+            &set_layouts.iter().collect::<Vec<_>>(),
+            &push_constant_ranges,
+        );
+        Ok(ReflectedLayout {
+            set_layouts,
+            pipeline_layout,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn fixture(name: &str) -> Vec<u32> {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/spirv")
+        .join(name);
+    std::fs::read(path)
+        .unwrap()
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect()
+}
+
+#[test]
+fn test_reflect_compute() {
+    let reflection = reflect(&fixture("compute.spv")).unwrap();
+    assert_eq!(
+        reflection.entry_points,
+        vec![EntryPoint {
+            name: "main".to_owned(),
+            stage: vk::ShaderStageFlags::COMPUTE,
+            workgroup_size: Some([8, 4, 1]),
+        }]
+    );
+    let bindings = reflection
+        .descriptor_bindings
+        .iter()
+        .map(|b| {
+            (
+                b.set,
+                b.binding,
+                b.descriptor_type,
+                b.count,
+                b.runtime_array,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        bindings,
+        vec![
+            (0, 0, vk::DescriptorType::STORAGE_BUFFER, 1, false),
+            (0, 1, vk::DescriptorType::UNIFORM_BUFFER, 1, false),
+            (1, 0, vk::DescriptorType::STORAGE_IMAGE, 4, false),
+        ]
+    );
+    assert_eq!(
+        reflection.descriptor_bindings[0].name.as_deref(),
+        Some("particles")
+    );
+    assert_eq!(reflection.push_constant_range, Some((0, 32)));
+}
+
+#[test]
+fn test_reflect_spec_constant_workgroup_size() {
+    let reflection = reflect(&fixture("compute_spec.spv")).unwrap();
+    assert_eq!(reflection.entry_points[0].workgroup_size, Some([64, 1, 1]));
+    assert_eq!(
+        reflection.specialization_constants,
+        vec![
+            SpecializationConstant {
+                id: 0,
+                name: Some("group_size".to_owned()),
+                size: 4,
+                kind: ScalarKind::Int { signed: false },
+            },
+            SpecializationConstant {
+                id: 1,
+                name: Some("use_fast_path".to_owned()),
+                size: 4,
+                kind: ScalarKind::Bool,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_reflect_builtin_workgroup_size_without_local_size() {
+    let words = fixture("compute_spec.spv");
+    let mut stripped = words[..5].to_vec();
+    let mut i = 5;
+    while i < words.len() {
+        let word_count = (words[i] >> 16) as usize;
+        if words[i] & 0xFFFF != op::EXECUTION_MODE {
+            stripped.extend_from_slice(&words[i..i + word_count]);
+        }
+        i += word_count;
+    }
+    assert!(stripped.len() < words.len());
+    let reflection = reflect(&stripped).unwrap();
+    assert_eq!(reflection.entry_points[0].workgroup_size, Some([64, 1, 1]));
+}
+
+#[test]
+fn test_reflect_ray_generation() {
+    let reflection = reflect(&fixture("raygen.spv")).unwrap();
+    assert_eq!(
+        reflection.entry_points[0].stage,
+        vk::ShaderStageFlags::RAYGEN_KHR
+    );
+    assert_eq!(reflection.entry_points[0].workgroup_size, None);
+    let types = reflection
+        .descriptor_bindings
+        .iter()
+        .map(|b| b.descriptor_type)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        vec![
+            vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            vk::DescriptorType::STORAGE_IMAGE,
+        ]
+    );
+    assert_eq!(reflection.push_constant_range, None);
+}
+
+#[test]
+fn test_merge_vertex_fragment() {
+    let vertex = reflect(&fixture("vertex.spv")).unwrap();
+    let fragment = reflect(&fixture("fragment.spv")).unwrap();
+    assert_eq!(vertex.push_constant_range, Some((0, 64)));
+    assert_eq!(fragment.push_constant_range, Some((64, 16)));
+    assert!(fragment.descriptor_bindings[1].runtime_array);
+
+    let (sets, push_constant_ranges) = merge_reflections(&[
+        (vk::ShaderStageFlags::VERTEX, &vertex),
+        (vk::ShaderStageFlags::FRAGMENT, &fragment),
+    ])
+    .unwrap();
+    assert_eq!(sets.len(), 2);
+    assert_eq!(
+        sets[&0],
+        vec![MergedBinding {
+            binding: 0,
+            descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+            count: 1,
+            runtime_array: false,
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+        }]
+    );
+    assert_eq!(sets[&1].len(), 2);
+    assert_eq!(
+        sets[&1][0].descriptor_type,
+        vk::DescriptorType::SAMPLED_IMAGE
+    );
+    assert_eq!(sets[&1][1].descriptor_type, vk::DescriptorType::SAMPLER);
+    assert_eq!(push_constant_ranges.len(), 1);
+    assert_eq!(push_constant_ranges[0].offset, 0);
+    assert_eq!(push_constant_ranges[0].size, 80);
+    assert_eq!(
+        push_constant_ranges[0].stage_flags,
+        vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT
+    );
+
+    let mut conflicting = fragment.clone();
+    conflicting.descriptor_bindings[0].descriptor_type = vk::DescriptorType::STORAGE_BUFFER;
+    assert!(merge_reflections(&[
+        (vk::ShaderStageFlags::VERTEX, &vertex),
+        (vk::ShaderStageFlags::FRAGMENT, &conflicting),
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_reflect_rejects_garbage() {
+    assert!(reflect(&[]).is_err());
+    assert!(reflect(&[0xdeadbeef, 0x10000, 0, 1, 0]).is_err());
+    // Cuts the first instruction, OpCapability, in half.
+    assert!(reflect(&fixture("compute.spv")[..6]).is_err());
+}
+
+#[test]
+fn test_size_of_rejects_overflow() {
+    let mut module = Module::default();
+    module.types.insert(
+        1,
+        Type::Scalar {
+            size: 4,
+            kind: ScalarKind::Float,
+        },
+    );
+    module.constants.insert(2, u32::MAX);
+    module.types.insert(
+        3,
+        Type::Array {
+            element: 1,
+            length: 2,
+        },
+    );
+    assert!(module.size_of(3, None).is_err());
+
+    module.types.insert(
+        4,
+        Type::Struct {
+            members: vec![1, 1],
+        },
+    );
+    module
+        .member_decorations
+        .insert((4, 1), vec![(decoration::OFFSET, vec![u32::MAX - 2])]);
+    assert!(module.size_of(4, None).is_err());
+    assert!(module.block_range(4).is_err());
+
+    // A struct containing itself would recurse forever.
+    module.types.insert(5, Type::Struct { members: vec![5] });
+    assert!(module.size_of(5, None).is_err());
+}
+
+#[test]
+fn test_reflected_layout_runtime_array_placement() {
+    use crate::device::test_device;
+
+    let device = test_device();
+
+    let module = |name: &str| {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/spirv")
+            .join(name);
+        device.create_shader_module_from_file(path).unwrap()
+    };
+    let compute = ShaderStage::new(
+        &module("compute.spv"),
+        vk::ShaderStageFlags::COMPUTE,
+        "main",
+    )
+    .unwrap();
+    assert!(device
+        .create_reflected_layout(None, &[&compute], 16)
+        .is_ok());
+
+    // fragment.spv declares a sampler after its runtime array of textures.
+    let vertex =
+        ShaderStage::new(&module("vertex.spv"), vk::ShaderStageFlags::VERTEX, "main").unwrap();
+    let fragment = ShaderStage::new(
+        &module("fragment.spv"),
+        vk::ShaderStageFlags::FRAGMENT,
+        "main",
+    )
+    .unwrap();
+    assert!(device
+        .create_reflected_layout(None, &[&vertex, &fragment], 16)
+        .is_err());
+}
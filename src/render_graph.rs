@@ -0,0 +1,1027 @@
+use std::collections::BTreeMap;
+
+use ash::vk;
+
+use crate::barrier::Access;
+use crate::{
+    BinarySemaphore, Buffer, CommandRecorder, Device, Image, ImageDesc, SubmitInfo,
+    TimelineSemaphore,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum QueueType {
+    Graphics,
+    Compute,
+    Transfer,
+}
+
+// Handle to an image or buffer declared on a RenderGraph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GraphResource(usize);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BufferDesc {
+    pub size: usize,
+    pub usage: vk::BufferUsageFlags,
+    pub location: gpu_allocator::MemoryLocation,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TransientDesc {
+    Image(ImageDesc),
+    Buffer(BufferDesc),
+}
+
+struct ResourceDecl {
+    name: String,
+    is_image: bool,
+    imported: bool,
+    initial_layout: vk::ImageLayout,
+    final_layout: Option<vk::ImageLayout>,
+    // Transient resources with equal descriptions and disjoint lifetimes share memory.
+    transient: Option<TransientDesc>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ResourceUse {
+    resource: usize,
+    stage: vk::PipelineStageFlags2KHR,
+    access: vk::AccessFlags2KHR,
+    write: bool,
+    layout: vk::ImageLayout,
+}
+
+struct PassDecl {
+    name: String,
+    queue: QueueType,
+    uses: Vec<ResourceUse>,
+    side_effects: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Barrier {
+    resource: usize,
+    src_stage: vk::PipelineStageFlags2KHR,
+    src_access: vk::AccessFlags2KHR,
+    dst_stage: vk::PipelineStageFlags2KHR,
+    dst_access: vk::AccessFlags2KHR,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+}
+
+#[derive(Debug)]
+struct ScheduledPass {
+    pass: usize,
+    barriers: Vec<Barrier>,
+}
+
+// A run of passes on one queue, submitted as a single command buffer. Every
+// batch signals the timeline semaphore of its queue with `signal_value`.
+#[derive(Debug)]
+struct Batch {
+    queue: QueueType,
+    passes: Vec<ScheduledPass>,
+    final_barriers: Vec<Barrier>,
+    waits: BTreeMap<QueueType, u64>,
+    signal_value: u64,
+}
+
+#[derive(Debug)]
+struct Schedule {
+    batches: Vec<Batch>,
+    // physical slot of every resource
+    slots: Vec<usize>,
+    first_batch: Vec<Option<usize>>,
+    last_batch: Vec<Option<usize>>,
+    final_layouts: Vec<vk::ImageLayout>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Usage {
+    stage: vk::PipelineStageFlags2KHR,
+    access: vk::AccessFlags2KHR,
+    // None for accesses made before the graph executes
+    batch: Option<usize>,
+}
+
+struct SlotState {
+    occupant: Option<usize>,
+    layout: vk::ImageLayout,
+    last_write: Option<Usage>,
+    reads: Vec<Usage>,
+    visible_stage: vk::PipelineStageFlags2KHR,
+    visible_access: vk::AccessFlags2KHR,
+}
+
+fn schedule(passes: &[PassDecl], resources: &[ResourceDecl]) -> Schedule {
+    // Walk backwards and keep passes whose results are observable.
+    let mut kept = vec![false; passes.len()];
+    let mut needed = vec![false; resources.len()];
+    for (i, pass) in passes.iter().enumerate().rev() {
+        let keep = pass.side_effects
+            || pass
+                .uses
+                .iter()
+                .any(|u| u.write && (resources[u.resource].imported || needed[u.resource]));
+        if keep {
+            kept[i] = true;
+            for u in &pass.uses {
+                needed[u.resource] = true;
+            }
+        }
+    }
+
+    // Hazards between kept passes, in declaration order.
+    let mut dependencies = vec![Vec::new(); passes.len()];
+    let mut last_writer: Vec<Option<usize>> = vec![None; resources.len()];
+    let mut readers: Vec<Vec<usize>> = vec![Vec::new(); resources.len()];
+    for (i, pass) in passes.iter().enumerate().filter(|(i, _)| kept[*i]) {
+        for u in &pass.uses {
+            if let Some(writer) = last_writer[u.resource] {
+                dependencies[i].push(writer);
+            }
+            if u.write {
+                dependencies[i].extend(readers[u.resource].iter().copied());
+                last_writer[u.resource] = Some(i);
+                readers[u.resource].clear();
+            } else {
+                readers[u.resource].push(i);
+            }
+        }
+        dependencies[i].retain(|d| *d != i);
+        dependencies[i].sort_unstable();
+        dependencies[i].dedup();
+    }
+
+    // Topological order that stays on the current queue for as long as possible.
+    let mut order = Vec::new();
+    let mut scheduled = vec![false; passes.len()];
+    let total = kept.iter().filter(|k| **k).count();
+    while order.len() < total {
+        let ready = (0..passes.len())
+            .filter(|i| kept[*i] && !scheduled[*i])
+            .filter(|i| dependencies[*i].iter().all(|d| scheduled[*d]))
+            .collect::<Vec<_>>();
+        let current_queue = order.last().map(|p: &usize| passes[*p].queue);
+        let next = ready
+            .iter()
+            .copied()
+            .find(|i| Some(passes[*i].queue) == current_queue)
+            .unwrap_or(ready[0]);
+        scheduled[next] = true;
+        order.push(next);
+    }
+
+    let mut batches: Vec<Batch> = Vec::new();
+    let mut signal_values = BTreeMap::new();
+    let mut batch_of = vec![0; passes.len()];
+    for &p in &order {
+        let queue = passes[p].queue;
+        if batches.last().map(|b| b.queue) != Some(queue) {
+            let value = signal_values.entry(queue).or_insert(0);
+            *value += 1;
+            batches.push(Batch {
+                queue,
+                passes: Vec::new(),
+                final_barriers: Vec::new(),
+                waits: BTreeMap::new(),
+                signal_value: *value,
+            });
+        }
+        batch_of[p] = batches.len() - 1;
+        batches.last_mut().unwrap().passes.push(ScheduledPass {
+            pass: p,
+            barriers: Vec::new(),
+        });
+    }
+
+    // Lifetimes as positions in `order`.
+    let mut first_use: Vec<Option<usize>> = vec![None; resources.len()];
+    let mut last_use: Vec<Option<usize>> = vec![None; resources.len()];
+    for (position, &p) in order.iter().enumerate() {
+        for u in &passes[p].uses {
+            first_use[u.resource].get_or_insert(position);
+            last_use[u.resource] = Some(position);
+        }
+    }
+
+    // Greedily alias transient resources onto physical slots.
+    let mut slots = (0..resources.len()).collect::<Vec<_>>();
+    let mut transients = (0..resources.len())
+        .filter(|r| resources[*r].transient.is_some() && first_use[*r].is_some())
+        .collect::<Vec<_>>();
+    transients.sort_by_key(|r| first_use[*r]);
+    let mut free_after: Vec<(usize, usize)> = Vec::new();
+    for r in transients {
+        let reusable = free_after.iter_mut().find(|(slot, end)| {
+            *end < first_use[r].unwrap() && resources[*slot].transient == resources[r].transient
+        });
+        match reusable {
+            Some((slot, end)) => {
+                slots[r] = *slot;
+                *end = last_use[r].unwrap();
+            }
+            None => free_after.push((r, last_use[r].unwrap())),
+        }
+    }
+
+    let mut states = resources
+        .iter()
+        .enumerate()
+        .map(|(r, decl)| {
+            SlotState {
+                occupant: match decl.imported {
+                    true => Some(r),
+                    false => None,
+                },
+                layout: decl.initial_layout,
+                last_write: match decl.imported {
+                    true => {
+                        Some(Usage {
+                            stage: Access::General.stage_mask(),
+                            access: Access::General.access_mask(),
+                            batch: None,
+                        })
+                    }
+                    false => None,
+                },
+                reads: Vec::new(),
+                visible_stage: vk::PipelineStageFlags2KHR::empty(),
+                visible_access: vk::AccessFlags2KHR::empty(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for &p in &order {
+        let b = batch_of[p];
+        let queue = passes[p].queue;
+        let mut barriers = Vec::new();
+        for u in &passes[p].uses {
+            let is_image = resources[u.resource].is_image;
+            let state = &mut states[slots[u.resource]];
+            let old_layout = match state.occupant == Some(u.resource) || !is_image {
+                true => state.layout,
+                // a new occupant does not care about the previous contents
+                false => vk::ImageLayout::UNDEFINED,
+            };
+            state.occupant = Some(u.resource);
+            let layout_change = is_image && old_layout != u.layout;
+
+            let mut sources = Vec::new();
+            if u.write || layout_change {
+                if !state.reads.is_empty() {
+                    sources.extend(state.reads.iter().copied());
+                } else if let Some(write) = state.last_write {
+                    sources.push(write);
+                }
+            } else if let Some(write) = state.last_write {
+                let cross_queue = write.batch.map_or(false, |wb| batches[wb].queue != queue);
+                let visible = state.visible_stage.contains(u.stage)
+                    && state.visible_access.contains(u.access);
+                if cross_queue || !visible {
+                    sources.push(write);
+                }
+            }
+
+            let mut src_stage = vk::PipelineStageFlags2KHR::empty();
+            let mut src_access = vk::AccessFlags2KHR::empty();
+            let mut cross_queue = false;
+            for source in sources {
+                match source.batch {
+                    Some(sb) if batches[sb].queue != queue => {
+                        let (source_queue, value) = (batches[sb].queue, batches[sb].signal_value);
+                        let wait = batches[b].waits.entry(source_queue).or_insert(0);
+                        *wait = (*wait).max(value);
+                        cross_queue = true;
+                    }
+                    _ => {
+                        src_stage |= source.stage;
+                        src_access |= source.access;
+                    }
+                }
+            }
+            // Semaphore waits use ALL_COMMANDS, chain the transition onto them.
+            if cross_queue && layout_change {
+                src_stage |= vk::PipelineStageFlags2KHR::ALL_COMMANDS;
+            }
+            if layout_change || !src_stage.is_empty() {
+                barriers.push(Barrier {
+                    resource: u.resource,
+                    src_stage,
+                    src_access,
+                    dst_stage: u.stage,
+                    dst_access: u.access,
+                    old_layout,
+                    new_layout: u.layout,
+                });
+            }
+
+            let usage = Usage {
+                stage: u.stage,
+                access: u.access,
+                batch: Some(b),
+            };
+            if u.write {
+                state.last_write = Some(usage);
+                state.reads.clear();
+                state.visible_stage = vk::PipelineStageFlags2KHR::empty();
+                state.visible_access = vk::AccessFlags2KHR::empty();
+            } else if layout_change {
+                // The transition behaves like a write ordered before this read.
+                state.last_write = Some(Usage {
+                    access: vk::AccessFlags2KHR::empty(),
+                    ..usage
+                });
+                state.reads = vec![usage];
+                state.visible_stage = u.stage;
+                state.visible_access = u.access;
+            } else {
+                if !src_stage.is_empty() {
+                    state.visible_stage |= u.stage;
+                    state.visible_access |= u.access;
+                }
+                state.reads.push(usage);
+            }
+            if is_image {
+                state.layout = u.layout;
+            }
+        }
+        let scheduled_pass = batches[b]
+            .passes
+            .iter_mut()
+            .find(|sp| sp.pass == p)
+            .unwrap();
+        scheduled_pass.barriers = barriers;
+    }
+
+    let position_batch = |position: Option<usize>| position.map(|pos| batch_of[order[pos]]);
+    let first_batch = first_use
+        .iter()
+        .map(|p| position_batch(*p))
+        .collect::<Vec<_>>();
+    let last_batch = last_use
+        .iter()
+        .map(|p| position_batch(*p))
+        .collect::<Vec<_>>();
+
+    let mut final_layouts = Vec::new();
+    for (r, decl) in resources.iter().enumerate() {
+        let state = &states[slots[r]];
+        let final_layout = match (decl.final_layout, last_batch[r]) {
+            (Some(layout), Some(b)) if decl.is_image && state.layout != layout => {
+                let queue = batches[b].queue;
+                let sources = match state.reads.is_empty() {
+                    true => state.last_write.into_iter().collect::<Vec<_>>(),
+                    false => state.reads.clone(),
+                };
+                let mut src_stage = vk::PipelineStageFlags2KHR::empty();
+                let mut src_access = vk::AccessFlags2KHR::empty();
+                for source in sources {
+                    match source.batch {
+                        Some(sb) if batches[sb].queue != queue => {
+                            let (source_queue, value) =
+                                (batches[sb].queue, batches[sb].signal_value);
+                            let wait = batches[b].waits.entry(source_queue).or_insert(0);
+                            *wait = (*wait).max(value);
+                            src_stage |= vk::PipelineStageFlags2KHR::ALL_COMMANDS;
+                        }
+                        _ => {
+                            src_stage |= source.stage;
+                            src_access |= source.access;
+                        }
+                    }
+                }
+                batches[b].final_barriers.push(Barrier {
+                    resource: r,
+                    src_stage,
+                    src_access,
+                    dst_stage: vk::PipelineStageFlags2KHR::NONE,
+                    dst_access: vk::AccessFlags2KHR::NONE,
+                    old_layout: state.layout,
+                    new_layout: layout,
+                });
+                layout
+            }
+            _ => state.layout,
+        };
+        final_layouts.push(final_layout);
+    }
+
+    Schedule {
+        batches,
+        slots,
+        first_batch,
+        last_batch,
+        final_layouts,
+    }
+}
+
+#[derive(Clone)]
+enum PhysicalResource {
+    Image(Image),
+    Buffer(Buffer),
+}
+
+// Resolves graph resources to the images and buffers backing them while a pass records.
+pub struct PassResources {
+    resources: Vec<Option<PhysicalResource>>,
+}
+
+impl PassResources {
+    pub fn image(&self, resource: GraphResource) -> &Image {
+        match &self.resources[resource.0] {
+            Some(PhysicalResource::Image(image)) => image,
+            _ => panic!("{:?} is not an image used by this graph", resource),
+        }
+    }
+
+    pub fn buffer(&self, resource: GraphResource) -> &Buffer {
+        match &self.resources[resource.0] {
+            Some(PhysicalResource::Buffer(buffer)) => buffer,
+            _ => panic!("{:?} is not a buffer used by this graph", resource),
+        }
+    }
+}
+
+type RecordFn<'g> = Box<dyn FnOnce(&mut CommandRecorder, &PassResources) + 'g>;
+
+pub struct RenderGraph<'g> {
+    resources: Vec<ResourceDecl>,
+    imported: Vec<Option<PhysicalResource>>,
+    passes: Vec<PassDecl>,
+    records: Vec<Option<RecordFn<'g>>>,
+    wait_semaphores: Vec<(usize, BinarySemaphore)>,
+    signal_semaphores: Vec<(usize, BinarySemaphore)>,
+}
+
+impl<'g> Default for RenderGraph<'g> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'g> RenderGraph<'g> {
+    pub fn new() -> Self {
+        Self {
+            resources: Vec::new(),
+            imported: Vec::new(),
+            passes: Vec::new(),
+            records: Vec::new(),
+            wait_semaphores: Vec::new(),
+            signal_semaphores: Vec::new(),
+        }
+    }
+
+    fn add_resource(
+        &mut self,
+        decl: ResourceDecl,
+        imported: Option<PhysicalResource>,
+    ) -> GraphResource {
+        self.resources.push(decl);
+        self.imported.push(imported);
+        GraphResource(self.resources.len() - 1)
+    }
+
+    // Imported images start in their tracked layout and keep the layout the graph
+    // leaves them in.
+    pub fn import_image(&mut self, image: &Image) -> GraphResource {
+        self.add_resource(
+            ResourceDecl {
+                name: image.inner.name.clone().unwrap_or_default(),
+                is_image: true,
+                imported: true,
+                initial_layout: image.layout(),
+                final_layout: None,
+                transient: None,
+            },
+            Some(PhysicalResource::Image(image.clone())),
+        )
+    }
+
+    pub fn import_buffer(&mut self, buffer: &Buffer) -> GraphResource {
+        self.add_resource(
+            ResourceDecl {
+                name: String::new(),
+                is_image: false,
+                imported: true,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: None,
+                transient: None,
+            },
+            Some(PhysicalResource::Buffer(buffer.clone())),
+        )
+    }
+
+    pub fn create_image(&mut self, name: &str, desc: &ImageDesc) -> GraphResource {
+        self.add_resource(
+            ResourceDecl {
+                name: name.to_owned(),
+                is_image: true,
+                imported: false,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: None,
+                transient: Some(TransientDesc::Image(desc.clone())),
+            },
+            None,
+        )
+    }
+
+    pub fn create_buffer(&mut self, name: &str, desc: &BufferDesc) -> GraphResource {
+        self.add_resource(
+            ResourceDecl {
+                name: name.to_owned(),
+                is_image: false,
+                imported: false,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: None,
+                transient: Some(TransientDesc::Buffer(desc.clone())),
+            },
+            None,
+        )
+    }
+
+    // e.g. PRESENT_SRC_KHR for swapchain images
+    pub fn set_final_layout(&mut self, image: GraphResource, layout: vk::ImageLayout) {
+        assert!(self.resources[image.0].imported && self.resources[image.0].is_image);
+        self.resources[image.0].final_layout = Some(layout);
+    }
+
+    // The first batch using `resource` waits on `semaphore`, e.g. a swapchain acquire.
+    pub fn wait_semaphore(&mut self, resource: GraphResource, semaphore: &BinarySemaphore) {
+        self.wait_semaphores.push((resource.0, semaphore.clone()));
+    }
+
+    // The last batch using `resource` signals `semaphore`, e.g. before presenting.
+    pub fn signal_semaphore(&mut self, resource: GraphResource, semaphore: &BinarySemaphore) {
+        self.signal_semaphores.push((resource.0, semaphore.clone()));
+    }
+
+    pub fn add_pass<'s>(&'s mut self, name: &str, queue: QueueType) -> PassBuilder<'s, 'g> {
+        PassBuilder {
+            graph: self,
+            decl: PassDecl {
+                name: name.to_owned(),
+                queue,
+                uses: Vec::new(),
+                side_effects: false,
+            },
+        }
+    }
+
+    // Records and submits every pass, then blocks until the GPU is done.
+    pub fn execute(mut self, device: &Device) {
+        let schedule = schedule(&self.passes, &self.resources);
+
+        let mut physical: BTreeMap<usize, PhysicalResource> = BTreeMap::new();
+        let mut resources = vec![None; self.resources.len()];
+        for (r, decl) in self.resources.iter().enumerate() {
+            if schedule.first_batch[r].is_none() {
+                continue;
+            }
+            let resource = physical
+                .entry(schedule.slots[r])
+                .or_insert_with(|| {
+                    match (&self.imported[r], &decl.transient) {
+                        (Some(imported), _) => imported.clone(),
+                        (None, Some(TransientDesc::Image(desc))) => {
+                            PhysicalResource::Image(
+                                Image::with_desc(Some(decl.name.as_str()), device, desc).unwrap(),
+                            )
+                        }
+                        (None, Some(TransientDesc::Buffer(desc))) => {
+                            PhysicalResource::Buffer(device.create_buffer(
+                                Some(decl.name.as_str()),
+                                desc.size,
+                                desc.usage,
+                                desc.location,
+                            ))
+                        }
+                        (None, None) => unreachable!(),
+                    }
+                })
+                .clone();
+            resources[r] = Some(resource);
+        }
+        let pass_resources = PassResources { resources };
+
+        let timelines = schedule
+            .batches
+            .iter()
+            .map(|batch| (batch.queue, TimelineSemaphore::new(device)))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut submissions = Vec::new();
+        for (i, batch) in schedule.batches.iter().enumerate() {
+            let queue = match batch.queue {
+                QueueType::Graphics => device.graphics_queue(),
+                QueueType::Compute => device.compute_queue(),
+                QueueType::Transfer => device.transfer_queue(),
+            };
+            let mut cmd_buf = device.create_command_buffer(
+                Some(&format!("render graph batch {}", i)),
+                queue.inner.queue_family_properties.index,
+            );
+            let records = &mut self.records;
+            let passes = &self.passes;
+            cmd_buf.encode(|recorder| {
+                for scheduled_pass in &batch.passes {
+                    log::trace!("recording pass {}", passes[scheduled_pass.pass].name);
+                    record_barriers(recorder, &scheduled_pass.barriers, &pass_resources);
+                    if let Some(record) = records[scheduled_pass.pass].take() {
+                        record(recorder, &pass_resources);
+                    }
+                }
+                record_barriers(recorder, &batch.final_barriers, &pass_resources);
+            });
+
+            let mut info = SubmitInfo::new().command_buffer(cmd_buf);
+            for (queue, value) in &batch.waits {
+                info = info.wait_timeline(
+                    &timelines[queue],
+                    *value,
+                    vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+                );
+            }
+            for (r, semaphore) in &self.wait_semaphores {
+                if schedule.first_batch[*r] == Some(i) {
+                    info = info.wait_binary(semaphore, vk::PipelineStageFlags2KHR::ALL_COMMANDS);
+                }
+            }
+            info = info.signal_timeline(
+                &timelines[&batch.queue],
+                batch.signal_value,
+                vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+            );
+            for (r, semaphore) in &self.signal_semaphores {
+                if schedule.last_batch[*r] == Some(i) {
+                    info = info.signal_binary(semaphore, vk::PipelineStageFlags2KHR::ALL_COMMANDS);
+                }
+            }
+            submissions.push(queue.submit(info).unwrap());
+        }
+
+        for submission in &submissions {
+            submission.wait();
+        }
+
+        for (r, imported) in self.imported.iter().enumerate() {
+            if let Some(PhysicalResource::Image(image)) = imported {
+                image.store_layout(schedule.final_layouts[r]);
+            }
+        }
+    }
+}
+
+fn record_barriers(
+    recorder: &mut CommandRecorder,
+    barriers: &[Barrier],
+    resources: &PassResources,
+) {
+    if barriers.is_empty() {
+        return;
+    }
+    let mut buffer_barriers = Vec::new();
+    let mut image_barriers = Vec::new();
+    for barrier in barriers {
+        match &resources.resources[barrier.resource] {
+            Some(PhysicalResource::Image(image)) => {
+                image_barriers.push(
+                    vk::ImageMemoryBarrier2KHR::builder()
+                        .src_stage_mask(barrier.src_stage)
+                        .src_access_mask(barrier.src_access)
+                        .dst_stage_mask(barrier.dst_stage)
+                        .dst_access_mask(barrier.dst_access)
+                        .old_layout(barrier.old_layout)
+                        .new_layout(barrier.new_layout)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(image.handle())
+                        .subresource_range(image.subresource_range())
+                        .build(),
+                );
+            }
+            Some(PhysicalResource::Buffer(buffer)) => {
+                buffer_barriers.push(
+                    vk::BufferMemoryBarrier2KHR::builder()
+                        .src_stage_mask(barrier.src_stage)
+                        .src_access_mask(barrier.src_access)
+                        .dst_stage_mask(barrier.dst_stage)
+                        .dst_access_mask(barrier.dst_access)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .buffer(buffer.handle())
+                        .offset(0)
+                        .size(vk::WHOLE_SIZE)
+                        .build(),
+                );
+            }
+            None => unreachable!(),
+        }
+    }
+    recorder.pipeline_barrier(
+        &vk::DependencyInfoKHR::builder()
+            .buffer_memory_barriers(&buffer_barriers)
+            .image_memory_barriers(&image_barriers)
+            .build(),
+    );
+}
+
+pub struct PassBuilder<'s, 'g> {
+    graph: &'s mut RenderGraph<'g>,
+    decl: PassDecl,
+}
+
+impl<'s, 'g> PassBuilder<'s, 'g> {
+    fn add_use(mut self, resource: GraphResource, access: Access, layout: vk::ImageLayout) -> Self {
+        let existing = self.decl.uses.iter_mut().find(|u| u.resource == resource.0);
+        match existing {
+            Some(existing) => {
+                assert_eq!(
+                    existing.layout, layout,
+                    "{:?} is used with two layouts in pass {}",
+                    resource, self.decl.name
+                );
+                existing.stage |= access.stage_mask();
+                existing.access |= access.access_mask();
+                existing.write |= access.is_write();
+            }
+            None => {
+                self.decl.uses.push(ResourceUse {
+                    resource: resource.0,
+                    stage: access.stage_mask(),
+                    access: access.access_mask(),
+                    write: access.is_write(),
+                    layout,
+                });
+            }
+        }
+        self
+    }
+
+    pub fn image(self, image: GraphResource, access: Access, layout: vk::ImageLayout) -> Self {
+        assert!(self.graph.resources[image.0].is_image);
+        assert_ne!(layout, vk::ImageLayout::UNDEFINED);
+        self.add_use(image, access, layout)
+    }
+
+    pub fn buffer(self, buffer: GraphResource, access: Access) -> Self {
+        assert!(!self.graph.resources[buffer.0].is_image);
+        self.add_use(buffer, access, vk::ImageLayout::UNDEFINED)
+    }
+
+    // Keeps the pass even if nothing reads what it writes.
+    pub fn side_effects(mut self) -> Self {
+        self.decl.side_effects = true;
+        self
+    }
+
+    pub fn record<F>(self, f: F)
+    where
+        F: FnOnce(&mut CommandRecorder, &PassResources) + 'g,
+    {
+        self.graph.passes.push(self.decl);
+        self.graph.records.push(Some(Box::new(f)));
+    }
+}
+
+#[cfg(test)]
+fn test_resource(imported: bool, is_image: bool, desc: Option<TransientDesc>) -> ResourceDecl {
+    ResourceDecl {
+        name: String::new(),
+        is_image,
+        imported,
+        initial_layout: vk::ImageLayout::UNDEFINED,
+        final_layout: None,
+        transient: desc,
+    }
+}
+
+#[cfg(test)]
+fn test_pass(queue: QueueType, uses: &[(usize, Access, vk::ImageLayout)]) -> PassDecl {
+    PassDecl {
+        name: String::new(),
+        queue,
+        uses: uses
+            .iter()
+            .map(|(resource, access, layout)| {
+                ResourceUse {
+                    resource: *resource,
+                    stage: access.stage_mask(),
+                    access: access.access_mask(),
+                    write: access.is_write(),
+                    layout: *layout,
+                }
+            })
+            .collect(),
+        side_effects: false,
+    }
+}
+
+#[cfg(test)]
+fn test_image_desc() -> TransientDesc {
+    TransientDesc::Image(ImageDesc::new_2d(
+        vk::Format::R8G8B8A8_UNORM,
+        4,
+        4,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+        gpu_allocator::MemoryLocation::GpuOnly,
+    ))
+}
+
+#[test]
+fn test_render_graph_culling_and_barriers() {
+    use crate::barrier::PipelineStage;
+
+    let mut output = test_resource(true, true, None);
+    output.initial_layout = vk::ImageLayout::GENERAL;
+    output.final_layout = Some(vk::ImageLayout::PRESENT_SRC_KHR);
+    let resources = vec![
+        test_resource(false, true, Some(test_image_desc())),
+        output,
+        test_resource(false, true, Some(test_image_desc())),
+    ];
+    let passes = vec![
+        test_pass(
+            QueueType::Graphics,
+            &[(
+                0,
+                Access::ShaderWrite(PipelineStage::Compute),
+                vk::ImageLayout::GENERAL,
+            )],
+        ),
+        // nothing reads resource 2, so this pass is culled
+        test_pass(
+            QueueType::Graphics,
+            &[(
+                2,
+                Access::ShaderWrite(PipelineStage::Compute),
+                vk::ImageLayout::GENERAL,
+            )],
+        ),
+        test_pass(
+            QueueType::Graphics,
+            &[
+                (
+                    0,
+                    Access::ShaderSampledRead(PipelineStage::Fragment),
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                ),
+                (
+                    1,
+                    Access::TransferWrite,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                ),
+            ],
+        ),
+    ];
+    let schedule = schedule(&passes, &resources);
+    assert_eq!(schedule.batches.len(), 1);
+    let batch = &schedule.batches[0];
+    assert_eq!(
+        batch.passes.iter().map(|p| p.pass).collect::<Vec<_>>(),
+        vec![0, 2]
+    );
+    assert_eq!(schedule.first_batch[2], None);
+
+    // transient: UNDEFINED -> GENERAL with nothing to wait for
+    assert_eq!(
+        batch.passes[0].barriers,
+        vec![Barrier {
+            resource: 0,
+            src_stage: vk::PipelineStageFlags2KHR::empty(),
+            src_access: vk::AccessFlags2KHR::empty(),
+            dst_stage: vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+            dst_access: vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+            old_layout: vk::ImageLayout::UNDEFINED,
+            new_layout: vk::ImageLayout::GENERAL,
+        }]
+    );
+    let barriers = &batch.passes[1].barriers;
+    assert_eq!(barriers.len(), 2);
+    assert_eq!(
+        barriers[0].src_stage,
+        vk::PipelineStageFlags2KHR::COMPUTE_SHADER
+    );
+    assert_eq!(
+        barriers[0].src_access,
+        vk::AccessFlags2KHR::SHADER_STORAGE_WRITE
+    );
+    assert_eq!(
+        barriers[0].new_layout,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+    );
+    // imported: anything may have touched it before the graph
+    assert_eq!(
+        barriers[1].src_stage,
+        vk::PipelineStageFlags2KHR::ALL_COMMANDS
+    );
+    assert_eq!(barriers[1].old_layout, vk::ImageLayout::GENERAL);
+
+    assert_eq!(batch.final_barriers.len(), 1);
+    assert_eq!(
+        batch.final_barriers[0].new_layout,
+        vk::ImageLayout::PRESENT_SRC_KHR
+    );
+    assert_eq!(schedule.final_layouts[1], vk::ImageLayout::PRESENT_SRC_KHR);
+}
+
+#[test]
+fn test_render_graph_cross_queue() {
+    use crate::barrier::PipelineStage;
+
+    let resources = vec![
+        test_resource(
+            false,
+            false,
+            Some(TransientDesc::Buffer(BufferDesc {
+                size: 64,
+                usage: vk::BufferUsageFlags::STORAGE_BUFFER,
+                location: gpu_allocator::MemoryLocation::GpuOnly,
+            })),
+        ),
+        test_resource(true, false, None),
+    ];
+    // independent of the graphics pass, so it is grouped with the first one
+    let mut independent = test_pass(QueueType::Compute, &[]);
+    independent.side_effects = true;
+    let passes = vec![
+        test_pass(
+            QueueType::Compute,
+            &[(
+                0,
+                Access::ShaderWrite(PipelineStage::Compute),
+                vk::ImageLayout::UNDEFINED,
+            )],
+        ),
+        test_pass(
+            QueueType::Graphics,
+            &[
+                (
+                    0,
+                    Access::ShaderRead(PipelineStage::Vertex),
+                    vk::ImageLayout::UNDEFINED,
+                ),
+                (1, Access::TransferWrite, vk::ImageLayout::UNDEFINED),
+            ],
+        ),
+        independent,
+    ];
+    let schedule = schedule(&passes, &resources);
+    assert_eq!(schedule.batches.len(), 2);
+    assert_eq!(schedule.batches[0].queue, QueueType::Compute);
+    assert_eq!(
+        schedule.batches[0]
+            .passes
+            .iter()
+            .map(|p| p.pass)
+            .collect::<Vec<_>>(),
+        vec![0, 2]
+    );
+    let graphics = &schedule.batches[1];
+    assert_eq!(graphics.queue, QueueType::Graphics);
+    assert_eq!(graphics.waits.get(&QueueType::Compute), Some(&1));
+    // the semaphore covers the buffer, only the imported one needs a barrier
+    assert_eq!(graphics.passes[0].barriers.len(), 1);
+    assert_eq!(graphics.passes[0].barriers[0].resource, 1);
+}
+
+#[test]
+fn test_render_graph_aliasing() {
+    use crate::barrier::PipelineStage;
+
+    let resources = vec![
+        test_resource(false, true, Some(test_image_desc())),
+        test_resource(false, true, Some(test_image_desc())),
+        test_resource(true, false, None),
+    ];
+    let write = Access::ShaderWrite(PipelineStage::Compute);
+    let read = Access::ShaderSampledRead(PipelineStage::Compute);
+    let passes = vec![
+        test_pass(QueueType::Compute, &[(0, write, vk::ImageLayout::GENERAL)]),
+        test_pass(
+            QueueType::Compute,
+            &[
+                (0, read, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+                (2, Access::TransferWrite, vk::ImageLayout::UNDEFINED),
+            ],
+        ),
+        test_pass(QueueType::Compute, &[(1, write, vk::ImageLayout::GENERAL)]),
+        test_pass(
+            QueueType::Compute,
+            &[
+                (1, read, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+                (2, Access::TransferWrite, vk::ImageLayout::UNDEFINED),
+            ],
+        ),
+    ];
+    let schedule = schedule(&passes, &resources);
+    assert_eq!(schedule.slots[1], schedule.slots[0]);
+    let barrier = &schedule.batches[0].passes[2].barriers[0];
+    // the new occupant discards the contents but waits for the last reader
+    assert_eq!(barrier.old_layout, vk::ImageLayout::UNDEFINED);
+    assert_eq!(
+        barrier.src_stage,
+        vk::PipelineStageFlags2KHR::COMPUTE_SHADER
+    );
+    assert_eq!(barrier.src_access, vk::AccessFlags2KHR::SHADER_SAMPLED_READ);
+}
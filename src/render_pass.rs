@@ -1,12 +1,16 @@
 use std::sync::Arc;
 
-use ash::vk;
+use anyhow::{bail, ensure, Result};
+use ash::vk::{self, Handle};
 
+use crate::image::format_aspect_mask;
 use crate::Device;
 
 pub(crate) struct RenderPassRef {
     pub(crate) handle: vk::RenderPass,
     device: Device,
+    // None for render passes created from a raw vk::RenderPassCreateInfo.
+    desc: Option<RenderPassDesc>,
 }
 
 #[derive(Clone)]
@@ -22,6 +26,7 @@ impl RenderPass {
                 inner: Arc::new(RenderPassRef {
                     handle,
                     device: device.clone(),
+                    desc: None,
                 }),
             }
         }
@@ -30,6 +35,11 @@ impl RenderPass {
     pub fn handle(&self) -> vk::RenderPass {
         self.inner.handle
     }
+
+    // The description the render pass was built from, if it came from a RenderPassBuilder.
+    pub fn desc(&self) -> Option<&RenderPassDesc> {
+        self.inner.desc.as_ref()
+    }
 }
 
 impl Drop for RenderPassRef {
@@ -43,8 +53,471 @@ impl Drop for RenderPassRef {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttachmentDesc {
+    pub format: vk::Format,
+    pub samples: vk::SampleCountFlags,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    pub stencil_load_op: vk::AttachmentLoadOp,
+    pub stencil_store_op: vk::AttachmentStoreOp,
+    pub initial_layout: vk::ImageLayout,
+    pub final_layout: vk::ImageLayout,
+}
+
+impl AttachmentDesc {
+    // Cleared and stored, ending in the optimal attachment layout for the format.
+    pub fn new(format: vk::Format) -> Self {
+        let final_layout = if is_depth_stencil_format(format) {
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        } else {
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+        };
+        Self {
+            format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::STORE,
+            stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout,
+        }
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn load_op(mut self, load_op: vk::AttachmentLoadOp) -> Self {
+        self.load_op = load_op;
+        self
+    }
+
+    pub fn store_op(mut self, store_op: vk::AttachmentStoreOp) -> Self {
+        self.store_op = store_op;
+        self
+    }
+
+    pub fn stencil_load_op(mut self, stencil_load_op: vk::AttachmentLoadOp) -> Self {
+        self.stencil_load_op = stencil_load_op;
+        self
+    }
+
+    pub fn stencil_store_op(mut self, stencil_store_op: vk::AttachmentStoreOp) -> Self {
+        self.stencil_store_op = stencil_store_op;
+        self
+    }
+
+    pub fn initial_layout(mut self, initial_layout: vk::ImageLayout) -> Self {
+        self.initial_layout = initial_layout;
+        self
+    }
+
+    pub fn final_layout(mut self, final_layout: vk::ImageLayout) -> Self {
+        self.final_layout = final_layout;
+        self
+    }
+}
+
+// Attachment indices used by one subpass.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubpassDesc {
+    pub color_attachments: Vec<u32>,
+    // Empty, or one resolve target per color attachment.
+    pub resolve_attachments: Vec<u32>,
+    pub input_attachments: Vec<u32>,
+    pub depth_stencil_attachment: Option<u32>,
+}
+
+impl SubpassDesc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn color(mut self, attachment: u32) -> Self {
+        self.color_attachments.push(attachment);
+        self
+    }
+
+    pub fn resolve(mut self, attachment: u32) -> Self {
+        self.resolve_attachments.push(attachment);
+        self
+    }
+
+    pub fn input(mut self, attachment: u32) -> Self {
+        self.input_attachments.push(attachment);
+        self
+    }
+
+    pub fn depth_stencil(mut self, attachment: u32) -> Self {
+        self.depth_stencil_attachment = Some(attachment);
+        self
+    }
+
+    // Attachments this subpass writes to.
+    fn outputs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.color_attachments
+            .iter()
+            .chain(&self.resolve_attachments)
+            .chain(&self.depth_stencil_attachment)
+            .copied()
+    }
+
+    fn uses(&self, attachment: u32) -> bool {
+        self.input_attachments.contains(&attachment) || self.outputs().any(|a| a == attachment)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderPassDesc {
+    pub attachments: Vec<AttachmentDesc>,
+    pub subpasses: Vec<SubpassDesc>,
+}
+
+impl RenderPassDesc {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.subpasses.is_empty(), "render pass has no subpasses");
+        for (i, attachment) in self.attachments.iter().enumerate() {
+            ensure!(
+                attachment.format != vk::Format::UNDEFINED,
+                "attachment {} has an undefined format",
+                i
+            );
+            ensure!(
+                attachment.samples.as_raw().count_ones() == 1,
+                "attachment {} must use exactly one sample count",
+                i
+            );
+            ensure!(
+                attachment.final_layout != vk::ImageLayout::UNDEFINED
+                    && attachment.final_layout != vk::ImageLayout::PREINITIALIZED,
+                "attachment {} can't end in layout {:?}",
+                i,
+                attachment.final_layout
+            );
+        }
+
+        for (i, subpass) in self.subpasses.iter().enumerate() {
+            for index in subpass
+                .outputs()
+                .chain(subpass.input_attachments.iter().copied())
+            {
+                ensure!(
+                    (index as usize) < self.attachments.len(),
+                    "subpass {} references attachment {}, but there are only {}",
+                    i,
+                    index,
+                    self.attachments.len()
+                );
+            }
+            for &index in &subpass.color_attachments {
+                ensure!(
+                    !is_depth_stencil_format(self.attachments[index as usize].format),
+                    "subpass {} uses depth/stencil attachment {} as a color attachment",
+                    i,
+                    index
+                );
+            }
+            if let Some(index) = subpass.depth_stencil_attachment {
+                ensure!(
+                    is_depth_stencil_format(self.attachments[index as usize].format),
+                    "subpass {} uses color attachment {} as its depth/stencil attachment",
+                    i,
+                    index
+                );
+                ensure!(
+                    !subpass.color_attachments.contains(&index),
+                    "subpass {} uses attachment {} as color and depth/stencil",
+                    i,
+                    index
+                );
+            }
+
+            let mut samples = subpass
+                .color_attachments
+                .iter()
+                .chain(&subpass.depth_stencil_attachment)
+                .map(|&index| self.attachments[index as usize].samples);
+            if let Some(first) = samples.next() {
+                if samples.any(|s| s != first) {
+                    bail!(
+                        "color and depth/stencil attachments of subpass {} differ in sample count",
+                        i
+                    );
+                }
+            }
+
+            if !subpass.resolve_attachments.is_empty() {
+                ensure!(
+                    subpass.resolve_attachments.len() == subpass.color_attachments.len(),
+                    "subpass {} has {} resolve attachments for {} color attachments",
+                    i,
+                    subpass.resolve_attachments.len(),
+                    subpass.color_attachments.len()
+                );
+                for (&color, &resolve) in subpass
+                    .color_attachments
+                    .iter()
+                    .zip(&subpass.resolve_attachments)
+                {
+                    let color = &self.attachments[color as usize];
+                    let resolve_attachment = &self.attachments[resolve as usize];
+                    ensure!(
+                        color.samples != vk::SampleCountFlags::TYPE_1,
+                        "subpass {} resolves a single sampled attachment",
+                        i
+                    );
+                    ensure!(
+                        resolve_attachment.samples == vk::SampleCountFlags::TYPE_1,
+                        "resolve attachment {} of subpass {} is multisampled",
+                        resolve,
+                        i
+                    );
+                    ensure!(
+                        color.format == resolve_attachment.format,
+                        "resolve attachment {} of subpass {} has format {:?}, expected {:?}",
+                        resolve,
+                        i,
+                        resolve_attachment.format,
+                        color.format
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // External dependencies around the render pass, plus one between every pair
+    // of subpasses where the later one uses an attachment the earlier one writes.
+    pub(crate) fn dependencies(&self) -> Vec<vk::SubpassDependency> {
+        let attachment_stages = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+        let attachment_writes = vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+        let attachment_access = attachment_writes
+            | vk::AccessFlags::COLOR_ATTACHMENT_READ
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ;
+
+        let mut dependencies = vec![vk::SubpassDependency {
+            src_subpass: vk::SUBPASS_EXTERNAL,
+            dst_subpass: 0,
+            src_stage_mask: attachment_stages,
+            dst_stage_mask: attachment_stages,
+            src_access_mask: attachment_writes,
+            dst_access_mask: attachment_access,
+            dependency_flags: vk::DependencyFlags::empty(),
+        }];
+        for (dst, dst_subpass) in self.subpasses.iter().enumerate() {
+            for (src, src_subpass) in self.subpasses[..dst].iter().enumerate() {
+                if !src_subpass.outputs().any(|a| dst_subpass.uses(a)) {
+                    continue;
+                }
+                dependencies.push(vk::SubpassDependency {
+                    src_subpass: src as u32,
+                    dst_subpass: dst as u32,
+                    src_stage_mask: attachment_stages,
+                    dst_stage_mask: attachment_stages | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    src_access_mask: attachment_writes,
+                    dst_access_mask: attachment_access | vk::AccessFlags::INPUT_ATTACHMENT_READ,
+                    dependency_flags: vk::DependencyFlags::BY_REGION,
+                });
+            }
+        }
+        dependencies.push(vk::SubpassDependency {
+            src_subpass: self.subpasses.len() as u32 - 1,
+            dst_subpass: vk::SUBPASS_EXTERNAL,
+            src_stage_mask: attachment_stages,
+            dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER
+                | vk::PipelineStageFlags::TRANSFER
+                | attachment_stages,
+            src_access_mask: attachment_writes,
+            dst_access_mask: vk::AccessFlags::SHADER_READ
+                | vk::AccessFlags::TRANSFER_READ
+                | attachment_access,
+            dependency_flags: vk::DependencyFlags::empty(),
+        });
+        dependencies
+    }
+}
+
+fn is_depth_stencil_format(format: vk::Format) -> bool {
+    format_aspect_mask(format)
+        .intersects(vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL)
+}
+
+#[derive(Default)]
+pub struct RenderPassBuilder {
+    name: Option<String>,
+    desc: RenderPassDesc,
+}
+
+impl RenderPassBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn attachment(mut self, attachment: AttachmentDesc) -> Self {
+        self.desc.attachments.push(attachment);
+        self
+    }
+
+    pub fn subpass(mut self, subpass: SubpassDesc) -> Self {
+        self.desc.subpasses.push(subpass);
+        self
+    }
+
+    pub fn build(self, device: &Device) -> Result<RenderPass> {
+        let desc = self.desc;
+        desc.validate()?;
+
+        let attachments = desc
+            .attachments
+            .iter()
+            .map(|attachment| {
+                vk::AttachmentDescription::builder()
+                    .format(attachment.format)
+                    .samples(attachment.samples)
+                    .load_op(attachment.load_op)
+                    .store_op(attachment.store_op)
+                    .stencil_load_op(attachment.stencil_load_op)
+                    .stencil_store_op(attachment.stencil_store_op)
+                    .initial_layout(attachment.initial_layout)
+                    .final_layout(attachment.final_layout)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let reference = |attachment: u32, layout: vk::ImageLayout| {
+            vk::AttachmentReference { attachment, layout }
+        };
+        let references = desc
+            .subpasses
+            .iter()
+            .map(|subpass| {
+                let color = subpass
+                    .color_attachments
+                    .iter()
+                    .map(|&a| reference(a, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+                    .collect::<Vec<_>>();
+                let resolve = subpass
+                    .resolve_attachments
+                    .iter()
+                    .map(|&a| reference(a, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+                    .collect::<Vec<_>>();
+                let input = subpass
+                    .input_attachments
+                    .iter()
+                    .map(|&a| {
+                        let layout = if is_depth_stencil_format(desc.attachments[a as usize].format)
+                        {
+                            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+                        } else {
+                            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+                        };
+                        reference(a, layout)
+                    })
+                    .collect::<Vec<_>>();
+                let depth = subpass
+                    .depth_stencil_attachment
+                    .map(|a| reference(a, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL));
+                (color, resolve, input, depth)
+            })
+            .collect::<Vec<_>>();
+        let subpasses = references
+            .iter()
+            .map(|(color, resolve, input, depth)| {
+                let mut subpass = vk::SubpassDescription::builder()
+                    .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                    .color_attachments(color)
+                    .input_attachments(input);
+                if !resolve.is_empty() {
+                    subpass = subpass.resolve_attachments(resolve);
+                }
+                if let Some(depth) = depth {
+                    subpass = subpass.depth_stencil_attachment(depth);
+                }
+                subpass.build()
+            })
+            .collect::<Vec<_>>();
+        let dependencies = desc.dependencies();
+
+        unsafe {
+            let handle = device.inner.handle.create_render_pass(
+                &vk::RenderPassCreateInfo::builder()
+                    .attachments(&attachments)
+                    .subpasses(&subpasses)
+                    .dependencies(&dependencies)
+                    .build(),
+                None,
+            )?;
+            if let Some(name) = &self.name {
+                device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::RENDER_PASS);
+            }
+            Ok(RenderPass {
+                inner: Arc::new(RenderPassRef {
+                    handle,
+                    device: device.clone(),
+                    desc: Some(desc),
+                }),
+            })
+        }
+    }
+}
+
 impl Device {
     pub fn create_render_pass(&self, info: &vk::RenderPassCreateInfo) -> RenderPass {
         RenderPass::new(&self, info)
     }
 }
+
+#[test]
+fn test_render_pass_desc_validation() {
+    let gbuffer = RenderPassDesc {
+        attachments: vec![
+            AttachmentDesc::new(vk::Format::R8G8B8A8_UNORM),
+            AttachmentDesc::new(vk::Format::D32_SFLOAT),
+            AttachmentDesc::new(vk::Format::B8G8R8A8_UNORM)
+                .final_layout(vk::ImageLayout::PRESENT_SRC_KHR),
+        ],
+        subpasses: vec![
+            SubpassDesc::new().color(0).depth_stencil(1),
+            SubpassDesc::new().input(0).input(1).color(2),
+        ],
+    };
+    gbuffer.validate().unwrap();
+    let dependencies = gbuffer.dependencies();
+    assert_eq!(dependencies.len(), 3);
+    assert_eq!(dependencies[1].src_subpass, 0);
+    assert_eq!(dependencies[1].dst_subpass, 1);
+    assert_eq!(dependencies[2].dst_subpass, vk::SUBPASS_EXTERNAL);
+
+    let mut out_of_range = gbuffer.clone();
+    out_of_range.subpasses[1].color_attachments.push(3);
+    assert!(out_of_range.validate().is_err());
+
+    let mut depth_as_color = gbuffer.clone();
+    depth_as_color.subpasses[0].color_attachments.push(1);
+    assert!(depth_as_color.validate().is_err());
+
+    let msaa = RenderPassDesc {
+        attachments: vec![
+            AttachmentDesc::new(vk::Format::R8G8B8A8_UNORM).samples(vk::SampleCountFlags::TYPE_4),
+            AttachmentDesc::new(vk::Format::R8G8B8A8_UNORM),
+            AttachmentDesc::new(vk::Format::D32_SFLOAT),
+        ],
+        subpasses: vec![SubpassDesc::new().color(0).resolve(1).depth_stencil(2)],
+    };
+    assert!(msaa.validate().is_err());
+    let mut msaa = msaa;
+    msaa.attachments[2].samples = vk::SampleCountFlags::TYPE_4;
+    msaa.validate().unwrap();
+
+    assert!(RenderPassDesc::default().validate().is_err());
+}
@@ -1,14 +1,189 @@
 use std::ffi::CString;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
 use crate::device::Device;
 use ash::vk;
 use ash::vk::Handle;
 
+#[derive(Clone, Copy, Debug)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    pub address_mode_w: vk::SamplerAddressMode,
+    pub mip_lod_bias: f32,
+    pub max_anisotropy: Option<f32>,
+    pub compare_op: Option<vk::CompareOp>,
+    pub min_lod: f32,
+    pub max_lod: f32,
+    pub border_color: vk::BorderColor,
+    pub unnormalized_coordinates: bool,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+            address_mode_w: vk::SamplerAddressMode::REPEAT,
+            mip_lod_bias: 0.0,
+            max_anisotropy: None,
+            compare_op: None,
+            min_lod: 0.0,
+            max_lod: vk::LOD_CLAMP_NONE,
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+            unnormalized_coordinates: false,
+        }
+    }
+}
+
+impl SamplerDesc {
+    pub fn filter(mut self, mag_filter: vk::Filter, min_filter: vk::Filter) -> Self {
+        self.mag_filter = mag_filter;
+        self.min_filter = min_filter;
+        self
+    }
+
+    pub fn mipmap_mode(mut self, mipmap_mode: vk::SamplerMipmapMode) -> Self {
+        self.mipmap_mode = mipmap_mode;
+        self
+    }
+
+    pub fn address_mode(mut self, address_mode: vk::SamplerAddressMode) -> Self {
+        self.address_mode_u = address_mode;
+        self.address_mode_v = address_mode;
+        self.address_mode_w = address_mode;
+        self
+    }
+
+    pub fn address_mode_uvw(
+        mut self,
+        address_mode_u: vk::SamplerAddressMode,
+        address_mode_v: vk::SamplerAddressMode,
+        address_mode_w: vk::SamplerAddressMode,
+    ) -> Self {
+        self.address_mode_u = address_mode_u;
+        self.address_mode_v = address_mode_v;
+        self.address_mode_w = address_mode_w;
+        self
+    }
+
+    pub fn mip_lod_bias(mut self, mip_lod_bias: f32) -> Self {
+        self.mip_lod_bias = mip_lod_bias;
+        self
+    }
+
+    pub fn lod_clamp(mut self, min_lod: f32, max_lod: f32) -> Self {
+        self.min_lod = min_lod;
+        self.max_lod = max_lod;
+        self
+    }
+
+    pub fn anisotropy(mut self, max_anisotropy: f32) -> Self {
+        self.max_anisotropy = Some(max_anisotropy);
+        self
+    }
+
+    pub fn compare_op(mut self, compare_op: vk::CompareOp) -> Self {
+        self.compare_op = Some(compare_op);
+        self
+    }
+
+    pub fn border_color(mut self, border_color: vk::BorderColor) -> Self {
+        self.border_color = border_color;
+        self
+    }
+
+    pub fn unnormalized_coordinates(mut self, unnormalized_coordinates: bool) -> Self {
+        self.unnormalized_coordinates = unnormalized_coordinates;
+        self
+    }
+
+    // Applies the device limits, so that requests which end up identical
+    // on this device also compare equal.
+    fn clamp_to_device(mut self, device: &Device) -> Self {
+        if let Some(max_anisotropy) = self.max_anisotropy {
+            if device.inner.enabled_features.sampler_anisotropy == vk::FALSE {
+                log::warn!("sampler anisotropy is not supported, disabling it");
+                self.max_anisotropy = None;
+            } else {
+                let limit = device.inner.pdevice.limits().max_sampler_anisotropy;
+                self.max_anisotropy = Some(max_anisotropy.max(1.0).min(limit));
+            }
+        }
+        self
+    }
+
+    fn create_info(&self) -> vk::SamplerCreateInfo {
+        vk::SamplerCreateInfo::builder()
+            .mag_filter(self.mag_filter)
+            .min_filter(self.min_filter)
+            .mipmap_mode(self.mipmap_mode)
+            .address_mode_u(self.address_mode_u)
+            .address_mode_v(self.address_mode_v)
+            .address_mode_w(self.address_mode_w)
+            .mip_lod_bias(self.mip_lod_bias)
+            .anisotropy_enable(self.max_anisotropy.is_some())
+            .max_anisotropy(self.max_anisotropy.unwrap_or(1.0))
+            .compare_enable(self.compare_op.is_some())
+            .compare_op(self.compare_op.unwrap_or(vk::CompareOp::NEVER))
+            .min_lod(self.min_lod)
+            .max_lod(self.max_lod)
+            .border_color(self.border_color)
+            .unnormalized_coordinates(self.unnormalized_coordinates)
+            .build()
+    }
+}
+
+impl PartialEq for SamplerDesc {
+    fn eq(&self, other: &Self) -> bool {
+        self.mag_filter == other.mag_filter
+            && self.min_filter == other.min_filter
+            && self.mipmap_mode == other.mipmap_mode
+            && self.address_mode_u == other.address_mode_u
+            && self.address_mode_v == other.address_mode_v
+            && self.address_mode_w == other.address_mode_w
+            && self.mip_lod_bias.to_bits() == other.mip_lod_bias.to_bits()
+            && self.max_anisotropy.map(f32::to_bits) == other.max_anisotropy.map(f32::to_bits)
+            && self.compare_op == other.compare_op
+            && self.min_lod.to_bits() == other.min_lod.to_bits()
+            && self.max_lod.to_bits() == other.max_lod.to_bits()
+            && self.border_color == other.border_color
+            && self.unnormalized_coordinates == other.unnormalized_coordinates
+    }
+}
+
+impl Eq for SamplerDesc {}
+
+impl Hash for SamplerDesc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.mag_filter.hash(state);
+        self.min_filter.hash(state);
+        self.mipmap_mode.hash(state);
+        self.address_mode_u.hash(state);
+        self.address_mode_v.hash(state);
+        self.address_mode_w.hash(state);
+        self.mip_lod_bias.to_bits().hash(state);
+        self.max_anisotropy.map(f32::to_bits).hash(state);
+        self.compare_op.hash(state);
+        self.min_lod.to_bits().hash(state);
+        self.max_lod.to_bits().hash(state);
+        self.border_color.hash(state);
+        self.unnormalized_coordinates.hash(state);
+    }
+}
+
 pub(crate) struct SamplerRef {
     pub(crate) handle: vk::Sampler,
     device: Device,
     name: Option<String>,
+    desc: SamplerDesc,
 }
 
 #[derive(Clone)]
@@ -25,14 +200,20 @@ impl Sampler {
         address_mode_u: vk::SamplerAddressMode,
         address_mode_v: vk::SamplerAddressMode,
     ) -> Self {
-        let info = vk::SamplerCreateInfo::builder()
-            .mag_filter(mag_filter)
-            .min_filter(min_filter)
-            .address_mode_u(address_mode_u)
-            .address_mode_v(address_mode_v)
-            .build();
+        let desc = SamplerDesc::default()
+            .filter(mag_filter, min_filter)
+            .address_mode_uvw(address_mode_u, address_mode_v, address_mode_v);
+        Self::with_desc(device, name, &desc)
+    }
+
+    pub fn with_desc(device: Device, name: Option<&str>, desc: &SamplerDesc) -> Self {
+        let desc = desc.clamp_to_device(&device);
         unsafe {
-            let handle = device.inner.handle.create_sampler(&info, None).unwrap();
+            let handle = device
+                .inner
+                .handle
+                .create_sampler(&desc.create_info(), None)
+                .unwrap();
             if let Some(name) = name {
                 device.debug_set_object_name(name, handle.as_raw(), vk::ObjectType::SAMPLER);
             }
@@ -41,10 +222,15 @@ impl Sampler {
                     handle,
                     device,
                     name: name.map(|s| s.to_owned()),
+                    desc,
                 }),
             }
         }
     }
+
+    pub fn desc(&self) -> &SamplerDesc {
+        &self.inner.desc
+    }
 }
 
 impl Device {
@@ -65,12 +251,26 @@ impl Device {
             address_mode_v,
         )
     }
+
+    // Identical descriptions share one sampler for as long as it is alive.
+    pub fn create_sampler_with_desc(&self, name: Option<&str>, desc: &SamplerDesc) -> Sampler {
+        let desc = desc.clamp_to_device(self);
+        let mut cache = self.inner.sampler_cache.lock().unwrap();
+        if let Some(inner) = cache.get(&desc).and_then(|weak| weak.upgrade()) {
+            return Sampler { inner };
+        }
+        cache.retain(|_, weak| weak.strong_count() > 0);
+        let sampler = Sampler::with_desc(self.clone(), name, &desc);
+        cache.insert(desc, Arc::downgrade(&sampler.inner));
+        sampler
+    }
 }
 
 impl Drop for SamplerRef {
     fn drop(&mut self) {
-        unsafe {
-            self.device.inner.handle.destroy_sampler(self.handle, None);
-        }
+        let handle = self.handle;
+        self.device.destroy_deferred(move |device| unsafe {
+            device.handle.destroy_sampler(handle, None);
+        });
     }
 }
@@ -5,12 +5,13 @@ use ash::vk;
 use crate::Device;
 
 pub struct TimelineSemaphoreRef {
-    handle: vk::Semaphore,
+    pub(crate) handle: vk::Semaphore,
     device: Device,
 }
 
+#[derive(Clone)]
 pub struct TimelineSemaphore {
-    inner: Arc<TimelineSemaphoreRef>,
+    pub(crate) inner: Arc<TimelineSemaphoreRef>,
 }
 
 impl TimelineSemaphore {
@@ -1,12 +1,32 @@
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 
+use anyhow::{ensure, Result};
 use ash::vk;
 
+use crate::hot_reload::Reloadable;
 use crate::Device;
 
-pub(crate) struct ShaderModuleRef {
+const MAGIC: u32 = 0x0723_0203;
+
+// One version of a module's code. Pipeline creation holds on to it so a hot
+// reload can't destroy the handle underneath it.
+pub(crate) struct ShaderModuleCode {
     pub(crate) handle: vk::ShaderModule,
-    device: Device,
+    pub(crate) device: Device,
+    // Kept for reflection.
+    pub(crate) words: Vec<u32>,
+    pub(crate) entry_points: Vec<(String, vk::ShaderStageFlags)>,
+}
+
+pub(crate) struct ShaderModuleRef {
+    code: RwLock<Arc<ShaderModuleCode>>,
+    // Set for modules created from a file, which can be hot reloaded.
+    path: Option<PathBuf>,
+    // Pipelines to rebuild when the module is reloaded.
+    pub(crate) dependents: Mutex<Vec<Weak<dyn Reloadable>>>,
+    // Held through a reload so concurrent ones commit in order.
+    pub(crate) reload_lock: Mutex<()>,
 }
 
 #[derive(Clone)]
@@ -14,45 +34,117 @@ pub struct ShaderModule {
     pub(crate) inner: Arc<ShaderModuleRef>,
 }
 
-#[repr(C, align(32))]
-struct AlignedSpirv {
-    pub code: Vec<u8>,
+// Converts SPIR-V bytes of either endianness to host words and checks the
+// header and instruction lengths.
+fn decode_words(bytes: &[u8]) -> Result<Vec<u32>> {
+    ensure!(
+        bytes.len() % 4 == 0,
+        "SPIR-V length {} is not a multiple of 4",
+        bytes.len()
+    );
+    ensure!(
+        bytes.len() >= 20,
+        "SPIR-V module is shorter than its header"
+    );
+    let little_endian = bytes[..4] == MAGIC.to_le_bytes();
+    ensure!(
+        little_endian || bytes[..4] == MAGIC.to_be_bytes(),
+        "not a SPIR-V module, magic number is {:02x?}",
+        &bytes[..4]
+    );
+    let words = bytes
+        .chunks_exact(4)
+        .map(|word| {
+            let word = [word[0], word[1], word[2], word[3]];
+            if little_endian {
+                u32::from_le_bytes(word)
+            } else {
+                u32::from_be_bytes(word)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let version = words[1];
+    let major = (version >> 16) & 0xff;
+    let minor = (version >> 8) & 0xff;
+    ensure!(
+        version & 0xff00_00ff == 0 && major == 1 && minor <= 6,
+        "unsupported SPIR-V version {:#010x}",
+        version
+    );
+    crate::reflection::instructions(&words)?;
+    Ok(words)
+}
+
+impl ShaderModuleCode {
+    pub(crate) fn new(device: &Device, spv: &[u8]) -> Result<Arc<Self>> {
+        let words = decode_words(spv)?;
+        let entry_points = crate::reflection::entry_points(&words)?;
+        let info = vk::ShaderModuleCreateInfo::builder().code(&words).build();
+        unsafe {
+            let handle = device.inner.handle.create_shader_module(&info, None)?;
+            Ok(Arc::new(Self {
+                handle,
+                device: device.clone(),
+                words,
+                entry_points,
+            }))
+        }
+    }
 }
 
 impl ShaderModule {
-    pub(crate) fn new<P>(device: Device, spv: P) -> Self
+    pub(crate) fn new<P>(device: Device, spv: P, path: Option<PathBuf>) -> Result<Self>
     where
         P: AsRef<[u8]>,
     {
-        let aligned = AlignedSpirv {
-            code: spv.as_ref().to_vec(),
-        };
-        let info = vk::ShaderModuleCreateInfo::builder()
-            .code(bytemuck::cast_slice(aligned.code.as_slice()))
-            .build();
-        unsafe {
-            let handle = device
-                .inner
-                .handle
-                .create_shader_module(&info, None)
-                .unwrap();
-            Self {
-                inner: Arc::new(ShaderModuleRef { handle, device }),
-            }
-        }
+        let code = ShaderModuleCode::new(&device, spv.as_ref())?;
+        Ok(Self {
+            inner: Arc::new(ShaderModuleRef {
+                code: RwLock::new(code),
+                path,
+                dependents: Mutex::new(Vec::new()),
+                reload_lock: Mutex::new(()),
+            }),
+        })
+    }
+
+    // The current code, which changes when the module is hot reloaded.
+    pub(crate) fn code(&self) -> Arc<ShaderModuleCode> {
+        self.inner.code.read().unwrap().clone()
+    }
+
+    pub(crate) fn replace_code(&self, code: Arc<ShaderModuleCode>) {
+        *self.inner.code.write().unwrap() = code;
+    }
+
+    pub fn entry_points(&self) -> Vec<(String, vk::ShaderStageFlags)> {
+        self.code().entry_points.clone()
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.inner.path.as_deref()
     }
 }
 
 impl Device {
-    pub fn create_shader_module<P>(&self, spv: P) -> ShaderModule
+    // Accepts little and big endian modules.
+    pub fn create_shader_module<P>(&self, spv: P) -> Result<ShaderModule>
     where
         P: AsRef<[u8]>,
     {
-        ShaderModule::new(self.clone(), spv)
+        ShaderModule::new(self.clone(), spv, None)
+    }
+
+    // Modules created from a file can be watched by a ShaderReloader.
+    pub fn create_shader_module_from_file<P: AsRef<Path>>(&self, path: P) -> Result<ShaderModule> {
+        let path = path.as_ref();
+        let spv = std::fs::read(path)?;
+        ShaderModule::new(self.clone(), spv, Some(path.to_owned()))
     }
 }
 
-impl Drop for ShaderModuleRef {
+impl Drop for ShaderModuleCode {
     fn drop(&mut self) {
         unsafe {
             self.device
@@ -62,3 +154,35 @@ impl Drop for ShaderModuleRef {
         }
     }
 }
+
+#[test]
+fn test_decode_words() {
+    let path =
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/spirv/compute.spv");
+    let bytes = std::fs::read(path).unwrap();
+    let words = decode_words(&bytes).unwrap();
+    assert_eq!(words[0], MAGIC);
+    assert_eq!(
+        crate::reflection::entry_points(&words).unwrap(),
+        vec![("main".to_owned(), vk::ShaderStageFlags::COMPUTE)]
+    );
+
+    let big_endian = bytes
+        .chunks_exact(4)
+        .flat_map(|word| vec![word[3], word[2], word[1], word[0]])
+        .collect::<Vec<_>>();
+    assert_eq!(decode_words(&big_endian).unwrap(), words);
+
+    assert!(decode_words(&bytes[..bytes.len() - 2]).is_err());
+    assert!(decode_words(&bytes[..16]).is_err());
+    // Cuts the first instruction, OpCapability, in half.
+    assert!(decode_words(&bytes[..24]).is_err());
+
+    let mut bad_magic = bytes.clone();
+    bad_magic[0] = 0;
+    assert!(decode_words(&bad_magic).is_err());
+
+    let mut bad_version = bytes.clone();
+    bad_version[6] = 2;
+    assert!(decode_words(&bad_version).is_err());
+}
@@ -1,33 +1,368 @@
 use std::ffi::CString;
+use std::sync::Arc;
 
+use anyhow::{bail, ensure, Result};
 use ash::vk;
 
+use crate::hot_reload::CodeOverride;
+use crate::reflection::{ScalarKind, ShaderReflection};
+use crate::shader_module::ShaderModuleCode;
 use crate::ShaderModule;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpecializationValue {
+    // Passed as a VkBool32.
+    Bool(bool),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+}
+
+impl SpecializationValue {
+    fn bytes(self) -> [u8; 4] {
+        match self {
+            SpecializationValue::Bool(value) => (value as u32).to_ne_bytes(),
+            SpecializationValue::U32(value) => value.to_ne_bytes(),
+            SpecializationValue::I32(value) => value.to_ne_bytes(),
+            SpecializationValue::F32(value) => value.to_ne_bytes(),
+        }
+    }
+
+    fn kind(self) -> ScalarKind {
+        match self {
+            SpecializationValue::Bool(_) => ScalarKind::Bool,
+            SpecializationValue::U32(_) => ScalarKind::Int { signed: false },
+            SpecializationValue::I32(_) => ScalarKind::Int { signed: true },
+            SpecializationValue::F32(_) => ScalarKind::Float,
+        }
+    }
+}
+
+impl From<bool> for SpecializationValue {
+    fn from(value: bool) -> Self {
+        SpecializationValue::Bool(value)
+    }
+}
+
+impl From<u32> for SpecializationValue {
+    fn from(value: u32) -> Self {
+        SpecializationValue::U32(value)
+    }
+}
+
+impl From<i32> for SpecializationValue {
+    fn from(value: i32) -> Self {
+        SpecializationValue::I32(value)
+    }
+}
+
+impl From<f32> for SpecializationValue {
+    fn from(value: f32) -> Self {
+        SpecializationValue::F32(value)
+    }
+}
+
+// Owned specialization data, shared by clones of the stage so the pointers
+// handed to pipeline creation stay valid.
+struct Specialization {
+    entries: Vec<vk::SpecializationMapEntry>,
+    data: Vec<u8>,
+    // One per entry when set from typed values, None for raw data.
+    values: Option<Vec<SpecializationValue>>,
+}
+
 #[derive(Clone)]
 pub struct ShaderStage {
     module: ShaderModule,
     pub(crate) stage: vk::ShaderStageFlags,
     entry_point: String,
     entry_point_cstr: CString,
+    specialization: Option<Arc<Specialization>>,
+}
+
+// Checks map entries against the data and, if reflected, the module's constants.
+// Typed `values` must also match the kind of their constant.
+fn validate_specialization(
+    entries: &[vk::SpecializationMapEntry],
+    data_size: usize,
+    values: Option<&[SpecializationValue]>,
+    reflection: Option<&ShaderReflection>,
+) -> Result<()> {
+    for (i, entry) in entries.iter().enumerate() {
+        let end = (entry.offset as usize).checked_add(entry.size);
+        ensure!(
+            end.map_or(false, |end| end <= data_size),
+            "specialization constant {} reads past the {} bytes of data",
+            entry.constant_id,
+            data_size
+        );
+        ensure!(
+            entries[..i]
+                .iter()
+                .all(|other| other.constant_id != entry.constant_id),
+            "specialization constant {} is set twice",
+            entry.constant_id
+        );
+        if let Some(reflection) = reflection {
+            let constant = reflection
+                .specialization_constants
+                .iter()
+                .find(|constant| constant.id == entry.constant_id);
+            match constant {
+                Some(constant) => {
+                    ensure!(
+                        constant.size as usize == entry.size,
+                        "specialization constant {} has {} bytes, not {}",
+                        entry.constant_id,
+                        constant.size,
+                        entry.size
+                    );
+                    if let Some(value) = values.map(|values| values[i]) {
+                        ensure!(
+                            value.kind() == constant.kind,
+                            "specialization constant {} is {:?}, not {:?}",
+                            entry.constant_id,
+                            constant.kind,
+                            value
+                        );
+                    }
+                }
+                None => {
+                    bail!(
+                        "shader has no specialization constant {}",
+                        entry.constant_id
+                    )
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 impl ShaderStage {
-    pub fn new(module: &ShaderModule, stage: vk::ShaderStageFlags, entry_point: &str) -> Self {
-        let entry_point_cstr = CString::new(entry_point).unwrap();
-        Self {
+    // Fails if the module has no entry point of that name for `stage`.
+    pub fn new(
+        module: &ShaderModule,
+        stage: vk::ShaderStageFlags,
+        entry_point: &str,
+    ) -> Result<Self> {
+        let stages = module
+            .entry_points()
+            .iter()
+            .filter(|(name, _)| name == entry_point)
+            .map(|(_, stage)| *stage)
+            .collect::<Vec<_>>();
+        if stages.is_empty() {
+            bail!("shader module has no entry point {}", entry_point);
+        }
+        ensure!(
+            stages.contains(&stage),
+            "entry point {} is a {:?} shader, not {:?}",
+            entry_point,
+            stages,
+            stage
+        );
+        let entry_point_cstr = CString::new(entry_point)?;
+        Ok(Self {
             module: module.clone(),
             stage,
             entry_point: entry_point.to_string(),
             entry_point_cstr,
+            specialization: None,
+        })
+    }
+
+    // Sets the given constants, replacing earlier specialization.
+    pub fn with_specialization(self, constants: &[(u32, SpecializationValue)]) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut data = Vec::new();
+        for (constant_id, value) in constants {
+            entries.push(vk::SpecializationMapEntry {
+                constant_id: *constant_id,
+                offset: data.len() as u32,
+                size: 4,
+            });
+            data.extend_from_slice(&value.bytes());
+        }
+        let values = constants.iter().map(|(_, value)| *value).collect();
+        self.specialize(entries, data, Some(values))
+    }
+
+    // Sets constants from a struct, `entries` locate them inside `data`.
+    pub fn with_specialization_data<T: bytemuck::Pod>(
+        self,
+        data: &T,
+        entries: &[vk::SpecializationMapEntry],
+    ) -> Result<Self> {
+        self.specialize(entries.to_vec(), bytemuck::bytes_of(data).to_vec(), None)
+    }
+
+    fn specialize(
+        mut self,
+        entries: Vec<vk::SpecializationMapEntry>,
+        data: Vec<u8>,
+        values: Option<Vec<SpecializationValue>>,
+    ) -> Result<Self> {
+        self.specialization = Some(Arc::new(Specialization {
+            entries,
+            data,
+            values,
+        }));
+        self.validate_specialization(&self.module.code())?;
+        Ok(self)
+    }
+
+    // Constant ids are only checked against modules reflection can parse.
+    fn validate_specialization(&self, code: &ShaderModuleCode) -> Result<()> {
+        let specialization = match &self.specialization {
+            Some(specialization) => specialization,
+            None => return Ok(()),
+        };
+        let reflection = match crate::reflection::reflect(&code.words) {
+            Ok(reflection) => Some(reflection),
+            Err(err) => {
+                log::debug!("not validating specialization constants: {}", err);
+                None
+            }
+        };
+        validate_specialization(
+            &specialization.entries,
+            specialization.data.len(),
+            specialization.values.as_deref(),
+            reflection.as_ref(),
+        )
+    }
+
+    // Constant ids with their bytes, in the order they were given.
+    pub fn specialization_constants(&self) -> Vec<(u32, Vec<u8>)> {
+        match &self.specialization {
+            Some(specialization) => {
+                specialization
+                    .entries
+                    .iter()
+                    .map(|entry| {
+                        let offset = entry.offset as usize;
+                        (
+                            entry.constant_id,
+                            specialization.data[offset..offset + entry.size].to_vec(),
+                        )
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    // Points into data owned by the stage.
+    pub(crate) fn specialization_info(&self) -> Option<vk::SpecializationInfo> {
+        self.specialization.as_ref().map(|specialization| {
+            vk::SpecializationInfo::builder()
+                .map_entries(&specialization.entries)
+                .data(&specialization.data)
+                .build()
+        })
+    }
+
+    pub fn module(&self) -> &ShaderModule {
+        &self.module
+    }
+
+    pub fn stage(&self) -> vk::ShaderStageFlags {
+        self.stage
+    }
+
+    pub fn entry_point(&self) -> &str {
+        &self.entry_point
+    }
+
+    // The code to build a pipeline with, checked to still contain the entry point.
+    pub(crate) fn code(&self, reload: CodeOverride) -> Result<Arc<ShaderModuleCode>> {
+        let (code, reloaded) = match reload {
+            Some((module, code)) if Arc::ptr_eq(&module.inner, &self.module.inner) => {
+                (code.clone(), true)
+            }
+            _ => (self.module.code(), false),
+        };
+        ensure!(
+            code.entry_points
+                .iter()
+                .any(|(name, stage)| *name == self.entry_point && *stage == self.stage),
+            "shader module has no {:?} entry point {}",
+            self.stage,
+            self.entry_point
+        );
+        // The new code may have dropped constants checked by with_specialization.
+        if reloaded {
+            self.validate_specialization(&code)?;
         }
+        Ok(code)
     }
 
-    pub(crate) fn shader_stage_create_info(&self) -> vk::PipelineShaderStageCreateInfo {
-        vk::PipelineShaderStageCreateInfo::builder()
-            .module(self.module.inner.handle)
+    // `code` and `specialization_info` must be kept alive until the pipeline is created.
+    pub(crate) fn shader_stage_create_info(
+        &self,
+        code: &ShaderModuleCode,
+        specialization_info: Option<&vk::SpecializationInfo>,
+    ) -> vk::PipelineShaderStageCreateInfo {
+        let mut info = vk::PipelineShaderStageCreateInfo::builder()
+            .module(code.handle)
             .stage(self.stage)
             .name(&self.entry_point_cstr)
-            .build()
+            .build();
+        if let Some(specialization_info) = specialization_info {
+            info.p_specialization_info = specialization_info;
+        }
+        info
     }
 }
+
+#[test]
+fn test_validate_specialization() {
+    // compute_spec.spv declares a u32 with SpecId 0 and a bool with SpecId 1.
+    let reflection =
+        crate::reflection::reflect(&crate::reflection::fixture("compute_spec.spv")).unwrap();
+    let entry = |constant_id, offset, size| {
+        vk::SpecializationMapEntry {
+            constant_id,
+            offset,
+            size,
+        }
+    };
+    let both = [entry(0, 0, 4), entry(1, 4, 4)];
+    assert!(validate_specialization(&both, 8, None, Some(&reflection)).is_ok());
+    // Unknown id.
+    assert!(validate_specialization(&[entry(2, 0, 4)], 4, None, Some(&reflection)).is_err());
+    // A 64 bit value for a 32 bit constant.
+    assert!(validate_specialization(&[entry(0, 0, 8)], 8, None, Some(&reflection)).is_err());
+    // Out of bounds, checked even without reflection.
+    assert!(validate_specialization(&[entry(0, 4, 4)], 4, None, None).is_err());
+    assert!(validate_specialization(&[entry(0, u32::MAX, usize::MAX)], 4, None, None).is_err());
+    assert!(validate_specialization(&[entry(0, 0, 4), entry(0, 0, 4)], 4, None, None).is_err());
+    assert!(validate_specialization(&[entry(7, 0, 4)], 4, None, None).is_ok());
+
+    // Typed values must match the reflected kind.
+    let values = [
+        SpecializationValue::U32(64),
+        SpecializationValue::Bool(false),
+    ];
+    assert!(validate_specialization(&both, 8, Some(&values), Some(&reflection)).is_ok());
+    let values = [
+        SpecializationValue::F32(64.0),
+        SpecializationValue::Bool(false),
+    ];
+    assert!(validate_specialization(&both, 8, Some(&values), Some(&reflection)).is_err());
+    let values = [
+        SpecializationValue::I32(64),
+        SpecializationValue::Bool(false),
+    ];
+    assert!(validate_specialization(&both, 8, Some(&values), Some(&reflection)).is_err());
+    let values = [SpecializationValue::U32(64), SpecializationValue::U32(0)];
+    assert!(validate_specialization(&both, 8, Some(&values), Some(&reflection)).is_err());
+
+    assert_eq!(SpecializationValue::from(true).bytes(), 1u32.to_ne_bytes());
+    assert_eq!(SpecializationValue::from(-1i32).bytes(), [0xff; 4]);
+    assert_eq!(
+        SpecializationValue::from(1.0f32).bytes(),
+        1.0f32.to_ne_bytes()
+    );
+}
@@ -0,0 +1,302 @@
+#!/usr/bin/env python3
+# Assembles the SPIR-V fixtures used by the reflection and pipeline tests.
+# They are written by hand, instead of compiled from GLSL, so the ids,
+# decorations and layouts the tests expect are spelled out below and don't
+# change with the compiler version. See README.md for the GLSL equivalents.
+#
+#     python3 tests/fixtures/spirv/generate.py
+import os
+import struct
+import sys
+
+# Opcodes
+NAME = 5
+EXTENSION = 10
+MEMORY_MODEL = 14
+ENTRY_POINT = 15
+EXECUTION_MODE = 16
+CAPABILITY = 17
+TYPE_VOID = 19
+TYPE_BOOL = 20
+TYPE_INT = 21
+TYPE_FLOAT = 22
+TYPE_VECTOR = 23
+TYPE_MATRIX = 24
+TYPE_IMAGE = 25
+TYPE_SAMPLER = 26
+TYPE_ARRAY = 28
+TYPE_RUNTIME_ARRAY = 29
+TYPE_STRUCT = 30
+TYPE_POINTER = 32
+TYPE_FUNCTION = 33
+CONSTANT = 43
+SPEC_CONSTANT_TRUE = 48
+SPEC_CONSTANT = 50
+SPEC_CONSTANT_COMPOSITE = 51
+FUNCTION = 54
+FUNCTION_END = 56
+VARIABLE = 59
+DECORATE = 71
+MEMBER_DECORATE = 72
+LABEL = 248
+RETURN = 253
+TYPE_ACCELERATION_STRUCTURE = 5341
+
+# Decorations
+SPEC_ID = 1
+BLOCK = 2
+ARRAY_STRIDE = 6
+MATRIX_STRIDE = 7
+BUILT_IN = 11
+BINDING = 33
+DESCRIPTOR_SET = 34
+OFFSET = 35
+COL_MAJOR = 5
+
+# Storage classes
+UNIFORM_CONSTANT = 0
+UNIFORM = 2
+PUSH_CONSTANT = 9
+STORAGE_BUFFER = 12
+
+# Execution models and modes
+VERTEX = 0
+FRAGMENT = 4
+GL_COMPUTE = 5
+RAY_GENERATION = 5313
+ORIGIN_UPPER_LEFT = 7
+LOCAL_SIZE = 17
+BUILT_IN_WORKGROUP_SIZE = 25
+
+# Capabilities
+SHADER = 1
+RUNTIME_DESCRIPTOR_ARRAY = 5302
+RAY_TRACING = 4479
+
+
+def string(text):
+    data = text.encode() + b"\0"
+    data += b"\0" * (-len(data) % 4)
+    return list(struct.unpack("<%dI" % (len(data) // 4), data))
+
+
+class Module:
+    def __init__(self, capabilities=(SHADER,), extensions=()):
+        self.words = []
+        self.bound = 1
+        for capability in capabilities:
+            self.op(CAPABILITY, capability)
+        for extension in extensions:
+            self.op(EXTENSION, string(extension))
+        # Logical addressing, GLSL450 memory model.
+        self.op(MEMORY_MODEL, 0, 1)
+
+    def ids(self, count):
+        ids = list(range(self.bound, self.bound + count))
+        self.bound += count
+        return ids
+
+    def op(self, opcode, *operands):
+        words = []
+        for operand in operands:
+            words += operand if isinstance(operand, list) else [operand]
+        self.words.append((len(words) + 1) << 16 | opcode)
+        self.words += words
+
+    # An empty `void main()`.
+    def main(self, main, void, function_type):
+        self.op(FUNCTION, void, main, 0, function_type)
+        (label,) = self.ids(1)
+        self.op(LABEL, label)
+        self.op(RETURN)
+        self.op(FUNCTION_END)
+
+    def write(self, path):
+        words = [0x07230203, 0x00010500, 0, self.bound, 0] + self.words
+        with open(path, "wb") as f:
+            f.write(struct.pack("<%dI" % len(words), *words))
+
+
+def compute(path):
+    m = Module()
+    (main, void, fn, f32, u32, vec4, runtime_array, particles_t, particles_p, particles,
+     params_t, params_p, params, image, four, images_t, images_p, images, push_t, push_p,
+     push) = m.ids(21)
+    m.op(ENTRY_POINT, GL_COMPUTE, main, string("main"))
+    m.op(EXECUTION_MODE, main, LOCAL_SIZE, 8, 4, 1)
+    m.op(NAME, particles, string("particles"))
+    m.op(NAME, params, string("params"))
+    m.op(NAME, images, string("images"))
+    m.op(NAME, push, string("push"))
+    m.op(DECORATE, runtime_array, ARRAY_STRIDE, 16)
+    m.op(MEMBER_DECORATE, particles_t, 0, OFFSET, 0)
+    m.op(DECORATE, particles_t, BLOCK)
+    m.op(DECORATE, particles, DESCRIPTOR_SET, 0)
+    m.op(DECORATE, particles, BINDING, 0)
+    m.op(MEMBER_DECORATE, params_t, 0, OFFSET, 0)
+    m.op(MEMBER_DECORATE, params_t, 1, OFFSET, 16)
+    m.op(DECORATE, params_t, BLOCK)
+    m.op(DECORATE, params, DESCRIPTOR_SET, 0)
+    m.op(DECORATE, params, BINDING, 1)
+    m.op(DECORATE, images, DESCRIPTOR_SET, 1)
+    m.op(DECORATE, images, BINDING, 0)
+    m.op(MEMBER_DECORATE, push_t, 0, OFFSET, 0)
+    m.op(MEMBER_DECORATE, push_t, 1, OFFSET, 16)
+    m.op(DECORATE, push_t, BLOCK)
+    m.op(TYPE_VOID, void)
+    m.op(TYPE_FUNCTION, fn, void)
+    m.op(TYPE_FLOAT, f32, 32)
+    m.op(TYPE_INT, u32, 32, 0)
+    m.op(TYPE_VECTOR, vec4, f32, 4)
+    m.op(TYPE_RUNTIME_ARRAY, runtime_array, vec4)
+    m.op(TYPE_STRUCT, particles_t, runtime_array)
+    m.op(TYPE_POINTER, particles_p, STORAGE_BUFFER, particles_t)
+    m.op(VARIABLE, particles_p, particles, STORAGE_BUFFER)
+    m.op(TYPE_STRUCT, params_t, vec4, u32)
+    m.op(TYPE_POINTER, params_p, UNIFORM, params_t)
+    m.op(VARIABLE, params_p, params, UNIFORM)
+    # 2D, not depth, not arrayed, single sampled, storage, rgba32f.
+    m.op(TYPE_IMAGE, image, f32, 1, 0, 0, 0, 2, 1)
+    m.op(CONSTANT, u32, four, 4)
+    m.op(TYPE_ARRAY, images_t, image, four)
+    m.op(TYPE_POINTER, images_p, UNIFORM_CONSTANT, images_t)
+    m.op(VARIABLE, images_p, images, UNIFORM_CONSTANT)
+    m.op(TYPE_STRUCT, push_t, vec4, vec4)
+    m.op(TYPE_POINTER, push_p, PUSH_CONSTANT, push_t)
+    m.op(VARIABLE, push_p, push, PUSH_CONSTANT)
+    m.main(main, void, fn)
+    m.write(path)
+
+
+def compute_spec(path):
+    m = Module()
+    main, void, fn, u32, boolean, uvec3, group_size, fast_path, one, workgroup_size = m.ids(10)
+    m.op(ENTRY_POINT, GL_COMPUTE, main, string("main"))
+    m.op(EXECUTION_MODE, main, LOCAL_SIZE, 1, 1, 1)
+    m.op(NAME, group_size, string("group_size"))
+    m.op(NAME, fast_path, string("use_fast_path"))
+    m.op(DECORATE, group_size, SPEC_ID, 0)
+    m.op(DECORATE, fast_path, SPEC_ID, 1)
+    m.op(DECORATE, workgroup_size, BUILT_IN, BUILT_IN_WORKGROUP_SIZE)
+    m.op(TYPE_VOID, void)
+    m.op(TYPE_FUNCTION, fn, void)
+    m.op(TYPE_INT, u32, 32, 0)
+    m.op(TYPE_BOOL, boolean)
+    m.op(TYPE_VECTOR, uvec3, u32, 3)
+    m.op(SPEC_CONSTANT, u32, group_size, 64)
+    m.op(SPEC_CONSTANT_TRUE, boolean, fast_path)
+    m.op(CONSTANT, u32, one, 1)
+    m.op(SPEC_CONSTANT_COMPOSITE, uvec3, workgroup_size, group_size, one, one)
+    m.main(main, void, fn)
+    m.write(path)
+
+
+def raygen(path):
+    m = Module(capabilities=(RAY_TRACING,), extensions=("SPV_KHR_ray_tracing",))
+    main, void, fn, f32, accel, tlas_p, tlas, image, output_p, output = m.ids(10)
+    m.op(ENTRY_POINT, RAY_GENERATION, main, string("main"), tlas, output)
+    m.op(NAME, tlas, string("tlas"))
+    m.op(NAME, output, string("output"))
+    m.op(DECORATE, tlas, DESCRIPTOR_SET, 0)
+    m.op(DECORATE, tlas, BINDING, 0)
+    m.op(DECORATE, output, DESCRIPTOR_SET, 0)
+    m.op(DECORATE, output, BINDING, 1)
+    m.op(TYPE_VOID, void)
+    m.op(TYPE_FUNCTION, fn, void)
+    m.op(TYPE_FLOAT, f32, 32)
+    m.op(TYPE_ACCELERATION_STRUCTURE, accel)
+    m.op(TYPE_POINTER, tlas_p, UNIFORM_CONSTANT, accel)
+    m.op(VARIABLE, tlas_p, tlas, UNIFORM_CONSTANT)
+    m.op(TYPE_IMAGE, image, f32, 1, 0, 0, 0, 2, 1)
+    m.op(TYPE_POINTER, output_p, UNIFORM_CONSTANT, image)
+    m.op(VARIABLE, output_p, output, UNIFORM_CONSTANT)
+    m.main(main, void, fn)
+    m.write(path)
+
+
+def vertex(path):
+    m = Module()
+    main, void, fn, f32, vec4, mat4, camera_t, camera_p, camera, push_t, push_p, push = m.ids(12)
+    m.op(ENTRY_POINT, VERTEX, main, string("main"))
+    m.op(NAME, camera, string("camera"))
+    m.op(NAME, push, string("push"))
+    m.op(MEMBER_DECORATE, camera_t, 0, COL_MAJOR)
+    m.op(MEMBER_DECORATE, camera_t, 0, OFFSET, 0)
+    m.op(MEMBER_DECORATE, camera_t, 0, MATRIX_STRIDE, 16)
+    m.op(DECORATE, camera_t, BLOCK)
+    m.op(DECORATE, camera, DESCRIPTOR_SET, 0)
+    m.op(DECORATE, camera, BINDING, 0)
+    m.op(MEMBER_DECORATE, push_t, 0, COL_MAJOR)
+    m.op(MEMBER_DECORATE, push_t, 0, OFFSET, 0)
+    m.op(MEMBER_DECORATE, push_t, 0, MATRIX_STRIDE, 16)
+    m.op(DECORATE, push_t, BLOCK)
+    m.op(TYPE_VOID, void)
+    m.op(TYPE_FUNCTION, fn, void)
+    m.op(TYPE_FLOAT, f32, 32)
+    m.op(TYPE_VECTOR, vec4, f32, 4)
+    m.op(TYPE_MATRIX, mat4, vec4, 4)
+    m.op(TYPE_STRUCT, camera_t, mat4)
+    m.op(TYPE_POINTER, camera_p, UNIFORM, camera_t)
+    m.op(VARIABLE, camera_p, camera, UNIFORM)
+    m.op(TYPE_STRUCT, push_t, mat4)
+    m.op(TYPE_POINTER, push_p, PUSH_CONSTANT, push_t)
+    m.op(VARIABLE, push_p, push, PUSH_CONSTANT)
+    m.main(main, void, fn)
+    m.write(path)
+
+
+def fragment(path):
+    m = Module(
+        capabilities=(SHADER, RUNTIME_DESCRIPTOR_ARRAY),
+        extensions=("SPV_EXT_descriptor_indexing",),
+    )
+    (main, void, fn, f32, vec4, mat4, camera_t, camera_p, camera, image, textures_t,
+     textures_p, textures, sampler_t, sampler_p, sampler, push_t, push_p, push) = m.ids(19)
+    m.op(ENTRY_POINT, FRAGMENT, main, string("main"))
+    m.op(EXECUTION_MODE, main, ORIGIN_UPPER_LEFT)
+    m.op(NAME, camera, string("camera"))
+    m.op(NAME, textures, string("textures"))
+    m.op(NAME, sampler, string("linear_sampler"))
+    m.op(NAME, push, string("push"))
+    m.op(MEMBER_DECORATE, camera_t, 0, COL_MAJOR)
+    m.op(MEMBER_DECORATE, camera_t, 0, OFFSET, 0)
+    m.op(MEMBER_DECORATE, camera_t, 0, MATRIX_STRIDE, 16)
+    m.op(DECORATE, camera_t, BLOCK)
+    m.op(DECORATE, camera, DESCRIPTOR_SET, 0)
+    m.op(DECORATE, camera, BINDING, 0)
+    m.op(DECORATE, textures, DESCRIPTOR_SET, 1)
+    m.op(DECORATE, textures, BINDING, 0)
+    m.op(DECORATE, sampler, DESCRIPTOR_SET, 1)
+    m.op(DECORATE, sampler, BINDING, 1)
+    m.op(MEMBER_DECORATE, push_t, 0, OFFSET, 64)
+    m.op(DECORATE, push_t, BLOCK)
+    m.op(TYPE_VOID, void)
+    m.op(TYPE_FUNCTION, fn, void)
+    m.op(TYPE_FLOAT, f32, 32)
+    m.op(TYPE_VECTOR, vec4, f32, 4)
+    m.op(TYPE_MATRIX, mat4, vec4, 4)
+    m.op(TYPE_STRUCT, camera_t, mat4)
+    m.op(TYPE_POINTER, camera_p, UNIFORM, camera_t)
+    m.op(VARIABLE, camera_p, camera, UNIFORM)
+    # 2D, not depth, not arrayed, single sampled, sampled, unknown format.
+    m.op(TYPE_IMAGE, image, f32, 1, 0, 0, 0, 1, 0)
+    m.op(TYPE_RUNTIME_ARRAY, textures_t, image)
+    m.op(TYPE_POINTER, textures_p, UNIFORM_CONSTANT, textures_t)
+    m.op(VARIABLE, textures_p, textures, UNIFORM_CONSTANT)
+    m.op(TYPE_SAMPLER, sampler_t)
+    m.op(TYPE_POINTER, sampler_p, UNIFORM_CONSTANT, sampler_t)
+    m.op(VARIABLE, sampler_p, sampler, UNIFORM_CONSTANT)
+    m.op(TYPE_STRUCT, push_t, vec4)
+    m.op(TYPE_POINTER, push_p, PUSH_CONSTANT, push_t)
+    m.op(VARIABLE, push_p, push, PUSH_CONSTANT)
+    m.main(main, void, fn)
+    m.write(path)
+
+
+if __name__ == "__main__":
+    out = sys.argv[1] if len(sys.argv) > 1 else os.path.dirname(os.path.abspath(__file__))
+    compute(os.path.join(out, "compute.spv"))
+    compute_spec(os.path.join(out, "compute_spec.spv"))
+    raygen(os.path.join(out, "raygen.spv"))
+    vertex(os.path.join(out, "vertex.spv"))
+    fragment(os.path.join(out, "fragment.spv"))
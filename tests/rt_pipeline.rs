@@ -22,6 +22,7 @@ struct Engine {
     descriptor_set: maligog::DescriptorSet,
     pipeline: maligog::RayTracingPipeline,
     shader_binding_tables: maligog::ShaderBindingTables,
+    render_finished_semaphore: maligog::BinarySemaphore,
 }
 
 impl Engine {
@@ -110,16 +111,19 @@ impl Engine {
         let module_path = result.module.unwrap_single();
         let module = std::fs::read(module_path).unwrap();
 
-        let module = device.create_shader_module(module);
+        let module = device.create_shader_module(module).unwrap();
         let rg_stage =
-            maligog::ShaderStage::new(&module, maligog::ShaderStageFlags::RAYGEN_KHR, "main");
+            maligog::ShaderStage::new(&module, maligog::ShaderStageFlags::RAYGEN_KHR, "main")
+                .unwrap();
         let hit_stage = maligog::ShaderStage::new(
             &module,
             maligog::ShaderStageFlags::CLOSEST_HIT_KHR,
             "closest_hit",
-        );
+        )
+        .unwrap();
         let miss_stage =
-            maligog::ShaderStage::new(&module, maligog::ShaderStageFlags::MISS_KHR, "miss");
+            maligog::ShaderStage::new(&module, maligog::ShaderStageFlags::MISS_KHR, "miss")
+                .unwrap();
         let tri_hg = maligog::TrianglesHitGroup::new(&hit_stage, None);
         let pipeline = device.create_ray_tracing_pipeline(
             Some("a rt pipeline"),
@@ -131,6 +135,8 @@ impl Engine {
         );
         let shader_binding_tables = pipeline.create_shader_binding_tables(&[0]);
 
+        let render_finished_semaphore = maligog::BinarySemaphore::new(&device);
+
         device.wait_idle();
 
         Self {
@@ -141,6 +147,7 @@ impl Engine {
             descriptor_set,
             shader_binding_tables,
             pipeline,
+            render_finished_semaphore,
         }
     }
 
@@ -148,70 +155,97 @@ impl Engine {
         let index = self.swapchain.acquire_next_image().unwrap();
         let present_img = self.swapchain.get_image(index);
 
-        let mut cmd_buf = self.device.create_command_buffer(
-            Some("main cmdbuf"),
-            self.device.graphics_queue_family_index(),
-        );
-        cmd_buf.encode(|rec| {
-            rec.bind_ray_tracing_pipeline(&self.pipeline, |rec| {
-                rec.bind_descriptor_sets(vec![&self.descriptor_set], 0);
-                rec.trace_ray(
-                    &self.shader_binding_tables.ray_gen_table(),
-                    &self.shader_binding_tables.miss_table(),
-                    &self.shader_binding_tables.hit_table(),
-                    &self.shader_binding_tables.callable_table(),
-                    self.image.width(),
-                    self.image.height(),
-                    1,
-                );
+        let mut graph = maligog::RenderGraph::new();
+        let storage = graph.import_image(&self.image);
+        let present = graph.import_image(&present_img);
+        graph.set_final_layout(present, vk::ImageLayout::PRESENT_SRC_KHR);
+        graph.wait_semaphore(present, &self.swapchain.image_available_semaphore());
+        graph.signal_semaphore(present, &self.render_finished_semaphore);
+
+        graph
+            .add_pass("trace", maligog::QueueType::Graphics)
+            .image(
+                storage,
+                maligog::Access::ShaderWrite(maligog::PipelineStage::RayTracing),
+                vk::ImageLayout::GENERAL,
+            )
+            .record(move |rec, _| {
+                rec.bind_ray_tracing_pipeline(&self.pipeline, |rec| {
+                    rec.bind_descriptor_sets(vec![&self.descriptor_set], 0);
+                    rec.trace_ray(
+                        &self.shader_binding_tables.ray_gen_table(),
+                        &self.shader_binding_tables.miss_table(),
+                        &self.shader_binding_tables.hit_table(),
+                        &self.shader_binding_tables.callable_table(),
+                        self.image.width(),
+                        self.image.height(),
+                        1,
+                    );
+                });
             });
 
-            rec.blit_image(
-                &self.image,
-                maligog::ImageLayout::GENERAL,
-                &present_img,
+        graph
+            .add_pass("blit", maligog::QueueType::Graphics)
+            .image(
+                storage,
+                maligog::Access::TransferRead,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            )
+            .image(
+                present,
+                maligog::Access::TransferWrite,
                 vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                &[vk::ImageBlit::builder()
-                    .src_subresource(
-                        vk::ImageSubresourceLayers::builder()
-                            .aspect_mask(vk::ImageAspectFlags::COLOR)
-                            .layer_count(1)
-                            .base_array_layer(0)
-                            .mip_level(0)
-                            .build(),
-                    )
-                    .src_offsets([
-                        vk::Offset3D { x: 0, y: 0, z: 0 },
-                        vk::Offset3D {
-                            x: self.image.width() as i32,
-                            y: self.image.height() as i32,
-                            z: 1,
-                        },
-                    ])
-                    .dst_offsets([
-                        vk::Offset3D { x: 0, y: 0, z: 0 },
-                        vk::Offset3D {
-                            x: present_img.width() as i32,
-                            y: present_img.height() as i32,
-                            z: 1,
-                        },
-                    ])
-                    .dst_subresource(
-                        vk::ImageSubresourceLayers::builder()
-                            .aspect_mask(vk::ImageAspectFlags::COLOR)
-                            .layer_count(1)
-                            .base_array_layer(0)
-                            .mip_level(0)
-                            .build(),
-                    )
-                    .build()],
-                vk::Filter::NEAREST,
-            );
-        });
-        self.device.graphics_queue().submit_blocking(&[cmd_buf]);
+            )
+            .record(move |rec, resources| {
+                let src = resources.image(storage);
+                let dst = resources.image(present);
+                rec.blit_image(
+                    src,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    dst,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[vk::ImageBlit::builder()
+                        .src_subresource(
+                            vk::ImageSubresourceLayers::builder()
+                                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                                .layer_count(1)
+                                .base_array_layer(0)
+                                .mip_level(0)
+                                .build(),
+                        )
+                        .src_offsets([
+                            vk::Offset3D { x: 0, y: 0, z: 0 },
+                            vk::Offset3D {
+                                x: src.width() as i32,
+                                y: src.height() as i32,
+                                z: 1,
+                            },
+                        ])
+                        .dst_offsets([
+                            vk::Offset3D { x: 0, y: 0, z: 0 },
+                            vk::Offset3D {
+                                x: dst.width() as i32,
+                                y: dst.height() as i32,
+                                z: 1,
+                            },
+                        ])
+                        .dst_subresource(
+                            vk::ImageSubresourceLayers::builder()
+                                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                                .layer_count(1)
+                                .base_array_layer(0)
+                                .mip_level(0)
+                                .build(),
+                        )
+                        .build()],
+                    vk::Filter::NEAREST,
+                );
+            });
+
+        graph.execute(&self.device);
 
         self.swapchain
-            .present(index, &[&self.swapchain.image_available_semaphore()]);
+            .present(index, &[&self.render_finished_semaphore]);
     }
 }
 